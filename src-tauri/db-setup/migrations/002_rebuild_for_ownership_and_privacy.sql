@@ -0,0 +1,969 @@
+-- Moves version 1 databases to the schema the app now uses: admin accounts,
+-- device names and retirement, entities owned by a user, note embeddings in
+-- their own table, and the search, consent, encryption, deletion, privacy
+-- zone, redaction and audit tables.
+--
+-- DuckDB can't alter or drop a table other tables reference, and every
+-- version 1 table hangs off users or devices, so they are copied aside,
+-- dropped and created again, and their rows are put back.
+
+CREATE TEMP TABLE v1_users AS SELECT * FROM users;
+CREATE TEMP TABLE v1_oauth_accounts AS SELECT * FROM oauth_accounts;
+CREATE TEMP TABLE v1_sessions AS SELECT * FROM sessions;
+CREATE TEMP TABLE v1_devices AS SELECT * FROM devices;
+CREATE TEMP TABLE v1_accelerometer_data AS SELECT * FROM accelerometer_data;
+CREATE TEMP TABLE v1_gyroscope_data AS SELECT * FROM gyroscope_data;
+CREATE TEMP TABLE v1_magnetometer_data AS SELECT * FROM magnetometer_data;
+CREATE TEMP TABLE v1_gps_data AS SELECT * FROM gps_data;
+CREATE TEMP TABLE v1_heart_rate_data AS SELECT * FROM heart_rate_data;
+CREATE TEMP TABLE v1_proximity_data AS SELECT * FROM proximity_data;
+CREATE TEMP TABLE v1_light_data AS SELECT * FROM light_data;
+CREATE TEMP TABLE v1_pressure_data AS SELECT * FROM pressure_data;
+CREATE TEMP TABLE v1_temperature_data AS SELECT * FROM temperature_data;
+CREATE TEMP TABLE v1_humidity_data AS SELECT * FROM humidity_data;
+CREATE TEMP TABLE v1_step_count_data AS SELECT * FROM step_count_data;
+CREATE TEMP TABLE v1_call_log_data AS SELECT * FROM call_log_data;
+CREATE TEMP TABLE v1_todos_data AS SELECT * FROM todos_data;
+CREATE TEMP TABLE v1_audio_level_data AS SELECT * FROM audio_level_data;
+CREATE TEMP TABLE v1_battery_data AS SELECT * FROM battery_data;
+CREATE TEMP TABLE v1_network_data AS SELECT * FROM network_data;
+CREATE TEMP TABLE v1_screen_state_data AS SELECT * FROM screen_state_data;
+CREATE TEMP TABLE v1_notification_data AS SELECT * FROM notification_data;
+CREATE TEMP TABLE v1_app_usage_data AS SELECT * FROM app_usage_data;
+CREATE TEMP TABLE v1_wifi_data AS SELECT * FROM wifi_data;
+CREATE TEMP TABLE v1_bluetooth_data AS SELECT * FROM bluetooth_data;
+CREATE TEMP TABLE v1_camera_data AS SELECT * FROM camera_data;
+CREATE TEMP TABLE v1_microphone_data AS SELECT * FROM microphone_data;
+CREATE TEMP TABLE v1_app_event_data AS SELECT * FROM app_event_data;
+CREATE TEMP TABLE v1_system_audio_data AS SELECT * FROM system_audio_data;
+CREATE TEMP TABLE v1_network_speed_data AS SELECT * FROM network_speed_data;
+CREATE TEMP TABLE v1_server_latency_data AS SELECT * FROM server_latency_data;
+CREATE TEMP TABLE v1_skin_temperature_data AS SELECT * FROM skin_temperature_data;
+CREATE TEMP TABLE v1_ecg_data AS SELECT * FROM ecg_data;
+CREATE TEMP TABLE v1_blood_oxygen_data AS SELECT * FROM blood_oxygen_data;
+CREATE TEMP TABLE v1_stress_data AS SELECT * FROM stress_data;
+CREATE TEMP TABLE v1_compass_data AS SELECT * FROM compass_data;
+CREATE TEMP TABLE v1_screen_details_data AS SELECT * FROM screen_details_data;
+CREATE TEMP TABLE v1_object_detection_data AS SELECT * FROM object_detection_data;
+CREATE TEMP TABLE v1_face_recognition_data AS SELECT * FROM face_recognition_data;
+CREATE TEMP TABLE v1_pose_detection_data AS SELECT * FROM pose_detection_data;
+CREATE TEMP TABLE v1_known_entities AS SELECT * FROM known_entities;
+CREATE TEMP TABLE v1_notes AS SELECT * FROM notes;
+CREATE TEMP TABLE v1_note_references AS SELECT * FROM note_references;
+
+DROP TABLE note_references;
+DROP TABLE notes;
+DROP TABLE known_entities;
+DROP TABLE pose_detection_data;
+DROP TABLE face_recognition_data;
+DROP TABLE object_detection_data;
+DROP TABLE screen_details_data;
+DROP TABLE compass_data;
+DROP TABLE stress_data;
+DROP TABLE blood_oxygen_data;
+DROP TABLE ecg_data;
+DROP TABLE skin_temperature_data;
+DROP TABLE server_latency_data;
+DROP TABLE network_speed_data;
+DROP TABLE system_audio_data;
+DROP TABLE app_event_data;
+DROP TABLE microphone_data;
+DROP TABLE camera_data;
+DROP TABLE bluetooth_data;
+DROP TABLE wifi_data;
+DROP TABLE app_usage_data;
+DROP TABLE notification_data;
+DROP TABLE screen_state_data;
+DROP TABLE network_data;
+DROP TABLE battery_data;
+DROP TABLE audio_level_data;
+DROP TABLE todos_data;
+DROP TABLE call_log_data;
+DROP TABLE step_count_data;
+DROP TABLE humidity_data;
+DROP TABLE temperature_data;
+DROP TABLE pressure_data;
+DROP TABLE light_data;
+DROP TABLE proximity_data;
+DROP TABLE heart_rate_data;
+DROP TABLE gps_data;
+DROP TABLE magnetometer_data;
+DROP TABLE gyroscope_data;
+DROP TABLE accelerometer_data;
+DROP TABLE devices;
+DROP TABLE sessions;
+DROP TABLE oauth_accounts;
+DROP TABLE users;
+
+-- User Management Tables
+CREATE TABLE IF NOT EXISTS users (
+    id VARCHAR PRIMARY KEY,
+    email VARCHAR UNIQUE NOT NULL,
+    name VARCHAR,
+    encrypted_password VARCHAR NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- Set for the first account; only it may encrypt, lock or back up the
+    -- whole database.
+    is_admin BOOLEAN NOT NULL DEFAULT false
+);
+
+CREATE TABLE IF NOT EXISTS oauth_accounts (
+    id VARCHAR PRIMARY KEY,
+    user_id VARCHAR NOT NULL REFERENCES users(id),
+    provider VARCHAR NOT NULL,
+    provider_user_id VARCHAR NOT NULL,
+    access_token VARCHAR NOT NULL,
+    refresh_token VARCHAR,
+    expires_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE(provider, provider_user_id)
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    id VARCHAR PRIMARY KEY,
+    user_id VARCHAR NOT NULL REFERENCES users(id),
+    token VARCHAR NOT NULL UNIQUE,
+    expires_at TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS devices (
+    device_id VARCHAR PRIMARY KEY,
+    user_id VARCHAR NOT NULL REFERENCES users(id),
+    device_type VARCHAR NOT NULL,
+    os_type VARCHAR NOT NULL,
+    os_version VARCHAR NOT NULL,
+    app_version VARCHAR NOT NULL,
+    -- JSON rather than VARCHAR[]: DuckDB rewrites list updates as
+    -- delete+insert, which the sensor tables' references would block.
+    available_sensors JSON,
+    capabilities JSON,
+    name VARCHAR,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_seen TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- Set when a device is retired or merged into its replacement. Its data
+    -- stays readable but it no longer shows up as an active device.
+    retired_at TIMESTAMP
+);
+
+-- Sensor Data Tables
+CREATE TABLE IF NOT EXISTS accelerometer_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    x FLOAT,
+    y FLOAT,
+    z FLOAT,
+    accuracy FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS gyroscope_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    x FLOAT,
+    y FLOAT,
+    z FLOAT,
+    accuracy FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS magnetometer_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    x FLOAT,
+    y FLOAT,
+    z FLOAT,
+    accuracy FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS gps_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    latitude DOUBLE PRECISION,
+    longitude DOUBLE PRECISION,
+    altitude DOUBLE PRECISION,
+    accuracy FLOAT,
+    speed FLOAT,
+    bearing FLOAT,
+    satellites INTEGER,
+    provider VARCHAR,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS heart_rate_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    bpm INTEGER,
+    confidence FLOAT,
+    rr_intervals FLOAT[],
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS proximity_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    distance FLOAT,
+    near BOOLEAN,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS light_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    lux FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS pressure_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    hectopascals FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS temperature_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    celsius FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS humidity_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    percentage FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS step_count_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    steps INTEGER,
+    activity_type VARCHAR,
+    confidence FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS call_log_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    call_type VARCHAR NOT NULL,
+    phone_number VARCHAR,
+    contact_name VARCHAR,
+    duration_seconds INTEGER,
+    is_missed BOOLEAN,
+    is_blocked BOOLEAN,
+    sim_slot INTEGER,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS todos_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    todo_id VARCHAR NOT NULL,
+    title VARCHAR NOT NULL,
+    description TEXT,
+    due_date TIMESTAMP,
+    completed BOOLEAN DEFAULT FALSE,
+    completed_at TIMESTAMP,
+    priority INTEGER,
+    tags VARCHAR[],
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id, todo_id)
+);
+
+CREATE TABLE IF NOT EXISTS audio_level_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    db FLOAT,
+    peak_db FLOAT,
+    volume FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS battery_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    percentage INTEGER,
+    charging BOOLEAN,
+    power_source VARCHAR,
+    temperature INTEGER,
+    voltage INTEGER,
+    current INTEGER,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS network_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    type connection_type,
+    state VARCHAR,
+    strength INTEGER,
+    carrier VARCHAR,
+    roaming BOOLEAN,
+    cellular_technology VARCHAR,
+    is_metered BOOLEAN,
+    dns_servers VARCHAR[],
+    gateway VARCHAR,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS screen_state_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    screen_on BOOLEAN,
+    brightness INTEGER,
+    orientation VARCHAR,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS notification_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    package_name VARCHAR,
+    title VARCHAR,
+    priority INTEGER,
+    category VARCHAR,
+    posted_at TIMESTAMP,
+    removed_at TIMESTAMP,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS app_usage_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    package_name VARCHAR,
+    start_time TIMESTAMP,
+    end_time TIMESTAMP,
+    activity_type VARCHAR,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS wifi_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    ssid VARCHAR,
+    bssid VARCHAR,
+    strength INTEGER,
+    frequency INTEGER,
+    ip_address VARCHAR,
+    link_speed INTEGER,
+    security_type VARCHAR,
+    is_5ghz BOOLEAN,
+    is_6ghz BOOLEAN,
+    is_passpoint BOOLEAN,
+    is_restricted BOOLEAN,
+    nearby_networks JSON,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS bluetooth_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    nearby_devices JSON,
+    connected_devices JSON,
+    enabled BOOLEAN,
+    discovering BOOLEAN,
+    local_name VARCHAR,
+    local_address VARCHAR,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS camera_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    camera_type camera_type,
+    light_level INTEGER,
+    scene_type VARCHAR,
+    objects JSON,
+    face_detection JSON,
+    focus_distance FLOAT,
+    flash_state VARCHAR,
+    zoom_level FLOAT,
+    capture_mode VARCHAR,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS microphone_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    average_frequency FLOAT,
+    dominant_frequency FLOAT,
+    raw_output VARCHAR,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS app_event_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    package_name VARCHAR,
+    event_type VARCHAR,
+    activity_name VARCHAR,
+    process_state JSON,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS system_audio_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    raw_output VARCHAR,
+    volume_level FLOAT,
+    audio_output VARCHAR,
+    is_music_playing BOOLEAN,
+    active_media_app VARCHAR,
+    active_streams JSON,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS network_speed_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    download_mbps FLOAT,
+    upload_mbps FLOAT,
+    download_packets_lost INTEGER,
+    upload_packets_lost INTEGER,
+    jitter_ms FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS server_latency_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    ping_ms FLOAT,
+    websocket_latency_ms FLOAT,
+    packet_loss_percentage INTEGER,
+    historic_pings FLOAT[],
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS skin_temperature_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    celsius FLOAT,
+    accuracy FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS ecg_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    voltage FLOAT[],
+    time FLOAT[],
+    rhythm_classification VARCHAR,
+    heart_rate FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS blood_oxygen_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    spo2 INTEGER,
+    confidence FLOAT,
+    raw_values FLOAT[],
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS stress_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    stress_score INTEGER,
+    stress_level VARCHAR,
+    hrv FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS compass_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    heading FLOAT,
+    accuracy FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS screen_details_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    screen_on BOOLEAN,
+    brightness_level INTEGER,
+    brightness_nits FLOAT,
+    auto_brightness BOOLEAN,
+    night_mode BOOLEAN,
+    display_mode VARCHAR,
+    refresh_rate INTEGER,
+    width INTEGER,
+    height INTEGER,
+    density FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS object_detection_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    objects JSON,
+    source camera_type,
+    frame_timestamp TIMESTAMP,
+    model_version VARCHAR,
+    inference_time_ms FLOAT,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS face_recognition_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    faces JSON,
+    source camera_type,
+    frame_timestamp TIMESTAMP,
+    model_version VARCHAR,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS pose_detection_data (
+    timestamp TIMESTAMP NOT NULL,
+    device_id VARCHAR NOT NULL REFERENCES devices(device_id),
+    poses JSON,
+    source camera_type,
+    frame_timestamp TIMESTAMP,
+    model_version VARCHAR,
+    metadata JSON,
+    PRIMARY KEY (timestamp, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS known_entities (
+    entity_id VARCHAR PRIMARY KEY,
+    user_id VARCHAR NOT NULL REFERENCES users(id),
+    type entity_type NOT NULL,
+    label VARCHAR NOT NULL,
+    embedding FLOAT[1024],
+    metadata JSON,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Notes Tables
+-- parent_id is deliberately not a foreign key: DuckDB turns updates of a
+-- referencing column into delete+insert, which note_references and replies
+-- pointing at the note would block, so notes couldn't be moved. Embeddings
+-- live in note_embeddings for the same reason.
+CREATE TABLE IF NOT EXISTS notes (
+    id VARCHAR PRIMARY KEY,
+    user_id VARCHAR NOT NULL REFERENCES users(id),
+    timestamp TIMESTAMP NOT NULL,
+    content TEXT NOT NULL,
+    priority note_priority DEFAULT 'MEDIUM',
+    parent_id VARCHAR,
+    tags VARCHAR[],
+    metadata JSON,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS note_references (
+    note_id VARCHAR NOT NULL REFERENCES notes(id),
+    reference_type VARCHAR NOT NULL,
+    reference_id VARCHAR NOT NULL,
+    timestamp TIMESTAMP,
+    metadata JSON,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (note_id, reference_type, reference_id)
+);
+
+-- One embedding per note, written by the background embedder once the note
+-- is saved and dropped whenever its content changes. note_id isn't a
+-- foreign key so notes can still be deleted and replaced around it.
+CREATE TABLE IF NOT EXISTS note_embeddings (
+    note_id VARCHAR PRIMARY KEY,
+    embedding FLOAT[1024] NOT NULL,
+    embedded_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Rows keep their columns by name; new columns take their defaults.
+INSERT INTO users BY NAME SELECT * FROM v1_users;
+-- The oldest account is the one that would have been made admin when it was
+-- created.
+UPDATE users SET is_admin = true
+WHERE id = (SELECT id FROM users ORDER BY created_at, id LIMIT 1);
+INSERT INTO oauth_accounts BY NAME SELECT * FROM v1_oauth_accounts;
+INSERT INTO sessions BY NAME SELECT * FROM v1_sessions;
+INSERT INTO devices BY NAME
+SELECT * REPLACE (to_json(available_sensors) AS available_sensors), last_seen AS updated_at
+FROM v1_devices;
+INSERT INTO accelerometer_data BY NAME SELECT * FROM v1_accelerometer_data;
+INSERT INTO gyroscope_data BY NAME SELECT * FROM v1_gyroscope_data;
+INSERT INTO magnetometer_data BY NAME SELECT * FROM v1_magnetometer_data;
+INSERT INTO gps_data BY NAME SELECT * FROM v1_gps_data;
+INSERT INTO heart_rate_data BY NAME SELECT * FROM v1_heart_rate_data;
+INSERT INTO proximity_data BY NAME SELECT * FROM v1_proximity_data;
+INSERT INTO light_data BY NAME SELECT * FROM v1_light_data;
+INSERT INTO pressure_data BY NAME SELECT * FROM v1_pressure_data;
+INSERT INTO temperature_data BY NAME SELECT * FROM v1_temperature_data;
+INSERT INTO humidity_data BY NAME SELECT * FROM v1_humidity_data;
+INSERT INTO step_count_data BY NAME SELECT * FROM v1_step_count_data;
+INSERT INTO call_log_data BY NAME SELECT * FROM v1_call_log_data;
+INSERT INTO todos_data BY NAME SELECT * FROM v1_todos_data;
+INSERT INTO audio_level_data BY NAME SELECT * FROM v1_audio_level_data;
+INSERT INTO battery_data BY NAME SELECT * FROM v1_battery_data;
+INSERT INTO network_data BY NAME SELECT * FROM v1_network_data;
+INSERT INTO screen_state_data BY NAME SELECT * FROM v1_screen_state_data;
+INSERT INTO notification_data BY NAME SELECT * FROM v1_notification_data;
+INSERT INTO app_usage_data BY NAME SELECT * FROM v1_app_usage_data;
+INSERT INTO wifi_data BY NAME SELECT * FROM v1_wifi_data;
+INSERT INTO bluetooth_data BY NAME SELECT * FROM v1_bluetooth_data;
+INSERT INTO camera_data BY NAME SELECT * FROM v1_camera_data;
+INSERT INTO microphone_data BY NAME SELECT * FROM v1_microphone_data;
+INSERT INTO app_event_data BY NAME SELECT * FROM v1_app_event_data;
+INSERT INTO system_audio_data BY NAME SELECT * FROM v1_system_audio_data;
+INSERT INTO network_speed_data BY NAME SELECT * FROM v1_network_speed_data;
+INSERT INTO server_latency_data BY NAME SELECT * FROM v1_server_latency_data;
+INSERT INTO skin_temperature_data BY NAME SELECT * FROM v1_skin_temperature_data;
+INSERT INTO ecg_data BY NAME SELECT * FROM v1_ecg_data;
+INSERT INTO blood_oxygen_data BY NAME SELECT * FROM v1_blood_oxygen_data;
+INSERT INTO stress_data BY NAME SELECT * FROM v1_stress_data;
+INSERT INTO compass_data BY NAME SELECT * FROM v1_compass_data;
+INSERT INTO screen_details_data BY NAME SELECT * FROM v1_screen_details_data;
+INSERT INTO object_detection_data BY NAME SELECT * FROM v1_object_detection_data;
+INSERT INTO face_recognition_data BY NAME SELECT * FROM v1_face_recognition_data;
+INSERT INTO pose_detection_data BY NAME SELECT * FROM v1_pose_detection_data;
+-- Version 1 entities had no owner; they go to the admin account.
+INSERT INTO known_entities BY NAME
+SELECT v1_known_entities.*, owner.id AS user_id
+FROM v1_known_entities, (SELECT id FROM users WHERE is_admin) owner;
+INSERT INTO notes BY NAME SELECT * EXCLUDE (embedding) FROM v1_notes;
+INSERT INTO note_embeddings (note_id, embedding, embedded_at)
+SELECT id, embedding, updated_at FROM v1_notes WHERE embedding IS NOT NULL;
+INSERT INTO note_references BY NAME SELECT * FROM v1_note_references;
+
+-- Indexes for performance
+CREATE INDEX IF NOT EXISTS idx_accelerometer_device_time ON accelerometer_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_gyroscope_device_time ON gyroscope_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_magnetometer_device_time ON magnetometer_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_gps_device_time ON gps_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_heart_rate_device_time ON heart_rate_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_proximity_device_time ON proximity_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_light_device_time ON light_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_pressure_device_time ON pressure_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_temperature_device_time ON temperature_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_humidity_device_time ON humidity_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_step_count_device_time ON step_count_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_audio_level_device_time ON audio_level_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_battery_device_time ON battery_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_network_device_time ON network_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_screen_state_device_time ON screen_state_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_notification_device_time ON notification_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_app_usage_device_time ON app_usage_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_wifi_device_time ON wifi_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_bluetooth_device_time ON bluetooth_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_camera_device_time ON camera_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_microphone_device_time ON microphone_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_app_event_device_time ON app_event_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_system_audio_device_time ON system_audio_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_network_speed_device_time ON network_speed_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_server_latency_device_time ON server_latency_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_skin_temperature_device_time ON skin_temperature_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_ecg_device_time ON ecg_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_blood_oxygen_device_time ON blood_oxygen_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_stress_device_time ON stress_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_compass_device_time ON compass_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_screen_details_device_time ON screen_details_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_object_detection_device_time ON object_detection_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_face_recognition_device_time ON face_recognition_data(device_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_pose_detection_device_time ON pose_detection_data(device_id, timestamp);
+
+CREATE INDEX IF NOT EXISTS idx_oauth_user ON oauth_accounts(user_id);
+CREATE INDEX IF NOT EXISTS idx_sessions_user ON sessions(user_id);
+CREATE INDEX IF NOT EXISTS idx_devices_user ON devices(user_id);
+CREATE INDEX IF NOT EXISTS idx_entities_type ON known_entities(type);
+CREATE INDEX IF NOT EXISTS idx_entities_user ON known_entities(user_id);
+CREATE INDEX IF NOT EXISTS idx_notes_user ON notes(user_id);
+CREATE INDEX IF NOT EXISTS idx_notes_timestamp ON notes(timestamp);
+CREATE INDEX IF NOT EXISTS idx_notes_parent ON notes(parent_id);
+CREATE INDEX IF NOT EXISTS idx_note_refs_timestamp ON note_references(timestamp);
+
+-- Vector similarity search indexes
+CREATE INDEX IF NOT EXISTS idx_entity_embedding ON known_entities USING HNSW (embedding);
+CREATE INDEX IF NOT EXISTS idx_note_embeddings ON note_embeddings USING HNSW (embedding);
+
+-- Full-text search corpus. Rebuilt from notes, notification titles, todos and
+-- call log contacts by Database::rebuild_search_index, which also (re)creates
+-- the FTS index over title and body.
+CREATE TABLE IF NOT EXISTS search_documents (
+    doc_id VARCHAR PRIMARY KEY,
+    source VARCHAR NOT NULL,
+    source_id VARCHAR,
+    user_id VARCHAR,
+    device_id VARCHAR,
+    timestamp TIMESTAMP NOT NULL,
+    title VARCHAR,
+    body TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_search_documents_user ON search_documents(user_id);
+
+-- Every time a detection is matched to a known entity. The detection's own
+-- embedding is kept so entities can be re-centred after a merge or split.
+-- entity_id is deliberately not a foreign key: DuckDB turns updates of the
+-- known_entities embedding into delete+insert, which a reference would block.
+-- Nor is device_id, as DuckDB can't reference devices once it is indexed.
+CREATE TABLE IF NOT EXISTS entity_sightings (
+    sighting_id VARCHAR PRIMARY KEY,
+    entity_id VARCHAR NOT NULL,
+    device_id VARCHAR NOT NULL,
+    timestamp TIMESTAMP NOT NULL,
+    source VARCHAR NOT NULL,
+    distance FLOAT,
+    embedding FLOAT[1024],
+    metadata JSON
+);
+
+CREATE INDEX IF NOT EXISTS idx_entity_sightings_entity_time ON entity_sightings(entity_id, timestamp);
+
+-- Per-user sensor choices that feed each device's collection plan. A table
+-- with no row here is collected at its default rate where the device can.
+CREATE TABLE IF NOT EXISTS sensor_preferences (
+    user_id VARCHAR NOT NULL REFERENCES users(id),
+    table_name VARCHAR NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT true,
+    interval_ms BIGINT,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, table_name),
+    CONSTRAINT valid_interval CHECK (interval_ms > 0)
+);
+
+-- What each user allows to be collected, per device and sensor table. '*'
+-- in device_id or table_name covers all devices or tables; the most specific
+-- row wins and anything without a row is collected.
+CREATE TYPE consent_level AS ENUM (
+    'COLLECT',     -- Stored here, including uploads from devices
+    'LOCAL_ONLY',  -- Sampled on the device but never uploaded
+    'NEVER'
+);
+
+CREATE TABLE IF NOT EXISTS consents (
+    user_id VARCHAR NOT NULL REFERENCES users(id),
+    device_id VARCHAR NOT NULL,
+    table_name VARCHAR NOT NULL,
+    level consent_level NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, device_id, table_name)
+);
+
+-- Append-only log of consent changes.
+CREATE TABLE IF NOT EXISTS consent_history (
+    id VARCHAR PRIMARY KEY,
+    user_id VARCHAR NOT NULL REFERENCES users(id),
+    device_id VARCHAR NOT NULL,
+    table_name VARCHAR NOT NULL,
+    previous_level consent_level,
+    level consent_level NOT NULL,
+    purged_rows BIGINT NOT NULL DEFAULT 0,
+    changed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_consent_history_user ON consent_history(user_id, changed_at);
+
+-- Field encryption keys. Values are sealed to the public key, so rows can be
+-- written while locked; the private key is wrapped under a key derived from
+-- the user's passphrase. Retired keys are kept to read older backups.
+CREATE TABLE IF NOT EXISTS field_keys (
+    key_id VARCHAR PRIMARY KEY,
+    public_key VARCHAR NOT NULL,
+    wrapped_secret VARCHAR NOT NULL,
+    salt VARCHAR NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    retired_at TIMESTAMP
+);
+
+-- Columns stored encrypted once a field key exists.
+CREATE TABLE IF NOT EXISTS encrypted_columns (
+    table_name VARCHAR NOT NULL,
+    column_name VARCHAR NOT NULL,
+    PRIMARY KEY (table_name, column_name)
+);
+
+INSERT OR IGNORE INTO encrypted_columns (table_name, column_name) VALUES
+    ('notes', 'content'),
+    ('call_log_data', 'phone_number'),
+    ('notification_data', 'title');
+
+-- Completed deletions, listing how many rows were removed from each table.
+-- Devices fetch them as tombstones and erase the same scope locally.
+CREATE TABLE IF NOT EXISTS deletion_receipts (
+    id VARCHAR PRIMARY KEY,
+    user_id VARCHAR NOT NULL REFERENCES users(id),
+    scope JSON NOT NULL,
+    removed JSON NOT NULL,
+    total_rows BIGINT NOT NULL,
+    deleted_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_deletion_receipts_user ON deletion_receipts(user_id, deleted_at);
+
+-- Areas and Wi-Fi networks whose readings are dropped, snapped to the zone
+-- or fuzzed before they are stored, exported or uploaded by devices.
+CREATE TYPE zone_mode AS ENUM ('DROP', 'SNAP', 'FUZZ');
+
+CREATE TABLE IF NOT EXISTS privacy_zones (
+    id VARCHAR PRIMARY KEY,
+    user_id VARCHAR NOT NULL REFERENCES users(id),
+    name VARCHAR NOT NULL,
+    latitude DOUBLE,
+    longitude DOUBLE,
+    radius_m DOUBLE,
+    ssid VARCHAR,
+    bssid VARCHAR,
+    mode zone_mode NOT NULL,
+    noise_m DOUBLE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT zone_has_area CHECK (
+        (latitude IS NOT NULL AND longitude IS NOT NULL AND radius_m > 0)
+        OR ssid IS NOT NULL OR bssid IS NOT NULL
+    ),
+    CONSTRAINT valid_noise CHECK (noise_m > 0)
+);
+
+CREATE INDEX IF NOT EXISTS idx_privacy_zones_user ON privacy_zones(user_id);
+
+-- Per-user settings for replacing third parties' phone numbers, emails,
+-- card numbers and names with salted pseudonyms. `detectors` is a JSON
+-- array of PiiDetector values.
+CREATE TABLE IF NOT EXISTS redaction_policies (
+    user_id VARCHAR PRIMARY KEY REFERENCES users(id),
+    salt VARCHAR NOT NULL,
+    detectors VARCHAR NOT NULL,
+    on_ingest BOOLEAN NOT NULL DEFAULT false,
+    on_export BOOLEAN NOT NULL DEFAULT false,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Salted digests of contact names seen in the call log, so the names can be
+-- found in notification titles and notes without being kept in clear text.
+CREATE TABLE IF NOT EXISTS redaction_names (
+    user_id VARCHAR NOT NULL REFERENCES users(id),
+    digest VARCHAR NOT NULL,
+    PRIMARY KEY (user_id, digest)
+);
+
+-- Per-user choice of sensor tables to keep a tamper-evident record of.
+-- `tables` is a JSON array of table names.
+CREATE TABLE IF NOT EXISTS audit_policies (
+    user_id VARCHAR PRIMARY KEY REFERENCES users(id),
+    tables VARCHAR NOT NULL,
+    daily_roots BOOLEAN NOT NULL DEFAULT false,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TYPE audit_action AS ENUM ('INSERT', 'DELETE', 'EXPORT');
+
+-- Append-only hash chain, one per user, of the rows inserted into, deleted
+-- from and exported out of audited tables. Rows are held as SHA-256 digests
+-- of their text, a JSON array in `row_digests`, so erased data doesn't
+-- linger here. Nothing updates or deletes entries.
+CREATE TABLE IF NOT EXISTS audit_log (
+    user_id VARCHAR NOT NULL REFERENCES users(id),
+    seq BIGINT NOT NULL,
+    action audit_action NOT NULL,
+    table_name VARCHAR NOT NULL,
+    device_id VARCHAR NOT NULL,
+    row_digests VARCHAR NOT NULL,
+    recorded_at TIMESTAMP NOT NULL,
+    prev_hash VARCHAR NOT NULL,
+    hash VARCHAR NOT NULL,
+    PRIMARY KEY (user_id, seq)
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_log_table ON audit_log(user_id, table_name, device_id);
+
+-- Merkle roots over each finished day's audit entries, for users with
+-- daily_roots set.
+CREATE TABLE IF NOT EXISTS audit_roots (
+    user_id VARCHAR NOT NULL REFERENCES users(id),
+    day DATE NOT NULL,
+    root VARCHAR NOT NULL,
+    entries BIGINT NOT NULL,
+    first_seq BIGINT NOT NULL,
+    last_seq BIGINT NOT NULL,
+    sealed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, day)
+);
+
+DROP TABLE v1_users;
+DROP TABLE v1_oauth_accounts;
+DROP TABLE v1_sessions;
+DROP TABLE v1_devices;
+DROP TABLE v1_accelerometer_data;
+DROP TABLE v1_gyroscope_data;
+DROP TABLE v1_magnetometer_data;
+DROP TABLE v1_gps_data;
+DROP TABLE v1_heart_rate_data;
+DROP TABLE v1_proximity_data;
+DROP TABLE v1_light_data;
+DROP TABLE v1_pressure_data;
+DROP TABLE v1_temperature_data;
+DROP TABLE v1_humidity_data;
+DROP TABLE v1_step_count_data;
+DROP TABLE v1_call_log_data;
+DROP TABLE v1_todos_data;
+DROP TABLE v1_audio_level_data;
+DROP TABLE v1_battery_data;
+DROP TABLE v1_network_data;
+DROP TABLE v1_screen_state_data;
+DROP TABLE v1_notification_data;
+DROP TABLE v1_app_usage_data;
+DROP TABLE v1_wifi_data;
+DROP TABLE v1_bluetooth_data;
+DROP TABLE v1_camera_data;
+DROP TABLE v1_microphone_data;
+DROP TABLE v1_app_event_data;
+DROP TABLE v1_system_audio_data;
+DROP TABLE v1_network_speed_data;
+DROP TABLE v1_server_latency_data;
+DROP TABLE v1_skin_temperature_data;
+DROP TABLE v1_ecg_data;
+DROP TABLE v1_blood_oxygen_data;
+DROP TABLE v1_stress_data;
+DROP TABLE v1_compass_data;
+DROP TABLE v1_screen_details_data;
+DROP TABLE v1_object_detection_data;
+DROP TABLE v1_face_recognition_data;
+DROP TABLE v1_pose_detection_data;
+DROP TABLE v1_known_entities;
+DROP TABLE v1_notes;
+DROP TABLE v1_note_references;
@@ -0,0 +1,15 @@
+-- Credentials devices upload with, one per device. Unlike sessions they
+-- don't expire and survive logout and password changes; they last until
+-- revoked or the device is deleted. Only a hash of the token is stored.
+-- device_id isn't a foreign key so a token can be issued before the device
+-- first registers, as OwnTracks phones do on their first upload.
+CREATE TABLE IF NOT EXISTS device_tokens (
+    id VARCHAR PRIMARY KEY,
+    user_id VARCHAR NOT NULL REFERENCES users(id),
+    device_id VARCHAR NOT NULL,
+    token VARCHAR NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_device_tokens_user ON device_tokens(user_id);
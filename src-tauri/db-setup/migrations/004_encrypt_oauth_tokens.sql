@@ -0,0 +1,6 @@
+-- OAuth tokens are field-encrypted like the other secrets users store.
+-- Tokens already stored are re-encrypted the next time the field key is
+-- rotated; new ones are encrypted as they're written.
+INSERT OR IGNORE INTO encrypted_columns (table_name, column_name) VALUES
+    ('oauth_accounts', 'access_token'),
+    ('oauth_accounts', 'refresh_token');
@@ -0,0 +1,186 @@
+// Hash chain and Merkle tree behind the tamper-evident audit log.
+
+use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use crate::crypto::{from_hex, to_hex};
+use crate::datatypes::audit::{AuditEntry, MerkleStep};
+
+/// `prev_hash` of the first entry in a chain.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// The digest a stored row is logged under. `row` is the row as DuckDB
+/// renders it with `CAST(t AS VARCHAR)`, so anyone holding an export of it
+/// can recompute the digest.
+pub fn row_digest(row: &str) -> String {
+    to_hex(&Sha256::digest(row.as_bytes()))
+}
+
+/// The hash of an entry over all of its fields but `hash` itself, one per
+/// line in a fixed order.
+pub fn entry_hash(entry: &AuditEntry) -> String {
+    let text = [
+        entry.prev_hash.clone(),
+        entry.user_id.clone(),
+        entry.seq.to_string(),
+        entry.action.as_str().to_string(),
+        entry.table_name.clone(),
+        entry.device_id.clone(),
+        entry.row_digests.join(","),
+        entry.recorded_at.to_rfc3339(),
+    ]
+    .join("\n");
+    to_hex(&Sha256::digest(text.as_bytes()))
+}
+
+/// The seq of the first entry in `entries`, a whole chain in order, that
+/// doesn't hash to its `hash` or doesn't follow on from the one before.
+pub fn find_break(entries: &[AuditEntry]) -> Option<i64> {
+    let mut prev_hash = GENESIS_HASH;
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.seq != i as i64 + 1 || entry.prev_hash != prev_hash || entry_hash(entry) != entry.hash {
+            return Some(entry.seq);
+        }
+        prev_hash = &entry.hash;
+    }
+    None
+}
+
+fn node_hash(left: &str, right: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(from_hex(left).unwrap_or_default());
+    hasher.update(from_hex(right).unwrap_or_default());
+    to_hex(&hasher.finalize())
+}
+
+/// The levels of the tree over `leaves`, bottom first. An odd node out is
+/// carried up a level as it is rather than paired with itself.
+fn merkle_levels(leaves: &[String]) -> Vec<Vec<String>> {
+    let mut levels = vec![leaves.to_vec()];
+    while levels.last().unwrap().len() > 1 {
+        let level = levels.last().unwrap();
+        let next = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node_hash(left, right),
+                [odd] => odd.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+        levels.push(next);
+    }
+    levels
+}
+
+/// The Merkle root over `leaves`, which must not be empty.
+pub fn merkle_root(leaves: &[String]) -> String {
+    merkle_levels(leaves).pop().unwrap()[0].clone()
+}
+
+/// The siblings needed to get from `leaves[index]` up to the root.
+pub fn merkle_path(leaves: &[String], index: usize) -> Vec<MerkleStep> {
+    let mut path = Vec::new();
+    let mut index = index;
+    for level in merkle_levels(leaves).iter().filter(|level| level.len() > 1) {
+        let sibling = index ^ 1;
+        if let Some(hash) = level.get(sibling) {
+            path.push(MerkleStep { hash: hash.clone(), left: sibling < index });
+        }
+        index /= 2;
+    }
+    path
+}
+
+/// Whether `path` leads from `leaf` to `root`.
+pub fn verify_path(leaf: &str, path: &[MerkleStep], root: &str) -> bool {
+    let computed = path.iter().fold(leaf.to_string(), |hash, step| {
+        if step.left { node_hash(&step.hash, &hash) } else { node_hash(&hash, &step.hash) }
+    });
+    computed == root
+}
+
+/// What turned `before` into `after`, both lists of row digests that may
+/// repeat: the digests that went, then the ones that came.
+pub fn digest_changes(before: &[String], after: &[String]) -> (Vec<String>, Vec<String>) {
+    let mut counts: HashMap<&str, i64> = HashMap::new();
+    for digest in before {
+        *counts.entry(digest).or_default() -= 1;
+    }
+    for digest in after {
+        *counts.entry(digest).or_default() += 1;
+    }
+
+    let (mut removed, mut added) = (Vec::new(), Vec::new());
+    for (digest, count) in counts {
+        let side = if count < 0 { &mut removed } else { &mut added };
+        side.extend(std::iter::repeat_n(digest.to_string(), count.unsigned_abs() as usize));
+    }
+    removed.sort();
+    added.sort();
+    (removed, added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use crate::datatypes::types::AuditAction;
+
+    fn chain(rows: &[&str]) -> Vec<AuditEntry> {
+        let mut entries: Vec<AuditEntry> = Vec::new();
+        for (i, row) in rows.iter().enumerate() {
+            let mut entry = AuditEntry {
+                user_id: "alice".to_string(),
+                seq: i as i64 + 1,
+                action: AuditAction::Insert,
+                table_name: "gps_data".to_string(),
+                device_id: "phone".to_string(),
+                row_digests: vec![row_digest(row)],
+                recorded_at: Utc::now(),
+                prev_hash: entries.last().map_or(GENESIS_HASH.to_string(), |e| e.hash.clone()),
+                hash: String::new(),
+            };
+            entry.hash = entry_hash(&entry);
+            entries.push(entry);
+        }
+        entries
+    }
+
+    #[test]
+    fn test_chain_breaks_where_edited() {
+        let mut entries = chain(&["a", "b", "c", "d"]);
+        assert_eq!(find_break(&entries), None);
+
+        entries[2].row_digests = vec![row_digest("x")];
+        assert_eq!(find_break(&entries), Some(3));
+
+        // Rehashing the edited entry just moves the break to the next one
+        entries[2].hash = entry_hash(&entries[2]);
+        assert_eq!(find_break(&entries), Some(4));
+
+        let mut entries = chain(&["a", "b", "c"]);
+        entries.remove(1);
+        assert_eq!(find_break(&entries), Some(3));
+    }
+
+    #[test]
+    fn test_merkle_paths_prove_every_leaf() {
+        for n in 1..=7 {
+            let leaves: Vec<String> = (0..n).map(|i| row_digest(&i.to_string())).collect();
+            let root = merkle_root(&leaves);
+            for (i, leaf) in leaves.iter().enumerate() {
+                let path = merkle_path(&leaves, i);
+                assert!(verify_path(leaf, &path, &root), "leaf {} of {}", i, n);
+                assert!(!verify_path(&row_digest("other"), &path, &root));
+            }
+        }
+    }
+
+    #[test]
+    fn test_digest_changes_count_repeats() {
+        let digests = |rows: &[&str]| rows.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        let (removed, added) = digest_changes(&digests(&["a", "a", "b"]), &digests(&["a", "c", "c"]));
+        assert_eq!(removed, digests(&["a", "b"]));
+        assert_eq!(added, digests(&["c", "c"]));
+        assert_eq!(digest_changes(&digests(&["a"]), &digests(&["a"])), (vec![], vec![]));
+    }
+}
@@ -0,0 +1,355 @@
+use std::fmt;
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use chrono::{Duration, Utc};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::crypto::to_hex;
+use crate::datatypes::user::{DeviceToken, Session, User};
+use crate::db::Database;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// How long a session lasts without being renewed.
+pub const DEFAULT_SESSION_TTL_DAYS: i64 = 30;
+
+/// Sessions in use are renewed at most this often, so authenticating
+/// doesn't write to the database on every command.
+const RENEW_INTERVAL_HOURS: i64 = 24;
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// Unknown email or wrong password; deliberately not told apart.
+    InvalidCredentials,
+    EmailTaken,
+    WeakPassword,
+    /// The token is unknown, revoked or expired.
+    InvalidSession,
+    Hashing(String),
+    Database(duckdb::Error),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::InvalidCredentials => write!(f, "invalid email or password"),
+            AuthError::EmailTaken => write!(f, "an account with this email already exists"),
+            AuthError::WeakPassword => write!(f, "password must be at least {} characters", MIN_PASSWORD_LENGTH),
+            AuthError::InvalidSession => write!(f, "session is invalid or has expired"),
+            AuthError::Hashing(message) => write!(f, "password hashing failed: {}", message),
+            AuthError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<duckdb::Error> for AuthError {
+    fn from(e: duckdb::Error) -> Self {
+        AuthError::Database(e)
+    }
+}
+
+/// Hashes a password with Argon2id and a random salt, producing a PHC string
+/// suitable for `User.encrypted_password`.
+pub fn hash_password(password: &str) -> Result<String, AuthError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| AuthError::Hashing(e.to_string()))
+}
+
+pub fn verify_password(password: &str, encrypted_password: &str) -> bool {
+    PasswordHash::new(encrypted_password)
+        .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+        .unwrap_or(false)
+}
+
+/// A fresh 256-bit session token, hex encoded.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// What the sessions table stores in place of the token itself, so a copy of
+/// the database can't be used to log in.
+pub fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Accounts, session lifecycle and device tokens. Sessions handed out by
+/// `login` and `renew`, and device tokens from `issue_device_token`, carry
+/// the raw token; only its hash is persisted.
+pub struct AuthService<'a> {
+    db: &'a Database,
+    pub session_ttl: Duration,
+}
+
+impl<'a> AuthService<'a> {
+    pub fn new(db: &'a Database) -> Self {
+        Self {
+            db,
+            session_ttl: Duration::days(DEFAULT_SESSION_TTL_DAYS),
+        }
+    }
+
+    pub fn register(&self, email: &str, name: Option<&str>, password: &str) -> Result<User, AuthError> {
+        if password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(AuthError::WeakPassword);
+        }
+        let email = normalize_email(email);
+        if self.db.get_user_by_email(&email)?.is_some() {
+            return Err(AuthError::EmailTaken);
+        }
+
+        let now = Utc::now();
+        let user = User {
+            id: Uuid::new_v4().to_string(),
+            email,
+            name: name.map(str::to_string),
+            encrypted_password: hash_password(password)?,
+            created_at: now,
+            updated_at: now,
+        };
+        self.db.insert_user(&user)?;
+        // The first account owns the installation and may encrypt, lock or
+        // back up the whole database.
+        self.db.claim_admin_if_first(&user.id)?;
+        Ok(user)
+    }
+
+    pub fn login(&self, email: &str, password: &str) -> Result<(User, Session), AuthError> {
+        let Some(user) = self.db.get_user_by_email(&normalize_email(email))? else {
+            // Spend the same time as a real check so unknown emails can't be
+            // told apart by how quickly they fail.
+            let _ = hash_password(password);
+            return Err(AuthError::InvalidCredentials);
+        };
+        if !verify_password(password, &user.encrypted_password) {
+            return Err(AuthError::InvalidCredentials);
+        }
+
+        let session = self.issue_session(&user.id)?;
+        Ok((user, session))
+    }
+
+    /// The user behind a live session token. Using a session renews it, so
+    /// only sessions left idle for `session_ttl` expire.
+    pub fn authenticate(&self, token: &str) -> Result<User, AuthError> {
+        let session = self.live_session(token)?;
+        let renewed_at = session.expires_at - self.session_ttl;
+        if Utc::now() - renewed_at >= Duration::hours(RENEW_INTERVAL_HOURS) {
+            self.db.set_session_expiry(&session.id, Utc::now() + self.session_ttl)?;
+        }
+        self.db.get_user(&session.user_id).map_err(AuthError::from)
+    }
+
+    /// Pushes the session's expiry out by another `session_ttl`.
+    pub fn renew(&self, token: &str) -> Result<Session, AuthError> {
+        let mut session = self.live_session(token)?;
+        session.expires_at = Utc::now() + self.session_ttl;
+        self.db.set_session_expiry(&session.id, session.expires_at)?;
+        session.token = token.to_string();
+        Ok(session)
+    }
+
+    pub fn logout(&self, token: &str) -> Result<(), AuthError> {
+        if let Some(session) = self.db.get_session_by_token(&hash_token(token))? {
+            self.db.delete_session(&session.id)?;
+        }
+        Ok(())
+    }
+
+    /// Revokes all of a user's sessions. Returns how many were removed.
+    pub fn logout_everywhere(&self, user_id: &str) -> Result<usize, AuthError> {
+        Ok(self.db.delete_user_sessions(user_id, None)?)
+    }
+
+    /// Changes the password of the session's user and revokes their other
+    /// sessions.
+    pub fn change_password(&self, token: &str, current: &str, new: &str) -> Result<(), AuthError> {
+        let session = self.live_session(token)?;
+        let user = self.db.get_user(&session.user_id)?;
+        if !verify_password(current, &user.encrypted_password) {
+            return Err(AuthError::InvalidCredentials);
+        }
+        if new.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(AuthError::WeakPassword);
+        }
+
+        self.db.update_user_password(&user.id, &hash_password(new)?)?;
+        self.db.delete_user_sessions(&user.id, Some(&session.id))?;
+        Ok(())
+    }
+
+    /// Deletes sessions that have run out. Returns how many were removed.
+    pub fn purge_expired(&self) -> Result<usize, AuthError> {
+        Ok(self.db.delete_expired_sessions(Utc::now())?)
+    }
+
+    /// A token `device_id` uploads to the device server with on `user_id`'s
+    /// behalf. It doesn't expire, and outlives logout and password changes,
+    /// until revoked with `revoke_device_token`.
+    pub fn issue_device_token(&self, user_id: &str, device_id: &str) -> Result<DeviceToken, AuthError> {
+        let token = generate_token();
+        let mut device_token = DeviceToken {
+            id: Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            device_id: device_id.to_string(),
+            token: hash_token(&token),
+            created_at: Utc::now(),
+            last_used_at: None,
+        };
+        self.db.insert_device_token(&device_token)?;
+        device_token.token = token;
+        Ok(device_token)
+    }
+
+    /// The user behind a device token, and the token, which names the one
+    /// device it may act for.
+    pub fn authenticate_device(&self, token: &str) -> Result<(User, DeviceToken), AuthError> {
+        let Some(device_token) = self.db.get_device_token_by_token(&hash_token(token))? else {
+            return Err(AuthError::InvalidSession);
+        };
+        self.db.set_device_token_used(&device_token.id, Utc::now())?;
+        Ok((self.db.get_user(&device_token.user_id)?, device_token))
+    }
+
+    /// Returns whether the user had a device token with this id.
+    pub fn revoke_device_token(&self, user_id: &str, id: &str) -> Result<bool, AuthError> {
+        Ok(self.db.delete_device_token(user_id, id)? > 0)
+    }
+
+    fn issue_session(&self, user_id: &str) -> Result<Session, AuthError> {
+        let token = generate_token();
+        let now = Utc::now();
+        let mut session = Session {
+            id: Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            token: hash_token(&token),
+            expires_at: now + self.session_ttl,
+            created_at: now,
+        };
+        self.db.insert_session(&session)?;
+        session.token = token;
+        Ok(session)
+    }
+
+    fn live_session(&self, token: &str) -> Result<Session, AuthError> {
+        match self.db.get_session_by_token(&hash_token(token))? {
+            Some(session) if session.expires_at > Utc::now() => Ok(session),
+            _ => Err(AuthError::InvalidSession),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_hash_and_verify_password() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+        assert!(!verify_password("correct horse", "not a phc string"));
+    }
+
+    #[test]
+    fn test_tokens_are_random_and_hashed() {
+        let a = generate_token();
+        let b = generate_token();
+        assert_eq!(a.len(), 64);
+        assert_ne!(a, b);
+        assert_eq!(hash_token(&a), hash_token(&a));
+        assert_ne!(hash_token(&a), a);
+    }
+
+    #[test]
+    fn test_session_lifecycle() -> Result<(), AuthError> {
+        let dir = tempdir().unwrap();
+        let db = Database::new(&dir.path().join("test.db"))?;
+        let auth = AuthService::new(&db);
+
+        let user = auth.register("Alice@Example.com", Some("Alice"), "hunter22!")?;
+        assert!(matches!(auth.register("alice@example.com", None, "whatever1"), Err(AuthError::EmailTaken)));
+        let bob = auth.register("bob@example.com", None, "hunter33!")?;
+        assert!(db.is_admin(&user.id)?);
+        assert!(!db.is_admin(&bob.id)?);
+        assert!(matches!(auth.login("alice@example.com", "wrong pass"), Err(AuthError::InvalidCredentials)));
+
+        let (_, session) = auth.login("alice@example.com", "hunter22!")?;
+        assert_eq!(auth.authenticate(&session.token)?.id, user.id);
+        assert!(db.get_session_by_token(&session.token)?.is_none());
+
+        auth.logout(&session.token)?;
+        assert!(matches!(auth.authenticate(&session.token), Err(AuthError::InvalidSession)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sessions_in_use_are_renewed_and_idle_ones_purged() -> Result<(), AuthError> {
+        let dir = tempdir().unwrap();
+        let db = Database::new(&dir.path().join("test.db"))?;
+        let auth = AuthService::new(&db);
+        auth.register("alice@example.com", None, "hunter22!")?;
+        let (user, active) = auth.login("alice@example.com", "hunter22!")?;
+        let (_, idle) = auth.login("alice@example.com", "hunter22!")?;
+
+        // Both were issued 29 days ago; only one has been used since
+        let nearly_expired = Utc::now() + Duration::days(1);
+        for session in [&active, &idle] {
+            db.set_session_expiry(&session.id, nearly_expired)?;
+        }
+        auth.authenticate(&active.token)?;
+        let expiry = |id: &str| {
+            db.get_user_sessions(&user.id).map(|sessions| sessions.into_iter().find(|s| s.id == id).map(|s| s.expires_at))
+        };
+        assert!(expiry(&active.id)?.unwrap() > Utc::now() + Duration::days(29));
+        assert!(expiry(&idle.id)?.unwrap() < Utc::now() + Duration::days(2));
+
+        db.set_session_expiry(&idle.id, Utc::now() - Duration::minutes(1))?;
+        assert_eq!(auth.purge_expired()?, 1);
+        assert!(expiry(&idle.id)?.is_none());
+        assert_eq!(auth.authenticate(&active.token)?.id, user.id);
+
+        Ok(())
+    }
+    #[test]
+    fn test_device_tokens_outlive_sessions_until_revoked() -> Result<(), AuthError> {
+        let dir = tempdir().unwrap();
+        let db = Database::new(&dir.path().join("test.db"))?;
+        let auth = AuthService::new(&db);
+        let user = auth.register("alice@example.com", None, "hunter22!")?;
+        let (_, session) = auth.login("alice@example.com", "hunter22!")?;
+
+        let phone = auth.issue_device_token(&user.id, "alice_phone")?;
+        let watch = auth.issue_device_token(&user.id, "alice_watch")?;
+        assert!(db.get_device_token_by_token(&phone.token)?.is_none());
+        assert!(matches!(auth.authenticate_device(&session.token), Err(AuthError::InvalidSession)));
+
+        auth.change_password(&session.token, "hunter22!", "correct horse")?;
+        auth.logout_everywhere(&user.id)?;
+        let (owner, device_token) = auth.authenticate_device(&phone.token)?;
+        assert_eq!((owner.id.as_str(), device_token.device_id.as_str()), (user.id.as_str(), "alice_phone"));
+        assert!(db.get_device_tokens(&user.id)?.iter().any(|t| t.id == phone.id && t.last_used_at.is_some()));
+
+        assert!(!auth.revoke_device_token("someone_else", &phone.id)?);
+        assert!(auth.revoke_device_token(&user.id, &phone.id)?);
+        assert!(matches!(auth.authenticate_device(&phone.token), Err(AuthError::InvalidSession)));
+        assert_eq!(auth.authenticate_device(&watch.token)?.1.device_id, "alice_watch");
+        Ok(())
+    }
+}
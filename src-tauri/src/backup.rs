@@ -0,0 +1,533 @@
+use std::{fmt, fs, io};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::PoisonError;
+use std::thread;
+use std::time::Duration;
+use chrono::Utc;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::crypto::{derive_key, from_hex, to_hex, CryptoError, SecretBox, SALT_LEN};
+use crate::datatypes::backup::{BackupCheck, BackupManifest, BackupPart, BackupSummary};
+use crate::db::{Database, SharedDatabase, SnapshotFile, SCHEMA_VERSION};
+
+const STORE_FORMAT: u32 = 1;
+const STORE_FILE: &str = "backup.json";
+const OBJECTS_DIR: &str = "objects";
+const SNAPSHOTS_DIR: &str = "snapshots";
+
+/// Sealed into `backup.json` so a wrong passphrase is caught up front
+/// rather than as a store full of undecryptable objects.
+const CHECK_VALUE: &[u8] = b"loom-backup";
+
+#[derive(Debug)]
+pub enum BackupError {
+    /// The directory holds something other than a backup store.
+    NotAStore(PathBuf),
+    WrongPassphrase,
+    UnknownSnapshot(String),
+    /// The snapshot was taken with a newer schema than this build's.
+    SchemaTooNew { snapshot: i64, current: i64 },
+    /// Restoring never overwrites an existing database.
+    TargetExists(PathBuf),
+    /// An object or snapshot failed to decrypt or doesn't match its name.
+    Corrupt(String),
+    Database(duckdb::Error),
+    Crypto(CryptoError),
+    Io(io::Error),
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupError::NotAStore(dir) => write!(f, "{} is not a backup directory", dir.display()),
+            BackupError::WrongPassphrase => write!(f, "wrong backup passphrase"),
+            BackupError::UnknownSnapshot(id) => write!(f, "no backup snapshot {}", id),
+            BackupError::SchemaTooNew { snapshot, current } => write!(
+                f,
+                "snapshot schema version {} is newer than this version's {}",
+                snapshot, current
+            ),
+            BackupError::TargetExists(path) => write!(f, "{} already exists", path.display()),
+            BackupError::Corrupt(what) => write!(f, "backup is corrupt: {}", what),
+            BackupError::Database(e) => write!(f, "{}", e),
+            BackupError::Crypto(e) => write!(f, "{}", e),
+            BackupError::Io(e) => write!(f, "backup i/o error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+impl From<duckdb::Error> for BackupError {
+    fn from(e: duckdb::Error) -> Self {
+        BackupError::Database(e)
+    }
+}
+
+impl From<CryptoError> for BackupError {
+    fn from(e: CryptoError) -> Self {
+        BackupError::Crypto(e)
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(e: io::Error) -> Self {
+        BackupError::Io(e)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoreFile {
+    format: u32,
+    salt: String,
+    check: String,
+}
+
+/// A directory of encrypted database snapshots.
+///
+/// Each snapshot is a set of Parquet files (see `Database::export_snapshot`),
+/// already ZSTD-compressed, stored under `objects/` sealed with a key derived
+/// from the passphrase. Objects are named by a keyed hash of their contents,
+/// so a backup only writes the tables and days that changed since the last
+/// one, and the names reveal nothing about the data. Manifests listing a
+/// snapshot's objects are sealed under `snapshots/`.
+pub struct BackupStore {
+    dir: PathBuf,
+    secrets: SecretBox,
+    id_key: [u8; 32],
+}
+
+impl BackupStore {
+    /// Opens the store in `dir`, creating it with `passphrase` if the
+    /// directory is empty or doesn't exist.
+    pub fn open(dir: &Path, passphrase: &str) -> Result<Self, BackupError> {
+        let store_path = dir.join(STORE_FILE);
+        if !store_path.exists() {
+            if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+                return Err(BackupError::NotAStore(dir.to_path_buf()));
+            }
+            return Self::create(dir, passphrase);
+        }
+
+        let not_a_store = || BackupError::NotAStore(dir.to_path_buf());
+        let file: StoreFile = serde_json::from_str(&fs::read_to_string(&store_path)?).map_err(|_| not_a_store())?;
+        let (Some(salt), Some(check)) = (from_hex(&file.salt), from_hex(&file.check)) else {
+            return Err(not_a_store());
+        };
+        if file.format != STORE_FORMAT {
+            return Err(not_a_store());
+        }
+        let store = Self::with_key(dir, &derive_key(passphrase, &salt)?);
+        match store.secrets.open_bytes(&check, STORE_FILE.as_bytes()) {
+            Ok(value) if value == CHECK_VALUE => Ok(store),
+            _ => Err(BackupError::WrongPassphrase),
+        }
+    }
+
+    fn create(dir: &Path, passphrase: &str) -> Result<Self, BackupError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let store = Self::with_key(dir, &derive_key(passphrase, &salt)?);
+
+        fs::create_dir_all(dir.join(OBJECTS_DIR))?;
+        fs::create_dir_all(dir.join(SNAPSHOTS_DIR))?;
+        let file = StoreFile {
+            format: STORE_FORMAT,
+            salt: to_hex(&salt),
+            check: to_hex(&store.secrets.seal_bytes(CHECK_VALUE, STORE_FILE.as_bytes())),
+        };
+        fs::write(dir.join(STORE_FILE), serde_json::to_string_pretty(&file).unwrap())?;
+        Ok(store)
+    }
+
+    fn with_key(dir: &Path, key: &[u8; 32]) -> Self {
+        let id_key: [u8; 32] = Sha256::new()
+            .chain_update(b"loom-backup-object-id")
+            .chain_update(key)
+            .finalize()
+            .into();
+        Self { dir: dir.to_path_buf(), secrets: SecretBox::new(key), id_key }
+    }
+
+    /// Takes a snapshot of `db`, storing only the parts that aren't in the
+    /// store yet.
+    pub fn backup(&self, db: &Database) -> Result<BackupSummary, BackupError> {
+        let parent = self.snapshots()?.pop().map(|snapshot| snapshot.id);
+        let scratch = Scratch::new()?;
+        let files = db.export_snapshot(&scratch.0)?;
+
+        let mut parts = Vec::with_capacity(files.len());
+        let (mut new_objects, mut reused_objects, mut bytes_written) = (0, 0, 0);
+        for file in files {
+            let (object, written) = self.put_object(&fs::read(&file.path)?)?;
+            match written {
+                Some(bytes) => {
+                    new_objects += 1;
+                    bytes_written += bytes;
+                }
+                None => reused_objects += 1,
+            }
+            parts.push(BackupPart { table_name: file.table, day: file.day, rows: file.rows, object });
+        }
+
+        let created_at = Utc::now();
+        let snapshot = BackupManifest {
+            id: format!("{}-{}", created_at.format("%Y%m%dT%H%M%SZ"), &Uuid::new_v4().simple().to_string()[..8]),
+            created_at,
+            schema_version: db.schema_version()?,
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            parent,
+            parts,
+        };
+        let sealed = self.secrets.seal_bytes(&serde_json::to_vec(&snapshot).unwrap(), snapshot.id.as_bytes());
+        bytes_written += sealed.len() as u64;
+        // The manifest goes last, so an interrupted backup leaves no snapshot
+        // pointing at objects that were never written
+        write_atomically(&self.dir.join(SNAPSHOTS_DIR).join(&snapshot.id), &sealed)?;
+
+        Ok(BackupSummary { snapshot, new_objects, reused_objects, bytes_written })
+    }
+
+    /// All snapshots, oldest first.
+    pub fn snapshots(&self) -> Result<Vec<BackupManifest>, BackupError> {
+        let mut snapshots = self
+            .snapshot_ids()?
+            .iter()
+            .map(|id| self.snapshot(id))
+            .collect::<Result<Vec<_>, _>>()?;
+        snapshots.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+        Ok(snapshots)
+    }
+
+    pub fn snapshot(&self, id: &str) -> Result<BackupManifest, BackupError> {
+        if !self.snapshot_ids()?.iter().any(|known| known == id) {
+            return Err(BackupError::UnknownSnapshot(id.to_string()));
+        }
+        let sealed = fs::read(self.dir.join(SNAPSHOTS_DIR).join(id))?;
+        let corrupt = || BackupError::Corrupt(format!("snapshot {}", id));
+        let plaintext = self.secrets.open_bytes(&sealed, id.as_bytes()).map_err(|_| corrupt())?;
+        serde_json::from_slice(&plaintext).map_err(|_| corrupt())
+    }
+
+    /// Decrypts every snapshot and object and checks each object against
+    /// its name. Problems are collected rather than stopping at the first.
+    pub fn verify(&self) -> Result<BackupCheck, BackupError> {
+        let mut problems = Vec::new();
+        let mut referenced = HashSet::new();
+        let snapshot_ids = self.snapshot_ids()?;
+        for id in &snapshot_ids {
+            match self.snapshot(id) {
+                Ok(snapshot) => referenced.extend(snapshot.parts.into_iter().map(|part| part.object)),
+                Err(BackupError::Corrupt(what)) => problems.push(what),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let objects = list_dir(&self.dir.join(OBJECTS_DIR))?;
+        for object in &objects {
+            if let Err(BackupError::Corrupt(what)) = self.get_object(object) {
+                problems.push(what);
+            }
+        }
+        let stored: HashSet<&String> = objects.iter().collect();
+        let mut missing: Vec<_> = referenced.iter().filter(|object| !stored.contains(object)).collect();
+        missing.sort();
+        problems.extend(missing.into_iter().map(|object| format!("object {} is missing", object)));
+
+        Ok(BackupCheck { snapshots: snapshot_ids.len(), objects: objects.len(), problems, checked_at: Utc::now() })
+    }
+
+    /// Restores snapshot `id` into a new database at `target`, which must
+    /// not exist yet, migrating snapshots from older schema versions. Every
+    /// object is checked before the database is created, and a failed
+    /// restore leaves nothing behind.
+    pub fn restore(&self, id: &str, target: &Path) -> Result<BackupManifest, BackupError> {
+        if target.exists() {
+            return Err(BackupError::TargetExists(target.to_path_buf()));
+        }
+        let snapshot = self.snapshot(id)?;
+        if snapshot.schema_version > SCHEMA_VERSION {
+            return Err(BackupError::SchemaTooNew { snapshot: snapshot.schema_version, current: SCHEMA_VERSION });
+        }
+
+        let scratch = Scratch::new()?;
+        let mut files = Vec::with_capacity(snapshot.parts.len());
+        for (i, part) in snapshot.parts.iter().enumerate() {
+            let path = scratch.0.join(format!("{:05}.parquet", i));
+            fs::write(&path, self.get_object(&part.object)?)?;
+            files.push(SnapshotFile { table: part.table_name.clone(), day: part.day, rows: part.rows, path });
+        }
+
+        let restored = Database::new_at_version(target, snapshot.schema_version).and_then(|db| db.load_snapshot(&files));
+        if let Err(e) = restored {
+            let _ = fs::remove_file(target);
+            let _ = fs::remove_file(target.with_extension("db.wal"));
+            return Err(e.into());
+        }
+        Ok(snapshot)
+    }
+
+    /// Seals `plaintext` into the store unless an object with the same
+    /// contents is already there. Returns the object's name and the bytes
+    /// written, if any.
+    fn put_object(&self, plaintext: &[u8]) -> Result<(String, Option<u64>), BackupError> {
+        let object = self.object_id(plaintext);
+        let path = self.dir.join(OBJECTS_DIR).join(&object);
+        if path.exists() {
+            return Ok((object, None));
+        }
+        let sealed = self.secrets.seal_bytes(plaintext, object.as_bytes());
+        write_atomically(&path, &sealed)?;
+        Ok((object, Some(sealed.len() as u64)))
+    }
+
+    fn get_object(&self, object: &str) -> Result<Vec<u8>, BackupError> {
+        let sealed = match fs::read(self.dir.join(OBJECTS_DIR).join(object)) {
+            Ok(sealed) => sealed,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(BackupError::Corrupt(format!("object {} is missing", object)))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match self.secrets.open_bytes(&sealed, object.as_bytes()) {
+            Ok(plaintext) if self.object_id(&plaintext) == object => Ok(plaintext),
+            _ => Err(BackupError::Corrupt(format!("object {} does not match its contents", object))),
+        }
+    }
+
+    fn object_id(&self, plaintext: &[u8]) -> String {
+        to_hex(&Sha256::new().chain_update(self.id_key).chain_update(plaintext).finalize())
+    }
+
+    fn snapshot_ids(&self) -> Result<Vec<String>, BackupError> {
+        list_dir(&self.dir.join(SNAPSHOTS_DIR))
+    }
+}
+
+/// Runs backups of the shared database every `every` from a background
+/// thread, holding it for the length of each run. Failed runs are logged and
+/// retried at the next interval.
+pub struct BackupSchedule {
+    stop: mpsc::Sender<()>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl BackupSchedule {
+    pub fn start(db: SharedDatabase, store: BackupStore, every: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || loop {
+            match stopped.recv_timeout(every) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => return,
+            }
+            let result = store.backup(&db.lock().unwrap_or_else(PoisonError::into_inner));
+            match result {
+                Ok(summary) => log::info!(
+                    "Backup {} written ({} new objects, {} bytes)",
+                    summary.snapshot.id, summary.new_objects, summary.bytes_written
+                ),
+                Err(e) => log::error!("Scheduled backup failed: {}", e),
+            }
+        });
+        Self { stop, handle }
+    }
+
+    /// Stops the schedule, waiting for a backup in progress to finish.
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.handle.join();
+    }
+}
+
+/// A temporary directory for Parquet files, removed when dropped.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new() -> io::Result<Self> {
+        let dir = std::env::temp_dir().join(format!("loom-backup-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir)?;
+        Ok(Self(dir))
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let partial = path.with_extension("partial");
+    fs::write(&partial, contents)?;
+    fs::rename(&partial, path)
+}
+
+fn list_dir(dir: &Path) -> Result<Vec<String>, BackupError> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if !name.ends_with(".partial") {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use tempfile::tempdir;
+    use crate::datatypes::{
+        device::{Device, DeviceCapabilities, ScreenDetails},
+        sensor::GpsData,
+        types::DeviceType,
+        user::User,
+    };
+    use crate::db::fixtures::user;
+
+    fn seeded_db(path: &Path) -> Database {
+        let db = Database::new(path).unwrap();
+        db.insert_user(&User {
+            id: "alice".to_string(),
+            email: "alice@example.com".to_string(),
+            name: None,
+            encrypted_password: "x".to_string(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })
+        .unwrap();
+        db.insert_device(&Device {
+            device_id: "phone".to_string(),
+            user_id: "alice".to_string(),
+            device_type: DeviceType::Smartphone,
+            os_type: "Android".to_string(),
+            os_version: "14".to_string(),
+            app_version: "1.0".to_string(),
+            available_sensors: vec![],
+            capabilities: DeviceCapabilities {
+                has_camera: false,
+                has_microphone: false,
+                has_gps: true,
+                has_accelerometer: false,
+                has_gyroscope: false,
+                has_magnetometer: false,
+                has_proximity: false,
+                has_light: false,
+                has_pressure: false,
+                has_temperature: false,
+                has_humidity: false,
+                has_step_counter: false,
+                has_heart_rate: false,
+                has_ecg: false,
+                has_blood_oxygen: false,
+                has_stress: false,
+                has_compass: false,
+                screen_details: ScreenDetails { width: 1080, height: 2400, density: 2.75, refresh_rate: 60 },
+            },
+            name: None,
+            created_at: Utc::now(),
+            last_seen: Utc::now(),
+            updated_at: Utc::now(),
+            retired_at: None,
+        })
+        .unwrap();
+        for day in [1, 2] {
+            db.insert_gps_data(&GpsData {
+                timestamp: Utc.with_ymd_and_hms(2024, 3, day, 9, 0, 0).unwrap(),
+                device_id: "phone".to_string(),
+                latitude: 52.37,
+                longitude: 4.89,
+                altitude: None,
+                accuracy: Some(5.0),
+                speed: None,
+                bearing: None,
+                satellites: None,
+                provider: None,
+                metadata: None,
+            })
+            .unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn test_backup_is_incremental_and_restores() {
+        let dir = tempdir().unwrap();
+        let store_dir = dir.path().join("backups");
+        let db = seeded_db(&dir.path().join("loom.db"));
+        let store = BackupStore::open(&store_dir, "correct horse").unwrap();
+
+        let first = store.backup(&db).unwrap();
+        assert!(first.new_objects > 0);
+        assert_eq!(first.snapshot.parent, None);
+        let gps_days = first.snapshot.parts.iter().filter(|part| part.table_name == "gps_data").count();
+        assert_eq!(gps_days, 2);
+
+        let second = store.backup(&db).unwrap();
+        assert_eq!(second.new_objects, 0);
+        assert_eq!(second.snapshot.parent.as_deref(), Some(first.snapshot.id.as_str()));
+
+        assert!(store.verify().unwrap().is_ok());
+
+        let target = dir.path().join("restored.db");
+        store.restore(&first.snapshot.id, &target).unwrap();
+        let restored = duckdb::Connection::open(&target).unwrap();
+        let count = |table: &str| -> i64 {
+            restored.query_row(&format!("SELECT count(*) FROM {}", table), [], |row| row.get(0)).unwrap()
+        };
+        assert_eq!(count("gps_data"), 2);
+        assert_eq!(count("devices"), 1);
+        drop(restored);
+
+        assert!(matches!(store.restore(&first.snapshot.id, &target), Err(BackupError::TargetExists(_))));
+    }
+
+    #[test]
+    fn test_older_snapshots_are_migrated_on_restore() {
+        let dir = tempdir().unwrap();
+        let store_dir = dir.path().join("backups");
+        let db = Database::new_at_version(&dir.path().join("loom.db"), 2).unwrap();
+        db.insert_user(&user("alice")).unwrap();
+        let store = BackupStore::open(&store_dir, "correct horse").unwrap();
+
+        let summary = store.backup(&db).unwrap();
+        assert_eq!(summary.snapshot.schema_version, 2);
+        assert!(summary.snapshot.parts.iter().all(|part| part.table_name != "device_tokens"));
+
+        let target = dir.path().join("restored.db");
+        store.restore(&summary.snapshot.id, &target).unwrap();
+        let restored = Database::new(&target).unwrap();
+        assert_eq!(restored.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(restored.get_user("alice").unwrap().email, user("alice").email);
+        assert!(restored.get_device_tokens("alice").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_wrong_passphrase_and_tampering_are_detected() {
+        let dir = tempdir().unwrap();
+        let store_dir = dir.path().join("backups");
+        let db = seeded_db(&dir.path().join("loom.db"));
+        let summary = BackupStore::open(&store_dir, "correct horse").unwrap().backup(&db).unwrap();
+
+        assert!(matches!(BackupStore::open(&store_dir, "battery staple"), Err(BackupError::WrongPassphrase)));
+
+        let store = BackupStore::open(&store_dir, "correct horse").unwrap();
+        let object = store_dir.join(OBJECTS_DIR).join(&summary.snapshot.parts[0].object);
+        let mut sealed = fs::read(&object).unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        fs::write(&object, sealed).unwrap();
+
+        let check = store.verify().unwrap();
+        assert_eq!(check.problems.len(), 1);
+        let target = dir.path().join("restored.db");
+        assert!(matches!(store.restore(&summary.snapshot.id, &target), Err(BackupError::Corrupt(_))));
+        assert!(!target.exists());
+    }
+}
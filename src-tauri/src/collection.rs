@@ -0,0 +1,510 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::path::PathBuf;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use crate::datatypes::{
+    config::{CollectionPlan, Consent, SensorPreference, SensorSchedule, SyncPriorityConfig},
+    device::{Device, DeviceCapabilities},
+    types::ConsentLevel,
+    sensor::{AccelerometerData, GyroscopeData, MagnetometerData, GpsData, HeartRateData, ECGData, BloodOxygenData, StressData, LightData, PressureData, TemperatureData, HumidityData, StepCountData, AudioLevelData, BatteryData, NetworkData, ScreenStateData, NotificationData, AppUsageData, WifiData, CallLogData, TodosData},
+};
+use crate::db::{effective_consent, is_sensor_table, AccessError, Database, UserContext};
+
+/// Used for tables without a more specific default.
+pub const DEFAULT_INTERVAL_MS: i64 = 60_000;
+
+/// Used when `sync_priorities` has no row for a table.
+const DEFAULT_BATCH_SIZE: i32 = 1000;
+
+#[derive(Debug)]
+pub enum CollectionError {
+    /// The device doesn't have the hardware the table needs.
+    Unsupported { table: String, capability: &'static str },
+    /// The device could collect the table but its plan doesn't include it,
+    /// usually because the user turned it off.
+    NotPlanned(String),
+    /// The user hasn't consented to the table being uploaded from the device.
+    ConsentWithheld { table: String, level: ConsentLevel },
+    /// Not a sensor table, or one that can't be ingested yet.
+    UnknownTable(String),
+    InvalidRow(serde_json::Error),
+    InvalidInterval(i64),
+    Access(AccessError),
+}
+
+impl fmt::Display for CollectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CollectionError::Unsupported { table, capability } => {
+                write!(f, "device cannot provide {} (missing {})", table, capability)
+            }
+            CollectionError::NotPlanned(table) => write!(f, "{} is not in the device's collection plan", table),
+            CollectionError::ConsentWithheld { table, level } => {
+                write!(f, "consent for {} is {}", table, level.as_str())
+            }
+            CollectionError::UnknownTable(table) => write!(f, "unknown sensor table: {}", table),
+            CollectionError::InvalidRow(e) => write!(f, "invalid row: {}", e),
+            CollectionError::InvalidInterval(ms) => write!(f, "invalid sampling interval: {}ms", ms),
+            CollectionError::Access(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CollectionError {}
+
+impl From<AccessError> for CollectionError {
+    fn from(e: AccessError) -> Self {
+        match e {
+            AccessError::ConsentWithheld(withheld) => CollectionError::ConsentWithheld {
+                table: withheld.table,
+                level: withheld.level,
+            },
+            e => CollectionError::Access(e),
+        }
+    }
+}
+
+impl From<duckdb::Error> for CollectionError {
+    fn from(e: duckdb::Error) -> Self {
+        AccessError::from(e).into()
+    }
+}
+
+type HasCapability = fn(&DeviceCapabilities) -> bool;
+
+/// The capability a device needs before it is asked for `table`. Tables fed
+/// by the OS rather than by hardware (battery, notifications, ...) need none.
+pub fn required_capability(table: &str) -> Option<(&'static str, HasCapability)> {
+    let required: (&'static str, HasCapability) = match table {
+        "accelerometer_data" => ("has_accelerometer", |c| c.has_accelerometer),
+        "gyroscope_data" => ("has_gyroscope", |c| c.has_gyroscope),
+        "magnetometer_data" => ("has_magnetometer", |c| c.has_magnetometer),
+        "gps_data" => ("has_gps", |c| c.has_gps),
+        "heart_rate_data" => ("has_heart_rate", |c| c.has_heart_rate),
+        "proximity_data" => ("has_proximity", |c| c.has_proximity),
+        "light_data" => ("has_light", |c| c.has_light),
+        "pressure_data" => ("has_pressure", |c| c.has_pressure),
+        "temperature_data" | "skin_temperature_data" => ("has_temperature", |c| c.has_temperature),
+        "humidity_data" => ("has_humidity", |c| c.has_humidity),
+        "step_count_data" => ("has_step_counter", |c| c.has_step_counter),
+        "ecg_data" => ("has_ecg", |c| c.has_ecg),
+        "blood_oxygen_data" => ("has_blood_oxygen", |c| c.has_blood_oxygen),
+        "stress_data" => ("has_stress", |c| c.has_stress),
+        "compass_data" => ("has_compass", |c| c.has_compass),
+        "camera_data" | "object_detection_data" | "face_recognition_data" | "pose_detection_data" => {
+            ("has_camera", |c| c.has_camera)
+        }
+        "microphone_data" | "audio_level_data" => ("has_microphone", |c| c.has_microphone),
+        _ => return None,
+    };
+    Some(required)
+}
+
+/// How often a table is sampled unless the user says otherwise.
+pub fn default_interval_ms(table: &str) -> i64 {
+    match table {
+        "accelerometer_data" | "gyroscope_data" => 20,
+        "magnetometer_data" | "compass_data" => 100,
+        "light_data" | "proximity_data" | "audio_level_data" | "ecg_data" => 1_000,
+        "heart_rate_data" => 5_000,
+        "gps_data" => 30_000,
+        _ => DEFAULT_INTERVAL_MS,
+    }
+}
+
+/// Whether `device` can provide `table` at all. A non-empty
+/// `available_sensors` list narrows this further to the sensors it names,
+/// by table name without the `_data` suffix.
+fn check_supported(device: &Device, table: &str) -> Result<(), CollectionError> {
+    if let Some((capability, has)) = required_capability(table) {
+        if !has(&device.capabilities) {
+            return Err(CollectionError::Unsupported { table: table.to_string(), capability });
+        }
+    }
+    let sensor = table.strip_suffix("_data").unwrap_or(table);
+    if !device.available_sensors.is_empty() && !device.available_sensors.iter().any(|s| s == sensor) {
+        return Err(CollectionError::Unsupported { table: table.to_string(), capability: "available_sensors" });
+    }
+    Ok(())
+}
+
+/// Every sensor table the device supports, the user hasn't turned off and
+/// hasn't withheld consent for. Tables consented to as `LocalOnly` are
+/// sampled but marked so the device keeps them.
+pub fn build_plan(
+    device: &Device,
+    preferences: &[SensorPreference],
+    consents: &[Consent],
+    priorities: &[SyncPriorityConfig],
+    now: DateTime<Utc>,
+) -> CollectionPlan {
+    let sensors = crate::db::SENSOR_TABLES
+        .iter()
+        .filter(|table| check_supported(device, table).is_ok())
+        .filter_map(|table| {
+            let preference = preferences.iter().find(|p| p.table_name == *table);
+            let consent = effective_consent(consents, &device.device_id, table);
+            if preference.is_some_and(|p| !p.enabled) || consent == ConsentLevel::Never {
+                return None;
+            }
+            let priority = priorities.iter().find(|p| p.table_name == *table);
+            Some(SensorSchedule {
+                table_name: table.to_string(),
+                interval_ms: preference
+                    .and_then(|p| p.interval_ms)
+                    .unwrap_or_else(|| default_interval_ms(table)),
+                batch_size: priority.map_or(DEFAULT_BATCH_SIZE, |p| p.batch_size),
+                max_delay_seconds: priority.and_then(|p| p.max_delay_seconds),
+                local_only: consent == ConsentLevel::LocalOnly,
+            })
+        })
+        .collect();
+
+    CollectionPlan {
+        device_id: device.device_id.clone(),
+        generated_at: now,
+        sensors,
+        privacy_zones: Vec::new(),
+    }
+}
+
+/// Rejects uploads for `table` that `device` shouldn't be sending under `plan`.
+pub fn validate(device: &Device, plan: &CollectionPlan, table: &str) -> Result<(), CollectionError> {
+    check_supported(device, table)?;
+    match plan.sensors.iter().find(|s| s.table_name == table) {
+        None => Err(CollectionError::NotPlanned(table.to_string())),
+        Some(schedule) if schedule.local_only => Err(CollectionError::ConsentWithheld {
+            table: table.to_string(),
+            level: ConsentLevel::LocalOnly,
+        }),
+        Some(_) => Ok(()),
+    }
+}
+
+/// `validate` for rows the app didn't collect: the table needn't be in the
+/// device's plan, but the device must support it and consent must allow
+/// storing it here.
+fn validate_import(device: &Device, consents: &[Consent], table: &str) -> Result<(), CollectionError> {
+    check_supported(device, table)?;
+    match effective_consent(consents, &device.device_id, table) {
+        ConsentLevel::Collect => Ok(()),
+        level => Err(CollectionError::ConsentWithheld { table: table.to_string(), level }),
+    }
+}
+
+/// Builds collection plans for a user's devices and checks incoming sensor
+/// data against them.
+pub struct CollectionService<'a> {
+    db: &'a Database,
+    user: UserContext,
+}
+
+impl<'a> CollectionService<'a> {
+    pub fn new(db: &'a Database, user: UserContext) -> Self {
+        Self { db, user }
+    }
+
+    pub fn plan(&self, device_id: &str) -> Result<CollectionPlan, CollectionError> {
+        let device = self.db.scoped(self.user.clone()).get_device(device_id)?;
+        self.plan_for(&device)
+    }
+
+    fn plan_for(&self, device: &Device) -> Result<CollectionPlan, CollectionError> {
+        let preferences = self.db.get_sensor_preferences(&self.user.user_id)?;
+        let consents = self.db.get_consents(&self.user.user_id)?;
+        let priorities = self.db.get_sync_priorities()?;
+        let mut plan = build_plan(device, &preferences, &consents, &priorities, Utc::now());
+        plan.privacy_zones = self.db.get_privacy_zones(&self.user.user_id)?;
+        Ok(plan)
+    }
+
+    pub fn preferences(&self) -> Result<Vec<SensorPreference>, CollectionError> {
+        Ok(self.db.get_sensor_preferences(&self.user.user_id)?)
+    }
+
+    pub fn set_preference(&self, table: &str, enabled: bool, interval_ms: Option<i64>) -> Result<SensorPreference, CollectionError> {
+        if !is_sensor_table(table) {
+            return Err(CollectionError::UnknownTable(table.to_string()));
+        }
+        if let Some(ms) = interval_ms.filter(|ms| *ms <= 0) {
+            return Err(CollectionError::InvalidInterval(ms));
+        }
+
+        let preference = SensorPreference {
+            user_id: self.user.user_id.clone(),
+            table_name: table.to_string(),
+            enabled,
+            interval_ms,
+            updated_at: Utc::now(),
+        };
+        self.db.set_sensor_preference(&preference)?;
+        Ok(preference)
+    }
+
+    pub fn reset_preference(&self, table: &str) -> Result<(), CollectionError> {
+        Ok(self.db.delete_sensor_preference(&self.user.user_id, table)?)
+    }
+
+    /// Stores uploaded `rows` for `table` if every row comes from one of the
+    /// user's devices and that device's plan includes the table for upload.
+    /// Either all rows are stored or none are.
+    pub fn ingest(&self, table: &str, rows: Vec<Value>) -> Result<usize, CollectionError> {
+        let rows = rows
+            .into_iter()
+            .map(|row| SensorRow::parse(table, row))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let scoped = self.db.scoped(self.user.clone());
+        let mut checked = HashSet::new();
+        for row in &rows {
+            if !checked.insert(row.device_id()) {
+                continue;
+            }
+            let device = scoped.get_device(row.device_id())?;
+            validate(&device, &self.plan_for(&device)?, table)?;
+        }
+
+        let inserted = self.db.in_transaction(|db| {
+            for row in &rows {
+                row.insert(db)?;
+            }
+            Ok(rows.len())
+        })?;
+        Ok(inserted)
+    }
+
+    /// Merges Parquet files into `table`, such as ones written by
+    /// `Database::export_parquet`. Rows go through the same checks and
+    /// filters as uploads, except that they needn't be in a collection plan
+    /// as the app didn't collect them. Rows already stored are skipped.
+    /// Either every new row is stored or none are.
+    pub fn import_parquet(&self, table: &str, paths: &[PathBuf]) -> Result<ParquetImport, CollectionError> {
+        let read = self.db.read_parquet_rows(table, paths)?;
+        let rows = read
+            .rows
+            .into_iter()
+            .map(|row| SensorRow::parse(table, row))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let scoped = self.db.scoped(self.user.clone());
+        let consents = self.db.get_consents(&self.user.user_id)?;
+        let mut checked = HashSet::new();
+        for row in &rows {
+            if !checked.insert(row.device_id()) {
+                continue;
+            }
+            let device = scoped.get_device(row.device_id())?;
+            validate_import(&device, &consents, table)?;
+        }
+
+        let imported = self.db.in_transaction(|db| {
+            for row in &rows {
+                row.insert(db)?;
+            }
+            Ok(rows.len())
+        })?;
+        Ok(ParquetImport {
+            table_name: table.to_string(),
+            rows_read: read.rows_read,
+            duplicates: read.duplicates,
+            imported,
+        })
+    }
+}
+
+/// What `CollectionService::import_parquet` stored.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ParquetImport {
+    pub table_name: String,
+    pub rows_read: usize,
+    /// Rows already stored, or repeated in the files.
+    pub duplicates: usize,
+    pub imported: usize,
+}
+
+/// A deserialized row for one of the tables `Database` has an insert method
+/// for. Shared with the file importers, which store rows the same way.
+macro_rules! sensor_rows {
+    ($($table:literal => $variant:ident($data:ty), $insert:ident;)*) => {
+        pub(crate) enum SensorRow {
+            $($variant($data),)*
+        }
+
+        impl SensorRow {
+            /// Whether rows for `table` can be parsed and stored.
+            pub(crate) fn supports(table: &str) -> bool {
+                matches!(table, $($table)|*)
+            }
+
+            pub(crate) fn parse(table: &str, row: Value) -> Result<Self, CollectionError> {
+                match table {
+                    $($table => serde_json::from_value(row).map(SensorRow::$variant).map_err(CollectionError::InvalidRow),)*
+                    _ => Err(CollectionError::UnknownTable(table.to_string())),
+                }
+            }
+
+            pub(crate) fn device_id(&self) -> &str {
+                match self {
+                    $(SensorRow::$variant(data) => &data.device_id,)*
+                }
+            }
+
+            pub(crate) fn timestamp(&self) -> DateTime<Utc> {
+                match self {
+                    $(SensorRow::$variant(data) => data.timestamp,)*
+                }
+            }
+
+            pub(crate) fn insert(&self, db: &Database) -> duckdb::Result<()> {
+                match self {
+                    $(SensorRow::$variant(data) => db.$insert(data),)*
+                }
+            }
+        }
+    };
+}
+
+sensor_rows! {
+    "accelerometer_data" => Accelerometer(AccelerometerData), insert_accelerometer_data;
+    "gyroscope_data" => Gyroscope(GyroscopeData), insert_gyroscope_data;
+    "magnetometer_data" => Magnetometer(MagnetometerData), insert_magnetometer_data;
+    "gps_data" => Gps(GpsData), insert_gps_data;
+    "heart_rate_data" => HeartRate(HeartRateData), insert_heart_rate_data;
+    "ecg_data" => Ecg(ECGData), insert_ecg_data;
+    "blood_oxygen_data" => BloodOxygen(BloodOxygenData), insert_blood_oxygen_data;
+    "stress_data" => Stress(StressData), insert_stress_data;
+    "light_data" => Light(LightData), insert_light_data;
+    "pressure_data" => Pressure(PressureData), insert_pressure_data;
+    "temperature_data" => Temperature(TemperatureData), insert_temperature_data;
+    "humidity_data" => Humidity(HumidityData), insert_humidity_data;
+    "step_count_data" => StepCount(StepCountData), insert_step_count_data;
+    "call_log_data" => CallLog(CallLogData), insert_call_log_data;
+    "todos_data" => Todos(TodosData), insert_todos_data;
+    "audio_level_data" => AudioLevel(AudioLevelData), insert_audio_level_data;
+    "battery_data" => Battery(BatteryData), insert_battery_data;
+    "network_data" => Network(NetworkData), insert_network_data;
+    "screen_state_data" => ScreenState(ScreenStateData), insert_screen_state_data;
+    "notification_data" => Notification(NotificationData), insert_notification_data;
+    "app_usage_data" => AppUsage(AppUsageData), insert_app_usage_data;
+    "wifi_data" => Wifi(WifiData), insert_wifi_data;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatypes::{device::ScreenDetails, types::DeviceType};
+
+    fn watch() -> Device {
+        Device {
+            device_id: "watch".to_string(),
+            user_id: "alice".to_string(),
+            device_type: DeviceType::Watch,
+            os_type: "WearOS".to_string(),
+            os_version: "4".to_string(),
+            app_version: "1.0".to_string(),
+            available_sensors: vec![],
+            capabilities: DeviceCapabilities {
+                has_camera: false,
+                has_microphone: false,
+                has_gps: true,
+                has_accelerometer: true,
+                has_gyroscope: false,
+                has_magnetometer: false,
+                has_proximity: false,
+                has_light: false,
+                has_pressure: false,
+                has_temperature: false,
+                has_humidity: false,
+                has_step_counter: true,
+                has_heart_rate: true,
+                has_ecg: false,
+                has_blood_oxygen: false,
+                has_stress: false,
+                has_compass: false,
+                screen_details: ScreenDetails { width: 450, height: 450, density: 2.0, refresh_rate: 60 },
+            },
+            name: None,
+            created_at: Utc::now(),
+            last_seen: Utc::now(),
+            updated_at: Utc::now(),
+            retired_at: None,
+        }
+    }
+
+    fn preference(table: &str, enabled: bool, interval_ms: Option<i64>) -> SensorPreference {
+        SensorPreference {
+            user_id: "alice".to_string(),
+            table_name: table.to_string(),
+            enabled,
+            interval_ms,
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn consent(table: &str, level: ConsentLevel) -> Consent {
+        Consent {
+            user_id: "alice".to_string(),
+            device_id: "watch".to_string(),
+            table_name: table.to_string(),
+            level,
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn planned(plan: &CollectionPlan, table: &str) -> Option<i64> {
+        plan.sensors.iter().find(|s| s.table_name == table).map(|s| s.interval_ms)
+    }
+
+    #[test]
+    fn test_plan_follows_capabilities_and_preferences() {
+        let preferences = [preference("gps_data", false, None), preference("heart_rate_data", true, Some(1_000))];
+        let plan = build_plan(&watch(), &preferences, &[], &[], Utc::now());
+
+        assert_eq!(planned(&plan, "accelerometer_data"), Some(20));
+        assert_eq!(planned(&plan, "heart_rate_data"), Some(1_000));
+        assert_eq!(planned(&plan, "battery_data"), Some(DEFAULT_INTERVAL_MS));
+        assert_eq!(planned(&plan, "gps_data"), None);
+        assert_eq!(planned(&plan, "ecg_data"), None);
+        assert_eq!(planned(&plan, "camera_data"), None);
+    }
+
+    #[test]
+    fn test_validate_rejects_unsupported_and_disabled_tables() {
+        let device = watch();
+        let plan = build_plan(&device, &[preference("gps_data", false, None)], &[], &[], Utc::now());
+
+        assert!(validate(&device, &plan, "heart_rate_data").is_ok());
+        assert!(matches!(
+            validate(&device, &plan, "ecg_data"),
+            Err(CollectionError::Unsupported { capability: "has_ecg", .. })
+        ));
+        assert!(matches!(validate(&device, &plan, "gps_data"), Err(CollectionError::NotPlanned(_))));
+    }
+
+    #[test]
+    fn test_available_sensors_narrow_the_plan() {
+        let mut device = watch();
+        device.available_sensors = vec!["heart_rate".to_string(), "battery".to_string()];
+        let plan = build_plan(&device, &[], &[], &[], Utc::now());
+
+        let tables: Vec<&str> = plan.sensors.iter().map(|s| s.table_name.as_str()).collect();
+        assert_eq!(tables, vec!["heart_rate_data", "battery_data"]);
+    }
+
+    #[test]
+    fn test_consent_shapes_the_plan() {
+        let device = watch();
+        let consents = [consent("gps_data", ConsentLevel::Never), consent("heart_rate_data", ConsentLevel::LocalOnly)];
+        let plan = build_plan(&device, &[], &consents, &[], Utc::now());
+
+        assert_eq!(planned(&plan, "gps_data"), None);
+        assert!(plan.sensors.iter().any(|s| s.table_name == "heart_rate_data" && s.local_only));
+        assert!(matches!(
+            validate(&device, &plan, "heart_rate_data"),
+            Err(CollectionError::ConsentWithheld { level: ConsentLevel::LocalOnly, .. })
+        ));
+        assert!(validate(&device, &plan, "step_count_data").is_ok());
+    }
+}
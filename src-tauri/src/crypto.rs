@@ -0,0 +1,357 @@
+use std::{fmt, fs, io, path::Path};
+use argon2::Argon2;
+use chacha20poly1305::{aead::{Aead, Payload}, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+pub const SALT_LEN: usize = 16;
+
+/// Prefix of every sealed value, so the format can change later.
+const SEALED_PREFIX: &str = "v1:";
+
+/// Prefix of an encrypted column value, followed by `<key_id>:<hex>`.
+pub const FIELD_PREFIX: &str = "enc:v1:";
+
+#[derive(Debug)]
+pub enum CryptoError {
+    /// The key file exists but isn't a 256-bit key.
+    InvalidKey,
+    /// The value is malformed, was tampered with or sealed under another key.
+    Decrypt,
+    /// Decrypting needs the private key, which is only held while unlocked.
+    Locked,
+    /// Key derivation from the passphrase failed.
+    Kdf(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::InvalidKey => write!(f, "key file does not contain a 256-bit key"),
+            CryptoError::Decrypt => write!(f, "value could not be decrypted"),
+            CryptoError::Locked => write!(f, "encrypted fields are locked"),
+            CryptoError::Kdf(e) => write!(f, "key derivation failed: {}", e),
+            CryptoError::Io(e) => write!(f, "key file error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+impl From<io::Error> for CryptoError {
+    fn from(e: io::Error) -> Self {
+        CryptoError::Io(e)
+    }
+}
+
+/// Seals short secrets (OAuth tokens and the like) with ChaCha20-Poly1305
+/// before they are written to the database.
+pub struct SecretBox {
+    cipher: ChaCha20Poly1305,
+}
+
+impl SecretBox {
+    pub fn new(key: &[u8; KEY_LEN]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    /// Reads the key at `path`, creating a random one there on first use.
+    pub fn load_or_create(path: &Path) -> Result<Self, CryptoError> {
+        if path.exists() {
+            let bytes = fs::read(path)?;
+            let key: [u8; KEY_LEN] = bytes.try_into().map_err(|_| CryptoError::InvalidKey)?;
+            return Ok(Self::new(&key));
+        }
+
+        let mut key = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut key);
+        write_private(path, &key)?;
+        Ok(Self::new(&key))
+    }
+
+    pub fn seal(&self, plaintext: &str) -> String {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+            .expect("encryption with a valid key cannot fail");
+
+        format!("{}{}{}", SEALED_PREFIX, to_hex(&nonce), to_hex(&ciphertext))
+    }
+
+    pub fn open(&self, sealed: &str) -> Result<String, CryptoError> {
+        let bytes = sealed
+            .strip_prefix(SEALED_PREFIX)
+            .and_then(from_hex)
+            .filter(|bytes| bytes.len() > NONCE_LEN)
+            .ok_or(CryptoError::Decrypt)?;
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| CryptoError::Decrypt)?;
+        String::from_utf8(plaintext).map_err(|_| CryptoError::Decrypt)
+    }
+
+    /// Like `seal` for binary data, as the nonce followed by the ciphertext.
+    /// `aad` is authenticated but not stored, so opening fails unless it is
+    /// passed again unchanged.
+    pub fn seal_bytes(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+            .expect("encryption with a valid key cannot fail");
+        [nonce.as_slice(), &ciphertext].concat()
+    }
+
+    pub fn open_bytes(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if sealed.len() <= NONCE_LEN {
+            return Err(CryptoError::Decrypt);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| CryptoError::Decrypt)
+    }
+}
+
+/// Key pair for encrypted columns. Sealing only needs the public half, so
+/// rows can still be written while the private half is locked away; opening
+/// needs the private half, which is unwrapped with the user's passphrase.
+#[derive(Clone)]
+pub struct FieldKey {
+    pub key_id: String,
+    public: PublicKey,
+    secret: Option<StaticSecret>,
+}
+
+/// A field key as stored: the public half in the clear and the private half
+/// sealed under a key derived from the passphrase with Argon2id.
+#[derive(Debug, Clone)]
+pub struct WrappedFieldKey {
+    pub key_id: String,
+    pub public_key: String,
+    pub wrapped_secret: String,
+    pub salt: String,
+}
+
+impl FieldKey {
+    pub fn generate() -> Self {
+        let mut id = [0u8; 8];
+        OsRng.fill_bytes(&mut id);
+        let secret = StaticSecret::random_from_rng(OsRng);
+        Self {
+            key_id: to_hex(&id),
+            public: PublicKey::from(&secret),
+            secret: Some(secret),
+        }
+    }
+
+    /// The public half only; enough to seal but not to open.
+    pub fn locked(wrapped: &WrappedFieldKey) -> Result<Self, CryptoError> {
+        let public: [u8; KEY_LEN] = from_hex(&wrapped.public_key)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(CryptoError::InvalidKey)?;
+        Ok(Self {
+            key_id: wrapped.key_id.clone(),
+            public: PublicKey::from(public),
+            secret: None,
+        })
+    }
+
+    /// Fails with `Decrypt` if the passphrase is wrong.
+    pub fn unlock(wrapped: &WrappedFieldKey, passphrase: &str) -> Result<Self, CryptoError> {
+        let salt = from_hex(&wrapped.salt).ok_or(CryptoError::InvalidKey)?;
+        let secret = SecretBox::new(&derive_key(passphrase, &salt)?).open(&wrapped.wrapped_secret)?;
+        let secret: [u8; KEY_LEN] = from_hex(&secret)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(CryptoError::InvalidKey)?;
+        let secret = StaticSecret::from(secret);
+
+        let key = Self::locked(wrapped)?;
+        if PublicKey::from(&secret) != key.public {
+            return Err(CryptoError::InvalidKey);
+        }
+        Ok(Self { secret: Some(secret), ..key })
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    /// Wraps the private half under `passphrase` with a fresh salt.
+    pub fn wrap(&self, passphrase: &str) -> Result<WrappedFieldKey, CryptoError> {
+        let secret = self.secret.as_ref().ok_or(CryptoError::Locked)?;
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Ok(WrappedFieldKey {
+            key_id: self.key_id.clone(),
+            public_key: to_hex(self.public.as_bytes()),
+            wrapped_secret: SecretBox::new(&derive_key(passphrase, &salt)?).seal(&to_hex(secret.as_bytes())),
+            salt: to_hex(&salt),
+        })
+    }
+
+    /// Encrypts to this key with a throwaway X25519 key, so each value has
+    /// its own symmetric key.
+    pub fn seal(&self, plaintext: &str) -> String {
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&self.public);
+        let sealed = SecretBox::new(&value_key(shared.as_bytes(), &ephemeral_public, &self.public)).seal(plaintext);
+
+        format!(
+            "{}{}:{}{}",
+            FIELD_PREFIX,
+            self.key_id,
+            to_hex(ephemeral_public.as_bytes()),
+            &sealed[SEALED_PREFIX.len()..],
+        )
+    }
+
+    pub fn open(&self, sealed: &str) -> Result<String, CryptoError> {
+        let secret = self.secret.as_ref().ok_or(CryptoError::Locked)?;
+        let body = sealed
+            .strip_prefix(FIELD_PREFIX)
+            .and_then(|rest| rest.strip_prefix(&self.key_id))
+            .and_then(|rest| rest.strip_prefix(':'))
+            .ok_or(CryptoError::Decrypt)?;
+        let (ephemeral_public, sealed) = body.split_at_checked(KEY_LEN * 2).ok_or(CryptoError::Decrypt)?;
+        let ephemeral_public: [u8; KEY_LEN] = from_hex(ephemeral_public)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(CryptoError::Decrypt)?;
+        let ephemeral_public = PublicKey::from(ephemeral_public);
+
+        let shared = secret.diffie_hellman(&ephemeral_public);
+        SecretBox::new(&value_key(shared.as_bytes(), &ephemeral_public, &self.public))
+            .open(&format!("{}{}", SEALED_PREFIX, sealed))
+    }
+}
+
+/// The key id of an encrypted column value, or `None` for plaintext.
+pub fn sealed_key_id(value: &str) -> Option<&str> {
+    value.strip_prefix(FIELD_PREFIX)?.split_once(':').map(|(key_id, _)| key_id)
+}
+
+/// A 256-bit key from `passphrase` with Argon2id.
+pub fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; KEY_LEN], CryptoError> {
+    let mut key = [0u8; KEY_LEN];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| CryptoError::Kdf(e.to_string()))?;
+    Ok(key)
+}
+
+fn value_key(shared: &[u8], ephemeral_public: &PublicKey, public: &PublicKey) -> [u8; KEY_LEN] {
+    let mut hasher = Sha256::new();
+    hasher.update(shared);
+    hasher.update(ephemeral_public.as_bytes());
+    hasher.update(public.as_bytes());
+    hasher.finalize().into()
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?.write_all(bytes)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    fs::write(path, bytes)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_seal_round_trip() {
+        let secrets = SecretBox::new(&[7u8; KEY_LEN]);
+        let sealed = secrets.seal("ya29.token");
+        assert!(sealed.starts_with(SEALED_PREFIX));
+        assert!(!sealed.contains("ya29"));
+        assert_ne!(sealed, secrets.seal("ya29.token"));
+        assert_eq!(secrets.open(&sealed).unwrap(), "ya29.token");
+    }
+
+    #[test]
+    fn test_open_rejects_wrong_key_and_tampering() {
+        let sealed = SecretBox::new(&[1u8; KEY_LEN]).seal("secret");
+        assert!(SecretBox::new(&[2u8; KEY_LEN]).open(&sealed).is_err());
+
+        let mut tampered = sealed.clone();
+        let last = if tampered.ends_with('0') { "1" } else { "0" };
+        tampered.replace_range(tampered.len() - 1.., last);
+        assert!(SecretBox::new(&[1u8; KEY_LEN]).open(&tampered).is_err());
+        assert!(SecretBox::new(&[1u8; KEY_LEN]).open("plaintext").is_err());
+    }
+
+    #[test]
+    fn test_sealed_bytes_are_bound_to_aad() {
+        let secrets = SecretBox::new(&[3u8; KEY_LEN]);
+        let sealed = secrets.seal_bytes(b"PAR1", b"object-a");
+        assert_eq!(secrets.open_bytes(&sealed, b"object-a").unwrap(), b"PAR1");
+        assert!(secrets.open_bytes(&sealed, b"object-b").is_err());
+        assert!(secrets.open_bytes(&sealed[..NONCE_LEN], b"object-a").is_err());
+    }
+
+    #[test]
+    fn test_load_or_create_reuses_key() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("loom.key");
+        let sealed = SecretBox::load_or_create(&path).unwrap().seal("secret");
+        assert_eq!(SecretBox::load_or_create(&path).unwrap().open(&sealed).unwrap(), "secret");
+    }
+
+    #[test]
+    fn test_hex_round_trip() {
+        assert_eq!(from_hex(&to_hex(&[0, 15, 255])).unwrap(), vec![0, 15, 255]);
+        assert!(from_hex("abc").is_none());
+        assert!(from_hex("zz").is_none());
+    }
+
+    #[test]
+    fn test_field_key_seals_while_locked() {
+        let key = FieldKey::generate();
+        let wrapped = key.wrap("correct horse").unwrap();
+
+        let locked = FieldKey::locked(&wrapped).unwrap();
+        let sealed = locked.seal("+44 7700 900123");
+        assert_eq!(sealed_key_id(&sealed), Some(key.key_id.as_str()));
+        assert!(matches!(locked.open(&sealed), Err(CryptoError::Locked)));
+
+        let unlocked = FieldKey::unlock(&wrapped, "correct horse").unwrap();
+        assert_eq!(unlocked.open(&sealed).unwrap(), "+44 7700 900123");
+        assert!(FieldKey::unlock(&wrapped, "wrong").is_err());
+        assert!(FieldKey::generate().open(&sealed).is_err());
+        assert_eq!(sealed_key_id("plain text"), None);
+    }
+}
@@ -0,0 +1,97 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use crate::datatypes::types::AuditAction;
+
+/// Which sensor tables a user keeps a tamper-evident record of. Rows stored
+/// before a table was added are taken into the log as they stand at that
+/// point.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditPolicy {
+    pub user_id: String,
+    pub tables: Vec<String>,
+    /// Seal each finished day's entries under a Merkle root, so single rows
+    /// can be proven against a value published or noted down elsewhere.
+    #[serde(default)]
+    pub daily_roots: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// One link in a user's audit chain. `hash` covers every other field and
+/// the previous entry's hash, so changing or dropping an entry breaks the
+/// chain from there on. Rows are recorded as digests only; see
+/// `audit::row_digest`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub user_id: String,
+    pub seq: i64,
+    pub action: AuditAction,
+    pub table_name: String,
+    pub device_id: String,
+    pub row_digests: Vec<String>,
+    pub recorded_at: DateTime<Utc>,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+/// The Merkle root over the hashes of one day's entries, in chain order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRoot {
+    pub user_id: String,
+    pub day: NaiveDate,
+    pub root: String,
+    pub entries: i64,
+    pub first_seq: i64,
+    pub last_seq: i64,
+    pub sealed_at: DateTime<Utc>,
+}
+
+/// Rows of one table and device that differ from what the log accounts for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditMismatch {
+    pub table_name: String,
+    pub device_id: String,
+    /// Logged rows no longer stored as logged: deleted or changed.
+    pub missing: i64,
+    /// Stored rows the log has no record of: added or changed.
+    pub unexpected: i64,
+}
+
+/// The outcome of checking a user's chain, daily roots and audited tables.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditReport {
+    pub user_id: String,
+    pub entries: i64,
+    /// The first entry whose hash or link to its predecessor is wrong.
+    pub broken_at: Option<i64>,
+    pub bad_roots: Vec<NaiveDate>,
+    pub mismatches: Vec<AuditMismatch>,
+    pub verified_at: DateTime<Utc>,
+}
+
+impl AuditReport {
+    pub fn is_intact(&self) -> bool {
+        self.broken_at.is_none() && self.bad_roots.is_empty() && self.mismatches.is_empty()
+    }
+}
+
+/// A sibling hash on the way from a leaf up to a Merkle root.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleStep {
+    pub hash: String,
+    /// Whether the sibling is hashed in before the running value.
+    pub left: bool,
+}
+
+/// Evidence that a stored row is as it was logged: the row as hashed, the
+/// entry that logged it and, once its day is sealed, the path from that
+/// entry up to the day's root.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditProof {
+    pub table_name: String,
+    pub device_id: String,
+    pub row: String,
+    pub row_digest: String,
+    pub entry: AuditEntry,
+    pub root: Option<AuditRoot>,
+    pub path: Vec<MerkleStep>,
+}
@@ -0,0 +1,54 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+/// One Parquet file of a backup snapshot. `object` names the encrypted
+/// blob holding it, derived from its contents, so a part that hasn't
+/// changed since the previous snapshot is stored only once.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupPart {
+    pub table_name: String,
+    /// Set for sensor tables, which are split by day.
+    pub day: Option<NaiveDate>,
+    pub rows: usize,
+    pub object: String,
+}
+
+/// Describes one snapshot; stored encrypted next to its objects.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+    /// Schema version of the database the snapshot came from. Restoring
+    /// loads it at that version and migrates it to the current one.
+    pub schema_version: i64,
+    pub app_version: String,
+    /// The snapshot this one was taken incrementally after, if any.
+    pub parent: Option<String>,
+    pub parts: Vec<BackupPart>,
+}
+
+/// What a backup run wrote.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupSummary {
+    pub snapshot: BackupManifest,
+    pub new_objects: usize,
+    pub reused_objects: usize,
+    pub bytes_written: u64,
+}
+
+/// Result of checking every snapshot and object in a backup directory.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupCheck {
+    pub snapshots: usize,
+    pub objects: usize,
+    /// Objects or snapshots that are missing, don't decrypt or don't match
+    /// their name.
+    pub problems: Vec<String>,
+    pub checked_at: DateTime<Utc>,
+}
+
+impl BackupCheck {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
@@ -1,6 +1,9 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use crate::datatypes::types::{CompressionAlgorithm, SyncPriority, Metadata};
+use crate::datatypes::{
+    privacy::PrivacyZone,
+    types::{CompressionAlgorithm, ConsentLevel, SyncPriority, Metadata},
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RetentionConfig {
@@ -41,6 +44,71 @@ pub struct SyncPriorityConfig {
     pub metadata: Option<Metadata>,
 }
 
+/// A user's choice for one sensor table, applied to all of their devices.
+/// Tables without a preference are collected at the default rate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SensorPreference {
+    pub user_id: String,
+    pub table_name: String,
+    pub enabled: bool,
+    /// Overrides the default sampling interval.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval_ms: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// What a device should sample, derived from its capabilities and its
+/// owner's preferences. Devices fetch it from the networking service.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollectionPlan {
+    pub device_id: String,
+    pub generated_at: DateTime<Utc>,
+    pub sensors: Vec<SensorSchedule>,
+    /// Applied on the device before upload, so readings inside a zone never
+    /// leave it precisely.
+    #[serde(default)]
+    pub privacy_zones: Vec<PrivacyZone>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SensorSchedule {
+    pub table_name: String,
+    pub interval_ms: i64,
+    /// Upload batching, from `sync_priorities`.
+    pub batch_size: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_delay_seconds: Option<i32>,
+    /// Sample for use on the device only; uploads will be rejected.
+    #[serde(default)]
+    pub local_only: bool,
+}
+
+/// Whether a user allows a sensor table to be collected. `device_id` and
+/// `table_name` may be `"*"` to cover all devices or all tables.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Consent {
+    pub user_id: String,
+    pub device_id: String,
+    pub table_name: String,
+    pub level: ConsentLevel,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// One entry in the consent history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConsentChange {
+    pub id: String,
+    pub user_id: String,
+    pub device_id: String,
+    pub table_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_level: Option<ConsentLevel>,
+    pub level: ConsentLevel,
+    /// Rows deleted because consent was withdrawn.
+    pub purged_rows: i64,
+    pub changed_at: DateTime<Utc>,
+}
+
 fn default_batch_size() -> i32 {
     1000
 }
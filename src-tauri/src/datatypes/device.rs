@@ -12,9 +12,14 @@ pub struct Device {
     pub app_version: String,
     pub available_sensors: Vec<String>,
     pub capabilities: DeviceCapabilities,
+    /// User-chosen label, e.g. "Work phone".
+    #[serde(default)]
+    pub name: Option<String>,
     pub created_at: DateTime<Utc>,
     pub last_seen: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    #[serde(default)]
+    pub retired_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
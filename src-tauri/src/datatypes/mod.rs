@@ -4,10 +4,16 @@ pub mod device;
 pub mod note;
 pub mod config;
 pub mod sensor;
+pub mod privacy;
+pub mod audit;
+pub mod backup;
 
 pub use types::*;
 pub use user::*;
 pub use device::*;
 pub use note::*;
 pub use config::*;
-pub use sensor::*; 
\ No newline at end of file
+pub use sensor::*;
+pub use privacy::*;
+pub use audit::*;
+pub use backup::*; 
\ No newline at end of file
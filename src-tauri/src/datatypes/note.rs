@@ -2,7 +2,7 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use crate::datatypes::types::{EntityType, NotePriority, Metadata};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Note {
     pub id: String,
     pub user_id: String,
@@ -34,9 +34,116 @@ pub struct NoteReference {
     pub created_at: DateTime<Utc>,
 }
 
+/// What a note reference points at. Stored in `note_references` as a
+/// `reference_type` plus an encoded `reference_id`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NoteTarget {
+    /// A single row in one of the sensor tables, keyed by (timestamp, device_id).
+    SensorEvent {
+        table: String,
+        device_id: String,
+        timestamp: DateTime<Utc>,
+    },
+    TimeWindow {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+    Location {
+        latitude: f64,
+        longitude: f64,
+        radius_m: f64,
+    },
+    Entity {
+        entity_id: String,
+    },
+    Note {
+        note_id: String,
+    },
+}
+
+impl NoteTarget {
+    pub fn reference_type(&self) -> &'static str {
+        match self {
+            NoteTarget::SensorEvent { .. } => "sensor_event",
+            NoteTarget::TimeWindow { .. } => "time_window",
+            NoteTarget::Location { .. } => "location",
+            NoteTarget::Entity { .. } => "entity",
+            NoteTarget::Note { .. } => "note",
+        }
+    }
+
+    /// Encodes the target as a `reference_id`:
+    /// - sensor events as `table:device_id@rfc3339`
+    /// - time windows as an ISO 8601 interval `start/end`
+    /// - locations as an RFC 5870 geo URI `geo:lat,lon;u=radius`
+    pub fn reference_id(&self) -> String {
+        match self {
+            NoteTarget::SensorEvent { table, device_id, timestamp } => {
+                format!("{}:{}@{}", table, device_id, timestamp.to_rfc3339())
+            }
+            NoteTarget::TimeWindow { start, end } => {
+                format!("{}/{}", start.to_rfc3339(), end.to_rfc3339())
+            }
+            NoteTarget::Location { latitude, longitude, radius_m } => {
+                format!("geo:{},{};u={}", latitude, longitude, radius_m)
+            }
+            NoteTarget::Entity { entity_id } => entity_id.clone(),
+            NoteTarget::Note { note_id } => note_id.clone(),
+        }
+    }
+
+    /// The moment this target is anchored to, used to answer "which notes
+    /// mention this time". Time windows anchor on their start.
+    pub fn anchor_time(&self) -> Option<DateTime<Utc>> {
+        match self {
+            NoteTarget::SensorEvent { timestamp, .. } => Some(*timestamp),
+            NoteTarget::TimeWindow { start, .. } => Some(*start),
+            _ => None,
+        }
+    }
+
+    pub fn parse(reference_type: &str, reference_id: &str) -> Option<Self> {
+        match reference_type {
+            "sensor_event" => {
+                let (table, rest) = reference_id.split_once(':')?;
+                let (device_id, timestamp) = rest.rsplit_once('@')?;
+                Some(NoteTarget::SensorEvent {
+                    table: table.to_string(),
+                    device_id: device_id.to_string(),
+                    timestamp: DateTime::parse_from_rfc3339(timestamp).ok()?.with_timezone(&Utc),
+                })
+            }
+            "time_window" => {
+                let (start, end) = reference_id.split_once('/')?;
+                Some(NoteTarget::TimeWindow {
+                    start: DateTime::parse_from_rfc3339(start).ok()?.with_timezone(&Utc),
+                    end: DateTime::parse_from_rfc3339(end).ok()?.with_timezone(&Utc),
+                })
+            }
+            "location" => {
+                let coords = reference_id.strip_prefix("geo:")?;
+                let (coords, radius) = coords.split_once(";u=").unwrap_or((coords, "0"));
+                let (latitude, longitude) = coords.split_once(',')?;
+                Some(NoteTarget::Location {
+                    latitude: latitude.parse().ok()?,
+                    longitude: longitude.parse().ok()?,
+                    radius_m: radius.parse().ok()?,
+                })
+            }
+            "entity" => Some(NoteTarget::Entity { entity_id: reference_id.to_string() }),
+            "note" => Some(NoteTarget::Note { note_id: reference_id.to_string() }),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct KnownEntity {
     pub entity_id: String,
+    /// Whose face, object or voice this is; only their devices' detections
+    /// are matched against it.
+    pub user_id: String,
     pub entity_type: EntityType,
     pub label: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -47,6 +154,23 @@ pub struct KnownEntity {
     pub updated_at: DateTime<Utc>,
 }
 
+/// One occasion on which a known entity was recognised in captured data.
+/// `source` is the sensor table the detection came from.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EntitySighting {
+    pub sighting_id: String,
+    pub entity_id: String,
+    pub device_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embedding: Option<Vec<f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
+}
+
 fn default_note_priority() -> NotePriority {
     NotePriority::Medium
 }
@@ -62,6 +186,10 @@ impl Note {
 }
 
 impl NoteReference {
+    pub fn target(&self) -> Option<NoteTarget> {
+        NoteTarget::parse(&self.reference_type, &self.reference_id)
+    }
+
     pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
         serde_json::from_str(json)
     }
@@ -0,0 +1,99 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::datatypes::types::{PiiDetector, ZoneMode};
+
+/// What a deletion request erases. Every scope also removes the note
+/// references, entity sightings and search documents derived from the rows.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DeletionScope {
+    /// Sensor rows, sightings and notes in the range; limited to one device
+    /// if given, in which case notes are kept.
+    TimeRange {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device_id: Option<String>,
+    },
+    /// The device and everything it recorded.
+    Device { device_id: String },
+    /// One sensor table across all of the user's devices.
+    Table { table: String },
+    /// Calls with this number or contact name, and notifications naming it.
+    Contact { contact: String },
+    /// GPS fixes within `radius_m` of a point, and notes' location references
+    /// centred inside it.
+    Place {
+        latitude: f64,
+        longitude: f64,
+        radius_m: f64,
+    },
+    /// All devices, sensor data and notes; the account itself is kept.
+    Everything,
+}
+
+/// Rows removed from one table by a deletion.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RemovedRows {
+    pub table_name: String,
+    pub rows: i64,
+}
+
+/// Proof of a completed deletion. Lists counts, never the removed values.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeletionReceipt {
+    pub id: String,
+    pub user_id: String,
+    pub scope: DeletionScope,
+    pub removed: Vec<RemovedRows>,
+    pub total_rows: i64,
+    pub deleted_at: DateTime<Utc>,
+}
+
+/// A deletion as passed on to the user's devices, which apply the same
+/// scope to the copies they still hold.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub id: String,
+    pub scope: DeletionScope,
+    pub deleted_at: DateTime<Utc>,
+}
+
+/// An area whose GPS fixes, or a Wi-Fi network whose readings, are never
+/// stored or exported precisely. A zone is a circle, a network, or both.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PrivacyZone {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub radius_m: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bssid: Option<String>,
+    pub mode: ZoneMode,
+    /// Average displacement for `Fuzz`; defaults to the radius.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub noise_m: Option<f64>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// How a user's third-party personal details are pseudonymised. Redaction
+/// can run as rows are stored, as they are exported, or on demand over
+/// what is already stored.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RedactionPolicy {
+    pub user_id: String,
+    pub detectors: Vec<PiiDetector>,
+    #[serde(default)]
+    pub on_ingest: bool,
+    #[serde(default)]
+    pub on_export: bool,
+    pub updated_at: DateTime<Utc>,
+}
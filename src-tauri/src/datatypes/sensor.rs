@@ -110,7 +110,9 @@ pub struct BloodOxygenData {
 pub struct StressData {
     pub timestamp: DateTime<Utc>,
     pub device_id: String,
-    pub stress_score: i32,
+    /// Unset for readings that only carry HRV, such as Apple Health's.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stress_score: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stress_level: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -252,7 +254,7 @@ pub struct ScreenStateData {
 }
 
 // TodosData
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TodosData {
     pub timestamp: DateTime<Utc>,
     pub device_id: String,
@@ -262,6 +264,7 @@ pub struct TodosData {
     pub due_date: Option<DateTime<Utc>>,
     pub completed: bool,
     pub completed_at: Option<DateTime<Utc>>,
+    /// 0 to 3, low to critical as with `NotePriority`.
     pub priority: Option<i32>,
     pub tags: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -294,7 +297,7 @@ pub struct CameraData {
     pub metadata: Option<Metadata>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppUsageData {
     pub timestamp: DateTime<Utc>,
     pub device_id: String,
@@ -306,7 +309,7 @@ pub struct AppUsageData {
     pub metadata: Option<Metadata>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WifiData {
     pub timestamp: DateTime<Utc>,
     pub device_id: String,
@@ -327,7 +330,7 @@ pub struct WifiData {
     pub metadata: Option<Metadata>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CallLogData {
     pub timestamp: DateTime<Utc>,
     pub device_id: String,
@@ -345,7 +348,7 @@ pub struct CallLogData {
     pub metadata: Option<Metadata>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GpsData {
     pub timestamp: DateTime<Utc>,
     pub device_id: String,
@@ -367,7 +370,7 @@ pub struct GpsData {
     pub metadata: Option<Metadata>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NotificationData {
     pub timestamp: DateTime<Utc>,
     pub device_id: String,
@@ -95,6 +95,103 @@ pub enum SyncPriority {
     Background,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ConsentLevel {
+    /// Stored here, including uploads from other devices.
+    Collect,
+    /// Devices may sample it for their own use but must not upload it.
+    LocalOnly,
+    Never,
+}
+
+impl ConsentLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConsentLevel::Collect => "COLLECT",
+            ConsentLevel::LocalOnly => "LOCAL_ONLY",
+            ConsentLevel::Never => "NEVER",
+        }
+    }
+}
+
+/// What happens to a location or Wi-Fi reading inside a privacy zone.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ZoneMode {
+    /// Not stored at all.
+    Drop,
+    /// Moved to the zone's centre, or relabelled with the zone's name for
+    /// Wi-Fi readings.
+    Snap,
+    /// Displaced by random noise of a set average distance. Wi-Fi readings
+    /// have no position to displace and are relabelled as with `Snap`.
+    Fuzz,
+}
+
+impl ZoneMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ZoneMode::Drop => "DROP",
+            ZoneMode::Snap => "SNAP",
+            ZoneMode::Fuzz => "FUZZ",
+        }
+    }
+}
+
+/// A kind of third-party personal detail the redaction engine looks for.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PiiDetector {
+    PhoneNumber,
+    Email,
+    /// Payment card numbers, recognised by their Luhn check digit.
+    CardNumber,
+    /// Names of contacts seen in the call log.
+    ContactName,
+}
+
+impl PiiDetector {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PiiDetector::PhoneNumber => "PHONE_NUMBER",
+            PiiDetector::Email => "EMAIL",
+            PiiDetector::CardNumber => "CARD_NUMBER",
+            PiiDetector::ContactName => "CONTACT_NAME",
+        }
+    }
+
+    /// The tag used in pseudonyms, e.g. `[phone:1f3a9c0e]`.
+    pub fn label(&self) -> &'static str {
+        match self {
+            PiiDetector::PhoneNumber => "phone",
+            PiiDetector::Email => "email",
+            PiiDetector::CardNumber => "card",
+            PiiDetector::ContactName => "name",
+        }
+    }
+}
+
+/// What happened to the rows listed in an audit log entry. Rows changed in
+/// place are logged as a `Delete` of the old row and an `Insert` of the new.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AuditAction {
+    Insert,
+    Delete,
+    Export,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Insert => "INSERT",
+            AuditAction::Delete => "DELETE",
+            AuditAction::Export => "EXPORT",
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum CompressionAlgorithm {
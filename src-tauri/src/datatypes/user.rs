@@ -36,6 +36,19 @@ pub struct Session {
     pub created_at: DateTime<Utc>,
 }
 
+/// A long-lived credential one device uploads with. `token` holds the hash
+/// except when the token has just been issued.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceToken {
+    pub id: String,
+    pub user_id: String,
+    pub device_id: String,
+    pub token: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
 impl User {
     pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
         serde_json::from_str(json)
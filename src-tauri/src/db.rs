@@ -1,19 +1,124 @@
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
 use duckdb::{Connection, Result};
 use chrono::{DateTime, Utc};
 use crate::datatypes::{
-    sensor::{AccelerometerData, GyroscopeData, MagnetometerData, GpsData, HeartRateData, ProximityData, LightData, PressureData, TemperatureData, HumidityData, StepCountData, AudioLevelData, BatteryData, NetworkData, ScreenStateData, NotificationData, AppUsageData, WifiData, CallLogData, TodosData},
+    sensor::{AccelerometerData, GyroscopeData, MagnetometerData, GpsData, HeartRateData, ECGData, BloodOxygenData, StressData, ProximityData, LightData, PressureData, TemperatureData, HumidityData, StepCountData, AudioLevelData, BatteryData, NetworkData, ScreenStateData, NotificationData, AppUsageData, WifiData, CallLogData, TodosData},
     device::*,
     user::*,
     types::ConnectionType,
 };
+use crate::redaction::Redactor;
+
+mod audit;
+mod backup;
+mod collection;
+mod consent;
+mod deletion;
+mod devices;
+mod encryption;
+mod entities;
+#[cfg(test)]
+pub(crate) mod fixtures;
+mod fulltext;
+mod notes;
+mod oauth;
+mod parquet;
+mod privacy;
+mod redaction;
+mod schema;
+mod scoped;
+mod sessions;
+mod vector;
+
+use encryption::FieldCipher;
+
+pub use backup::SnapshotFile;
+pub use consent::{effective_consent, ConsentWithheld, CONSENT_WILDCARD};
+pub use encryption::ENCRYPTABLE_COLUMNS;
+pub use entities::detection_source;
+pub use fulltext::{highlight_snippet, KeywordQuery, SearchHit, SearchIndexReport, SearchSource, Snippet};
+pub use notes::ResolvedReference;
+pub use parquet::{ParquetPartition, ParquetRows};
+pub use schema::SCHEMA_VERSION;
+pub use scoped::{AccessError, ScopedDatabase, UserContext};
+pub use vector::{DistanceMetric, EntityMatch, NoteMatch, VectorQuery};
+
+/// Every table keyed by (timestamp, device_id) that holds captured data.
+/// Anything that builds SQL from a table name must check it against this list.
+pub const SENSOR_TABLES: &[&str] = &[
+    "accelerometer_data",
+    "gyroscope_data",
+    "magnetometer_data",
+    "gps_data",
+    "heart_rate_data",
+    "proximity_data",
+    "light_data",
+    "pressure_data",
+    "temperature_data",
+    "humidity_data",
+    "step_count_data",
+    "call_log_data",
+    "todos_data",
+    "audio_level_data",
+    "battery_data",
+    "network_data",
+    "screen_state_data",
+    "notification_data",
+    "app_usage_data",
+    "wifi_data",
+    "bluetooth_data",
+    "camera_data",
+    "microphone_data",
+    "app_event_data",
+    "system_audio_data",
+    "network_speed_data",
+    "server_latency_data",
+    "skin_temperature_data",
+    "ecg_data",
+    "blood_oxygen_data",
+    "stress_data",
+    "compass_data",
+    "screen_details_data",
+    "object_detection_data",
+    "face_recognition_data",
+    "pose_detection_data",
+];
+
+/// todos_data's columns in the order `todo_from_row` reads them, with the
+/// tags list as JSON.
+const TODO_COLUMNS: &str = "timestamp, device_id, todo_id, title, description, due_date, completed, \
+    completed_at, priority, CAST(to_json(tags) AS VARCHAR), metadata";
+
+pub fn is_sensor_table(table: &str) -> bool {
+    SENSOR_TABLES.contains(&table)
+}
 
 pub struct Database {
     conn: Connection,
+    fields: RwLock<FieldCipher>,
+    /// What the search index was last built from, if it has been built since
+    /// the database was opened.
+    search_sources: Mutex<Option<String>>,
 }
 
+/// The app's one open database, shared by its commands, the device server
+/// and background jobs. DuckDB only lets a process open a file once.
+pub type SharedDatabase = Arc<Mutex<Database>>;
+
 impl Database {
     pub fn new(db_path: &Path) -> Result<Self> {
+        Self::open(db_path, schema::SCHEMA_VERSION)
+    }
+
+    /// Opens the database without migrating it past `schema_version`.
+    /// Restoring a backup loads it into the schema it was taken with.
+    pub(crate) fn new_at_version(db_path: &Path, schema_version: i64) -> Result<Self> {
+        Self::open(db_path, schema_version)
+    }
+
+    fn open(db_path: &Path, schema_version: i64) -> Result<Self> {
         let conn = Connection::open(db_path)?;
 
         // Enable extensions via SQL
@@ -22,17 +127,46 @@ impl Database {
             LOAD vss;
             INSTALL json;
             LOAD json;
+            INSTALL fts;
+            LOAD fts;
+            SET hnsw_enable_experimental_persistence = true;
         ")?;
 
-        // Initialize schema from init.sql
-        let init_sql = include_str!("../db-setup/init.sql");
-        conn.execute_batch(init_sql)?;
+        // Create the schema, or bring an older database up to date
+        schema::migrate_to(&conn, schema_version)?;
+
+        let fields = RwLock::new(FieldCipher::load(&conn, &HashMap::new())?);
+        Ok(Self { conn, fields, search_sources: Mutex::new(None) })
+    }
+
+    /// Runs `f` inside a transaction, rolling back if it returns an error.
+    pub(crate) fn in_transaction<T>(&self, f: impl FnOnce(&Self) -> Result<T>) -> Result<T> {
+        self.conn.execute_batch("BEGIN TRANSACTION")?;
+        match f(self) {
+            Ok(value) => {
+                self.conn.execute_batch("COMMIT")?;
+                Ok(value)
+            }
+            Err(e) => {
+                let _ = self.conn.execute_batch("ROLLBACK");
+                Err(e)
+            }
+        }
+    }
 
-        Ok(Self { conn })
+    /// Whether `table` already has a row from `device_id` at `timestamp`.
+    /// `table` must be one of `SENSOR_TABLES`.
+    pub(crate) fn row_exists(&self, table: &str, device_id: &str, timestamp: DateTime<Utc>) -> Result<bool> {
+        debug_assert!(is_sensor_table(table));
+        self.conn.query_row(
+            &format!("SELECT EXISTS (SELECT 1 FROM {} WHERE device_id = ? AND timestamp = ?)", table),
+            [device_id, &timestamp.to_string()],
+            |row| row.get(0),
+        )
     }
 
     // User methods
-    pub fn insert_user(&self, user: &User) -> Result<()> {
+    pub(crate) fn insert_user(&self, user: &User) -> Result<()> {
         self.conn.execute(
             "INSERT INTO users (
                 id, email, name, encrypted_password, created_at, updated_at
@@ -49,7 +183,7 @@ impl Database {
         Ok(())
     }
 
-    pub fn get_user(&self, id: &str) -> Result<User> {
+    pub(crate) fn get_user(&self, id: &str) -> Result<User> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM users WHERE id = ?"
         )?;
@@ -69,71 +203,61 @@ impl Database {
     }
 
     // Device methods
-    pub fn insert_device(&self, device: &Device) -> Result<()> {
+    pub(crate) fn insert_device(&self, device: &Device) -> Result<()> {
         self.conn.execute(
             "INSERT INTO devices (
-                device_id, user_id, device_type, os_type, os_version, 
-                app_version, available_sensors, created_at, last_seen
-            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            &[
+                device_id, user_id, device_type, os_type, os_version, app_version,
+                available_sensors, capabilities, name, created_at, last_seen, updated_at, retired_at
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            duckdb::params![
                 &device.device_id,
-                &device.user_id, 
+                &device.user_id,
                 &device.device_type.to_string(),
                 &device.os_type,
                 &device.os_version,
                 &device.app_version,
                 &serde_json::to_string(&device.available_sensors).unwrap(),
+                &device.capabilities.to_json().unwrap(),
+                &device.name,
                 &device.created_at.to_string(),
                 &device.last_seen.to_string(),
+                &device.updated_at.to_string(),
+                &device.retired_at.map(|t| t.to_string()),
             ],
         )?;
         Ok(())
     }
 
-    pub fn get_device(&self, device_id: &str) -> Result<Device> {
-        let mut stmt = self.conn.prepare(
-            "SELECT * FROM devices WHERE device_id = ?"
-        )?;
-        
-        let device = stmt.query_row([device_id], |row| {
-            Ok(Device {
-                device_id: row.get(0)?,
-                user_id: row.get(1)?,
-                device_type: serde_json::from_str(&row.get::<_,String>(2)?).unwrap(),
-                os_type: row.get(3)?,
-                os_version: row.get(4)?,
-                app_version: row.get(5)?,
-                available_sensors: serde_json::from_str(&row.get::<_,String>(6)?).unwrap(),
-                capabilities: serde_json::from_str(&row.get::<_,String>(7)?).unwrap(),
-                created_at: row.get::<_,String>(8)?.parse::<DateTime<Utc>>().unwrap(),
-                last_seen: row.get::<_,String>(9)?.parse::<DateTime<Utc>>().unwrap(),
-                updated_at: row.get::<_,String>(10)?.parse::<DateTime<Utc>>().unwrap(),
-            })
-        })?;
-
-        Ok(device)
+    pub(crate) fn get_device(&self, device_id: &str) -> Result<Device> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM devices WHERE device_id = ?", devices::DEVICE_COLUMNS
+        ))?;
+        stmt.query_row([device_id], devices::device_from_row)
     }
 
     // Sensor data methods
-    pub fn insert_accelerometer_data(&self, data: &AccelerometerData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO accelerometer_data (
-                timestamp, device_id, x, y, z, accuracy, metadata
-            ) VALUES (?, ?, ?, ?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.x.to_string(),
-                &data.y.to_string(), 
-                &data.z.to_string(),
-                &data.accuracy.map(|f| f.to_string()).unwrap_or_default(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_accelerometer_data(&self, data: &AccelerometerData) -> Result<()> {
+        self.check_consent(&data.device_id, "accelerometer_data")?;
+        self.audited_insert("accelerometer_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO accelerometer_data (
+                    timestamp, device_id, x, y, z, accuracy, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                &[
+                    &data.timestamp.to_string(),
+                    &data.device_id,
+                    &data.x.to_string(),
+                    &data.y.to_string(), 
+                    &data.z.to_string(),
+                    &data.accuracy.map(|f| f.to_string()).unwrap_or_default(),
+                    &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_accelerometer_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<AccelerometerData>> {
+    pub(crate) fn get_accelerometer_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<AccelerometerData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM accelerometer_data 
              WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
@@ -159,25 +283,28 @@ impl Database {
         Ok(data)
     }
 
-    pub fn insert_gyroscope_data(&self, data: &GyroscopeData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO gyroscope_data (
-                timestamp, device_id, x, y, z, accuracy, metadata
-            ) VALUES (?, ?, ?, ?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.x.to_string(),
-                &data.y.to_string(),
-                &data.z.to_string(), 
-                &data.accuracy.map(|f| f.to_string()).unwrap_or_default(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_gyroscope_data(&self, data: &GyroscopeData) -> Result<()> {
+        self.check_consent(&data.device_id, "gyroscope_data")?;
+        self.audited_insert("gyroscope_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO gyroscope_data (
+                    timestamp, device_id, x, y, z, accuracy, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                &[
+                    &data.timestamp.to_string(),
+                    &data.device_id,
+                    &data.x.to_string(),
+                    &data.y.to_string(),
+                    &data.z.to_string(), 
+                    &data.accuracy.map(|f| f.to_string()).unwrap_or_default(),
+                    &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_gyroscope_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<GyroscopeData>> {
+    pub(crate) fn get_gyroscope_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<GyroscopeData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM gyroscope_data 
              WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
@@ -203,25 +330,28 @@ impl Database {
         Ok(data)
     }
 
-    pub fn insert_magnetometer_data(&self, data: &MagnetometerData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO magnetometer_data (
-                timestamp, device_id, x, y, z, accuracy, metadata
-            ) VALUES (?, ?, ?, ?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.x.to_string(),
-                &data.y.to_string(),
-                &data.z.to_string(),
-                &data.accuracy.map(|f| f.to_string()).unwrap_or_default(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_magnetometer_data(&self, data: &MagnetometerData) -> Result<()> {
+        self.check_consent(&data.device_id, "magnetometer_data")?;
+        self.audited_insert("magnetometer_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO magnetometer_data (
+                    timestamp, device_id, x, y, z, accuracy, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                &[
+                    &data.timestamp.to_string(),
+                    &data.device_id,
+                    &data.x.to_string(),
+                    &data.y.to_string(),
+                    &data.z.to_string(),
+                    &data.accuracy.map(|f| f.to_string()).unwrap_or_default(),
+                    &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_magnetometer_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<MagnetometerData>> {
+    pub(crate) fn get_magnetometer_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<MagnetometerData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM magnetometer_data 
              WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
@@ -247,50 +377,43 @@ impl Database {
         Ok(data)
     }
 
-    pub fn insert_gps_data(&self, data: &GpsData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO gps_data (
-                timestamp, device_id, latitude, longitude, altitude, 
-                accuracy, speed, bearing, satellites, provider, metadata
-            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.latitude.to_string(),
-                &data.longitude.to_string(),
-                &data.altitude.map(|a| a.to_string()).unwrap_or_default(),
-                &data.accuracy.map(|a| a.to_string()).unwrap_or_default(),
-                &data.speed.map(|s| s.to_string()).unwrap_or_default(),
-                &data.bearing.map(|b| b.to_string()).unwrap_or_default(),
-                &data.satellites.map(|s| s.to_string()).unwrap_or_default(),
-                &data.provider.as_deref().unwrap_or_default().to_string(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_gps_data(&self, data: &GpsData) -> Result<()> {
+        self.check_consent(&data.device_id, "gps_data")?;
+        let zones = self.get_privacy_zones_for_device(&data.device_id)?;
+        let Some(data) = &crate::privacy::shield_gps(&zones, data, &mut rand::thread_rng()) else {
+            return Ok(());
+        };
+        self.audited_insert("gps_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO gps_data (
+                    timestamp, device_id, latitude, longitude, altitude, 
+                    accuracy, speed, bearing, satellites, provider, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                duckdb::params![
+                    data.timestamp.to_string(),
+                    &data.device_id,
+                    data.latitude,
+                    data.longitude,
+                    data.altitude,
+                    data.accuracy,
+                    data.speed,
+                    data.bearing,
+                    data.satellites,
+                    data.provider.as_deref(),
+                    data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_gps_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<GpsData>> {
+    pub(crate) fn get_gps_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<GpsData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM gps_data 
              WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
         )?;
 
-        let rows = stmt.query_map([device_id, &start.to_string(), &end.to_string()], |row| {
-            Ok(GpsData {
-                timestamp: row.get::<_,String>(0)?.parse::<DateTime<Utc>>().unwrap(),
-                device_id: row.get(1)?,
-                latitude: row.get(2)?,
-                longitude: row.get(3)?,
-                altitude: row.get(4)?,
-                accuracy: row.get(5)?,
-                speed: row.get(6)?,
-                bearing: row.get(7)?,
-                satellites: row.get(8)?,
-                provider: row.get(9)?,
-                metadata: row.get::<_,Option<String>>(10)?.map(|s| serde_json::from_str(&s).unwrap()),
-            })
-        })?;
+        let rows = stmt.query_map([device_id, &start.to_string(), &end.to_string()], gps_from_row)?;
 
         let mut data = Vec::new();
         for row in rows {
@@ -300,27 +423,30 @@ impl Database {
     }
 
     /* Heart Rate Data */
-    pub fn insert_heart_rate_data(&self, data: &HeartRateData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO heart_rate_data (
-                timestamp, device_id, bpm, confidence, rr_intervals, metadata
-            ) VALUES (?, ?, ?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.bpm.to_string(),
-                &data.confidence.map(|c| c.to_string()).unwrap_or_default(),
-                &serde_json::to_string(&data.rr_intervals).unwrap(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_heart_rate_data(&self, data: &HeartRateData) -> Result<()> {
+        self.check_consent(&data.device_id, "heart_rate_data")?;
+        self.audited_insert("heart_rate_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO heart_rate_data (
+                    timestamp, device_id, bpm, confidence, rr_intervals, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?)",
+                duckdb::params![
+                    data.timestamp.to_string(),
+                    &data.device_id,
+                    data.bpm,
+                    data.confidence,
+                    data.rr_intervals.as_ref().map(|rr| serde_json::to_string(rr).unwrap()),
+                    data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_heart_rate_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<HeartRateData>> {
+    pub(crate) fn get_heart_rate_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<HeartRateData>> {
         let mut stmt = self.conn.prepare(
-            "SELECT * FROM heart_rate_data 
-             WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
+            "SELECT timestamp, device_id, bpm, confidence, CAST(rr_intervals AS VARCHAR), metadata
+             FROM heart_rate_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
         )?;
 
         let rows = stmt.query_map([device_id, &start.to_string(), &end.to_string()], |row| {
@@ -329,7 +455,7 @@ impl Database {
                 device_id: row.get(1)?,
                 bpm: row.get(2)?,
                 confidence: row.get(3)?,
-                rr_intervals: serde_json::from_str(&row.get::<_,String>(4)?).unwrap(),
+                rr_intervals: row.get::<_,Option<String>>(4)?.map(|s| serde_json::from_str(&s).unwrap()),
                 metadata: row.get::<_,Option<String>>(5)?.map(|s| serde_json::from_str(&s).unwrap()),
             })
         })?;
@@ -341,6 +467,130 @@ impl Database {
         Ok(data)
     }
 
+    /* ECG Data */
+    pub(crate) fn insert_ecg_data(&self, data: &ECGData) -> Result<()> {
+        self.check_consent(&data.device_id, "ecg_data")?;
+        self.audited_insert("ecg_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO ecg_data (
+                    timestamp, device_id, voltage, time, rhythm_classification, heart_rate, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                duckdb::params![
+                    data.timestamp.to_string(),
+                    &data.device_id,
+                    serde_json::to_string(&data.voltage).unwrap(),
+                    serde_json::to_string(&data.time).unwrap(),
+                    data.rhythm_classification.as_deref(),
+                    data.heart_rate,
+                    data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()),
+                ],
+            )
+        })?;
+        Ok(())
+    }
+
+    pub(crate) fn get_ecg_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<ECGData>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT timestamp, device_id, CAST(voltage AS VARCHAR), CAST(time AS VARCHAR), rhythm_classification, heart_rate, metadata
+             FROM ecg_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
+        )?;
+
+        let rows = stmt.query_map([device_id, &start.to_string(), &end.to_string()], |row| {
+            Ok(ECGData {
+                timestamp: row.get::<_,String>(0)?.parse::<DateTime<Utc>>().unwrap(),
+                device_id: row.get(1)?,
+                voltage: serde_json::from_str(&row.get::<_,String>(2)?).unwrap(),
+                time: serde_json::from_str(&row.get::<_,String>(3)?).unwrap(),
+                rhythm_classification: row.get(4)?,
+                heart_rate: row.get(5)?,
+                metadata: row.get::<_,Option<String>>(6)?.map(|s| serde_json::from_str(&s).unwrap()),
+            })
+        })?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /* Blood Oxygen Data */
+    pub(crate) fn insert_blood_oxygen_data(&self, data: &BloodOxygenData) -> Result<()> {
+        self.check_consent(&data.device_id, "blood_oxygen_data")?;
+        self.audited_insert("blood_oxygen_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO blood_oxygen_data (
+                    timestamp, device_id, spo2, confidence, raw_values, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?)",
+                duckdb::params![
+                    data.timestamp.to_string(),
+                    &data.device_id,
+                    data.spo2,
+                    data.confidence,
+                    data.raw_values.as_ref().map(|v| serde_json::to_string(v).unwrap()),
+                    data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()),
+                ],
+            )
+        })?;
+        Ok(())
+    }
+
+    pub(crate) fn get_blood_oxygen_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<BloodOxygenData>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT timestamp, device_id, spo2, confidence, CAST(raw_values AS VARCHAR), metadata
+             FROM blood_oxygen_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
+        )?;
+
+        let rows = stmt.query_map([device_id, &start.to_string(), &end.to_string()], |row| {
+            Ok(BloodOxygenData {
+                timestamp: row.get::<_,String>(0)?.parse::<DateTime<Utc>>().unwrap(),
+                device_id: row.get(1)?,
+                spo2: row.get(2)?,
+                confidence: row.get(3)?,
+                raw_values: row.get::<_,Option<String>>(4)?.map(|s| serde_json::from_str(&s).unwrap()),
+                metadata: row.get::<_,Option<String>>(5)?.map(|s| serde_json::from_str(&s).unwrap()),
+            })
+        })?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /* Stress Data */
+    pub(crate) fn insert_stress_data(&self, data: &StressData) -> Result<()> {
+        self.check_consent(&data.device_id, "stress_data")?;
+        self.audited_insert("stress_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO stress_data (
+                    timestamp, device_id, stress_score, stress_level, hrv, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?)",
+                duckdb::params![
+                    data.timestamp.to_string(),
+                    &data.device_id,
+                    data.stress_score,
+                    data.stress_level.as_deref(),
+                    data.hrv,
+                    data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()),
+                ],
+            )
+        })?;
+        Ok(())
+    }
+
+    pub(crate) fn get_stress_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<StressData>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT * FROM stress_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
+        )?;
+
+        let rows = stmt.query_map([device_id, &start.to_string(), &end.to_string()], |row| {
+            Ok(StressData {
+                timestamp: row.get::<_,String>(0)?.parse::<DateTime<Utc>>().unwrap(),
+                device_id: row.get(1)?,
+                stress_score: row.get(2)?,
+                stress_level: row.get(3)?,
+                hrv: row.get(4)?,
+                metadata: row.get::<_,Option<String>>(5)?.map(|s| serde_json::from_str(&s).unwrap()),
+            })
+        })?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
     /* Proximity Data */
     // pub fn insert_proximity_data(&self, data: &ProximityData) -> Result<()> {
     //     self.conn.execute(
@@ -358,7 +608,7 @@ impl Database {
     //     Ok(())
     // }
 
-    pub fn get_proximity_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<ProximityData>> {
+    pub(crate) fn get_proximity_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<ProximityData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM proximity_data 
              WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
@@ -381,20 +631,23 @@ impl Database {
         Ok(data)
     }
 
-    pub fn insert_light_data(&self, data: &LightData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO light_data (timestamp, device_id, lux, metadata) VALUES (?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.lux.to_string(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_light_data(&self, data: &LightData) -> Result<()> {
+        self.check_consent(&data.device_id, "light_data")?;
+        self.audited_insert("light_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO light_data (timestamp, device_id, lux, metadata) VALUES (?, ?, ?, ?)",
+                &[
+                    &data.timestamp.to_string(),
+                    &data.device_id,
+                    &data.lux.to_string(),
+                    &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_light_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<LightData>> {
+    pub(crate) fn get_light_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<LightData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM light_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
         )?;
@@ -411,20 +664,23 @@ impl Database {
         Ok(rows.collect::<Result<_, _>>()?)
     }
 
-    pub fn insert_pressure_data(&self, data: &PressureData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO pressure_data (timestamp, device_id, hectopascals, metadata) VALUES (?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.hectopascals.to_string(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_pressure_data(&self, data: &PressureData) -> Result<()> {
+        self.check_consent(&data.device_id, "pressure_data")?;
+        self.audited_insert("pressure_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO pressure_data (timestamp, device_id, hectopascals, metadata) VALUES (?, ?, ?, ?)",
+                &[
+                    &data.timestamp.to_string(),
+                    &data.device_id,
+                    &data.hectopascals.to_string(),
+                    &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_pressure_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<PressureData>> {
+    pub(crate) fn get_pressure_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<PressureData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM pressure_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
         )?;
@@ -442,20 +698,23 @@ impl Database {
     }
 
     /* Temperature Data */
-    pub fn insert_temperature_data(&self, data: &TemperatureData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO temperature_data (timestamp, device_id, celsius, metadata) VALUES (?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.celsius.to_string(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_temperature_data(&self, data: &TemperatureData) -> Result<()> {
+        self.check_consent(&data.device_id, "temperature_data")?;
+        self.audited_insert("temperature_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO temperature_data (timestamp, device_id, celsius, metadata) VALUES (?, ?, ?, ?)",
+                &[
+                    &data.timestamp.to_string(),
+                    &data.device_id,
+                    &data.celsius.to_string(),
+                    &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_temperature_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<TemperatureData>> {
+    pub(crate) fn get_temperature_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<TemperatureData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM temperature_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
         )?;
@@ -473,20 +732,23 @@ impl Database {
     }
 
     /* Humidity Data */
-    pub fn insert_humidity_data(&self, data: &HumidityData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO humidity_data (timestamp, device_id, percentage, metadata) VALUES (?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.percentage.to_string(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_humidity_data(&self, data: &HumidityData) -> Result<()> {
+        self.check_consent(&data.device_id, "humidity_data")?;
+        self.audited_insert("humidity_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO humidity_data (timestamp, device_id, percentage, metadata) VALUES (?, ?, ?, ?)",
+                &[
+                    &data.timestamp.to_string(),
+                    &data.device_id,
+                    &data.percentage.to_string(),
+                    &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_humidity_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<HumidityData>> {
+    pub(crate) fn get_humidity_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<HumidityData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM humidity_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
         )?;
@@ -504,22 +766,25 @@ impl Database {
     }
 
     /* Step Count Data */
-    pub fn insert_step_count_data(&self, data: &StepCountData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO step_count_data (timestamp, device_id, steps, activity_type, confidence, metadata) VALUES (?, ?, ?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.steps.to_string(),
-                &data.activity_type.as_deref().unwrap_or_default().to_string(),
-                &data.confidence.map(|c| c.to_string()).unwrap_or_default(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_step_count_data(&self, data: &StepCountData) -> Result<()> {
+        self.check_consent(&data.device_id, "step_count_data")?;
+        self.audited_insert("step_count_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO step_count_data (timestamp, device_id, steps, activity_type, confidence, metadata) VALUES (?, ?, ?, ?, ?, ?)",
+                duckdb::params![
+                    data.timestamp.to_string(),
+                    &data.device_id,
+                    data.steps,
+                    data.activity_type.as_deref(),
+                    data.confidence,
+                    data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_step_count_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<StepCountData>> {
+    pub(crate) fn get_step_count_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<StepCountData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM step_count_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
         )?;
@@ -539,29 +804,33 @@ impl Database {
     }
 
     /* Call Log Data */
-    pub fn insert_call_log_data(&self, data: &CallLogData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO call_log_data (
-                timestamp, device_id, call_type, phone_number, contact_name, 
-                duration_seconds, is_missed, is_blocked, sim_slot, metadata
-            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.call_type,
-                &data.phone_number.as_deref().unwrap_or_default().to_string(),
-                &data.contact_name.as_deref().unwrap_or_default().to_string(),
-                &data.duration_seconds.to_string(),
-                &data.is_missed.to_string(),
-                &data.is_blocked.to_string(),
-                &data.sim_slot.map(|s| s.to_string()).unwrap_or_default(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_call_log_data(&self, data: &CallLogData) -> Result<()> {
+        self.check_consent(&data.device_id, "call_log_data")?;
+        let data = &self.redact_call_log_on_ingest(data)?;
+        self.audited_insert("call_log_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO call_log_data (
+                    timestamp, device_id, call_type, phone_number, contact_name, 
+                    duration_seconds, is_missed, is_blocked, sim_slot, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                duckdb::params![
+                    data.timestamp.to_string(),
+                    &data.device_id,
+                    &data.call_type,
+                    data.phone_number.as_deref().map(|n| self.seal_field("call_log_data", "phone_number", n)),
+                    data.contact_name.as_deref().map(|n| self.seal_field("call_log_data", "contact_name", n)),
+                    data.duration_seconds,
+                    data.is_missed,
+                    data.is_blocked,
+                    data.sim_slot,
+                    data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_call_log_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<CallLogData>> {
+    pub(crate) fn get_call_log_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<CallLogData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM call_log_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
         )?;
@@ -571,8 +840,8 @@ impl Database {
                 timestamp: row.get::<_,String>(0)?.parse::<DateTime<Utc>>().unwrap(),
                 device_id: row.get(1)?,
                 call_type: row.get(2)?,
-                phone_number: row.get(3)?,
-                contact_name: row.get(4)?,
+                phone_number: self.open_optional_field(row.get(3)?)?,
+                contact_name: self.open_optional_field(row.get(4)?)?,
                 duration_seconds: row.get(5)?,
                 is_missed: row.get(6)?,
                 is_blocked: row.get(7)?,
@@ -585,70 +854,90 @@ impl Database {
     }
 
     /* Todos Data */
-    pub fn insert_todos_data(&self, data: &TodosData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO todos_data (
-                timestamp, device_id, todo_id, title, description, 
-                due_date, completed, completed_at, priority, tags, metadata
-            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.todo_id,
-                &data.title,
-                &data.description.as_deref().unwrap_or_default().to_string(),
-                &data.due_date.map(|d| d.to_string()).unwrap_or_default(),
-                &data.completed.to_string(),
-                &data.completed_at.map(|c| c.to_string()).unwrap_or_default(),
-                &data.priority.map(|p| p.to_string()).unwrap_or_default(),
-                &serde_json::to_string(&data.tags).unwrap(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_todos_data(&self, data: &TodosData) -> Result<()> {
+        self.check_consent(&data.device_id, "todos_data")?;
+        self.audited_insert("todos_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO todos_data (
+                    timestamp, device_id, todo_id, title, description, 
+                    due_date, completed, completed_at, priority, tags, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, from_json(?, '[\"VARCHAR\"]'), ?)",
+                duckdb::params![
+                    data.timestamp.to_string(),
+                    &data.device_id,
+                    &data.todo_id,
+                    self.seal_field("todos_data", "title", &data.title),
+                    data.description.as_deref().map(|d| self.seal_field("todos_data", "description", d)),
+                    data.due_date.map(|d| d.to_string()),
+                    data.completed,
+                    data.completed_at.map(|c| c.to_string()),
+                    data.priority,
+                    serde_json::to_string(&data.tags).unwrap(),
+                    data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_todos_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<TodosData>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT * FROM todos_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
-        )?;
+    pub(crate) fn get_todos_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<TodosData>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM todos_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?", TODO_COLUMNS
+        ))?;
 
-        let rows = stmt.query_map([device_id, &start.to_string(), &end.to_string()], |row| {
-            Ok(TodosData {
-                timestamp: row.get::<_,String>(0)?.parse::<DateTime<Utc>>().unwrap(),
-                device_id: row.get(1)?,
-                todo_id: row.get(2)?,
-                title: row.get(3)?,
-                description: row.get(4)?,
-                due_date: row.get::<_,Option<String>>(5)?.map(|s| s.parse::<DateTime<Utc>>().unwrap()),
-                completed: row.get(6)?,
-                completed_at: row.get::<_,Option<String>>(7)?.map(|s| s.parse::<DateTime<Utc>>().unwrap()),
-                priority: row.get(8)?,
-                tags: serde_json::from_str(&row.get::<_,String>(9)?).unwrap(),
-                metadata: row.get::<_,Option<String>>(10)?.map(|s| serde_json::from_str(&s).unwrap()),
-            })
-        })?;
+        let rows = stmt.query_map([device_id, &start.to_string(), &end.to_string()], |row| self.todo_from_row(row))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
 
+    /// The newest row of each of the device's todos, however long ago it
+    /// was written: the todos as they stand now.
+    pub(crate) fn get_latest_todos(&self, device_id: &str) -> Result<Vec<TodosData>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM todos_data WHERE device_id = ?
+             QUALIFY row_number() OVER (PARTITION BY todo_id ORDER BY timestamp DESC) = 1
+             ORDER BY todo_id", TODO_COLUMNS
+        ))?;
+
+        let rows = stmt.query_map([device_id], |row| self.todo_from_row(row))?;
         Ok(rows.collect::<Result<_, _>>()?)
     }
 
+    fn todo_from_row(&self, row: &duckdb::Row<'_>) -> duckdb::Result<TodosData> {
+        Ok(TodosData {
+            timestamp: row.get::<_,String>(0)?.parse::<DateTime<Utc>>().unwrap(),
+            device_id: row.get(1)?,
+            todo_id: row.get(2)?,
+            title: self.open_field(row.get(3)?)?,
+            description: self.open_optional_field(row.get(4)?)?,
+            due_date: row.get::<_,Option<String>>(5)?.map(|s| s.parse::<DateTime<Utc>>().unwrap()),
+            completed: row.get(6)?,
+            completed_at: row.get::<_,Option<String>>(7)?.map(|s| s.parse::<DateTime<Utc>>().unwrap()),
+            priority: row.get(8)?,
+            tags: row.get::<_,Option<String>>(9)?.map(|s| serde_json::from_str(&s).unwrap()).unwrap_or_default(),
+            metadata: row.get::<_,Option<String>>(10)?.map(|s| serde_json::from_str(&s).unwrap()),
+        })
+    }
+
      /* Audio Level Data */
-     pub fn insert_audio_level_data(&self, data: &AudioLevelData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO audio_level_data (timestamp, device_id, db, peak_db, volume, metadata) VALUES (?, ?, ?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.db.to_string(),
-                &data.peak_db.map(|p| p.to_string()).unwrap_or_default(),
-                &data.volume.map(|v| v.to_string()).unwrap_or_default(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+     pub(crate) fn insert_audio_level_data(&self, data: &AudioLevelData) -> Result<()> {
+        self.check_consent(&data.device_id, "audio_level_data")?;
+        self.audited_insert("audio_level_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO audio_level_data (timestamp, device_id, db, peak_db, volume, metadata) VALUES (?, ?, ?, ?, ?, ?)",
+                &[
+                    &data.timestamp.to_string(),
+                    &data.device_id,
+                    &data.db.to_string(),
+                    &data.peak_db.map(|p| p.to_string()).unwrap_or_default(),
+                    &data.volume.map(|v| v.to_string()).unwrap_or_default(),
+                    &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_audio_level_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<AudioLevelData>> {
+    pub(crate) fn get_audio_level_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<AudioLevelData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM audio_level_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
         )?;
@@ -668,28 +957,31 @@ impl Database {
     }
 
     /* Battery Data */
-    pub fn insert_battery_data(&self, data: &BatteryData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO battery_data (
-                timestamp, device_id, percentage, charging, power_source, 
-                temperature, voltage, current, metadata
-            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.percentage.to_string(),
-                &data.charging.to_string(),
-                &data.power_source.as_deref().unwrap_or_default().to_string(),
-                &data.temperature.map(|t| t.to_string()).unwrap_or_default(),
-                &data.voltage.map(|v| v.to_string()).unwrap_or_default(),
-                &data.current.map(|c| c.to_string()).unwrap_or_default(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_battery_data(&self, data: &BatteryData) -> Result<()> {
+        self.check_consent(&data.device_id, "battery_data")?;
+        self.audited_insert("battery_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO battery_data (
+                    timestamp, device_id, percentage, charging, power_source, 
+                    temperature, voltage, current, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                &[
+                    &data.timestamp.to_string(),
+                    &data.device_id,
+                    &data.percentage.to_string(),
+                    &data.charging.to_string(),
+                    &data.power_source.as_deref().unwrap_or_default().to_string(),
+                    &data.temperature.map(|t| t.to_string()).unwrap_or_default(),
+                    &data.voltage.map(|v| v.to_string()).unwrap_or_default(),
+                    &data.current.map(|c| c.to_string()).unwrap_or_default(),
+                    &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_battery_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<BatteryData>> {
+    pub(crate) fn get_battery_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<BatteryData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM battery_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
         )?;
@@ -712,28 +1004,31 @@ impl Database {
     }
 
     /* Network Data */
-    pub fn insert_network_data(&self, data: &NetworkData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO network_data (
-                timestamp, device_id, type, state, strength, carrier, 
-                roaming, cellular_technology, is_metered, dns_servers, 
-                gateway, metadata
-            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.connection_type.to_string(),
-                &data.state.as_deref().unwrap_or_default().to_string(),
-                &data.strength.map(|s| s.to_string()).unwrap_or_default(),
-                &data.carrier.as_deref().unwrap_or_default().to_string(),
-                &data.roaming.map(|r| r.to_string()).unwrap_or_default(),
-                &data.cellular_technology.as_deref().unwrap_or_default().to_string(),
-                &data.is_metered.map(|m| m.to_string()).unwrap_or_default(),
-                &serde_json::to_string(&data.dns_servers).unwrap(),
-                &data.gateway.as_deref().unwrap_or_default().to_string(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_network_data(&self, data: &NetworkData) -> Result<()> {
+        self.check_consent(&data.device_id, "network_data")?;
+        self.audited_insert("network_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO network_data (
+                    timestamp, device_id, type, state, strength, carrier, 
+                    roaming, cellular_technology, is_metered, dns_servers, 
+                    gateway, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                &[
+                    &data.timestamp.to_string(),
+                    &data.device_id,
+                    &data.connection_type.to_string(),
+                    &data.state.as_deref().unwrap_or_default().to_string(),
+                    &data.strength.map(|s| s.to_string()).unwrap_or_default(),
+                    &data.carrier.as_deref().unwrap_or_default().to_string(),
+                    &data.roaming.map(|r| r.to_string()).unwrap_or_default(),
+                    &data.cellular_technology.as_deref().unwrap_or_default().to_string(),
+                    &data.is_metered.map(|m| m.to_string()).unwrap_or_default(),
+                    &serde_json::to_string(&data.dns_servers).unwrap(),
+                    &data.gateway.as_deref().unwrap_or_default().to_string(),
+                    &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
+                ],
+            )
+        })?;
         Ok(())
     }
 
@@ -763,22 +1058,25 @@ impl Database {
     // }
 
     /* Screen State Data */
-    pub fn insert_screen_state_data(&self, data: &ScreenStateData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO screen_state_data (timestamp, device_id, screen_on, brightness, orientation, metadata) VALUES (?, ?, ?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.screen_on.to_string(),
-                &data.brightness.map(|b| b.to_string()).unwrap_or_default(),
-                &data.orientation.as_deref().unwrap_or_default().to_string(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_screen_state_data(&self, data: &ScreenStateData) -> Result<()> {
+        self.check_consent(&data.device_id, "screen_state_data")?;
+        self.audited_insert("screen_state_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO screen_state_data (timestamp, device_id, screen_on, brightness, orientation, metadata) VALUES (?, ?, ?, ?, ?, ?)",
+                &[
+                    &data.timestamp.to_string(),
+                    &data.device_id,
+                    &data.screen_on.to_string(),
+                    &data.brightness.map(|b| b.to_string()).unwrap_or_default(),
+                    &data.orientation.as_deref().unwrap_or_default().to_string(),
+                    &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_screen_state_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<ScreenStateData>> {
+    pub(crate) fn get_screen_state_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<ScreenStateData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM screen_state_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
         )?;
@@ -798,29 +1096,32 @@ impl Database {
     }
 
     /* Notification Data */
-    pub fn insert_notification_data(&self, data: &NotificationData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO notification_data (
-                timestamp, device_id, package_name, title, priority, 
-                category, posted_at, removed_at, metadata
-            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.package_name.as_deref().unwrap_or_default().to_string(),
-                &data.package_name.as_deref().unwrap_or_default().to_string(),
-                &data.title.as_deref().unwrap_or_default().to_string(),
-                &data.priority.map(|p| p.to_string()).unwrap_or_default(),
-                &data.category.as_deref().unwrap_or_default().to_string(),
-                &data.posted_at.map(|p| p.to_string()).unwrap_or_default(),
-                &data.removed_at.map(|r| r.to_string()).unwrap_or_default(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_notification_data(&self, data: &NotificationData) -> Result<()> {
+        self.check_consent(&data.device_id, "notification_data")?;
+        let data = &self.redact_on_ingest(&data.device_id, data, Redactor::redact_notification)?;
+        self.audited_insert("notification_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO notification_data (
+                    timestamp, device_id, package_name, title, priority, 
+                    category, posted_at, removed_at, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                duckdb::params![
+                    data.timestamp.to_string(),
+                    &data.device_id,
+                    data.package_name.as_deref(),
+                    data.title.as_deref().map(|t| self.seal_field("notification_data", "title", t)),
+                    data.priority,
+                    data.category.as_deref(),
+                    data.posted_at.map(|p| p.to_string()),
+                    data.removed_at.map(|r| r.to_string()),
+                    data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_notification_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<NotificationData>> {
+    pub(crate) fn get_notification_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<NotificationData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM notification_data WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
         )?;
@@ -830,7 +1131,7 @@ impl Database {
                 timestamp: row.get::<_,String>(0)?.parse::<DateTime<Utc>>().unwrap(),
                 device_id: row.get(1)?,
                 package_name: row.get(2)?,
-                title: row.get(3)?,
+                title: self.open_optional_field(row.get(3)?)?,
                 priority: row.get(4)?,
                 category: row.get(5)?,
                 posted_at: row.get::<_,Option<String>>(6)?.map(|s| s.parse::<DateTime<Utc>>().unwrap()),
@@ -843,25 +1144,29 @@ impl Database {
     }
 
     /* App Usage Data */
-    pub fn insert_app_usage_data(&self, data: &AppUsageData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO app_usage_data (
-                timestamp, device_id, package_name, start_time, end_time, activity_type, metadata
-            ) VALUES (?, ?, ?, ?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.package_name,
-                &data.start_time.to_string(),
-                &data.end_time.to_string(),
-                &data.activity_type,
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_app_usage_data(&self, data: &AppUsageData) -> Result<()> {
+        self.check_consent(&data.device_id, "app_usage_data")?;
+        let data = &self.redact_on_ingest(&data.device_id, data, Redactor::redact_app_usage)?;
+        self.audited_insert("app_usage_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO app_usage_data (
+                    timestamp, device_id, package_name, start_time, end_time, activity_type, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                &[
+                    &data.timestamp.to_string(),
+                    &data.device_id,
+                    &data.package_name,
+                    &data.start_time.to_string(),
+                    &data.end_time.to_string(),
+                    &data.activity_type,
+                    &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_app_usage_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<AppUsageData>> {
+    pub(crate) fn get_app_usage_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<AppUsageData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM app_usage_data 
              WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
@@ -887,34 +1192,41 @@ impl Database {
     }
 
     /* Wifi Data */
-    pub fn insert_wifi_data(&self, data: &WifiData) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO wifi_data (
-                timestamp, device_id, ssid, bssid, strength, frequency, ip_address, link_speed, security_type, 
-                is_5ghz, is_6ghz, is_passpoint, is_restricted, nearby_networks, metadata
-            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            &[
-                &data.timestamp.to_string(),
-                &data.device_id,
-                &data.ssid,
-                &data.bssid,
-                &data.strength.to_string(),
-                &data.frequency.to_string(),
-                &data.ip_address,
-                &data.link_speed.to_string(),
-                &data.security_type,
-                &data.is_5ghz.to_string(),
-                &data.is_6ghz.to_string(),
-                &data.is_passpoint.to_string(),
-                &data.is_restricted.to_string(),
-                &data.nearby_networks.as_ref().map(|n| serde_json::to_string(n).unwrap()).unwrap_or_default(),
-                &data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()).unwrap_or_default(),
-            ],
-        )?;
+    pub(crate) fn insert_wifi_data(&self, data: &WifiData) -> Result<()> {
+        self.check_consent(&data.device_id, "wifi_data")?;
+        let zones = self.get_privacy_zones_for_device(&data.device_id)?;
+        let Some(data) = &crate::privacy::shield_wifi(&zones, data) else {
+            return Ok(());
+        };
+        self.audited_insert("wifi_data", &data.device_id, data.timestamp, |db| {
+            db.conn.execute(
+                "INSERT INTO wifi_data (
+                    timestamp, device_id, ssid, bssid, strength, frequency, ip_address, link_speed, security_type, 
+                    is_5ghz, is_6ghz, is_passpoint, is_restricted, nearby_networks, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                duckdb::params![
+                    data.timestamp.to_string(),
+                    &data.device_id,
+                    &data.ssid,
+                    &data.bssid,
+                    data.strength,
+                    data.frequency,
+                    &data.ip_address,
+                    data.link_speed,
+                    &data.security_type,
+                    data.is_5ghz,
+                    data.is_6ghz,
+                    data.is_passpoint,
+                    data.is_restricted,
+                    data.nearby_networks.as_ref().map(|n| serde_json::to_string(n).unwrap()),
+                    data.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()),
+                ],
+            )
+        })?;
         Ok(())
     }
 
-    pub fn get_wifi_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<WifiData>> {
+    pub(crate) fn get_wifi_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<WifiData>> {
         let mut stmt = self.conn.prepare(
             "SELECT * FROM wifi_data 
              WHERE device_id = ? AND timestamp BETWEEN ? AND ?"
@@ -948,6 +1260,22 @@ impl Database {
     }
 }
 
+pub(crate) fn gps_from_row(row: &duckdb::Row<'_>) -> Result<GpsData> {
+    Ok(GpsData {
+        timestamp: row.get::<_,String>(0)?.parse::<DateTime<Utc>>().unwrap(),
+        device_id: row.get(1)?,
+        latitude: row.get(2)?,
+        longitude: row.get(3)?,
+        altitude: row.get(4)?,
+        accuracy: row.get(5)?,
+        speed: row.get(6)?,
+        bearing: row.get(7)?,
+        satellites: row.get(8)?,
+        provider: row.get(9)?,
+        metadata: row.get::<_,Option<String>>(10)?.map(|s| serde_json::from_str(&s).unwrap()),
+    })
+}
+
 impl std::fmt::Display for ConnectionType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?}", self)
@@ -999,9 +1327,11 @@ mod tests {
                     refresh_rate: 60,
                 },
             },
+            name: None,
             created_at: Utc::now(),
             last_seen: Utc::now(),
             updated_at: Utc::now(),
+            retired_at: None,
         };
 
         db.insert_device(&device)?;
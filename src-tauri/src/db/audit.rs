@@ -0,0 +1,550 @@
+use std::collections::HashMap;
+use chrono::{DateTime, NaiveDate, SubsecRound, Utc};
+use duckdb::{OptionalExt, Result};
+use crate::audit::{digest_changes, entry_hash, find_break, merkle_path, merkle_root, row_digest, GENESIS_HASH};
+use crate::datatypes::{
+    audit::{AuditEntry, AuditMismatch, AuditPolicy, AuditProof, AuditReport, AuditRoot},
+    types::AuditAction,
+};
+use super::{is_sensor_table, Database};
+
+const ENTRY_COLUMNS: &str =
+    "user_id, seq, CAST(action AS VARCHAR), table_name, device_id, row_digests, recorded_at, prev_hash, hash";
+
+impl Database {
+    pub(crate) fn get_audit_policy(&self, user_id: &str) -> Result<Option<AuditPolicy>> {
+        self.conn
+            .query_row(
+                "SELECT user_id, tables, daily_roots, updated_at FROM audit_policies WHERE user_id = ?",
+                [user_id],
+                |row| {
+                    Ok(AuditPolicy {
+                        user_id: row.get(0)?,
+                        tables: serde_json::from_str(&row.get::<_,String>(1)?).unwrap(),
+                        daily_roots: row.get(2)?,
+                        updated_at: row.get::<_,String>(3)?.parse::<DateTime<Utc>>().unwrap(),
+                    })
+                },
+            )
+            .optional()
+    }
+
+    /// Stores the policy. Tables it adds are taken into the log as they
+    /// stand: rows the log doesn't account for, say because they were
+    /// stored or changed while the table wasn't audited, are logged now.
+    pub(crate) fn set_audit_policy(&self, policy: &AuditPolicy) -> Result<AuditPolicy> {
+        if let Some(table) = policy.tables.iter().find(|table| !is_sensor_table(table)) {
+            return Err(duckdb::Error::ToSqlConversionFailure(
+                format!("unknown sensor table: {}", table).into(),
+            ));
+        }
+
+        self.in_transaction(|db| {
+            let before = db.get_audit_policy(&policy.user_id)?.map(|p| p.tables).unwrap_or_default();
+            let mut stored = policy.clone();
+            stored.updated_at = Utc::now();
+            db.conn.execute(
+                "INSERT OR REPLACE INTO audit_policies (user_id, tables, daily_roots, updated_at) VALUES (?, ?, ?, ?)",
+                duckdb::params![
+                    &stored.user_id,
+                    serde_json::to_string(&stored.tables).unwrap(),
+                    stored.daily_roots,
+                    stored.updated_at.to_string(),
+                ],
+            )?;
+
+            let added: Vec<&String> = stored.tables.iter().filter(|table| !before.contains(table)).collect();
+            if !added.is_empty() {
+                let expected = expected_digests(&db.get_audit_log(&stored.user_id)?);
+                for table in added {
+                    for device in db.get_device_ids_for_user(&stored.user_id)? {
+                        let logged = expected.get(&(table.clone(), device.clone())).cloned().unwrap_or_default();
+                        let rows = db.stored_digests(table, &device, "true", &[])?;
+                        db.log_changes(&stored.user_id, table, &device, &logged, &rows)?;
+                    }
+                }
+            }
+            Ok(stored)
+        })
+    }
+
+    /// The rows of `table` from `device_id` matching `filter`, as their
+    /// digests and the text those are taken over.
+    fn stored_rows(&self, table: &str, device_id: &str, filter: &str, params: &[String]) -> Result<Vec<(String, String)>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT CAST(t AS VARCHAR) FROM {table} t WHERE device_id = ? AND ({filter})"
+        ))?;
+        let params = std::iter::once(device_id.to_string()).chain(params.iter().cloned());
+        let rows = stmt.query_map(duckdb::params_from_iter(params), |row| {
+            let text: String = row.get(0)?;
+            Ok((row_digest(&text), text))
+        })?;
+        rows.collect()
+    }
+
+    fn stored_digests(&self, table: &str, device_id: &str, filter: &str, params: &[String]) -> Result<Vec<String>> {
+        Ok(self.stored_rows(table, device_id, filter, params)?.into_iter().map(|(digest, _)| digest).collect())
+    }
+
+    /// The owners of those of `device_ids` whose owner audits `table`.
+    fn audited_devices(&self, table: &str, device_ids: &[String]) -> Result<Vec<(String, String)>> {
+        let mut policies: HashMap<String, Option<AuditPolicy>> = HashMap::new();
+        let mut audited = Vec::new();
+        for device in device_ids {
+            let Some(user_id) = self.get_device_owner(device)? else {
+                continue;
+            };
+            if !policies.contains_key(&user_id) {
+                policies.insert(user_id.clone(), self.get_audit_policy(&user_id)?);
+            }
+            if policies[&user_id].as_ref().is_some_and(|p| p.tables.iter().any(|t| t == table)) {
+                audited.push((user_id, device.clone()));
+            }
+        }
+        Ok(audited)
+    }
+
+    /// Runs `f`, which changes rows of `table`, and logs the rows it took
+    /// away and added for each of `device_ids` whose owner audits the
+    /// table. Rows are compared before and after among those matching
+    /// `filter`, which must cover everything `f` touches.
+    pub(super) fn audited<T>(
+        &self,
+        table: &str,
+        device_ids: &[String],
+        filter: &str,
+        params: &[String],
+        f: impl FnOnce(&Self) -> Result<T>,
+    ) -> Result<T> {
+        let audited = self.audited_devices(table, device_ids)?;
+        if audited.is_empty() {
+            return f(self);
+        }
+
+        let before = audited
+            .iter()
+            .map(|(_, device)| self.stored_digests(table, device, filter, params))
+            .collect::<Result<Vec<_>>>()?;
+        let value = f(self)?;
+        for ((user_id, device), before) in audited.iter().zip(before) {
+            let after = self.stored_digests(table, device, filter, params)?;
+            self.log_changes(user_id, table, device, &before, &after)?;
+        }
+        Ok(value)
+    }
+
+    /// `audited` for an insert of one row from `device_id` at `timestamp`.
+    pub(super) fn audited_insert(
+        &self,
+        table: &str,
+        device_id: &str,
+        timestamp: DateTime<Utc>,
+        f: impl FnOnce(&Self) -> Result<usize>,
+    ) -> Result<()> {
+        self.audited(table, &[device_id.to_string()], "timestamp = ?", &[timestamp.to_string()], f)?;
+        Ok(())
+    }
+
+    /// Logs what turned the rows `before` into `after`: a `Delete` of the
+    /// rows that went, then an `Insert` of those that came.
+    fn log_changes(&self, user_id: &str, table: &str, device_id: &str, before: &[String], after: &[String]) -> Result<()> {
+        let (removed, added) = digest_changes(before, after);
+        if !removed.is_empty() {
+            self.append_audit_entry(user_id, AuditAction::Delete, table, device_id, removed)?;
+        }
+        if !added.is_empty() {
+            self.append_audit_entry(user_id, AuditAction::Insert, table, device_id, added)?;
+        }
+        Ok(())
+    }
+
+    /// Logs that the rows of `table` from `device_id` between `start` and
+    /// `end` were exported, if the owner audits the table.
+    pub(super) fn audit_export(&self, table: &str, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<()> {
+        let range = [start.to_string(), end.to_string()];
+        for (user_id, device) in self.audited_devices(table, &[device_id.to_string()])? {
+            let mut digests = self.stored_digests(table, &device, "timestamp BETWEEN ? AND ?", &range)?;
+            if !digests.is_empty() {
+                digests.sort();
+                self.append_audit_entry(&user_id, AuditAction::Export, table, &device, digests)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn append_audit_entry(
+        &self,
+        user_id: &str,
+        action: AuditAction,
+        table: &str,
+        device_id: &str,
+        row_digests: Vec<String>,
+    ) -> Result<AuditEntry> {
+        // Stored timestamps keep microseconds, and the hash must survive
+        // the round trip
+        let recorded_at = Utc::now().trunc_subsecs(6);
+        if self.get_audit_policy(user_id)?.is_some_and(|p| p.daily_roots) {
+            self.seal_audit_days(user_id, recorded_at.date_naive())?;
+        }
+
+        let last = self
+            .conn
+            .query_row(
+                "SELECT seq, hash FROM audit_log WHERE user_id = ? ORDER BY seq DESC LIMIT 1",
+                [user_id],
+                |row| Ok((row.get::<_,i64>(0)?, row.get::<_,String>(1)?)),
+            )
+            .optional()?;
+        let (seq, prev_hash) = last.unwrap_or((0, GENESIS_HASH.to_string()));
+
+        let mut entry = AuditEntry {
+            user_id: user_id.to_string(),
+            seq: seq + 1,
+            action,
+            table_name: table.to_string(),
+            device_id: device_id.to_string(),
+            row_digests,
+            recorded_at,
+            prev_hash,
+            hash: String::new(),
+        };
+        entry.hash = entry_hash(&entry);
+        self.conn.execute(
+            "INSERT INTO audit_log (
+                user_id, seq, action, table_name, device_id, row_digests, recorded_at, prev_hash, hash
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            duckdb::params![
+                &entry.user_id,
+                entry.seq,
+                entry.action.as_str(),
+                &entry.table_name,
+                &entry.device_id,
+                serde_json::to_string(&entry.row_digests).unwrap(),
+                entry.recorded_at.to_string(),
+                &entry.prev_hash,
+                &entry.hash,
+            ],
+        )?;
+        Ok(entry)
+    }
+
+    /// The user's whole chain, in order.
+    pub(crate) fn get_audit_log(&self, user_id: &str) -> Result<Vec<AuditEntry>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM audit_log WHERE user_id = ? ORDER BY seq", ENTRY_COLUMNS
+        ))?;
+        let rows = stmt.query_map([user_id], |row| {
+            Ok(AuditEntry {
+                user_id: row.get(0)?,
+                seq: row.get(1)?,
+                action: serde_json::from_value(serde_json::Value::String(row.get(2)?)).unwrap(),
+                table_name: row.get(3)?,
+                device_id: row.get(4)?,
+                row_digests: serde_json::from_str(&row.get::<_,String>(5)?).unwrap(),
+                recorded_at: row.get::<_,String>(6)?.parse::<DateTime<Utc>>().unwrap(),
+                prev_hash: row.get(7)?,
+                hash: row.get(8)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub(crate) fn get_audit_roots(&self, user_id: &str) -> Result<Vec<AuditRoot>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT user_id, CAST(day AS VARCHAR), root, entries, first_seq, last_seq, sealed_at
+             FROM audit_roots WHERE user_id = ? ORDER BY day"
+        )?;
+        let rows = stmt.query_map([user_id], |row| {
+            Ok(AuditRoot {
+                user_id: row.get(0)?,
+                day: row.get::<_,String>(1)?.parse::<NaiveDate>().unwrap(),
+                root: row.get(2)?,
+                entries: row.get(3)?,
+                first_seq: row.get(4)?,
+                last_seq: row.get(5)?,
+                sealed_at: row.get::<_,String>(6)?.parse::<DateTime<Utc>>().unwrap(),
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Stores the Merkle root of every day before `today` whose entries
+    /// haven't been sealed yet.
+    fn seal_audit_days(&self, user_id: &str, today: NaiveDate) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "SELECT seq, hash, recorded_at FROM audit_log
+             WHERE user_id = ? AND seq > (SELECT coalesce(max(last_seq), 0) FROM audit_roots WHERE user_id = ?)
+             ORDER BY seq"
+        )?;
+        let entries = stmt
+            .query_map([user_id, user_id], |row| {
+                Ok((
+                    row.get::<_,i64>(0)?,
+                    row.get::<_,String>(1)?,
+                    row.get::<_,String>(2)?.parse::<DateTime<Utc>>().unwrap().date_naive(),
+                ))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        for day in entries.chunk_by(|a, b| a.2 == b.2) {
+            let date = day[0].2;
+            if date >= today {
+                break;
+            }
+            let leaves: Vec<String> = day.iter().map(|(_, hash, _)| hash.clone()).collect();
+            self.conn.execute(
+                "INSERT INTO audit_roots (user_id, day, root, entries, first_seq, last_seq, sealed_at)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)",
+                duckdb::params![
+                    user_id,
+                    date.to_string(),
+                    merkle_root(&leaves),
+                    leaves.len() as i64,
+                    day[0].0,
+                    day[day.len() - 1].0,
+                    Utc::now().to_string(),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Checks the user's chain links and hashes, the daily roots, and that
+    /// every audited table holds exactly the rows the log accounts for.
+    pub(crate) fn verify_audit_log(&self, user_id: &str) -> Result<AuditReport> {
+        let entries = self.get_audit_log(user_id)?;
+        let broken_at = find_break(&entries);
+
+        let by_seq: HashMap<i64, &AuditEntry> = entries.iter().map(|e| (e.seq, e)).collect();
+        let bad_roots = self
+            .get_audit_roots(user_id)?
+            .into_iter()
+            .filter(|root| {
+                let leaves: Option<Vec<String>> = (root.first_seq..=root.last_seq)
+                    .map(|seq| by_seq.get(&seq).filter(|e| e.recorded_at.date_naive() == root.day).map(|e| e.hash.clone()))
+                    .collect();
+                !leaves.is_some_and(|leaves| leaves.len() as i64 == root.entries && merkle_root(&leaves) == root.root)
+            })
+            .map(|root| root.day)
+            .collect();
+
+        let mut expected = expected_digests(&entries);
+        let tables = self.get_audit_policy(user_id)?.map(|p| p.tables).unwrap_or_default();
+        for table in &tables {
+            for device in self.get_device_ids_for_user(user_id)? {
+                expected.entry((table.clone(), device)).or_default();
+            }
+        }
+
+        let mut mismatches = Vec::new();
+        let mut keys: Vec<_> = expected.keys().cloned().collect();
+        keys.sort();
+        for (table, device) in keys {
+            let logged = &expected[&(table.clone(), device.clone())];
+            let stored = self.stored_digests(&table, &device, "true", &[])?;
+            let (missing, unexpected) = digest_changes(logged, &stored);
+            if !missing.is_empty() || !unexpected.is_empty() {
+                mismatches.push(AuditMismatch {
+                    table_name: table,
+                    device_id: device,
+                    missing: missing.len() as i64,
+                    unexpected: unexpected.len() as i64,
+                });
+            }
+        }
+
+        Ok(AuditReport {
+            user_id: user_id.to_string(),
+            entries: entries.len() as i64,
+            broken_at,
+            bad_roots,
+            mismatches,
+            verified_at: Utc::now(),
+        })
+    }
+
+    /// Proofs for the rows of `table` from `device_id` between `start` and
+    /// `end`, to hand over with an export of the same rows. Rows the log
+    /// has no record of get no proof. Finished days are sealed first if the
+    /// user keeps daily roots, and the export itself is logged.
+    pub(crate) fn export_audit_proofs(
+        &self,
+        user_id: &str,
+        table: &str,
+        device_id: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<AuditProof>> {
+        if !is_sensor_table(table) {
+            return Err(duckdb::Error::ToSqlConversionFailure(
+                format!("unknown sensor table: {}", table).into(),
+            ));
+        }
+        if self.get_audit_policy(user_id)?.is_some_and(|p| p.daily_roots) {
+            self.seal_audit_days(user_id, Utc::now().date_naive())?;
+        }
+
+        let entries = self.get_audit_log(user_id)?;
+        let mut logged_by: HashMap<&str, &AuditEntry> = HashMap::new();
+        for entry in entries.iter().filter(|e| e.action == AuditAction::Insert && e.table_name == table && e.device_id == device_id) {
+            for digest in &entry.row_digests {
+                logged_by.insert(digest, entry);
+            }
+        }
+        let roots = self.get_audit_roots(user_id)?;
+
+        let range = [start.to_string(), end.to_string()];
+        let mut proofs = Vec::new();
+        for (digest, row) in self.stored_rows(table, device_id, "timestamp BETWEEN ? AND ?", &range)? {
+            let Some(&entry) = logged_by.get(digest.as_str()) else {
+                continue;
+            };
+            let root = roots.iter().find(|r| (r.first_seq..=r.last_seq).contains(&entry.seq));
+            let path = match root {
+                Some(root) => {
+                    let leaves: Vec<String> = entries
+                        .iter()
+                        .filter(|e| (root.first_seq..=root.last_seq).contains(&e.seq))
+                        .map(|e| e.hash.clone())
+                        .collect();
+                    merkle_path(&leaves, (entry.seq - root.first_seq) as usize)
+                }
+                None => Vec::new(),
+            };
+            proofs.push(AuditProof {
+                table_name: table.to_string(),
+                device_id: device_id.to_string(),
+                row,
+                row_digest: digest,
+                entry: entry.clone(),
+                root: root.cloned(),
+                path,
+            });
+        }
+
+        self.audit_export(table, device_id, start, end)?;
+        Ok(proofs)
+    }
+}
+
+/// The rows each table and device should hold according to `entries`, as
+/// digests.
+fn expected_digests(entries: &[AuditEntry]) -> HashMap<(String, String), Vec<String>> {
+    let mut counts: HashMap<(String, String), HashMap<&str, i64>> = HashMap::new();
+    for entry in entries {
+        let change = match entry.action {
+            AuditAction::Insert => 1,
+            AuditAction::Delete => -1,
+            AuditAction::Export => continue,
+        };
+        let rows = counts.entry((entry.table_name.clone(), entry.device_id.clone())).or_default();
+        for digest in &entry.row_digests {
+            *rows.entry(digest).or_default() += change;
+        }
+    }
+    counts
+        .into_iter()
+        .map(|(key, rows)| {
+            let digests = rows
+                .into_iter()
+                .flat_map(|(digest, count)| std::iter::repeat_n(digest.to_string(), count.max(0) as usize))
+                .collect();
+            (key, digests)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatypes::{privacy::PrivacyZone, sensor::GpsData, types::{AuditAction, ZoneMode}};
+    use crate::db::{fixtures::{device, temp_database, user}, AccessError, UserContext};
+
+    #[test]
+    fn test_audit_log_detects_tampering() -> std::result::Result<(), AccessError> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_user(&user("bob"))?;
+
+        let alice = db.scoped(UserContext::new("alice"));
+        let bob = db.scoped(UserContext::new("bob"));
+        alice.insert_device(&device("alice_phone", "alice"))?;
+
+        let now = Utc::now();
+        let fix = |minutes: i64, latitude: f64, longitude: f64| GpsData {
+            timestamp: now - chrono::Duration::minutes(minutes),
+            device_id: "alice_phone".to_string(),
+            latitude,
+            longitude,
+            altitude: None,
+            accuracy: Some(5.0),
+            speed: None,
+            bearing: None,
+            satellites: None,
+            provider: None,
+            metadata: None,
+        };
+        // Stored before auditing starts, and taken in when it does
+        alice.insert_gps_data(&fix(3, 51.5080, -0.1270))?;
+
+        let policy = AuditPolicy {
+            user_id: "alice".to_string(),
+            tables: vec!["gps_data".to_string()],
+            daily_roots: true,
+            updated_at: now,
+        };
+        assert!(matches!(bob.set_audit_policy(&policy), Err(AccessError::Denied(_))));
+        alice.set_audit_policy(&policy)?;
+        alice.insert_gps_data(&fix(2, 51.5200, -0.1000))?;
+
+        // Changes made through the app are logged and check out
+        let zone = PrivacyZone {
+            id: "home".to_string(),
+            user_id: "alice".to_string(),
+            name: "Home".to_string(),
+            latitude: Some(51.5074),
+            longitude: Some(-0.1278),
+            radius_m: Some(200.0),
+            ssid: None,
+            bssid: None,
+            mode: ZoneMode::Snap,
+            noise_m: None,
+            created_at: now,
+            updated_at: now,
+        };
+        assert_eq!(alice.set_privacy_zone(&zone, true)?, 1);
+        let start = now - chrono::Duration::hours(1);
+        assert_eq!(alice.export_gps_data("alice_phone", start, now)?.len(), 2);
+
+        let actions: Vec<AuditAction> = alice.get_audit_log()?.iter().map(|e| e.action).collect();
+        use AuditAction::*;
+        assert_eq!(actions, [Insert, Insert, Delete, Insert, Export]);
+        let report = alice.verify_audit_log()?;
+        assert!(report.is_intact(), "{:?}", report);
+
+        let proofs = alice.export_audit_proofs("gps_data", "alice_phone", start, now)?;
+        assert_eq!(proofs.len(), 2);
+        for proof in &proofs {
+            assert_eq!(crate::audit::row_digest(&proof.row), proof.row_digest);
+            assert!(proof.entry.row_digests.contains(&proof.row_digest));
+        }
+        assert!(matches!(
+            bob.export_audit_proofs("gps_data", "alice_phone", start, now),
+            Err(AccessError::Denied(_))
+        ));
+
+        // Edits behind the app's back show up in the table and in the chain
+        db.conn.execute("UPDATE gps_data SET latitude = 48.8566 WHERE latitude > 51.51", [])?;
+        let report = alice.verify_audit_log()?;
+        assert_eq!(report.broken_at, None);
+        assert_eq!(
+            report.mismatches,
+            [AuditMismatch { table_name: "gps_data".to_string(), device_id: "alice_phone".to_string(), missing: 1, unexpected: 1 }]
+        );
+
+        db.conn.execute("UPDATE audit_log SET row_digests = '[]' WHERE seq = 2", [])?;
+        assert_eq!(alice.verify_audit_log()?.broken_at, Some(2));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use chrono::NaiveDate;
+use duckdb::Result;
+use super::{is_sensor_table, parquet::sql_path, schema, Database};
+
+/// One Parquet file of a snapshot: a whole table, or a single day of a
+/// sensor table so that days which haven't changed produce identical files.
+#[derive(Debug, Clone)]
+pub struct SnapshotFile {
+    pub table: String,
+    pub day: Option<NaiveDate>,
+    pub rows: usize,
+    pub path: PathBuf,
+}
+
+/// Tables in the order the schema's migrations create them, which is also
+/// an order their foreign keys can be loaded in.
+pub fn schema_tables() -> Vec<&'static str> {
+    let mut seen = HashSet::new();
+    schema::migrations()
+        .iter()
+        .flat_map(|sql| sql.lines())
+        .filter_map(|line| line.trim_start().strip_prefix("CREATE TABLE "))
+        .map(|rest| rest.strip_prefix("IF NOT EXISTS ").unwrap_or(rest))
+        .filter_map(|rest| rest.split(|c: char| c.is_whitespace() || c == '(').next())
+        .filter(|table| seen.insert(*table))
+        .collect()
+}
+
+impl Database {
+    /// The number of schema migrations applied to this database.
+    pub(crate) fn schema_version(&self) -> Result<i64> {
+        schema::current_version(&self.conn)
+    }
+
+    /// Writes every table to ZSTD-compressed Parquet files in `dir`, all
+    /// read from one transaction so the snapshot is consistent. Rows are
+    /// sorted, so unchanged data always produces the same bytes.
+    pub(crate) fn export_snapshot(&self, dir: &Path) -> Result<Vec<SnapshotFile>> {
+        self.in_transaction(|db| {
+            let existing = db.existing_tables()?;
+            let mut files = Vec::new();
+            for (i, table) in schema_tables().into_iter().enumerate() {
+                if !existing.contains(table) {
+                    continue;
+                }
+                if !is_sensor_table(table) {
+                    let path = dir.join(format!("{:03}-{}.parquet", i, table));
+                    let rows = db.copy_to_parquet(&format!("SELECT * FROM {} ORDER BY ALL", table), &path)?;
+                    files.push(SnapshotFile { table: table.to_string(), day: None, rows, path });
+                    continue;
+                }
+                let days = db.copy_days(table, |day| dir.join(format!("{:03}-{}-{}.parquet", i, table, day)))?;
+                for (day, rows, path) in days {
+                    files.push(SnapshotFile { table: table.to_string(), day: Some(day), rows, path });
+                }
+            }
+            Ok(files)
+        })
+    }
+
+    /// Loads files written by `export_snapshot` into this database, then
+    /// migrates it to the current schema. The database should be freshly
+    /// created at the snapshot's schema version: the rows init.sql seeds
+    /// are replaced.
+    pub(crate) fn load_snapshot(&self, files: &[SnapshotFile]) -> Result<()> {
+        let known = schema_tables();
+        let existing = self.existing_tables()?;
+        if let Some(file) = files.iter().find(|file| !existing.contains(&file.table)) {
+            return Err(duckdb::Error::ToSqlConversionFailure(
+                format!("snapshot contains unknown table {}", file.table).into(),
+            ));
+        }
+
+        // Committed on its own: DuckDB can't re-insert a key deleted in the
+        // same transaction
+        self.in_transaction(|db| {
+            for table in known.iter().rev().filter(|table| existing.contains(**table)) {
+                db.conn.execute(&format!("DELETE FROM {}", table), [])?;
+            }
+            Ok(())
+        })?;
+        self.in_transaction(|db| {
+            // Files keep export order, so referenced tables are filled first
+            for file in files {
+                db.conn.execute(
+                    &format!("INSERT INTO {} SELECT * FROM read_parquet({})", file.table, sql_path(&file.path)),
+                    [],
+                )?;
+            }
+            Ok(())
+        })?;
+        schema::migrate(&self.conn)?;
+        self.reload_fields()?;
+        self.rebuild_search_index()?;
+        Ok(())
+    }
+
+    fn existing_tables(&self) -> Result<HashSet<String>> {
+        let mut stmt = self.conn.prepare("SELECT table_name FROM duckdb_tables() WHERE schema_name = 'main'")?;
+        let rows = stmt.query_map([], |row| row.get::<_,String>(0))?;
+        rows.collect()
+    }
+}
+
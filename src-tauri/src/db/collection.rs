@@ -0,0 +1,68 @@
+use chrono::{DateTime, Utc};
+use duckdb::Result;
+use crate::datatypes::config::{SensorPreference, SyncPriorityConfig};
+use super::Database;
+
+impl Database {
+    pub(crate) fn set_sensor_preference(&self, preference: &SensorPreference) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO sensor_preferences (user_id, table_name, enabled, interval_ms, updated_at)
+             VALUES (?, ?, ?, ?, ?)",
+            duckdb::params![
+                &preference.user_id,
+                &preference.table_name,
+                preference.enabled,
+                preference.interval_ms,
+                &preference.updated_at.to_string(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn get_sensor_preferences(&self, user_id: &str) -> Result<Vec<SensorPreference>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT user_id, table_name, enabled, interval_ms, updated_at
+             FROM sensor_preferences WHERE user_id = ? ORDER BY table_name"
+        )?;
+        let rows = stmt.query_map([user_id], |row| {
+            Ok(SensorPreference {
+                user_id: row.get(0)?,
+                table_name: row.get(1)?,
+                enabled: row.get(2)?,
+                interval_ms: row.get(3)?,
+                updated_at: row.get::<_,String>(4)?.parse::<DateTime<Utc>>().unwrap(),
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Goes back to the default for `table_name`.
+    pub(crate) fn delete_sensor_preference(&self, user_id: &str, table_name: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM sensor_preferences WHERE user_id = ? AND table_name = ?",
+            [user_id, table_name],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn get_sync_priorities(&self) -> Result<Vec<SyncPriorityConfig>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT table_name, CAST(priority AS VARCHAR), batch_size, max_delay_seconds, retry_count,
+                    created_at, updated_at, metadata
+             FROM sync_priorities ORDER BY table_name"
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(SyncPriorityConfig {
+                table_name: row.get(0)?,
+                priority: serde_json::from_value(serde_json::Value::String(row.get(1)?)).unwrap(),
+                batch_size: row.get(2)?,
+                max_delay_seconds: row.get(3)?,
+                retry_count: row.get(4)?,
+                created_at: row.get::<_,String>(5)?.parse::<DateTime<Utc>>().unwrap(),
+                updated_at: row.get::<_,String>(6)?.parse::<DateTime<Utc>>().unwrap(),
+                metadata: row.get::<_,Option<String>>(7)?.map(|m| serde_json::from_str(&m).unwrap()),
+            })
+        })?;
+        rows.collect()
+    }
+}
@@ -0,0 +1,285 @@
+use std::fmt;
+use chrono::{DateTime, Utc};
+use duckdb::{OptionalExt, Result};
+use crate::datatypes::{
+    config::{Consent, ConsentChange},
+    types::ConsentLevel,
+};
+use super::{deletion::Removed, is_sensor_table, Database, SENSOR_TABLES};
+
+/// Wildcard for `device_id` or `table_name` in a consent row.
+pub const CONSENT_WILDCARD: &str = "*";
+
+/// Why an insert was refused because the user has not consented to it.
+/// `Database` inserts return it boxed inside
+/// `duckdb::Error::ToSqlConversionFailure`; converting the error to an
+/// `AccessError` or `CollectionError` turns it back into its own variant.
+#[derive(Debug)]
+pub struct ConsentWithheld {
+    pub device_id: String,
+    pub table: String,
+    pub level: ConsentLevel,
+}
+
+impl fmt::Display for ConsentWithheld {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "consent for {} from {} is {}", self.table, self.device_id, self.level.as_str())
+    }
+}
+
+impl std::error::Error for ConsentWithheld {}
+
+/// The level that applies to `table` on `device_id`. A row naming the table
+/// beats a `*` one, then a row naming the device beats a `*` one, so "never
+/// GPS" still holds for a device that is otherwise allowed everything.
+pub fn effective_consent(consents: &[Consent], device_id: &str, table: &str) -> ConsentLevel {
+    consents
+        .iter()
+        .filter(|c| (c.device_id == device_id || c.device_id == CONSENT_WILDCARD) && (c.table_name == table || c.table_name == CONSENT_WILDCARD))
+        .max_by_key(|c| (c.table_name != CONSENT_WILDCARD, c.device_id != CONSENT_WILDCARD))
+        .map_or(ConsentLevel::Collect, |c| c.level)
+}
+
+impl Database {
+    pub(crate) fn get_consents(&self, user_id: &str) -> Result<Vec<Consent>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT user_id, device_id, table_name, CAST(level AS VARCHAR), updated_at
+             FROM consents WHERE user_id = ? ORDER BY device_id, table_name"
+        )?;
+        let rows = stmt.query_map([user_id], consent_from_row)?;
+        rows.collect()
+    }
+
+    /// The level for a row `device_id` is sending to `table`, under its
+    /// owner's consents.
+    pub(crate) fn get_consent_level(&self, device_id: &str, table: &str) -> Result<ConsentLevel> {
+        let mut stmt = self.conn.prepare(
+            "SELECT c.user_id, c.device_id, c.table_name, CAST(c.level AS VARCHAR), c.updated_at
+             FROM consents c JOIN devices d ON d.user_id = c.user_id
+             WHERE d.device_id = ? AND c.device_id IN (?, '*') AND c.table_name IN (?, '*')"
+        )?;
+        let consents = stmt
+            .query_map([device_id, device_id, table], consent_from_row)?
+            .collect::<Result<Vec<_>>>()?;
+        Ok(effective_consent(&consents, device_id, table))
+    }
+
+    /// Fails with `ConsentWithheld` if rows from `device_id` may not be
+    /// stored in `table` at all. Every sensor insert goes through this.
+    pub(crate) fn check_consent(&self, device_id: &str, table: &str) -> Result<()> {
+        match self.get_consent_level(device_id, table)? {
+            ConsentLevel::Never => Err(duckdb::Error::ToSqlConversionFailure(Box::new(ConsentWithheld {
+                device_id: device_id.to_string(),
+                table: table.to_string(),
+                level: ConsentLevel::Never,
+            }))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records a consent decision and its history entry. With `purge`,
+    /// withdrawing consent (`Never`) also deletes what was already collected.
+    pub(crate) fn set_consent(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        table: &str,
+        level: ConsentLevel,
+        purge: bool,
+    ) -> Result<ConsentChange> {
+        if table != CONSENT_WILDCARD && !is_sensor_table(table) {
+            return Err(duckdb::Error::ToSqlConversionFailure(
+                format!("unknown sensor table: {}", table).into(),
+            ));
+        }
+
+        self.in_transaction(|db| {
+            let previous_level = db
+                .conn
+                .query_row(
+                    "SELECT CAST(level AS VARCHAR) FROM consents WHERE user_id = ? AND device_id = ? AND table_name = ?",
+                    [user_id, device_id, table],
+                    |row| row.get::<_,String>(0),
+                )
+                .optional()?
+                .map(|level| serde_json::from_value(serde_json::Value::String(level)).unwrap());
+
+            let now = Utc::now();
+            db.conn.execute(
+                "INSERT OR REPLACE INTO consents (user_id, device_id, table_name, level, updated_at)
+                 VALUES (?, ?, ?, ?, ?)",
+                [user_id, device_id, table, level.as_str(), &now.to_string()],
+            )?;
+
+            let purged_rows = if purge && level == ConsentLevel::Never {
+                db.purge_sensor_data(user_id, device_id, table)?
+            } else {
+                0
+            };
+
+            let change = ConsentChange {
+                id: uuid::Uuid::new_v4().to_string(),
+                user_id: user_id.to_string(),
+                device_id: device_id.to_string(),
+                table_name: table.to_string(),
+                previous_level,
+                level,
+                purged_rows: purged_rows as i64,
+                changed_at: now,
+            };
+            db.conn.execute(
+                "INSERT INTO consent_history (
+                    id, user_id, device_id, table_name, previous_level, level, purged_rows, changed_at
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                duckdb::params![
+                    &change.id,
+                    &change.user_id,
+                    &change.device_id,
+                    &change.table_name,
+                    change.previous_level.map(|l| l.as_str()),
+                    change.level.as_str(),
+                    change.purged_rows,
+                    change.changed_at.to_string(),
+                ],
+            )?;
+            Ok(change)
+        })
+    }
+
+    pub(crate) fn get_consent_history(&self, user_id: &str) -> Result<Vec<ConsentChange>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, user_id, device_id, table_name, CAST(previous_level AS VARCHAR), CAST(level AS VARCHAR),
+                    purged_rows, changed_at
+             FROM consent_history WHERE user_id = ? ORDER BY changed_at DESC"
+        )?;
+        let rows = stmt.query_map([user_id], |row| {
+            Ok(ConsentChange {
+                id: row.get(0)?,
+                user_id: row.get(1)?,
+                device_id: row.get(2)?,
+                table_name: row.get(3)?,
+                previous_level: row
+                    .get::<_,Option<String>>(4)?
+                    .map(|level| serde_json::from_value(serde_json::Value::String(level)).unwrap()),
+                level: serde_json::from_value(serde_json::Value::String(row.get(5)?)).unwrap(),
+                purged_rows: row.get(6)?,
+                changed_at: row.get::<_,String>(7)?.parse::<DateTime<Utc>>().unwrap(),
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Deletes the user's rows in `table` from `device_id`, either of which
+    /// may be `*`, along with the note references, search documents and
+    /// entity sightings derived from them. Returns the number of sensor rows
+    /// deleted.
+    fn purge_sensor_data(&self, user_id: &str, device_id: &str, table: &str) -> Result<usize> {
+        let devices = if device_id == CONSENT_WILDCARD {
+            self.get_device_ids_for_user(user_id)?
+        } else {
+            vec![device_id.to_string()]
+        };
+        let tables: Vec<&str> = if table == CONSENT_WILDCARD { SENSOR_TABLES.to_vec() } else { vec![table] };
+
+        let mut removed = Removed::new();
+        let mut purged = 0;
+        for device in &devices {
+            for table in tables.iter().copied() {
+                purged += self.delete_sensor_rows(table, device, "true", &[], &mut removed)?;
+            }
+        }
+        Ok(purged)
+    }
+}
+
+fn consent_from_row(row: &duckdb::Row<'_>) -> Result<Consent> {
+    Ok(Consent {
+        user_id: row.get(0)?,
+        device_id: row.get(1)?,
+        table_name: row.get(2)?,
+        level: serde_json::from_value(serde_json::Value::String(row.get(3)?)).unwrap(),
+        updated_at: row.get::<_,String>(4)?.parse::<DateTime<Utc>>().unwrap(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatypes::sensor::AccelerometerData;
+    use crate::db::{fixtures::{device, temp_database, user}, AccessError, UserContext};
+
+    fn consent(device_id: &str, table: &str, level: ConsentLevel) -> Consent {
+        Consent {
+            user_id: "alice".to_string(),
+            device_id: device_id.to_string(),
+            table_name: table.to_string(),
+            level,
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_most_specific_consent_wins() {
+        let consents = [
+            consent(CONSENT_WILDCARD, CONSENT_WILDCARD, ConsentLevel::LocalOnly),
+            consent("phone", CONSENT_WILDCARD, ConsentLevel::Collect),
+            consent(CONSENT_WILDCARD, "gps_data", ConsentLevel::Never),
+            consent("watch", "heart_rate_data", ConsentLevel::Never),
+        ];
+
+        assert_eq!(effective_consent(&consents, "phone", "accelerometer_data"), ConsentLevel::Collect);
+        assert_eq!(effective_consent(&consents, "phone", "gps_data"), ConsentLevel::Never);
+        assert_eq!(effective_consent(&consents, "watch", "heart_rate_data"), ConsentLevel::Never);
+        assert_eq!(effective_consent(&consents, "watch", "step_count_data"), ConsentLevel::LocalOnly);
+        assert_eq!(effective_consent(&[], "watch", "gps_data"), ConsentLevel::Collect);
+    }
+
+    #[test]
+    fn test_withdrawing_consent_blocks_and_purges() -> std::result::Result<(), AccessError> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_user(&user("bob"))?;
+
+        let alice = db.scoped(UserContext::new("alice"));
+        let bob = db.scoped(UserContext::new("bob"));
+        alice.insert_device(&device("alice_phone", "alice"))?;
+
+        let reading = AccelerometerData {
+            timestamp: Utc::now(),
+            device_id: "alice_phone".to_string(),
+            x: 0.0,
+            y: 0.0,
+            z: 9.8,
+            accuracy: None,
+            metadata: None,
+        };
+        alice.insert_accelerometer_data(&reading)?;
+        assert!(matches!(
+            bob.set_consent("alice_phone", "accelerometer_data", ConsentLevel::Never, true),
+            Err(AccessError::Denied(_))
+        ));
+
+        let change = alice.set_consent(CONSENT_WILDCARD, "accelerometer_data", ConsentLevel::Never, true)?;
+        assert_eq!(change.purged_rows, 1);
+        assert!(change.previous_level.is_none());
+
+        let start = Utc::now() - chrono::Duration::hours(1);
+        assert!(alice.get_accelerometer_data("alice_phone", start, Utc::now())?.is_empty());
+        match alice.insert_accelerometer_data(&reading) {
+            Err(AccessError::ConsentWithheld(withheld)) => {
+                assert_eq!(withheld.table, "accelerometer_data");
+                assert_eq!(withheld.level, ConsentLevel::Never);
+            }
+            other => panic!("expected the insert to be refused, got {:?}", other),
+        }
+
+        alice.set_consent(CONSENT_WILDCARD, "accelerometer_data", ConsentLevel::Collect, false)?;
+        alice.insert_accelerometer_data(&reading)?;
+
+        let history = alice.get_consent_history()?;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].previous_level, Some(ConsentLevel::Never));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,530 @@
+use std::collections::{BTreeMap, HashSet};
+use chrono::{DateTime, Utc};
+use duckdb::Result;
+use crate::datatypes::{
+    note::NoteTarget,
+    privacy::{DeletionReceipt, DeletionScope, RemovedRows, Tombstone},
+    types::PiiDetector,
+};
+use crate::geo::{bounding_box, haversine_m};
+use super::{is_sensor_table, Database, SENSOR_TABLES};
+
+/// Rows removed so far, by table.
+pub(super) type Removed = BTreeMap<String, usize>;
+
+/// Ids go into `IN (...)` lists this many at a time.
+pub(super) const ID_CHUNK: usize = 1000;
+
+fn tally(removed: &mut Removed, table: &str, rows: usize) {
+    if rows > 0 {
+        *removed.entry(table.to_string()).or_default() += rows;
+    }
+}
+
+/// The `search_documents.source` built from a sensor table, if any.
+fn search_source(table: &str) -> Option<&'static str> {
+    match table {
+        "notification_data" => Some("notification"),
+        "todos_data" => Some("todo"),
+        "call_log_data" => Some("call_log"),
+        _ => None,
+    }
+}
+
+/// Whether a call with this number and name belongs to `contact`, which may
+/// be either. Numbers match on their trailing digits so "+44 7700 900123"
+/// and "07700 900123" are the same contact.
+fn matches_contact(contact: &str, phone_number: Option<&str>, contact_name: Option<&str>) -> bool {
+    let digits = |s: &str| s.chars().filter(char::is_ascii_digit).collect::<String>();
+    let wanted = digits(contact);
+    if wanted.len() >= 7 {
+        if let Some(number) = phone_number.map(digits) {
+            let n = wanted.len().min(number.len()).min(9);
+            if n >= 7 && wanted[wanted.len() - n..] == number[number.len() - n..] {
+                return true;
+            }
+        }
+    }
+    contact_name.is_some_and(|name| !name.trim().is_empty() && name.trim().eq_ignore_ascii_case(contact.trim()))
+}
+
+/// Whether `text` contains `name` as whole words, ignoring case.
+fn mentions(text: &str, name: &str) -> bool {
+    let (text, name) = (text.to_lowercase(), name.trim().to_lowercase());
+    if name.is_empty() {
+        return false;
+    }
+    text.match_indices(&name).any(|(i, _)| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + name.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+impl Database {
+    /// Erases everything in `scope` for `user_id` and records a receipt,
+    /// which is also what devices receive as a tombstone. Device ids in the
+    /// scope must already have been checked against the user.
+    pub(crate) fn erase(&self, user_id: &str, scope: &DeletionScope) -> Result<DeletionReceipt> {
+        if let DeletionScope::Table { table } = scope {
+            if !is_sensor_table(table) {
+                return Err(duckdb::Error::ToSqlConversionFailure(
+                    format!("unknown sensor table: {}", table).into(),
+                ));
+            }
+        }
+
+        self.in_transaction(|db| {
+            let mut removed = Removed::new();
+            match scope {
+                DeletionScope::TimeRange { start, end, device_id } => {
+                    let devices = match device_id {
+                        Some(device_id) => vec![device_id.clone()],
+                        None => db.get_device_ids_for_user(user_id)?,
+                    };
+                    let range = [start.to_string(), end.to_string()];
+                    for device in &devices {
+                        for table in SENSOR_TABLES {
+                            db.delete_sensor_rows(table, device, "timestamp BETWEEN ? AND ?", &range, &mut removed)?;
+                        }
+                        for table in ["entity_sightings", "search_documents"] {
+                            let rows = db.conn.execute(
+                                &format!("DELETE FROM {} WHERE device_id = ? AND timestamp BETWEEN ? AND ?", table),
+                                [device, &range[0], &range[1]],
+                            )?;
+                            tally(&mut removed, table, rows);
+                        }
+                    }
+                    if device_id.is_none() {
+                        db.delete_notes(user_id, "timestamp BETWEEN ? AND ?", &range, &mut removed)?;
+                    }
+                }
+                DeletionScope::Device { device_id } => db.delete_device(device_id, &mut removed)?,
+                DeletionScope::Table { table } => {
+                    for device in db.get_device_ids_for_user(user_id)? {
+                        db.delete_sensor_rows(table, &device, "true", &[], &mut removed)?;
+                    }
+                }
+                DeletionScope::Contact { contact } => db.delete_contact(user_id, contact, &mut removed)?,
+                DeletionScope::Place { latitude, longitude, radius_m } => {
+                    db.delete_place(user_id, *latitude, *longitude, *radius_m, &mut removed)?
+                }
+                DeletionScope::Everything => {
+                    for device in db.get_device_ids_for_user(user_id)? {
+                        db.delete_device(&device, &mut removed)?;
+                    }
+                    db.delete_notes(user_id, "true", &[], &mut removed)?;
+                    db.delete_entities(user_id, &mut removed)?;
+                }
+            }
+
+            let receipt = DeletionReceipt {
+                id: uuid::Uuid::new_v4().to_string(),
+                user_id: user_id.to_string(),
+                scope: scope.clone(),
+                total_rows: removed.values().sum::<usize>() as i64,
+                removed: removed
+                    .into_iter()
+                    .map(|(table_name, rows)| RemovedRows { table_name, rows: rows as i64 })
+                    .collect(),
+                deleted_at: Utc::now(),
+            };
+            db.conn.execute(
+                "INSERT INTO deletion_receipts (id, user_id, scope, removed, total_rows, deleted_at)
+                 VALUES (?, ?, ?, ?, ?, ?)",
+                duckdb::params![
+                    &receipt.id,
+                    &receipt.user_id,
+                    serde_json::to_string(&receipt.scope).unwrap(),
+                    serde_json::to_string(&receipt.removed).unwrap(),
+                    receipt.total_rows,
+                    receipt.deleted_at.to_string(),
+                ],
+            )?;
+            Ok(receipt)
+        })
+    }
+
+    pub(crate) fn get_deletion_receipts(&self, user_id: &str) -> Result<Vec<DeletionReceipt>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, user_id, scope, removed, total_rows, deleted_at
+             FROM deletion_receipts WHERE user_id = ? ORDER BY deleted_at DESC"
+        )?;
+        let rows = stmt.query_map([user_id], |row| {
+            Ok(DeletionReceipt {
+                id: row.get(0)?,
+                user_id: row.get(1)?,
+                scope: serde_json::from_str(&row.get::<_,String>(2)?).unwrap(),
+                removed: serde_json::from_str(&row.get::<_,String>(3)?).unwrap(),
+                total_rows: row.get(4)?,
+                deleted_at: row.get::<_,String>(5)?.parse::<DateTime<Utc>>().unwrap(),
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Deletions made after `since`, oldest first, for devices to replay.
+    pub(crate) fn get_tombstones(&self, user_id: &str, since: Option<DateTime<Utc>>) -> Result<Vec<Tombstone>> {
+        let mut sql = String::from("SELECT id, scope, deleted_at FROM deletion_receipts WHERE user_id = ?");
+        let mut params = vec![user_id.to_string()];
+        if let Some(since) = since {
+            sql.push_str(" AND deleted_at > ?");
+            params.push(since.to_string());
+        }
+        sql.push_str(" ORDER BY deleted_at");
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(duckdb::params_from_iter(params), |row| {
+            Ok(Tombstone {
+                id: row.get(0)?,
+                scope: serde_json::from_str(&row.get::<_,String>(1)?).unwrap(),
+                deleted_at: row.get::<_,String>(2)?.parse::<DateTime<Utc>>().unwrap(),
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Deletes the rows of `table` from `device_id` matching `filter`, a
+    /// condition on the table's own columns, along with the note references,
+    /// entity sightings and search documents derived from them.
+    pub(super) fn delete_sensor_rows(
+        &self,
+        table: &str,
+        device_id: &str,
+        filter: &str,
+        params: &[String],
+        removed: &mut Removed,
+    ) -> Result<usize> {
+        let with_device = || std::iter::once(device_id.to_string()).chain(params.iter().cloned());
+
+        let mut stmt = self.conn.prepare(
+            "SELECT note_id, reference_id FROM note_references
+             WHERE reference_type = 'sensor_event' AND starts_with(reference_id, ?)"
+        )?;
+        let references = stmt
+            .query_map([format!("{}:{}@", table, device_id)], |row| {
+                Ok((row.get::<_,String>(0)?, row.get::<_,String>(1)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        if !references.is_empty() {
+            let mut stmt = self.conn.prepare(&format!(
+                "SELECT timestamp FROM {} WHERE device_id = ? AND ({})", table, filter
+            ))?;
+            let timestamps = stmt
+                .query_map(duckdb::params_from_iter(with_device()), |row| {
+                    Ok(row.get::<_,String>(0)?.parse::<DateTime<Utc>>().unwrap())
+                })?
+                .collect::<Result<HashSet<_>>>()?;
+
+            for (note_id, reference_id) in references {
+                let Some(NoteTarget::SensorEvent { timestamp, .. }) = NoteTarget::parse("sensor_event", &reference_id) else {
+                    continue;
+                };
+                if timestamps.contains(&timestamp) {
+                    let rows = self.conn.execute(
+                        "DELETE FROM note_references WHERE note_id = ? AND reference_type = 'sensor_event' AND reference_id = ?",
+                        [&note_id, &reference_id],
+                    )?;
+                    tally(removed, "note_references", rows);
+                }
+            }
+        }
+
+        let derived = std::iter::once(("entity_sightings", table)).chain(search_source(table).map(|s| ("search_documents", s)));
+        for (derived_table, source) in derived {
+            let rows = self.conn.execute(
+                &format!(
+                    "DELETE FROM {derived_table} d WHERE d.device_id = ? AND d.source = ? AND EXISTS (
+                        SELECT 1 FROM {table} WHERE device_id = d.device_id AND timestamp = d.timestamp AND ({filter})
+                    )"
+                ),
+                duckdb::params_from_iter([device_id.to_string(), source.to_string()].into_iter().chain(params.iter().cloned())),
+            )?;
+            tally(removed, derived_table, rows);
+        }
+
+        let rows = self.audited(table, &[device_id.to_string()], filter, params, |db| {
+            db.conn.execute(
+                &format!("DELETE FROM {} WHERE device_id = ? AND ({})", table, filter),
+                duckdb::params_from_iter(with_device()),
+            )
+        })?;
+        tally(removed, table, rows);
+        Ok(rows)
+    }
+
+    /// Deletes `user_id`'s notes matching `filter` and the replies under
+    /// them, with their references in both directions, their search
+    /// documents and their embeddings.
+    fn delete_notes(&self, user_id: &str, filter: &str, params: &[String], removed: &mut Removed) -> Result<()> {
+        let mut stmt = self.conn.prepare(&format!(
+            "WITH RECURSIVE doomed(id) AS (
+                SELECT id FROM notes WHERE user_id = ? AND ({})
+                UNION SELECT n.id FROM notes n JOIN doomed d ON n.parent_id = d.id
+            ) SELECT id FROM doomed",
+            filter
+        ))?;
+        let ids = stmt
+            .query_map(duckdb::params_from_iter(std::iter::once(user_id.to_string()).chain(params.iter().cloned())), |row| {
+                row.get::<_,String>(0)
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        for chunk in ids.chunks(ID_CHUNK) {
+            let placeholders = vec!["?"; chunk.len()].join(", ");
+            let rows = self.conn.execute(
+                &format!(
+                    "DELETE FROM note_references
+                     WHERE note_id IN ({placeholders}) OR (reference_type = 'note' AND reference_id IN ({placeholders}))"
+                ),
+                duckdb::params_from_iter(chunk.iter().chain(chunk)),
+            )?;
+            tally(removed, "note_references", rows);
+
+            let rows = self.conn.execute(
+                &format!("DELETE FROM search_documents WHERE source = 'note' AND source_id IN ({})", placeholders),
+                duckdb::params_from_iter(chunk),
+            )?;
+            tally(removed, "search_documents", rows);
+
+            let rows = self.conn.execute(
+                &format!("DELETE FROM note_embeddings WHERE note_id IN ({})", placeholders),
+                duckdb::params_from_iter(chunk),
+            )?;
+            tally(removed, "note_embeddings", rows);
+
+            let rows = self.conn.execute(
+                &format!("DELETE FROM notes WHERE id IN ({})", placeholders),
+                duckdb::params_from_iter(chunk),
+            )?;
+            tally(removed, "notes", rows);
+        }
+        Ok(())
+    }
+
+    fn delete_device(&self, device_id: &str, removed: &mut Removed) -> Result<()> {
+        for table in SENSOR_TABLES {
+            self.delete_sensor_rows(table, device_id, "true", &[], removed)?;
+        }
+        for table in ["entity_sightings", "search_documents", "consents", "device_tokens", "devices"] {
+            let rows = self.conn.execute(&format!("DELETE FROM {} WHERE device_id = ?", table), [device_id])?;
+            tally(removed, table, rows);
+        }
+        Ok(())
+    }
+
+    /// The user's known entities, their sightings and any references to them.
+    fn delete_entities(&self, user_id: &str, removed: &mut Removed) -> Result<()> {
+        let rows = self.conn.execute(
+            "DELETE FROM entity_sightings
+             WHERE entity_id IN (SELECT entity_id FROM known_entities WHERE user_id = ?)",
+            [user_id],
+        )?;
+        tally(removed, "entity_sightings", rows);
+        let rows = self.conn.execute(
+            "DELETE FROM note_references
+             WHERE reference_type = 'entity'
+               AND reference_id IN (SELECT entity_id FROM known_entities WHERE user_id = ?)",
+            [user_id],
+        )?;
+        tally(removed, "note_references", rows);
+        let rows = self.conn.execute("DELETE FROM known_entities WHERE user_id = ?", [user_id])?;
+        tally(removed, "known_entities", rows);
+        Ok(())
+    }
+
+    /// Calls with the contact and notifications whose title names them.
+    /// Needs the database unlocked if those columns are encrypted.
+    fn delete_contact(&self, user_id: &str, contact: &str, removed: &mut Removed) -> Result<()> {
+        // Rows redacted under the user's policy hold the contact's pseudonyms
+        let pseudonyms: Vec<String> = match self.get_redactor(user_id)? {
+            Some((_, redactor)) => [PiiDetector::PhoneNumber, PiiDetector::ContactName]
+                .into_iter()
+                .map(|detector| redactor.pseudonym(detector, contact))
+                .collect(),
+            None => Vec::new(),
+        };
+        let is_pseudonym_of_contact = |value: Option<&str>| value.is_some_and(|v| pseudonyms.iter().any(|p| p == v));
+
+        for device in self.get_device_ids_for_user(user_id)? {
+            let mut stmt = self.conn.prepare(
+                "SELECT rowid, phone_number, contact_name FROM call_log_data WHERE device_id = ?"
+            )?;
+            let calls = stmt
+                .query_map([&device], |row| Ok((row.get::<_,i64>(0)?, row.get(1)?, row.get(2)?)))?
+                .collect::<Result<Vec<(i64, Option<String>, Option<String>)>>>()?;
+            let mut rowids = Vec::new();
+            for (rowid, phone_number, contact_name) in calls {
+                let phone_number = self.open_optional_field(phone_number)?;
+                let contact_name = self.open_optional_field(contact_name)?;
+                if matches_contact(contact, phone_number.as_deref(), contact_name.as_deref())
+                    || is_pseudonym_of_contact(phone_number.as_deref())
+                    || is_pseudonym_of_contact(contact_name.as_deref())
+                {
+                    rowids.push(rowid);
+                }
+            }
+            self.delete_rowids("call_log_data", &device, &rowids, removed)?;
+
+            let mut stmt = self.conn.prepare(
+                "SELECT rowid, title FROM notification_data WHERE device_id = ? AND title IS NOT NULL AND title <> ''"
+            )?;
+            let notifications = stmt
+                .query_map([&device], |row| Ok((row.get::<_,i64>(0)?, row.get::<_,String>(1)?)))?
+                .collect::<Result<Vec<_>>>()?;
+            let mut rowids = Vec::new();
+            for (rowid, title) in notifications {
+                let title = self.open_field(title)?;
+                if mentions(&title, contact) || pseudonyms.iter().any(|p| title.contains(p.as_str())) {
+                    rowids.push(rowid);
+                }
+            }
+            self.delete_rowids("notification_data", &device, &rowids, removed)?;
+        }
+        Ok(())
+    }
+
+    fn delete_place(&self, user_id: &str, latitude: f64, longitude: f64, radius_m: f64, removed: &mut Removed) -> Result<()> {
+        let (min_lat, max_lat, min_lon, max_lon) = bounding_box(latitude, longitude, radius_m);
+        for device in self.get_device_ids_for_user(user_id)? {
+            let mut stmt = self.conn.prepare(
+                "SELECT rowid, latitude, longitude FROM gps_data
+                 WHERE device_id = ? AND latitude BETWEEN ? AND ? AND longitude BETWEEN ? AND ?"
+            )?;
+            let fixes = stmt
+                .query_map(duckdb::params![&device, min_lat, max_lat, min_lon, max_lon], |row| {
+                    Ok((row.get::<_,i64>(0)?, row.get::<_,f64>(1)?, row.get::<_,f64>(2)?))
+                })?
+                .collect::<Result<Vec<_>>>()?;
+            let rowids: Vec<i64> = fixes
+                .into_iter()
+                .filter(|(_, lat, lon)| haversine_m(latitude, longitude, *lat, *lon) <= radius_m)
+                .map(|(rowid, _, _)| rowid)
+                .collect();
+            self.delete_rowids("gps_data", &device, &rowids, removed)?;
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT r.note_id, r.reference_id FROM note_references r
+             JOIN notes n ON n.id = r.note_id
+             WHERE n.user_id = ? AND r.reference_type = 'location'"
+        )?;
+        let references = stmt
+            .query_map([user_id], |row| Ok((row.get::<_,String>(0)?, row.get::<_,String>(1)?)))?
+            .collect::<Result<Vec<_>>>()?;
+        for (note_id, reference_id) in references {
+            let Some(NoteTarget::Location { latitude: lat, longitude: lon, .. }) = NoteTarget::parse("location", &reference_id) else {
+                continue;
+            };
+            if haversine_m(latitude, longitude, lat, lon) <= radius_m {
+                let rows = self.conn.execute(
+                    "DELETE FROM note_references WHERE note_id = ? AND reference_type = 'location' AND reference_id = ?",
+                    [&note_id, &reference_id],
+                )?;
+                tally(removed, "note_references", rows);
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn delete_rowids(&self, table: &str, device_id: &str, rowids: &[i64], removed: &mut Removed) -> Result<()> {
+        for chunk in rowids.chunks(ID_CHUNK) {
+            let list = chunk.iter().map(i64::to_string).collect::<Vec<_>>().join(", ");
+            self.delete_sensor_rows(table, device_id, &format!("rowid IN ({})", list), &[], removed)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatypes::{note::{Note, NoteTarget}, sensor::CallLogData, types::NotePriority};
+    use crate::db::{fixtures::{device, temp_database, user}, AccessError, UserContext};
+
+    #[test]
+    fn test_matches_contact() {
+        assert!(matches_contact("+44 7700 900123", Some("07700 900123"), None));
+        assert!(matches_contact("07700900123", Some("+447700900123"), Some("Sam")));
+        assert!(matches_contact("sam ", None, Some("Sam")));
+        assert!(!matches_contact("+44 7700 900123", Some("07700 900124"), None));
+        assert!(!matches_contact("123", Some("123"), None));
+        assert!(!matches_contact("Sam", Some("07700 900123"), Some("")));
+
+        assert!(mentions("Sam: running late", "sam"));
+        assert!(!mentions("Samsung update", "Sam"));
+    }
+
+    #[test]
+    fn test_forgetting_a_contact_and_a_time_range() -> std::result::Result<(), AccessError> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_user(&user("bob"))?;
+
+        let alice = db.scoped(UserContext::new("alice"));
+        let bob = db.scoped(UserContext::new("bob"));
+        alice.insert_device(&device("alice_phone", "alice"))?;
+
+        let now = Utc::now();
+        let call = |minutes: i64, number: &str| CallLogData {
+            timestamp: now - chrono::Duration::minutes(minutes),
+            device_id: "alice_phone".to_string(),
+            call_type: "incoming".to_string(),
+            phone_number: Some(number.to_string()),
+            contact_name: None,
+            duration_seconds: 60,
+            is_missed: false,
+            is_blocked: false,
+            sim_slot: None,
+            metadata: None,
+        };
+        alice.insert_call_log_data(&call(1, "+44 7700 900123"))?;
+        alice.insert_call_log_data(&call(2, "07700 900123"))?;
+        alice.insert_call_log_data(&call(3, "+1 555 0100 200"))?;
+
+        alice.insert_note(&Note {
+            id: "n1".to_string(),
+            user_id: "alice".to_string(),
+            timestamp: now,
+            content: "Call back".to_string(),
+            priority: NotePriority::Medium,
+            parent_id: None,
+            tags: None,
+            embedding: None,
+            metadata: None,
+            created_at: now,
+            updated_at: now,
+        })?;
+        let target = NoteTarget::SensorEvent {
+            table: "call_log_data".to_string(),
+            device_id: "alice_phone".to_string(),
+            timestamp: now - chrono::Duration::minutes(1),
+        };
+        alice.attach_note("n1", &target, None)?;
+
+        assert!(matches!(
+            bob.erase(&DeletionScope::Device { device_id: "alice_phone".to_string() }),
+            Err(AccessError::Denied(_))
+        ));
+
+        let receipt = alice.erase(&DeletionScope::Contact { contact: "07700900123".to_string() })?;
+        assert_eq!(receipt.removed, vec![
+            RemovedRows { table_name: "call_log_data".to_string(), rows: 2 },
+            RemovedRows { table_name: "note_references".to_string(), rows: 1 },
+        ]);
+        let start = now - chrono::Duration::hours(1);
+        assert_eq!(alice.get_call_log_data("alice_phone", start, now)?.len(), 1);
+        assert!(alice.resolve_note_references("n1")?.is_empty());
+
+        let range = DeletionScope::TimeRange { start, end: now, device_id: None };
+        assert_eq!(alice.erase(&range)?.total_rows, 2);
+        assert!(alice.get_note("n1").is_err());
+
+        let tombstones = alice.get_tombstones(Some(start))?;
+        assert_eq!(tombstones.len(), 2);
+        assert_eq!(tombstones[1].scope, range);
+        assert!(bob.get_tombstones(None)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+use chrono::{DateTime, Utc};
+use duckdb::Result;
+use crate::datatypes::{
+    device::{Device, DeviceCapabilities},
+    note::NoteTarget,
+};
+use super::{Database, SENSOR_TABLES};
+
+pub(super) const DEVICE_COLUMNS: &str =
+    "device_id, user_id, device_type, os_type, os_version, app_version, available_sensors, \
+     capabilities, name, created_at, last_seen, updated_at, retired_at";
+
+impl Database {
+    /// The user's devices, most recently seen first.
+    pub(crate) fn get_devices_for_user(&self, user_id: &str, include_retired: bool) -> Result<Vec<Device>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM devices WHERE user_id = ? {} ORDER BY last_seen DESC",
+            DEVICE_COLUMNS,
+            if include_retired { "" } else { "AND retired_at IS NULL" },
+        ))?;
+        let rows = stmt.query_map([user_id], device_from_row)?;
+        rows.collect()
+    }
+
+    pub(crate) fn update_device_capabilities(
+        &self,
+        device_id: &str,
+        capabilities: &DeviceCapabilities,
+        available_sensors: &[String],
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE devices SET capabilities = ?, available_sensors = ?, updated_at = ? WHERE device_id = ?",
+            [
+                &capabilities.to_json().unwrap(),
+                &serde_json::to_string(available_sensors).unwrap(),
+                &Utc::now().to_string(),
+                device_id,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Records an OS or app upgrade reported by the device.
+    pub(crate) fn update_device_software(&self, device_id: &str, os_version: &str, app_version: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE devices SET os_version = ?, app_version = ?, updated_at = ? WHERE device_id = ?",
+            [os_version, app_version, &Utc::now().to_string(), device_id],
+        )?;
+        Ok(())
+    }
+
+    /// Bumps `last_seen`. Heartbeats can arrive out of order after a device
+    /// comes back online, so it never moves backwards.
+    pub(crate) fn heartbeat_device(&self, device_id: &str, at: DateTime<Utc>) -> Result<()> {
+        self.conn.execute(
+            "UPDATE devices SET last_seen = greatest(last_seen, ?::TIMESTAMP) WHERE device_id = ?",
+            [&at.to_string(), device_id],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn rename_device(&self, device_id: &str, name: Option<&str>) -> Result<()> {
+        self.conn.execute(
+            "UPDATE devices SET name = ?, updated_at = ? WHERE device_id = ?",
+            duckdb::params![name, Utc::now().to_string(), device_id],
+        )?;
+        Ok(())
+    }
+
+    /// Hides the device from the active list and revokes its device tokens.
+    /// Its data is kept, since the sensor tables still reference it.
+    pub(crate) fn retire_device(&self, device_id: &str, at: DateTime<Utc>) -> Result<()> {
+        self.conn.execute(
+            "UPDATE devices SET retired_at = coalesce(retired_at, ?::TIMESTAMP), updated_at = ? WHERE device_id = ?",
+            [&at.to_string(), &Utc::now().to_string(), device_id],
+        )?;
+        self.conn.execute("DELETE FROM device_tokens WHERE device_id = ?", [device_id])?;
+        Ok(())
+    }
+
+    /// Moves everything captured by `old_id` onto `new_id`, for when a phone
+    /// is replaced, and retires `old_id`. Where both devices have a row for
+    /// the same moment the new device's row wins. Returns the number of
+    /// sensor rows moved.
+    ///
+    /// Rows are copied under `new_id` and then deleted rather than updated in
+    /// place: `device_id` is part of each sensor table's key, and DuckDB turns
+    /// updates of key columns into a delete and insert of the same row.
+    /// Search documents for `old_id` are dropped rather than rewritten;
+    /// `rebuild_search_index` picks the moved rows up again.
+    pub(crate) fn merge_devices(&self, old_id: &str, new_id: &str) -> Result<usize> {
+        let old = self.get_device(old_id)?;
+        let new = self.get_device(new_id)?;
+        if old_id == new_id || old.user_id != new.user_id {
+            return Err(duckdb::Error::ToSqlConversionFailure(
+                format!("cannot merge device {} into {}", old_id, new_id).into(),
+            ));
+        }
+
+        self.in_transaction(|db| {
+            let mut moved = 0;
+            for table in SENSOR_TABLES {
+                let same_row = merge_key(table)
+                    .iter()
+                    .map(|column| format!("n.{column} = o.{column}"))
+                    .collect::<Vec<_>>()
+                    .join(" AND ");
+                let devices = [old_id.to_string(), new_id.to_string()];
+                moved += db.audited(table, &devices, "true", &[], |db| {
+                    let copied = db.conn.execute(
+                        &format!(
+                            "INSERT INTO {table}
+                             SELECT * REPLACE (?::VARCHAR AS device_id) FROM {table} o
+                             WHERE o.device_id = ? AND NOT EXISTS (
+                                 SELECT 1 FROM {table} n WHERE n.device_id = ? AND {same_row}
+                             )"
+                        ),
+                        [new_id, old_id, new_id],
+                    )?;
+                    db.conn.execute(&format!("DELETE FROM {} WHERE device_id = ?", table), [old_id])?;
+                    Ok(copied)
+                })?;
+            }
+
+            db.conn.execute(
+                "UPDATE entity_sightings SET device_id = ? WHERE device_id = ?",
+                [new_id, old_id],
+            )?;
+            db.conn.execute("DELETE FROM search_documents WHERE device_id = ?", [old_id])?;
+            db.move_sensor_references(old_id, new_id)?;
+            db.retire_device(old_id, Utc::now())?;
+            Ok(moved)
+        })
+    }
+
+    /// Points note references at sensor rows of `old_id` to the same rows
+    /// under `new_id`. `reference_id` is part of the key, so references are
+    /// copied and the old ones deleted, as in `merge_devices`.
+    fn move_sensor_references(&self, old_id: &str, new_id: &str) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "SELECT note_id, reference_id FROM note_references WHERE reference_type = 'sensor_event'"
+        )?;
+        let references = stmt
+            .query_map([], |row| Ok((row.get::<_,String>(0)?, row.get::<_,String>(1)?)))?
+            .collect::<Result<Vec<_>>>()?;
+
+        for (note_id, reference_id) in references {
+            let Some(NoteTarget::SensorEvent { table, device_id, timestamp }) =
+                NoteTarget::parse("sensor_event", &reference_id)
+            else {
+                continue;
+            };
+            if device_id != old_id {
+                continue;
+            }
+
+            let moved = NoteTarget::SensorEvent { table, device_id: new_id.to_string(), timestamp }.reference_id();
+            self.conn.execute(
+                "INSERT INTO note_references
+                 SELECT * REPLACE (?::VARCHAR AS reference_id) FROM note_references o
+                 WHERE o.note_id = ? AND o.reference_type = 'sensor_event' AND o.reference_id = ?
+                   AND NOT EXISTS (
+                       SELECT 1 FROM note_references n
+                       WHERE n.note_id = o.note_id AND n.reference_type = 'sensor_event' AND n.reference_id = ?
+                   )",
+                [&moved, &note_id, &reference_id, &moved],
+            )?;
+            self.conn.execute(
+                "DELETE FROM note_references WHERE note_id = ? AND reference_type = 'sensor_event' AND reference_id = ?",
+                [&note_id, &reference_id],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The columns besides `device_id` that identify a row in a sensor table.
+fn merge_key(table: &str) -> &'static [&'static str] {
+    match table {
+        "todos_data" => &["timestamp", "todo_id"],
+        _ => &["timestamp"],
+    }
+}
+
+pub(super) fn device_from_row(row: &duckdb::Row<'_>) -> Result<Device> {
+    Ok(Device {
+        device_id: row.get(0)?,
+        user_id: row.get(1)?,
+        device_type: serde_json::from_value(serde_json::Value::String(row.get(2)?)).unwrap(),
+        os_type: row.get(3)?,
+        os_version: row.get(4)?,
+        app_version: row.get(5)?,
+        available_sensors: row
+            .get::<_,Option<String>>(6)?
+            .map(|s| serde_json::from_str(&s).unwrap())
+            .unwrap_or_default(),
+        capabilities: DeviceCapabilities::from_json(&row.get::<_,String>(7)?).unwrap(),
+        name: row.get(8)?,
+        created_at: row.get::<_,String>(9)?.parse::<DateTime<Utc>>().unwrap(),
+        last_seen: row.get::<_,String>(10)?.parse::<DateTime<Utc>>().unwrap(),
+        updated_at: row.get::<_,String>(11)?.parse::<DateTime<Utc>>().unwrap(),
+        retired_at: row.get::<_,Option<String>>(12)?.map(|s| s.parse::<DateTime<Utc>>().unwrap()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeZone, Utc};
+    use duckdb::Result;
+    use crate::datatypes::{note::NoteTarget, sensor::AccelerometerData};
+    use crate::db::{fixtures::{device, note, temp_database, user}, AccessError, ResolvedReference, UserContext};
+
+    fn reading(device_id: &str, timestamp: DateTime<Utc>, z: f32) -> AccelerometerData {
+        AccelerometerData { timestamp, device_id: device_id.to_string(), x: 0.0, y: 0.0, z, accuracy: None, metadata: None }
+    }
+
+    fn row(device_id: &str, timestamp: DateTime<Utc>) -> NoteTarget {
+        NoteTarget::SensorEvent { table: "accelerometer_data".to_string(), device_id: device_id.to_string(), timestamp }
+    }
+
+    #[test]
+    fn test_merging_devices_moves_rows_that_notes_refer_to() -> Result<()> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_device(&device("old_phone", "alice"))?;
+        db.insert_device(&device("new_phone", "alice"))?;
+
+        let only_old = Utc.with_ymd_and_hms(2024, 5, 1, 9, 0, 0).unwrap();
+        let both = Utc.with_ymd_and_hms(2024, 5, 1, 9, 5, 0).unwrap();
+        db.insert_accelerometer_data(&reading("old_phone", only_old, 1.0))?;
+        db.insert_accelerometer_data(&reading("old_phone", both, 2.0))?;
+        db.insert_accelerometer_data(&reading("new_phone", both, 3.0))?;
+
+        db.insert_note(&note("walk", "alice", "Tripped twice"))?;
+        db.insert_note(&note("same", "alice", "Both phones felt that"))?;
+        db.attach_note("walk", &row("old_phone", only_old), None)?;
+        db.attach_note("walk", &row("old_phone", both), None)?;
+        db.attach_note("same", &row("old_phone", both), None)?;
+        db.attach_note("same", &row("new_phone", both), None)?;
+
+        assert_eq!(db.merge_devices("old_phone", "new_phone")?, 1);
+        assert!(db.get_accelerometer_data("old_phone", only_old, both)?.is_empty());
+        let mut moved: Vec<f32> = db.get_accelerometer_data("new_phone", only_old, both)?.iter().map(|r| r.z).collect();
+        moved.sort_by(f32::total_cmp);
+        assert_eq!(moved, [1.0, 3.0]);
+
+        // References follow the rows, and collapse where both devices had one
+        let targets = |note_id: &str| -> Result<Vec<Option<NoteTarget>>> {
+            Ok(db.get_note_references(note_id)?.iter().map(|r| r.target()).collect())
+        };
+        let mut walk = targets("walk")?;
+        walk.sort_by_key(|target| target.as_ref().map(|t| t.reference_id()));
+        assert_eq!(walk, [Some(row("new_phone", only_old)), Some(row("new_phone", both))]);
+        assert_eq!(targets("same")?, [Some(row("new_phone", both))]);
+        for (_, resolved) in db.resolve_note_references("walk")? {
+            assert!(matches!(resolved, ResolvedReference::SensorEvent { .. }));
+        }
+        assert!(db.get_device("old_phone")?.retired_at.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replacing_a_phone_moves_its_data() -> std::result::Result<(), AccessError> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_user(&user("bob"))?;
+
+        let alice = db.scoped(UserContext::new("alice"));
+        let bob = db.scoped(UserContext::new("bob"));
+        alice.insert_device(&device("old_phone", "alice"))?;
+        alice.insert_device(&device("new_phone", "alice"))?;
+        bob.insert_device(&device("bob_phone", "bob"))?;
+
+        let overlap = Utc::now() - chrono::Duration::minutes(5);
+        alice.insert_accelerometer_data(&reading("old_phone", Utc::now() - chrono::Duration::minutes(10), 1.0))?;
+        alice.insert_accelerometer_data(&reading("old_phone", overlap, 2.0))?;
+        alice.insert_accelerometer_data(&reading("new_phone", overlap, 3.0))?;
+
+        alice.rename_device("new_phone", Some("Pixel"))?;
+        assert!(matches!(bob.rename_device("new_phone", Some("mine")), Err(AccessError::Denied(_))));
+        assert!(matches!(alice.merge_devices("bob_phone", "new_phone"), Err(AccessError::Denied(_))));
+
+        assert_eq!(alice.merge_devices("old_phone", "new_phone")?, 1);
+
+        let start = Utc::now() - chrono::Duration::hours(1);
+        let moved = alice.get_accelerometer_data("new_phone", start, Utc::now())?;
+        assert_eq!(moved.len(), 2);
+        assert!(moved.iter().any(|r| r.z == 3.0) && !moved.iter().any(|r| r.z == 2.0));
+        assert!(alice.get_accelerometer_data("old_phone", start, Utc::now())?.is_empty());
+
+        let active = alice.get_devices(false)?;
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].name.as_deref(), Some("Pixel"));
+        assert!(alice.get_device("old_phone")?.retired_at.is_some());
+
+        Ok(())
+    }
+}
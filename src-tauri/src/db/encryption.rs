@@ -0,0 +1,422 @@
+use std::collections::{HashMap, HashSet};
+use chrono::{DateTime, Utc};
+use duckdb::{Connection, OptionalExt, Result};
+use crate::crypto::{sealed_key_id, CryptoError, FieldKey, WrappedFieldKey, FIELD_PREFIX};
+use super::{deletion::ID_CHUNK, Database};
+
+/// Columns `Database` knows how to encrypt and decrypt on the way in and
+/// out. Which of them are actually encrypted is set in `encrypted_columns`.
+pub const ENCRYPTABLE_COLUMNS: &[(&str, &str)] = &[
+    ("notes", "content"),
+    ("call_log_data", "phone_number"),
+    ("call_log_data", "contact_name"),
+    ("notification_data", "title"),
+    ("todos_data", "title"),
+    ("todos_data", "description"),
+    ("oauth_accounts", "access_token"),
+    ("oauth_accounts", "refresh_token"),
+];
+
+/// Field keys and column settings, loaded when the database is opened.
+/// Keys start out locked: values can be sealed with them but not opened.
+/// Unlocked keys live here, on the shared `Database`, until `lock` or exit.
+#[derive(Default)]
+pub(super) struct FieldCipher {
+    columns: HashSet<(String, String)>,
+    keys: HashMap<String, FieldKey>,
+    active: Option<String>,
+}
+
+impl FieldCipher {
+    /// Reads the settings and keys from the database. Keys found in
+    /// `unlocked` keep their private half.
+    pub(super) fn load(conn: &Connection, unlocked: &HashMap<String, FieldKey>) -> Result<Self> {
+        let mut cipher = Self::default();
+
+        let mut stmt = conn.prepare("SELECT table_name, column_name FROM encrypted_columns")?;
+        for column in stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))? {
+            cipher.columns.insert(column?);
+        }
+
+        for (wrapped, retired) in wrapped_keys(conn)? {
+            let key = match unlocked.get(&wrapped.key_id) {
+                Some(key) => key.clone(),
+                None => FieldKey::locked(&wrapped).map_err(crypto_error)?,
+            };
+            if !retired {
+                cipher.active = Some(key.key_id.clone());
+            }
+            cipher.keys.insert(key.key_id.clone(), key);
+        }
+        Ok(cipher)
+    }
+
+    fn unlocked_keys(&self) -> HashMap<String, FieldKey> {
+        self.keys
+            .iter()
+            .filter(|(_, key)| key.is_unlocked())
+            .map(|(key_id, key)| (key_id.clone(), key.clone()))
+            .collect()
+    }
+
+    fn seal(&self, table: &str, column: &str, value: &str) -> Option<String> {
+        if value.is_empty() || !self.columns.contains(&(table.to_string(), column.to_string())) {
+            return None;
+        }
+        let key = self.keys.get(self.active.as_ref()?)?;
+        Some(key.seal(value))
+    }
+
+    fn open(&self, value: &str) -> std::result::Result<String, CryptoError> {
+        let key_id = sealed_key_id(value).ok_or(CryptoError::Decrypt)?;
+        self.keys.get(key_id).ok_or(CryptoError::Decrypt)?.open(value)
+    }
+}
+
+/// Crypto failures surface as `ToSqlConversionFailure` wrapping the
+/// `CryptoError`, so callers can tell a locked database apart.
+fn crypto_error(e: CryptoError) -> duckdb::Error {
+    duckdb::Error::ToSqlConversionFailure(Box::new(e))
+}
+
+/// Every stored key, oldest first, with whether it has been retired.
+fn wrapped_keys(conn: &Connection) -> Result<Vec<(WrappedFieldKey, bool)>> {
+    let mut stmt = conn.prepare(
+        "SELECT key_id, public_key, wrapped_secret, salt, retired_at IS NOT NULL
+         FROM field_keys ORDER BY created_at"
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            WrappedFieldKey {
+                key_id: row.get(0)?,
+                public_key: row.get(1)?,
+                wrapped_secret: row.get(2)?,
+                salt: row.get(3)?,
+            },
+            row.get(4)?,
+        ))
+    })?;
+    rows.collect()
+}
+
+impl Database {
+    /// Encrypts `value` if `table.column` is configured for encryption and a
+    /// field key exists; otherwise returns it unchanged. Works while locked.
+    pub(crate) fn seal_field(&self, table: &str, column: &str, value: &str) -> String {
+        let fields = self.fields.read().unwrap();
+        fields.seal(table, column, value).unwrap_or_else(|| value.to_string())
+    }
+
+    /// Decrypts a value read from an encrypted column. Plaintext passes
+    /// through, so rows written before a column was encrypted still read.
+    /// Fails with `CryptoError::Locked` while locked.
+    pub(crate) fn open_field(&self, value: String) -> Result<String> {
+        if !value.starts_with(FIELD_PREFIX) {
+            return Ok(value);
+        }
+        self.fields.read().unwrap().open(&value).map_err(crypto_error)
+    }
+
+    pub(crate) fn open_optional_field(&self, value: Option<String>) -> Result<Option<String>> {
+        value.map(|v| self.open_field(v)).transpose()
+    }
+
+    pub(crate) fn is_field_encryption_enabled(&self) -> bool {
+        self.fields.read().unwrap().active.is_some()
+    }
+
+    /// True while encrypted fields can be written but not read.
+    pub(crate) fn is_locked(&self) -> bool {
+        let fields = self.fields.read().unwrap();
+        fields.keys.values().any(|key| !key.is_unlocked())
+    }
+
+    pub(crate) fn get_encrypted_columns(&self) -> Vec<(String, String)> {
+        let mut columns: Vec<_> = self.fields.read().unwrap().columns.iter().cloned().collect();
+        columns.sort();
+        columns
+    }
+
+    /// When the active field key was created, if encryption is enabled.
+    pub(crate) fn get_field_key_created_at(&self) -> Result<Option<DateTime<Utc>>> {
+        self.conn
+            .query_row("SELECT created_at FROM field_keys WHERE retired_at IS NULL", [], |row| {
+                row.get::<_,String>(0)
+            })
+            .optional()
+            .map(|created| created.map(|s| s.parse::<DateTime<Utc>>().unwrap()))
+    }
+
+    /// Creates the first field key, wrapped under `passphrase`, and encrypts
+    /// whatever is already stored in the configured columns. Leaves the
+    /// database unlocked. Returns the number of values encrypted.
+    pub(crate) fn enable_field_encryption(&self, passphrase: &str) -> Result<usize> {
+        if self.is_field_encryption_enabled() {
+            return Err(duckdb::Error::ToSqlConversionFailure(
+                "field encryption is already enabled".into(),
+            ));
+        }
+        self.install_field_key(passphrase)
+    }
+
+    /// Replaces the active field key with a new one and re-encrypts every
+    /// stored value under it. Old keys are retired rather than deleted so
+    /// backups taken before the rotation can still be read. Returns the
+    /// number of values re-encrypted.
+    pub(crate) fn rotate_field_key(&self, passphrase: &str) -> Result<usize> {
+        if !self.is_field_encryption_enabled() {
+            return Err(duckdb::Error::ToSqlConversionFailure(
+                "field encryption is not enabled".into(),
+            ));
+        }
+        self.unlock(passphrase)?;
+        self.install_field_key(passphrase)
+    }
+
+    /// Unwraps every field key. Fails, leaving the database locked, if the
+    /// passphrase is wrong.
+    pub(crate) fn unlock(&self, passphrase: &str) -> Result<()> {
+        let keys = wrapped_keys(&self.conn)?
+            .iter()
+            .map(|(wrapped, _)| FieldKey::unlock(wrapped, passphrase))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(crypto_error)?;
+
+        let mut unlocked = self.fields.read().unwrap().unlocked_keys();
+        unlocked.extend(keys.into_iter().map(|key| (key.key_id.clone(), key)));
+        self.load_fields(&unlocked)
+    }
+
+    /// Forgets the private keys. New rows are still encrypted.
+    pub(crate) fn lock(&self) -> Result<()> {
+        self.load_fields(&HashMap::new())
+    }
+
+    /// Re-wraps every field key under `new_passphrase`. The encrypted values
+    /// themselves are untouched.
+    pub(crate) fn change_passphrase(&self, passphrase: &str, new_passphrase: &str) -> Result<()> {
+        self.unlock(passphrase)?;
+        let wrapped = self
+            .fields
+            .read()
+            .unwrap()
+            .keys
+            .values()
+            .map(|key| key.wrap(new_passphrase))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(crypto_error)?;
+
+        self.in_transaction(|db| {
+            for key in &wrapped {
+                db.conn.execute(
+                    "UPDATE field_keys SET wrapped_secret = ?, salt = ? WHERE key_id = ?",
+                    [&key.wrapped_secret, &key.salt, &key.key_id],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Turns encryption of `table.column` on or off and rewrites the values
+    /// already stored to match. Needs the database unlocked if any values
+    /// are already encrypted.
+    pub(crate) fn set_column_encrypted(&self, table: &str, column: &str, encrypted: bool) -> Result<usize> {
+        if !ENCRYPTABLE_COLUMNS.contains(&(table, column)) {
+            return Err(duckdb::Error::ToSqlConversionFailure(
+                format!("{}.{} cannot be encrypted", table, column).into(),
+            ));
+        }
+
+        let rewritten = self.in_transaction(|db| {
+            let sql = if encrypted {
+                "INSERT OR IGNORE INTO encrypted_columns (table_name, column_name) VALUES (?, ?)"
+            } else {
+                "DELETE FROM encrypted_columns WHERE table_name = ? AND column_name = ?"
+            };
+            db.conn.execute(sql, [table, column])?;
+            db.reload_fields()?;
+            db.reseal_column(table, column)
+        });
+        if rewritten.is_err() {
+            self.reload_fields()?;
+        }
+        self.drop_stale_search_text(rewritten)
+    }
+
+    /// Rereads the field settings and keys, keeping the keys that are
+    /// unlocked.
+    pub(super) fn reload_fields(&self) -> Result<()> {
+        let unlocked = self.fields.read().unwrap().unlocked_keys();
+        self.load_fields(&unlocked)
+    }
+
+    fn load_fields(&self, unlocked: &HashMap<String, FieldKey>) -> Result<()> {
+        *self.fields.write().unwrap() = FieldCipher::load(&self.conn, unlocked)?;
+        Ok(())
+    }
+
+    /// Generates a key, makes it the active one and brings every configured
+    /// column in line with it.
+    fn install_field_key(&self, passphrase: &str) -> Result<usize> {
+        let key = FieldKey::generate();
+        let wrapped = key.wrap(passphrase).map_err(crypto_error)?;
+        let mut unlocked = self.fields.read().unwrap().unlocked_keys();
+        unlocked.insert(key.key_id.clone(), key);
+
+        let resealed = self.in_transaction(|db| {
+            db.conn.execute(
+                "UPDATE field_keys SET retired_at = ? WHERE retired_at IS NULL",
+                [Utc::now().to_string()],
+            )?;
+            db.conn.execute(
+                "INSERT INTO field_keys (key_id, public_key, wrapped_secret, salt, created_at) VALUES (?, ?, ?, ?, ?)",
+                [
+                    &wrapped.key_id,
+                    &wrapped.public_key,
+                    &wrapped.wrapped_secret,
+                    &wrapped.salt,
+                    &Utc::now().to_string(),
+                ],
+            )?;
+            db.load_fields(&unlocked)?;
+
+            let mut total = 0;
+            for (table, column) in ENCRYPTABLE_COLUMNS {
+                total += db.reseal_column(table, column)?;
+            }
+            Ok(total)
+        });
+        if resealed.is_err() {
+            // The key's row was rolled back, so reloading drops it
+            self.reload_fields()?;
+        }
+        self.drop_stale_search_text(resealed)
+    }
+
+    /// Rebuilds the search index after values were newly encrypted, so the
+    /// copies it holds of their plain text don't outlive them.
+    fn drop_stale_search_text(&self, changed: Result<usize>) -> Result<usize> {
+        let changed = changed?;
+        if changed > 0 {
+            self.rebuild_search_index()?;
+        }
+        Ok(changed)
+    }
+
+    /// Brings every stored value of `table.column` in line with the current
+    /// settings: sealed under the active key if the column is encrypted,
+    /// plaintext otherwise. Returns the number of values changed.
+    fn reseal_column(&self, table: &str, column: &str) -> Result<usize> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT rowid, {column} FROM {table} WHERE {column} IS NOT NULL AND {column} <> ''"
+        ))?;
+        let values = stmt
+            .query_map([], |row| Ok((row.get::<_,i64>(0)?, row.get::<_,String>(1)?)))?
+            .collect::<Result<Vec<_>>>()?;
+
+        let fields = self.fields.read().unwrap();
+        let encrypted = fields.columns.contains(&(table.to_string(), column.to_string()));
+        let active = fields.active.clone();
+        drop(fields);
+
+        let stale: Vec<(i64, String)> = values
+            .into_iter()
+            .filter(|(_, value)| match sealed_key_id(value) {
+                Some(key_id) => !(encrypted && Some(key_id) == active.as_deref()),
+                None => encrypted && active.is_some(),
+            })
+            .collect();
+        if stale.is_empty() {
+            return Ok(0);
+        }
+
+        let mut stmt = self.conn.prepare("SELECT device_id FROM devices")?;
+        let devices = stmt.query_map([], |row| row.get(0))?.collect::<Result<Vec<String>>>()?;
+        let mut changed = 0;
+        for chunk in stale.chunks(ID_CHUNK) {
+            let list = chunk.iter().map(|(rowid, _)| rowid.to_string()).collect::<Vec<_>>().join(", ");
+            changed += self.audited(table, &devices, &format!("rowid IN ({})", list), &[], |db| {
+                for (rowid, value) in chunk {
+                    let plaintext = db.open_field(value.clone())?;
+                    db.conn.execute(
+                        &format!("UPDATE {table} SET {column} = ? WHERE rowid = ?"),
+                        duckdb::params![db.seal_field(table, column, &plaintext), rowid],
+                    )?;
+                }
+                Ok(chunk.len())
+            })?;
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use crate::datatypes::{note::Note, types::NotePriority, user::User};
+
+    fn note(id: &str, content: &str) -> Note {
+        Note {
+            id: id.to_string(),
+            user_id: "test_user".to_string(),
+            timestamp: Utc::now(),
+            content: content.to_string(),
+            priority: NotePriority::Medium,
+            parent_id: None,
+            tags: None,
+            embedding: None,
+            metadata: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn stored_content(db: &Database, id: &str) -> String {
+        db.conn.query_row("SELECT content FROM notes WHERE id = ?", [id], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn test_locked_database_still_ingests() -> Result<()> {
+        let dir = tempdir().unwrap();
+        let db = Database::new(&dir.path().join("test.db"))?;
+        db.insert_user(&User {
+            id: "test_user".to_string(),
+            email: "test@example.com".to_string(),
+            name: None,
+            encrypted_password: "x".to_string(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+        db.insert_note(&note("n1", "Gate code is 4412"))?;
+
+        assert_eq!(db.enable_field_encryption("correct horse")?, 1);
+        assert!(stored_content(&db, "n1").starts_with(FIELD_PREFIX));
+        assert_eq!(db.get_note("n1")?.content, "Gate code is 4412");
+
+        db.lock()?;
+        assert!(db.is_locked());
+        db.insert_note(&note("n2", "Call the bank"))?;
+        assert!(stored_content(&db, "n2").starts_with(FIELD_PREFIX));
+        assert!(db.get_note("n2").is_err());
+
+        assert!(db.unlock("wrong").is_err());
+        db.unlock("correct horse")?;
+        assert_eq!(db.get_note("n2")?.content, "Call the bank");
+
+        let before = sealed_key_id(&stored_content(&db, "n1")).unwrap().to_string();
+        assert_eq!(db.rotate_field_key("correct horse")?, 2);
+        assert_ne!(sealed_key_id(&stored_content(&db, "n1")).unwrap(), before);
+
+        db.change_passphrase("correct horse", "battery staple")?;
+        db.lock()?;
+        assert!(db.unlock("correct horse").is_err());
+        db.unlock("battery staple")?;
+
+        assert_eq!(db.set_column_encrypted("notes", "content", false)?, 2);
+        assert_eq!(stored_content(&db, "n1"), "Gate code is 4412");
+        assert!(db.set_column_encrypted("notes", "id", true).is_err());
+
+        Ok(())
+    }
+}
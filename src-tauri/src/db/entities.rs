@@ -0,0 +1,280 @@
+use chrono::{DateTime, Utc};
+use duckdb::{OptionalExt, Result};
+use crate::datatypes::{note::{EntitySighting, KnownEntity}, types::EntityType};
+use crate::embedding::EMBEDDING_DIMENSIONS;
+use super::{vector::vector_literal, Database};
+
+pub(super) const ENTITY_COLUMNS: &str = "entity_id, user_id, type, label, metadata, created_at, updated_at";
+
+const SIGHTING_COLUMNS: &str = "sighting_id, entity_id, device_id, timestamp, source, distance, metadata";
+
+impl Database {
+    pub(crate) fn insert_known_entity(&self, entity: &KnownEntity) -> Result<()> {
+        let embedding = entity.embedding.as_deref().map(vector_literal).transpose()?;
+        self.conn.execute(
+            &format!(
+                "INSERT INTO known_entities (
+                    entity_id, user_id, type, label, embedding, metadata, created_at, updated_at
+                ) VALUES (?, ?, ?, ?, ?::FLOAT[{}], ?, ?, ?)",
+                EMBEDDING_DIMENSIONS
+            ),
+            duckdb::params![
+                &entity.entity_id,
+                &entity.user_id,
+                entity_type_to_sql(&entity.entity_type),
+                &entity.label,
+                &embedding,
+                &entity.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()),
+                &entity.created_at.to_string(),
+                &entity.updated_at.to_string(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn get_known_entity(&self, entity_id: &str) -> Result<KnownEntity> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM known_entities WHERE entity_id = ?", ENTITY_COLUMNS
+        ))?;
+        stmt.query_row([entity_id], entity_from_row)
+    }
+
+    pub(crate) fn get_known_entities(&self, user_id: &str, entity_type: Option<&EntityType>) -> Result<Vec<KnownEntity>> {
+        let mut sql = format!("SELECT {} FROM known_entities WHERE user_id = ?", ENTITY_COLUMNS);
+        let mut params = vec![user_id.to_string()];
+        if let Some(entity_type) = entity_type {
+            sql.push_str(" AND type = ?");
+            params.push(entity_type_to_sql(entity_type));
+        }
+        sql.push_str(" ORDER BY label");
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(duckdb::params_from_iter(params), entity_from_row)?;
+        rows.collect()
+    }
+
+    pub(crate) fn rename_known_entity(&self, entity_id: &str, label: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE known_entities SET label = ?, updated_at = ? WHERE entity_id = ?",
+            [label, &Utc::now().to_string(), entity_id],
+        )?;
+        Ok(())
+    }
+
+    /// Deletes an entity together with its sightings. Notes referring to it
+    /// keep their reference, which then resolves as missing.
+    pub(crate) fn delete_known_entity(&self, entity_id: &str) -> Result<()> {
+        self.in_transaction(|db| {
+            db.conn.execute("DELETE FROM entity_sightings WHERE entity_id = ?", [entity_id])?;
+            db.conn.execute("DELETE FROM known_entities WHERE entity_id = ?", [entity_id])?;
+            Ok(())
+        })
+    }
+
+    /// Recording a sighting id that already exists is a no-op, so frames can be
+    /// reprocessed safely.
+    pub(crate) fn insert_entity_sighting(&self, sighting: &EntitySighting) -> Result<()> {
+        let embedding = sighting.embedding.as_deref().map(vector_literal).transpose()?;
+        self.conn.execute(
+            &format!(
+                "INSERT OR IGNORE INTO entity_sightings (
+                    sighting_id, entity_id, device_id, timestamp, source, distance, embedding, metadata
+                ) VALUES (?, ?, ?, ?, ?, ?, ?::FLOAT[{}], ?)",
+                EMBEDDING_DIMENSIONS
+            ),
+            duckdb::params![
+                &sighting.sighting_id,
+                &sighting.entity_id,
+                &sighting.device_id,
+                &sighting.timestamp.to_string(),
+                &sighting.source,
+                &sighting.distance,
+                &embedding,
+                &sighting.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Sightings of an entity in `[start, end]`, newest first.
+    pub(crate) fn get_entity_sightings(&self, entity_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<EntitySighting>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM entity_sightings
+             WHERE entity_id = ? AND timestamp BETWEEN ? AND ?
+             ORDER BY timestamp DESC",
+            SIGHTING_COLUMNS
+        ))?;
+        let rows = stmt.query_map([entity_id, &start.to_string(), &end.to_string()], sighting_from_row)?;
+        rows.collect()
+    }
+
+    pub(crate) fn get_last_sighting(&self, entity_id: &str) -> Result<Option<EntitySighting>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM entity_sightings WHERE entity_id = ? ORDER BY timestamp DESC LIMIT 1",
+            SIGHTING_COLUMNS
+        ))?;
+        stmt.query_row([entity_id], sighting_from_row).optional()
+    }
+
+    /// The most recent sighting of any entity labelled `label` (case
+    /// insensitive), with the entity it belongs to. With `user_id`, only that
+    /// user's entities and sightings made by their devices count.
+    pub(crate) fn get_last_sighting_by_label(&self, label: &str, user_id: Option<&str>) -> Result<Option<(KnownEntity, EntitySighting)>> {
+        let mut sql = String::from(
+            "SELECT e.entity_id, e.user_id, e.type, e.label, e.metadata, e.created_at, e.updated_at,
+                    s.sighting_id, s.entity_id, s.device_id, s.timestamp, s.source, s.distance, s.metadata
+             FROM entity_sightings s
+             JOIN known_entities e ON e.entity_id = s.entity_id
+             JOIN devices d ON d.device_id = s.device_id
+             WHERE lower(e.label) = lower(?)"
+        );
+        let mut params = vec![label.to_string()];
+        if let Some(user_id) = user_id {
+            sql.push_str(" AND e.user_id = ? AND d.user_id = ?");
+            params.extend([user_id.to_string(), user_id.to_string()]);
+        }
+        sql.push_str(" ORDER BY s.timestamp DESC LIMIT 1");
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        stmt.query_row(duckdb::params_from_iter(params), |row| {
+            Ok((entity_from_row(row)?, sighting_from_row_at(row, 7)?))
+        })
+        .optional()
+    }
+
+    /// Stored detection embeddings of an entity's sightings, used to
+    /// recompute its centroid.
+    pub(crate) fn get_sighting_embeddings(&self, entity_id: &str) -> Result<Vec<Vec<f32>>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT CAST(embedding AS VARCHAR) FROM entity_sightings
+             WHERE entity_id = ? AND embedding IS NOT NULL"
+        )?;
+        let rows = stmt.query_map([entity_id], |row| {
+            Ok(serde_json::from_str(&row.get::<_, String>(0)?).unwrap())
+        })?;
+        rows.collect()
+    }
+
+    /// Moves those of the given sightings that belong to `from_id` over to
+    /// `to_id`. Returns how many moved.
+    pub(crate) fn reassign_sightings(&self, from_id: &str, sighting_ids: &[String], to_id: &str) -> Result<usize> {
+        if sighting_ids.is_empty() {
+            return Ok(0);
+        }
+        let placeholders = vec!["?"; sighting_ids.len()].join(", ");
+        let mut params = vec![to_id.to_string(), from_id.to_string()];
+        params.extend(sighting_ids.iter().cloned());
+
+        self.conn.execute(
+            &format!(
+                "UPDATE entity_sightings SET entity_id = ? WHERE entity_id = ? AND sighting_id IN ({})",
+                placeholders
+            ),
+            duckdb::params_from_iter(params),
+        )
+    }
+
+    /// Folds `merged_id` into `kept_id`: its sightings and note references
+    /// move over and the merged entity is deleted. The caller is expected to
+    /// recompute the kept entity's embedding afterwards.
+    pub(crate) fn merge_known_entities(&self, kept_id: &str, merged_id: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE entity_sightings SET entity_id = ? WHERE entity_id = ?",
+            [kept_id, merged_id],
+        )?;
+        // reference_id is part of the key, so references are copied over
+        // rather than updated, skipping notes that already point at both.
+        self.conn.execute(
+            "INSERT INTO note_references
+             SELECT * REPLACE (?::VARCHAR AS reference_id) FROM note_references o
+             WHERE o.reference_type = 'entity' AND o.reference_id = ?
+               AND NOT EXISTS (
+                   SELECT 1 FROM note_references n
+                   WHERE n.note_id = o.note_id AND n.reference_type = 'entity' AND n.reference_id = ?
+               )",
+            [kept_id, merged_id, kept_id],
+        )?;
+        self.conn.execute(
+            "DELETE FROM note_references WHERE reference_type = 'entity' AND reference_id = ?",
+            [merged_id],
+        )?;
+        self.conn.execute("DELETE FROM known_entities WHERE entity_id = ?", [merged_id])?;
+        Ok(())
+    }
+
+    /// Detection frames a device captured in `[start, end]` as (timestamp,
+    /// detections JSON), oldest first. Audio has no detection table, so it
+    /// always comes back empty.
+    pub(crate) fn get_detection_frames(
+        &self,
+        entity_type: &EntityType,
+        device_id: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<(DateTime<Utc>, serde_json::Value)>> {
+        let Some((table, column)) = detection_source(entity_type) else {
+            return Ok(Vec::new());
+        };
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT timestamp, {} FROM {}
+             WHERE device_id = ? AND timestamp BETWEEN ? AND ? AND {} IS NOT NULL
+             ORDER BY timestamp",
+            column, table, column
+        ))?;
+        let rows = stmt.query_map([device_id, &start.to_string(), &end.to_string()], |row| {
+            Ok((
+                row.get::<_,String>(0)?.parse::<DateTime<Utc>>().unwrap(),
+                serde_json::from_str(&row.get::<_,String>(1)?).unwrap(),
+            ))
+        })?;
+        rows.collect()
+    }
+}
+
+/// The sensor table and JSON column holding detections of this kind.
+pub fn detection_source(entity_type: &EntityType) -> Option<(&'static str, &'static str)> {
+    match entity_type {
+        EntityType::Face => Some(("face_recognition_data", "faces")),
+        EntityType::Object => Some(("object_detection_data", "objects")),
+        EntityType::Pose => Some(("pose_detection_data", "poses")),
+        EntityType::Audio => None,
+    }
+}
+
+pub(super) fn entity_type_to_sql(entity_type: &EntityType) -> String {
+    serde_json::to_value(entity_type).unwrap().as_str().unwrap().to_string()
+}
+
+/// Reads a row selected with `ENTITY_COLUMNS`. Embeddings are left out; they
+/// are only ever compared inside DuckDB.
+pub(super) fn entity_from_row(row: &duckdb::Row<'_>) -> Result<KnownEntity> {
+    Ok(KnownEntity {
+        entity_id: row.get(0)?,
+        user_id: row.get(1)?,
+        entity_type: serde_json::from_value(serde_json::Value::String(row.get(2)?)).unwrap(),
+        label: row.get(3)?,
+        embedding: None,
+        metadata: row.get::<_,Option<String>>(4)?.map(|s| serde_json::from_str(&s).unwrap()),
+        created_at: row.get::<_,String>(5)?.parse::<DateTime<Utc>>().unwrap(),
+        updated_at: row.get::<_,String>(6)?.parse::<DateTime<Utc>>().unwrap(),
+    })
+}
+
+fn sighting_from_row(row: &duckdb::Row<'_>) -> Result<EntitySighting> {
+    sighting_from_row_at(row, 0)
+}
+
+/// Reads `SIGHTING_COLUMNS` starting at column `offset`.
+fn sighting_from_row_at(row: &duckdb::Row<'_>, offset: usize) -> Result<EntitySighting> {
+    Ok(EntitySighting {
+        sighting_id: row.get(offset)?,
+        entity_id: row.get(offset + 1)?,
+        device_id: row.get(offset + 2)?,
+        timestamp: row.get::<_,String>(offset + 3)?.parse::<DateTime<Utc>>().unwrap(),
+        source: row.get(offset + 4)?,
+        distance: row.get(offset + 5)?,
+        embedding: None,
+        metadata: row.get::<_,Option<String>>(offset + 6)?.map(|s| serde_json::from_str(&s).unwrap()),
+    })
+}
@@ -0,0 +1,102 @@
+//! Records and databases shared by the database tests.
+
+use chrono::{DateTime, Utc};
+use duckdb::Result;
+use tempfile::{tempdir, TempDir};
+use crate::datatypes::{
+    device::{Device, DeviceCapabilities, ScreenDetails},
+    note::Note,
+    types::{DeviceType, EntityType, NotePriority},
+    user::User,
+};
+use super::{detection_source, Database};
+
+/// A new, empty database. Keep the directory alive as long as the database.
+pub(crate) fn temp_database() -> Result<(TempDir, Database)> {
+    let dir = tempdir().unwrap();
+    let db = Database::new(&dir.path().join("test.db"))?;
+    Ok((dir, db))
+}
+
+pub(crate) fn user(id: &str) -> User {
+    User {
+        id: id.to_string(),
+        email: format!("{}@example.com", id),
+        name: None,
+        encrypted_password: "x".to_string(),
+        created_at: Utc::now(),
+        updated_at: Utc::now(),
+    }
+}
+
+/// A phone with only an accelerometer.
+pub(crate) fn device(device_id: &str, user_id: &str) -> Device {
+    Device {
+        device_id: device_id.to_string(),
+        user_id: user_id.to_string(),
+        device_type: DeviceType::Smartphone,
+        os_type: "Android".to_string(),
+        os_version: "14".to_string(),
+        app_version: "1.0".to_string(),
+        available_sensors: vec![],
+        capabilities: DeviceCapabilities {
+            has_camera: false,
+            has_microphone: false,
+            has_gps: false,
+            has_accelerometer: true,
+            has_gyroscope: false,
+            has_magnetometer: false,
+            has_proximity: false,
+            has_light: false,
+            has_pressure: false,
+            has_temperature: false,
+            has_humidity: false,
+            has_step_counter: false,
+            has_heart_rate: false,
+            has_ecg: false,
+            has_blood_oxygen: false,
+            has_stress: false,
+            has_compass: false,
+            screen_details: ScreenDetails { width: 0, height: 0, density: 1.0, refresh_rate: 60 },
+        },
+        name: None,
+        created_at: Utc::now(),
+        last_seen: Utc::now(),
+        updated_at: Utc::now(),
+        retired_at: None,
+    }
+}
+
+/// A top-level note written now.
+pub(crate) fn note(id: &str, user_id: &str, content: &str) -> Note {
+    Note {
+        id: id.to_string(),
+        user_id: user_id.to_string(),
+        timestamp: Utc::now(),
+        content: content.to_string(),
+        priority: NotePriority::Medium,
+        parent_id: None,
+        tags: None,
+        embedding: None,
+        metadata: None,
+        created_at: Utc::now(),
+        updated_at: Utc::now(),
+    }
+}
+
+/// Stores one frame of face, object or pose detections, as the capture
+/// pipeline would. There's no typed insert for these tables.
+pub(crate) fn insert_detections(
+    db: &Database,
+    entity_type: &EntityType,
+    device_id: &str,
+    timestamp: DateTime<Utc>,
+    detections: &serde_json::Value,
+) -> Result<()> {
+    let (table, column) = detection_source(entity_type).expect("audio has no detection table");
+    db.conn.execute(
+        &format!("INSERT INTO {} (timestamp, device_id, {}) VALUES (?, ?, ?)", table, column),
+        [&timestamp.to_string(), device_id, &detections.to_string()],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,545 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use duckdb::Result;
+use serde::{Deserialize, Serialize};
+use crate::datatypes::note::NoteTarget;
+use super::{vector::{DistanceMetric, VectorQuery}, Database};
+
+const SNIPPET_CHARS: usize = 160;
+
+/// A digest of everything the search index is built from. Any insert, edit,
+/// deletion or re-encryption in the source tables changes it.
+const SEARCH_SOURCES_SQL: &str = "SELECT concat_ws('|',
+    (SELECT count(*) || ':' || coalesce(bit_xor(hash(id, content)), 0) FROM notes),
+    (SELECT count(*) || ':' || coalesce(bit_xor(hash(device_id, timestamp, title)), 0) FROM notification_data),
+    (SELECT count(*) || ':' || coalesce(bit_xor(hash(device_id, todo_id, timestamp, title, description)), 0) FROM todos_data),
+    (SELECT count(*) || ':' || coalesce(bit_xor(hash(device_id, timestamp, contact_name)), 0) FROM call_log_data)
+)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchSource {
+    Note,
+    Notification,
+    Todo,
+    CallLog,
+}
+
+impl SearchSource {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SearchSource::Note => "note",
+            SearchSource::Notification => "notification",
+            SearchSource::Todo => "todo",
+            SearchSource::CallLog => "call_log",
+        }
+    }
+
+    fn from_str(source: &str) -> Option<Self> {
+        match source {
+            "note" => Some(SearchSource::Note),
+            "notification" => Some(SearchSource::Notification),
+            "todo" => Some(SearchSource::Todo),
+            "call_log" => Some(SearchSource::CallLog),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KeywordQuery {
+    pub text: String,
+    pub limit: usize,
+    pub user_id: Option<String>,
+    /// Restrict to these sources; empty searches all of them.
+    pub sources: Vec<SearchSource>,
+}
+
+impl KeywordQuery {
+    pub fn new(text: &str, limit: usize) -> Self {
+        Self {
+            text: text.to_string(),
+            limit,
+            user_id: None,
+            sources: Vec::new(),
+        }
+    }
+}
+
+/// A short excerpt around the first match. `highlights` are byte ranges into
+/// `text` covering each matched word.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Snippet {
+    pub text: String,
+    pub highlights: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    pub source: SearchSource,
+    /// Where the hit lives, in the same form notes use to reference it.
+    pub target: NoteTarget,
+    pub user_id: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    pub title: Option<String>,
+    pub snippet: Snippet,
+    /// BM25 for keyword searches, the blended score for hybrid ones.
+    pub score: f64,
+}
+
+/// What `rebuild_search_index` put in the index.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SearchIndexReport {
+    pub documents: usize,
+    /// Encrypted values left out. The index is stored, backed up and
+    /// exported like any table, so it only ever holds plain-text columns.
+    pub encrypted: usize,
+}
+
+impl Database {
+    /// Repopulates `search_documents` from the source tables and rebuilds the
+    /// FTS index. The index is a snapshot; searches rebuild it when the
+    /// sources have changed since. Encrypted values stay out of it whether
+    /// or not the database is unlocked, and are counted in the report.
+    pub(crate) fn rebuild_search_index(&self) -> Result<SearchIndexReport> {
+        let (sources, encrypted) = self.in_transaction(|db| {
+            let sources: String = db.conn.query_row(SEARCH_SOURCES_SQL, [], |row| row.get(0))?;
+            db.conn.execute_batch(
+                "DELETE FROM search_documents;
+
+                INSERT INTO search_documents
+                SELECT 'note:' || id, 'note', id, user_id, NULL, timestamp, NULL, content
+                FROM notes;
+
+                INSERT INTO search_documents
+                SELECT 'notification:' || n.device_id || '@' || CAST(n.timestamp AS VARCHAR),
+                       'notification', NULL, d.user_id, n.device_id, n.timestamp, n.title, NULL
+                FROM notification_data n LEFT JOIN devices d ON d.device_id = n.device_id
+                WHERE n.title IS NOT NULL AND n.title <> '';
+
+                INSERT INTO search_documents
+                SELECT 'todo:' || t.device_id || ':' || t.todo_id,
+                       'todo', t.todo_id, d.user_id, t.device_id, t.timestamp, t.title, t.description
+                FROM todos_data t LEFT JOIN devices d ON d.device_id = t.device_id
+                QUALIFY row_number() OVER (PARTITION BY t.device_id, t.todo_id ORDER BY t.timestamp DESC) = 1;
+
+                INSERT INTO search_documents
+                SELECT 'call_log:' || c.device_id || '@' || CAST(c.timestamp AS VARCHAR),
+                       'call_log', NULL, d.user_id, c.device_id, c.timestamp, c.contact_name, NULL
+                FROM call_log_data c LEFT JOIN devices d ON d.device_id = c.device_id
+                WHERE c.contact_name IS NOT NULL AND c.contact_name <> '';"
+            )?;
+            Ok((sources, db.drop_sealed_search_text()?))
+        })?;
+
+        // The FTS index is built from the committed table
+        self.conn.execute_batch("PRAGMA create_fts_index('search_documents', 'doc_id', 'title', 'body', overwrite=1)")?;
+
+        let documents = self.conn.query_row("SELECT count(*) FROM search_documents", [], |row| {
+            Ok(row.get::<_, i64>(0)? as usize)
+        })?;
+        *self.search_sources.lock().unwrap() = Some(sources);
+        Ok(SearchIndexReport { documents, encrypted })
+    }
+
+    /// Rebuilds the index unless it was built from the sources as they are
+    /// now. The first search after opening the database always rebuilds.
+    fn refresh_search_index(&self) -> Result<()> {
+        let sources: String = self.conn.query_row(SEARCH_SOURCES_SQL, [], |row| row.get(0))?;
+        if self.search_sources.lock().unwrap().as_deref() != Some(sources.as_str()) {
+            self.rebuild_search_index()?;
+        }
+        Ok(())
+    }
+
+    /// Clears the sealed titles and bodies copied into `search_documents`
+    /// before the transaction commits, then drops documents left with no
+    /// text. Returns how many values were cleared.
+    fn drop_sealed_search_text(&self) -> Result<usize> {
+        let encrypted: i64 = self.conn.query_row(
+            "SELECT count(*) FILTER (WHERE title LIKE 'enc:v1:%') + count(*) FILTER (WHERE body LIKE 'enc:v1:%')
+             FROM search_documents",
+            [],
+            |row| row.get(0),
+        )?;
+        self.conn.execute_batch(
+            "UPDATE search_documents
+             SET title = CASE WHEN title LIKE 'enc:v1:%' THEN NULL ELSE title END,
+                 body = CASE WHEN body LIKE 'enc:v1:%' THEN NULL ELSE body END
+             WHERE title LIKE 'enc:v1:%' OR body LIKE 'enc:v1:%';
+             DELETE FROM search_documents WHERE title IS NULL AND body IS NULL;"
+        )?;
+        Ok(encrypted as usize)
+    }
+
+    /// BM25-ranked keyword search across notes, notifications, todos and calls.
+    pub(crate) fn search_keyword(&self, query: &KeywordQuery) -> Result<Vec<SearchHit>> {
+        self.refresh_search_index()?;
+        let mut sql = String::from(
+            "SELECT doc_id, source, source_id, user_id, device_id, timestamp, title, body, score FROM (
+                SELECT *, fts_main_search_documents.match_bm25(doc_id, ?) AS score
+                FROM search_documents
+            ) WHERE score IS NOT NULL"
+        );
+        let mut params = vec![query.text.clone()];
+
+        if let Some(user_id) = &query.user_id {
+            sql.push_str(" AND user_id = ?");
+            params.push(user_id.clone());
+        }
+        if !query.sources.is_empty() {
+            let placeholders = vec!["?"; query.sources.len()].join(", ");
+            sql.push_str(&format!(" AND source IN ({})", placeholders));
+            params.extend(query.sources.iter().map(|s| s.as_str().to_string()));
+        }
+        sql.push_str(&format!(" ORDER BY score DESC LIMIT {}", query.limit));
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(duckdb::params_from_iter(params), |row| {
+            let source: String = row.get(1)?;
+            let source_id: Option<String> = row.get(2)?;
+            let device_id: Option<String> = row.get(4)?;
+            let timestamp = row.get::<_,String>(5)?.parse::<DateTime<Utc>>().unwrap();
+            let title: Option<String> = row.get(6)?;
+            let body: Option<String> = row.get(7)?;
+            let source = SearchSource::from_str(&source).unwrap();
+
+            Ok(SearchHit {
+                source,
+                target: hit_target(source, source_id, device_id, timestamp),
+                user_id: row.get(3)?,
+                timestamp,
+                snippet: snippet_for(title.as_deref(), body.as_deref(), &query.text),
+                title,
+                score: row.get(8)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Blends BM25 with embedding similarity from note_embeddings:
+    /// `alpha * bm25 / max_bm25 + (1 - alpha) * cosine_similarity`. Hits with
+    /// no embedding (everything that isn't a note) only get the keyword part,
+    /// and notes can surface on similarity alone.
+    pub(crate) fn search_hybrid(&self, query: &KeywordQuery, vector: &[f32], alpha: f64) -> Result<Vec<SearchHit>> {
+        let pool = query.limit * 4;
+        let mut keyword_query = query.clone();
+        keyword_query.limit = pool;
+        let keyword_hits = self.search_keyword(&keyword_query)?;
+
+        let mut vector_query = VectorQuery::new(vector.to_vec(), pool);
+        vector_query.metric = DistanceMetric::Cosine;
+        vector_query.user_id = query.user_id.clone();
+        let semantic = if query.sources.is_empty() || query.sources.contains(&SearchSource::Note) {
+            self.search_notes_by_vector(&vector_query)?
+        } else {
+            Vec::new()
+        };
+
+        let max_bm25 = keyword_hits.iter().map(|h| h.score).fold(0.0, f64::max);
+        let mut hits: HashMap<String, SearchHit> = HashMap::new();
+
+        for mut hit in keyword_hits {
+            let key = target_key(&hit.target);
+            hit.score = if max_bm25 > 0.0 { alpha * hit.score / max_bm25 } else { 0.0 };
+            hits.insert(key, hit);
+        }
+
+        for found in semantic {
+            let similarity = (1.0 - found.distance as f64).clamp(0.0, 1.0);
+            let target = NoteTarget::Note { note_id: found.note.id.clone() };
+            let entry = hits.entry(target_key(&target)).or_insert_with(|| SearchHit {
+                source: SearchSource::Note,
+                target,
+                user_id: Some(found.note.user_id.clone()),
+                timestamp: found.note.timestamp,
+                title: None,
+                snippet: highlight_snippet(&found.note.content, &query.text, SNIPPET_CHARS),
+                score: 0.0,
+            });
+            entry.score += (1.0 - alpha) * similarity;
+        }
+
+        let mut hits: Vec<SearchHit> = hits.into_values().collect();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(query.limit);
+        Ok(hits)
+    }
+}
+
+fn target_key(target: &NoteTarget) -> String {
+    format!("{}:{}", target.reference_type(), target.reference_id())
+}
+
+fn hit_target(source: SearchSource, source_id: Option<String>, device_id: Option<String>, timestamp: DateTime<Utc>) -> NoteTarget {
+    let table = match source {
+        SearchSource::Note => return NoteTarget::Note { note_id: source_id.unwrap_or_default() },
+        SearchSource::Notification => "notification_data",
+        SearchSource::Todo => "todos_data",
+        SearchSource::CallLog => "call_log_data",
+    };
+    NoteTarget::SensorEvent {
+        table: table.to_string(),
+        device_id: device_id.unwrap_or_default(),
+        timestamp,
+    }
+}
+
+fn snippet_for(title: Option<&str>, body: Option<&str>, query: &str) -> Snippet {
+    let body_snippet = body.map(|b| highlight_snippet(b, query, SNIPPET_CHARS));
+    match body_snippet {
+        Some(snippet) if !snippet.highlights.is_empty() => snippet,
+        _ => match title {
+            Some(title) => highlight_snippet(title, query, SNIPPET_CHARS),
+            None => body_snippet.unwrap_or(Snippet { text: String::new(), highlights: Vec::new() }),
+        },
+    }
+}
+
+fn words(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                spans.push((s, i));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        spans.push((s, text.len()));
+    }
+    spans
+}
+
+/// Cuts a window of at most `max_chars` characters around the first word
+/// matching a query term and records where each match falls. Words match when
+/// they start with a term, which roughly follows the FTS stemmer ("dentists"
+/// for "dentist").
+pub fn highlight_snippet(text: &str, query: &str, max_chars: usize) -> Snippet {
+    let terms: Vec<String> = words(query)
+        .into_iter()
+        .map(|(s, e)| query[s..e].to_lowercase())
+        .collect();
+    let matches: Vec<(usize, usize)> = words(text)
+        .into_iter()
+        .filter(|&(s, e)| {
+            let word = text[s..e].to_lowercase();
+            terms.iter().any(|term| word.starts_with(term.as_str()))
+        })
+        .collect();
+
+    let offsets: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+    let first_match = matches.first().map(|m| m.0).unwrap_or(0);
+    let first_char = offsets.partition_point(|&b| b < first_match);
+
+    let mut start_char = first_char.saturating_sub(max_chars / 4);
+    let end_char = (start_char + max_chars).min(offsets.len());
+    start_char = start_char.min(end_char.saturating_sub(max_chars));
+
+    let start = offsets.get(start_char).copied().unwrap_or(text.len());
+    let end = offsets.get(end_char).copied().unwrap_or(text.len());
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    let shift = snippet.len();
+    snippet.push_str(&text[start..end]);
+    if end < text.len() {
+        snippet.push('…');
+    }
+
+    let highlights = matches
+        .into_iter()
+        .filter(|&(s, e)| s >= start && e <= end)
+        .map(|(s, e)| (s - start + shift, e - start + shift))
+        .collect();
+
+    Snippet { text: snippet, highlights }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::fixtures::{note, temp_database, user};
+    use crate::embedding::{Embedder, HashEmbedder};
+
+    fn ids(hits: &[SearchHit]) -> Vec<String> {
+        hits.iter().map(|hit| hit.target.reference_id()).collect()
+    }
+
+    #[test]
+    fn test_keyword_search_ranks_by_bm25() -> Result<()> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_note(&note("once", "alice", "dentist then groceries then the gym then laundry"))?;
+        db.insert_note(&note("twice", "alice", "dentist appointment, dentist bill"))?;
+        db.insert_note(&note("never", "alice", "ran 5k along the river"))?;
+        assert_eq!(db.rebuild_search_index()?.documents, 3);
+
+        let hits = db.search_keyword(&KeywordQuery::new("dentist", 10))?;
+        assert_eq!(ids(&hits), ["twice", "once"]);
+        assert!(hits[0].score > hits[1].score);
+        assert!(hits.iter().all(|hit| hit.source == SearchSource::Note));
+
+        let mut todos_only = KeywordQuery::new("dentist", 10);
+        todos_only.sources = vec![SearchSource::Todo];
+        assert!(db.search_keyword(&todos_only)?.is_empty());
+
+        // Rebuilding replaces the documents rather than adding to them
+        assert_eq!(db.rebuild_search_index()?.documents, 3);
+        assert_eq!(db.search_keyword(&KeywordQuery::new("dentist", 10))?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_keeps_up_with_new_rows() -> Result<()> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        // Never built: the first search builds it
+        db.insert_note(&note("first", "alice", "dentist on tuesday"))?;
+        assert_eq!(ids(&db.search_keyword(&KeywordQuery::new("dentist", 10))?), ["first"]);
+
+        db.insert_note(&note("second", "alice", "dentist again, dentist bill"))?;
+        assert_eq!(ids(&db.search_keyword(&KeywordQuery::new("dentist", 10))?), ["second", "first"]);
+
+        db.update_note(&note("first", "alice", "groceries on tuesday"))?;
+        assert_eq!(ids(&db.search_keyword(&KeywordQuery::new("dentist", 10))?), ["second"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_is_filtered_by_user() -> Result<()> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_user(&user("bob"))?;
+        db.insert_note(&note("a1", "alice", "dentist on tuesday"))?;
+        db.insert_note(&note("b1", "bob", "dentist on friday"))?;
+        let embedder = HashEmbedder::default();
+        db.set_note_embedding("a1", &embedder.embed("dentist on tuesday").unwrap())?;
+        db.set_note_embedding("b1", &embedder.embed("dentist on friday").unwrap())?;
+        db.rebuild_search_index()?;
+
+        let mut query = KeywordQuery::new("dentist", 10);
+        assert_eq!(db.search_keyword(&query)?.len(), 2);
+
+        query.user_id = Some("alice".to_string());
+        let hits = db.search_keyword(&query)?;
+        assert_eq!(ids(&hits), ["a1"]);
+        assert_eq!(hits[0].user_id.as_deref(), Some("alice"));
+
+        let hits = db.search_hybrid(&query, &embedder.embed("dentist").unwrap(), 0.5)?;
+        assert_eq!(ids(&hits), ["a1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hybrid_search_blends_bm25_and_similarity() -> Result<()> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        let embedder = HashEmbedder::default();
+        for (id, content) in [
+            ("keyword", "dentist appointment, dentist bill"),
+            ("both", "dentist"),
+            ("similar", "tooth ache"),
+        ] {
+            db.insert_note(&note(id, "alice", content))?;
+        }
+        // "similar" shares no words with the query but is embedded as if it did
+        db.set_note_embedding("keyword", &embedder.embed("appointment bill").unwrap())?;
+        db.set_note_embedding("both", &embedder.embed("dentist").unwrap())?;
+        db.set_note_embedding("similar", &embedder.embed("dentist").unwrap())?;
+        db.rebuild_search_index()?;
+
+        let mut query = KeywordQuery::new("dentist", 10);
+        query.user_id = Some("alice".to_string());
+        let vector = embedder.embed("dentist").unwrap();
+
+        let bm25: HashMap<String, f64> = db.search_keyword(&query)?.into_iter().map(|h| (h.target.reference_id(), h.score)).collect();
+        let max_bm25 = bm25.values().copied().fold(0.0, f64::max);
+        let mut vector_query = VectorQuery::new(vector.clone(), 10);
+        vector_query.metric = DistanceMetric::Cosine;
+        let similarity: HashMap<String, f64> = db
+            .search_notes_by_vector(&vector_query)?
+            .into_iter()
+            .map(|m| (m.note.id, (1.0 - m.distance as f64).clamp(0.0, 1.0)))
+            .collect();
+
+        for alpha in [0.0, 0.3, 1.0] {
+            let hits = db.search_hybrid(&query, &vector, alpha)?;
+            assert_eq!(hits.len(), 3);
+            for hit in &hits {
+                let id = hit.target.reference_id();
+                let expected = alpha * bm25.get(&id).copied().unwrap_or(0.0) / max_bm25
+                    + (1.0 - alpha) * similarity.get(&id).copied().unwrap_or(0.0);
+                assert!((hit.score - expected).abs() < 1e-9, "{} at alpha {}", id, alpha);
+            }
+            assert!(hits.windows(2).all(|w| w[0].score >= w[1].score));
+        }
+
+        // Only keywords: the note without the word scores nothing
+        let hits = db.search_hybrid(&query, &vector, 1.0)?;
+        assert_eq!(hits.last().unwrap().target.reference_id(), "similar");
+        assert_eq!(hits.last().unwrap().score, 0.0);
+        // Only similarity: it ranks with the exact match
+        let hits = db.search_hybrid(&query, &vector, 0.0)?;
+        assert_eq!(hits.last().unwrap().target.reference_id(), "keyword");
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_values_are_left_out_of_the_index() -> Result<()> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_note(&note("n1", "alice", "gate code for the dentist"))?;
+        assert_eq!(db.rebuild_search_index()?.documents, 1);
+        db.enable_field_encryption("correct horse")?;
+        // Encrypting the note took its indexed copy with it
+        let indexed: i64 = db.conn.query_row("SELECT count(*) FROM search_documents", [], |row| row.get(0))?;
+        assert_eq!(indexed, 0);
+
+        // Unlocked or not, the note's text never reaches the index
+        assert_eq!(db.rebuild_search_index()?, SearchIndexReport { documents: 0, encrypted: 1 });
+        assert!(db.search_keyword(&KeywordQuery::new("dentist", 10))?.is_empty());
+        db.lock()?;
+        assert_eq!(db.rebuild_search_index()?, SearchIndexReport { documents: 0, encrypted: 1 });
+
+        let stored: i64 = db.conn.query_row(
+            "SELECT count(*) FROM search_documents WHERE body LIKE '%dentist%' OR body LIKE 'enc:v1:%'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(stored, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_highlight_snippet_marks_matches() {
+        let snippet = highlight_snippet("Call the dentist about Tuesday", "Dentist tuesday", 100);
+        assert_eq!(snippet.text, "Call the dentist about Tuesday");
+        let marked: Vec<&str> = snippet.highlights.iter().map(|&(s, e)| &snippet.text[s..e]).collect();
+        assert_eq!(marked, vec!["dentist", "Tuesday"]);
+    }
+
+    #[test]
+    fn test_highlight_snippet_windows_long_text() {
+        let text = format!("{} dentist {}", "lorem ipsum ".repeat(40), "dolor sit ".repeat(40));
+        let snippet = highlight_snippet(&text, "dentist", 60);
+
+        assert!(snippet.text.starts_with('…') && snippet.text.ends_with('…'));
+        assert_eq!(snippet.text.chars().count(), 62);
+        let (s, e) = snippet.highlights[0];
+        assert_eq!(&snippet.text[s..e], "dentist");
+    }
+
+    #[test]
+    fn test_highlight_snippet_handles_multibyte_text() {
+        let snippet = highlight_snippet("Zahnarzt für Jürgen — Dienstag", "jürgen", 12);
+        let (s, e) = snippet.highlights[0];
+        assert_eq!(&snippet.text[s..e], "Jürgen");
+    }
+}
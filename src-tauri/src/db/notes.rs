@@ -0,0 +1,412 @@
+use std::collections::HashSet;
+use chrono::{DateTime, Utc};
+use duckdb::{OptionalExt, Result};
+use serde::Serialize;
+use crate::datatypes::{
+    note::{KnownEntity, Note, NoteReference, NoteTarget},
+    sensor::GpsData,
+    types::{Metadata, NotePriority},
+};
+use crate::geo::{bounding_box, haversine_m};
+use super::{gps_from_row, is_sensor_table, Database};
+
+pub(super) const NOTE_COLUMNS: &str =
+    "id, user_id, timestamp, content, priority, parent_id, CAST(to_json(tags) AS VARCHAR), metadata, created_at, updated_at";
+
+/// A note reference looked up against the record it points at.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResolvedReference {
+    SensorEvent {
+        table: String,
+        row: serde_json::Value,
+    },
+    TimeWindow {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+    /// The referenced place, with the owner's GPS fixes that fall inside it.
+    Location {
+        latitude: f64,
+        longitude: f64,
+        radius_m: f64,
+        fixes: Vec<GpsData>,
+    },
+    Entity {
+        entity: KnownEntity,
+    },
+    Note {
+        note: Note,
+    },
+    /// The reference is malformed or its target has since been deleted.
+    Missing {
+        reference_type: String,
+        reference_id: String,
+    },
+}
+
+impl Database {
+    pub(crate) fn insert_note(&self, note: &Note) -> Result<()> {
+        let note = &self.redact_note_on_ingest(note)?;
+        self.conn.execute(
+            "INSERT INTO notes (
+                id, user_id, timestamp, content, priority, parent_id,
+                tags, metadata, created_at, updated_at
+            ) VALUES (?, ?, ?, ?, ?, ?, from_json(?, '[\"VARCHAR\"]'), ?, ?, ?)",
+            duckdb::params![
+                &note.id,
+                &note.user_id,
+                &note.timestamp.to_string(),
+                &self.seal_field("notes", "content", &note.content),
+                priority_to_sql(&note.priority),
+                &note.parent_id,
+                &note.tags.as_ref().map(|t| serde_json::to_string(t).unwrap()),
+                &note.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()),
+                &note.created_at.to_string(),
+                &note.updated_at.to_string(),
+            ],
+        )?;
+        if let Some(embedding) = &note.embedding {
+            self.set_note_embedding(&note.id, embedding)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn get_note(&self, id: &str) -> Result<Note> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM notes WHERE id = ?", NOTE_COLUMNS
+        ))?;
+        stmt.query_row([id], |row| self.open_note(note_from_row(row)?))
+    }
+
+    pub(crate) fn get_notes(&self, user_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<Note>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM notes
+             WHERE user_id = ? AND timestamp BETWEEN ? AND ?
+             ORDER BY timestamp", NOTE_COLUMNS
+        ))?;
+
+        let rows = stmt.query_map([user_id, &start.to_string(), &end.to_string()], |row| {
+            self.open_note(note_from_row(row)?)
+        })?;
+        rows.collect()
+    }
+
+    /// All of a user's notes, oldest first.
+    pub(crate) fn get_all_notes(&self, user_id: &str) -> Result<Vec<Note>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM notes WHERE user_id = ? ORDER BY timestamp", NOTE_COLUMNS
+        ))?;
+
+        let rows = stmt.query_map([user_id], |row| self.open_note(note_from_row(row)?))?;
+        rows.collect()
+    }
+
+    /// Rewrites a note's content, priority, parent, tags, metadata and
+    /// `updated_at`. The embedding is dropped so the new content gets
+    /// embedded again.
+    pub(crate) fn update_note(&self, note: &Note) -> Result<usize> {
+        let note = &self.redact_note_on_ingest(note)?;
+        self.conn.execute("DELETE FROM note_embeddings WHERE note_id = ?", [&note.id])?;
+        self.conn.execute(
+            "UPDATE notes SET content = ?, priority = ?, parent_id = ?, tags = from_json(?, '[\"VARCHAR\"]'),
+                metadata = ?, updated_at = ?
+             WHERE id = ?",
+            duckdb::params![
+                &self.seal_field("notes", "content", &note.content),
+                priority_to_sql(&note.priority),
+                &note.parent_id,
+                &note.tags.as_ref().map(|t| serde_json::to_string(t).unwrap()),
+                &note.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()),
+                &note.updated_at.to_string(),
+                &note.id,
+            ],
+        )
+    }
+
+    /// Links a note to a sensor row, time window, place, entity or other note.
+    /// Attaching the same target twice replaces the earlier reference.
+    pub(crate) fn attach_note(&self, note_id: &str, target: &NoteTarget, metadata: Option<&Metadata>) -> Result<NoteReference> {
+        let reference = NoteReference {
+            note_id: note_id.to_string(),
+            reference_type: target.reference_type().to_string(),
+            reference_id: target.reference_id(),
+            timestamp: target.anchor_time(),
+            metadata: metadata.cloned(),
+            created_at: Utc::now(),
+        };
+
+        self.conn.execute(
+            "INSERT OR REPLACE INTO note_references (
+                note_id, reference_type, reference_id, timestamp, metadata, created_at
+            ) VALUES (?, ?, ?, ?, ?, ?)",
+            duckdb::params![
+                &reference.note_id,
+                &reference.reference_type,
+                &reference.reference_id,
+                &reference.timestamp.map(|t| t.to_string()),
+                &reference.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()),
+                &reference.created_at.to_string(),
+            ],
+        )?;
+        Ok(reference)
+    }
+
+    pub(crate) fn detach_note(&self, note_id: &str, target: &NoteTarget) -> Result<usize> {
+        self.conn.execute(
+            "DELETE FROM note_references WHERE note_id = ? AND reference_type = ? AND reference_id = ?",
+            [note_id, target.reference_type(), &target.reference_id()],
+        )
+    }
+
+    pub(crate) fn get_note_references(&self, note_id: &str) -> Result<Vec<NoteReference>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT note_id, reference_type, reference_id, timestamp, metadata, created_at
+             FROM note_references WHERE note_id = ? ORDER BY created_at"
+        )?;
+
+        let rows = stmt.query_map([note_id], reference_from_row)?;
+        rows.collect()
+    }
+
+    /// Notes that reference anything which happened in `[start, end]`: sensor
+    /// rows in that range and time windows overlapping it. Used by the timeline.
+    pub(crate) fn get_notes_mentioning(&self, user_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<Note>> {
+        let mut note_ids = HashSet::new();
+
+        let mut stmt = self.conn.prepare(
+            "SELECT DISTINCT r.note_id FROM note_references r
+             JOIN notes n ON n.id = r.note_id
+             WHERE n.user_id = ? AND r.reference_type <> 'time_window'
+               AND r.timestamp BETWEEN ? AND ?"
+        )?;
+        for id in stmt.query_map([user_id, &start.to_string(), &end.to_string()], |row| row.get::<_, String>(0))? {
+            note_ids.insert(id?);
+        }
+
+        // Windows are stored anchored on their start, so the end has to be
+        // checked after decoding the reference id.
+        let mut stmt = self.conn.prepare(
+            "SELECT r.note_id, r.reference_id FROM note_references r
+             JOIN notes n ON n.id = r.note_id
+             WHERE n.user_id = ? AND r.reference_type = 'time_window' AND r.timestamp <= ?"
+        )?;
+        let windows = stmt.query_map([user_id, &end.to_string()], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for window in windows {
+            let (note_id, reference_id) = window?;
+            if let Some(NoteTarget::TimeWindow { end: window_end, .. }) = NoteTarget::parse("time_window", &reference_id) {
+                if window_end >= start {
+                    note_ids.insert(note_id);
+                }
+            }
+        }
+
+        let mut notes = note_ids
+            .iter()
+            .map(|id| self.get_note(id))
+            .collect::<Result<Vec<_>>>()?;
+        notes.sort_by_key(|n| n.timestamp);
+        Ok(notes)
+    }
+
+    pub(crate) fn resolve_note_reference(&self, reference: &NoteReference) -> Result<ResolvedReference> {
+        let missing = || ResolvedReference::Missing {
+            reference_type: reference.reference_type.clone(),
+            reference_id: reference.reference_id.clone(),
+        };
+
+        let target = match reference.target() {
+            Some(target) => target,
+            None => return Ok(missing()),
+        };
+
+        let resolved = match target {
+            NoteTarget::SensorEvent { table, device_id, timestamp } => {
+                if !is_sensor_table(&table) {
+                    return Ok(missing());
+                }
+                self.conn
+                    .query_row(
+                        &format!("SELECT to_json(t) FROM {} t WHERE device_id = ? AND timestamp = ?", table),
+                        [&device_id, &timestamp.to_string()],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()?
+                    .map(|json| ResolvedReference::SensorEvent {
+                        table,
+                        row: serde_json::from_str(&json).unwrap(),
+                    })
+            }
+            NoteTarget::TimeWindow { start, end } => Some(ResolvedReference::TimeWindow { start, end }),
+            NoteTarget::Location { latitude, longitude, radius_m } => {
+                let owner = self.get_note(&reference.note_id)?.user_id;
+                let fixes = self.get_gps_fixes_near(&owner, latitude, longitude, radius_m)?;
+                Some(ResolvedReference::Location { latitude, longitude, radius_m, fixes })
+            }
+            NoteTarget::Entity { entity_id } => self
+                .get_known_entity(&entity_id)
+                .optional()?
+                .map(|entity| ResolvedReference::Entity { entity }),
+            NoteTarget::Note { note_id } => self
+                .get_note(&note_id)
+                .optional()?
+                .map(|note| ResolvedReference::Note { note }),
+        };
+
+        Ok(resolved.unwrap_or_else(missing))
+    }
+
+    pub(crate) fn resolve_note_references(&self, note_id: &str) -> Result<Vec<(NoteReference, ResolvedReference)>> {
+        self.get_note_references(note_id)?
+            .into_iter()
+            .map(|reference| {
+                let resolved = self.resolve_note_reference(&reference)?;
+                Ok((reference, resolved))
+            })
+            .collect()
+    }
+
+    /// GPS fixes from any of the user's devices within `radius_m` of a point.
+    pub(crate) fn get_gps_fixes_near(&self, user_id: &str, latitude: f64, longitude: f64, radius_m: f64) -> Result<Vec<GpsData>> {
+        let (min_lat, max_lat, min_lon, max_lon) = bounding_box(latitude, longitude, radius_m);
+        let mut stmt = self.conn.prepare(
+            "SELECT g.* FROM gps_data g
+             JOIN devices d ON d.device_id = g.device_id
+             WHERE d.user_id = ?
+               AND g.latitude BETWEEN ? AND ?
+               AND g.longitude BETWEEN ? AND ?
+             ORDER BY g.timestamp"
+        )?;
+
+        let rows = stmt.query_map(
+            duckdb::params![user_id, min_lat, max_lat, min_lon, max_lon],
+            gps_from_row,
+        )?;
+
+        let mut fixes = Vec::new();
+        for fix in rows {
+            let fix = fix?;
+            if haversine_m(latitude, longitude, fix.latitude, fix.longitude) <= radius_m {
+                fixes.push(fix);
+            }
+        }
+        Ok(fixes)
+    }
+
+    /// Decrypts the content of a note read with `note_from_row`.
+    pub(super) fn open_note(&self, mut note: Note) -> Result<Note> {
+        note.content = self.open_field(note.content)?;
+        Ok(note)
+    }
+}
+
+fn priority_to_sql(priority: &NotePriority) -> String {
+    serde_json::to_value(priority).unwrap().as_str().unwrap().to_string()
+}
+
+/// Reads a row selected with `NOTE_COLUMNS`. Embeddings live in
+/// note_embeddings and are not loaded here; they are only needed inside
+/// DuckDB for similarity search.
+pub(super) fn note_from_row(row: &duckdb::Row<'_>) -> Result<Note> {
+    Ok(Note {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        timestamp: row.get::<_,String>(2)?.parse::<DateTime<Utc>>().unwrap(),
+        content: row.get(3)?,
+        priority: serde_json::from_value(serde_json::Value::String(row.get(4)?)).unwrap(),
+        parent_id: row.get(5)?,
+        tags: row.get::<_,Option<String>>(6)?.map(|s| serde_json::from_str(&s).unwrap()),
+        embedding: None,
+        metadata: row.get::<_,Option<String>>(7)?.map(|s| serde_json::from_str(&s).unwrap()),
+        created_at: row.get::<_,String>(8)?.parse::<DateTime<Utc>>().unwrap(),
+        updated_at: row.get::<_,String>(9)?.parse::<DateTime<Utc>>().unwrap(),
+    })
+}
+
+fn reference_from_row(row: &duckdb::Row<'_>) -> Result<NoteReference> {
+    Ok(NoteReference {
+        note_id: row.get(0)?,
+        reference_type: row.get(1)?,
+        reference_id: row.get(2)?,
+        timestamp: row.get::<_,Option<String>>(3)?.map(|s| s.parse::<DateTime<Utc>>().unwrap()),
+        metadata: row.get::<_,Option<String>>(4)?.map(|s| serde_json::from_str(&s).unwrap()),
+        created_at: row.get::<_,String>(5)?.parse::<DateTime<Utc>>().unwrap(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use tempfile::tempdir;
+    use crate::datatypes::user::User;
+
+    fn test_note(id: &str, user_id: &str) -> Note {
+        Note {
+            id: id.to_string(),
+            user_id: user_id.to_string(),
+            timestamp: Utc::now(),
+            content: "Parked on level 3".to_string(),
+            priority: NotePriority::Medium,
+            parent_id: None,
+            tags: Some(vec!["parking".to_string()]),
+            embedding: None,
+            metadata: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_attach_and_find_notes() -> Result<()> {
+        let dir = tempdir().unwrap();
+        let db = Database::new(&dir.path().join("test.db"))?;
+        db.insert_user(&User {
+            id: "test_user".to_string(),
+            email: "test@example.com".to_string(),
+            name: None,
+            encrypted_password: "x".to_string(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+        db.insert_note(&test_note("n1", "test_user"))?;
+        db.insert_note(&test_note("n2", "test_user"))?;
+
+        let now = Utc::now();
+        db.attach_note("n1", &NoteTarget::TimeWindow { start: now - Duration::hours(2), end: now }, None)?;
+        db.attach_note("n2", &NoteTarget::Note { note_id: "n1".to_string() }, None)?;
+
+        let references = db.get_note_references("n1")?;
+        assert_eq!(references.len(), 1);
+        assert_eq!(references[0].reference_type, "time_window");
+
+        let mentioning = db.get_notes_mentioning("test_user", now - Duration::minutes(30), now)?;
+        assert_eq!(mentioning.len(), 1);
+        assert_eq!(mentioning[0].id, "n1");
+
+        let resolved = db.resolve_note_references("n2")?;
+        assert!(matches!(&resolved[0].1, ResolvedReference::Note { note } if note.id == "n1"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reference_id_round_trip() {
+        let targets = vec![
+            NoteTarget::SensorEvent {
+                table: "heart_rate_data".to_string(),
+                device_id: "watch:1".to_string(),
+                timestamp: "2024-05-01T12:00:00Z".parse().unwrap(),
+            },
+            NoteTarget::Location { latitude: 37.5, longitude: -122.25, radius_m: 50.0 },
+            NoteTarget::Entity { entity_id: "e1".to_string() },
+        ];
+
+        for target in targets {
+            let parsed = NoteTarget::parse(target.reference_type(), &target.reference_id());
+            assert_eq!(parsed, Some(target));
+        }
+    }
+}
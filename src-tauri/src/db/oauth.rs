@@ -0,0 +1,111 @@
+use chrono::{DateTime, Utc};
+use duckdb::{OptionalExt, Result};
+use crate::datatypes::user::OAuthAccount;
+use super::Database;
+
+const OAUTH_COLUMNS: &str =
+    "id, user_id, provider, provider_user_id, access_token, refresh_token, expires_at, created_at, updated_at";
+
+// Token columns hold whatever the caller passes in; `oauth::OAuthStore`
+// seals them before they get here. Where the columns are field-encrypted
+// they're sealed again on the way in and read back as stored, for
+// `OAuthStore` to open while the database is unlocked.
+impl Database {
+    pub(crate) fn insert_oauth_account(&self, account: &OAuthAccount) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO oauth_accounts (
+                id, user_id, provider, provider_user_id, access_token,
+                refresh_token, expires_at, created_at, updated_at
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            duckdb::params![
+                &account.id,
+                &account.user_id,
+                &account.provider,
+                &account.provider_user_id,
+                self.seal_field("oauth_accounts", "access_token", &account.access_token),
+                account.refresh_token.as_deref().map(|t| self.seal_field("oauth_accounts", "refresh_token", t)),
+                &account.expires_at.map(|t| t.to_string()),
+                &account.created_at.to_string(),
+                &account.updated_at.to_string(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn get_oauth_account(&self, id: &str) -> Result<Option<OAuthAccount>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM oauth_accounts WHERE id = ?", OAUTH_COLUMNS
+        ))?;
+        stmt.query_row([id], oauth_account_from_row).optional()
+    }
+
+    pub(crate) fn find_oauth_account(&self, provider: &str, provider_user_id: &str) -> Result<Option<OAuthAccount>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM oauth_accounts WHERE provider = ? AND provider_user_id = ?", OAUTH_COLUMNS
+        ))?;
+        stmt.query_row([provider, provider_user_id], oauth_account_from_row).optional()
+    }
+
+    pub(crate) fn get_oauth_accounts(&self, user_id: &str) -> Result<Vec<OAuthAccount>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM oauth_accounts WHERE user_id = ? ORDER BY provider", OAUTH_COLUMNS
+        ))?;
+        let rows = stmt.query_map([user_id], oauth_account_from_row)?;
+        rows.collect()
+    }
+
+    /// Accounts with a refresh token whose access token expires before `before`.
+    pub(crate) fn get_oauth_accounts_expiring(&self, before: DateTime<Utc>) -> Result<Vec<OAuthAccount>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM oauth_accounts
+             WHERE refresh_token IS NOT NULL AND expires_at IS NOT NULL AND expires_at <= ?
+             ORDER BY expires_at",
+            OAUTH_COLUMNS
+        ))?;
+        let rows = stmt.query_map([before.to_string()], oauth_account_from_row)?;
+        rows.collect()
+    }
+
+    /// Stores freshly issued tokens. A `None` refresh token keeps the old one,
+    /// since most providers only send it on the first grant.
+    pub(crate) fn update_oauth_tokens(
+        &self,
+        id: &str,
+        access_token: &str,
+        refresh_token: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE oauth_accounts
+             SET access_token = ?, refresh_token = coalesce(?, refresh_token), expires_at = ?, updated_at = ?
+             WHERE id = ?",
+            duckdb::params![
+                self.seal_field("oauth_accounts", "access_token", access_token),
+                refresh_token.map(|t| self.seal_field("oauth_accounts", "refresh_token", t)),
+                expires_at.map(|t| t.to_string()),
+                Utc::now().to_string(),
+                id,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn delete_oauth_account(&self, id: &str) -> Result<()> {
+        self.conn.execute("DELETE FROM oauth_accounts WHERE id = ?", [id])?;
+        Ok(())
+    }
+}
+
+fn oauth_account_from_row(row: &duckdb::Row<'_>) -> Result<OAuthAccount> {
+    Ok(OAuthAccount {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        provider: row.get(2)?,
+        provider_user_id: row.get(3)?,
+        access_token: row.get(4)?,
+        refresh_token: row.get(5)?,
+        expires_at: row.get::<_,Option<String>>(6)?.map(|s| s.parse::<DateTime<Utc>>().unwrap()),
+        created_at: row.get::<_,String>(7)?.parse::<DateTime<Utc>>().unwrap(),
+        updated_at: row.get::<_,String>(8)?.parse::<DateTime<Utc>>().unwrap(),
+    })
+}
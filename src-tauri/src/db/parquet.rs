@@ -0,0 +1,473 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Days, NaiveDate, NaiveDateTime, Utc};
+use duckdb::{types::ToSql, Result};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use crate::crypto::FIELD_PREFIX;
+use super::{encryption::ENCRYPTABLE_COLUMNS, is_sensor_table, Database};
+
+const EXPORT_STAGE: &str = "parquet_export";
+const IMPORT_STAGE: &str = "parquet_import";
+
+/// A file written by `export_parquet`: one table's rows for one day.
+#[derive(Debug, Clone, Serialize)]
+pub struct ParquetPartition {
+    pub table_name: String,
+    pub day: NaiveDate,
+    pub rows: usize,
+    pub path: PathBuf,
+}
+
+/// New rows read from Parquet files by `read_parquet_rows`, as JSON objects
+/// shaped like the table's `datatypes::sensor` struct.
+#[derive(Debug, Clone, Default)]
+pub struct ParquetRows {
+    pub rows_read: usize,
+    /// Rows already stored, or repeated within the files.
+    pub duplicates: usize,
+    pub rows: Vec<Value>,
+}
+
+impl Database {
+    /// Writes the rows of `tables` from `device_ids` between `start` and
+    /// `end` to `dir` as `<table>/<day>.parquet`, ready for pandas or Arrow.
+    /// Rows leave the way the other exports return them: with privacy zones
+    /// and export redaction applied and encrypted fields opened, so this
+    /// fails while locked. Each table and device is logged as an export.
+    pub(crate) fn export_parquet(
+        &self,
+        tables: &[String],
+        device_ids: &[String],
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        dir: &Path,
+    ) -> Result<Vec<ParquetPartition>> {
+        if let Some(table) = tables.iter().find(|table| !is_sensor_table(table)) {
+            return Err(duckdb::Error::ToSqlConversionFailure(
+                format!("unknown sensor table: {}", table).into(),
+            ));
+        }
+        if device_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut partitions = Vec::new();
+        for table in tables {
+            let written = self.stage_export(table, device_ids, start, end).and_then(|_| {
+                let table_dir = dir.join(table);
+                fs::create_dir_all(&table_dir).map_err(|e| duckdb::Error::ToSqlConversionFailure(Box::new(e)))?;
+                self.copy_days(EXPORT_STAGE, |day| table_dir.join(format!("{}.parquet", day)))
+            });
+            self.conn.execute_batch(&format!("DROP TABLE IF EXISTS {}", EXPORT_STAGE))?;
+            partitions.extend(written?.into_iter().map(|(day, rows, path)| ParquetPartition {
+                table_name: table.clone(),
+                day,
+                rows,
+                path,
+            }));
+        }
+        Ok(partitions)
+    }
+
+    /// Reads Parquet files for import into `table`. The files may only have
+    /// columns the table has, with the same types (enums and JSON may be
+    /// plain strings), and must have every column it requires. Rows whose
+    /// (timestamp, device_id) is already stored or repeated are dropped.
+    pub(crate) fn read_parquet_rows(&self, table: &str, paths: &[PathBuf]) -> Result<ParquetRows> {
+        if !is_sensor_table(table) {
+            return Err(duckdb::Error::ToSqlConversionFailure(
+                format!("unknown sensor table: {}", table).into(),
+            ));
+        }
+        if paths.is_empty() {
+            return Ok(ParquetRows::default());
+        }
+        let files = format!(
+            "read_parquet([{}])",
+            paths.iter().map(|path| sql_path(path)).collect::<Vec<_>>().join(", ")
+        );
+
+        let columns = self.file_columns(table, &files)?;
+        self.conn.execute_batch(&format!("CREATE OR REPLACE TEMP TABLE {} AS SELECT * FROM {}", IMPORT_STAGE, files))?;
+        let result = self.new_staged_rows(table, &columns);
+        self.conn.execute_batch(&format!("DROP TABLE IF EXISTS {}", IMPORT_STAGE))?;
+        result
+    }
+
+    fn new_staged_rows(&self, table: &str, columns: &[(String, String)]) -> Result<ParquetRows> {
+        let rows_read = self.conn.query_row(&format!("SELECT count(*) FROM {}", IMPORT_STAGE), [], |row| row.get::<_,usize>(0))?;
+        let select = columns.iter().map(|(name, data_type)| json_expression(name, data_type)).collect::<Vec<_>>().join(", ");
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM (
+                 SELECT *, row_number() OVER (PARTITION BY timestamp, device_id) AS occurrence FROM {}
+             ) s
+             WHERE occurrence = 1
+               AND NOT EXISTS (SELECT 1 FROM {} t WHERE t.timestamp = s.timestamp AND t.device_id = s.device_id)
+             ORDER BY timestamp, device_id",
+            select, IMPORT_STAGE, table
+        ))?;
+        let rows = stmt
+            .query_map([], |row| {
+                let mut object = Map::new();
+                for (i, (name, data_type)) in columns.iter().enumerate() {
+                    if let Some(text) = row.get::<_,Option<String>>(i)? {
+                        object.insert(name.clone(), json_value(&text, data_type));
+                    }
+                }
+                Ok(Value::Object(object))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ParquetRows { rows_read, duplicates: rows_read - rows.len(), rows })
+    }
+
+    /// Copies the rows of `source` to one Parquet file per day, named by
+    /// `path_for`. Rows are sorted, so the same rows always give the same
+    /// bytes. Returns each day with its row count and path.
+    pub(super) fn copy_days(
+        &self,
+        source: &str,
+        path_for: impl Fn(NaiveDate) -> PathBuf,
+    ) -> Result<Vec<(NaiveDate, usize, PathBuf)>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT DISTINCT CAST(CAST(timestamp AS DATE) AS VARCHAR) FROM {} ORDER BY 1",
+            source
+        ))?;
+        let days = stmt
+            .query_map([], |row| Ok(row.get::<_,String>(0)?.parse::<NaiveDate>().unwrap()))?
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut written = Vec::with_capacity(days.len());
+        for day in days {
+            let path = path_for(day);
+            let rows = self.copy_to_parquet(
+                &format!(
+                    "SELECT * FROM {} WHERE timestamp >= '{}' AND timestamp < '{}' ORDER BY ALL",
+                    source, day, day + Days::new(1)
+                ),
+                &path,
+            )?;
+            written.push((day, rows, path));
+        }
+        Ok(written)
+    }
+
+    pub(super) fn copy_to_parquet(&self, query: &str, path: &Path) -> Result<usize> {
+        self.conn.execute(
+            &format!("COPY ({}) TO {} (FORMAT PARQUET, COMPRESSION ZSTD)", query, sql_path(path)),
+            [],
+        )
+    }
+
+    fn stage_export(&self, table: &str, device_ids: &[String], start: DateTime<Utc>, end: DateTime<Utc>) -> Result<()> {
+        self.conn.execute(
+            &format!(
+                "CREATE OR REPLACE TEMP TABLE {} AS
+                 SELECT * FROM {} WHERE device_id IN ({}) AND timestamp BETWEEN ? AND ?",
+                EXPORT_STAGE,
+                table,
+                vec!["?"; device_ids.len()].join(", ")
+            ),
+            duckdb::params_from_iter(device_ids.iter().cloned().chain([start.to_string(), end.to_string()])),
+        )?;
+
+        for (_, column) in ENCRYPTABLE_COLUMNS.iter().filter(|(t, _)| *t == table) {
+            let mut stmt = self.conn.prepare(&format!(
+                "SELECT rowid, {} FROM {} WHERE starts_with({}, ?)",
+                column, EXPORT_STAGE, column
+            ))?;
+            let sealed = stmt
+                .query_map([FIELD_PREFIX], |row| Ok((row.get::<_,i64>(0)?, row.get::<_,String>(1)?)))?
+                .collect::<Result<Vec<_>>>()?;
+            for (rowid, value) in sealed {
+                self.conn.execute(
+                    &format!("UPDATE {} SET {} = ? WHERE rowid = ?", EXPORT_STAGE, column),
+                    duckdb::params![self.open_field(value)?, rowid],
+                )?;
+            }
+        }
+
+        for device_id in device_ids {
+            self.apply_export_view(table, device_id, start, end)?;
+        }
+        Ok(())
+    }
+
+    /// Brings the staged rows of tables with an export view (privacy zones,
+    /// export redaction) in line with it: rows the view drops are removed,
+    /// and fields it changes are overwritten.
+    fn apply_export_view(&self, table: &str, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<()> {
+        let (stored, exported) = match table {
+            "gps_data" => (
+                json_rows(self.get_gps_data(device_id, start, end)?),
+                json_rows(self.export_gps_data(device_id, start, end)?),
+            ),
+            "wifi_data" => (
+                json_rows(self.get_wifi_data(device_id, start, end)?),
+                json_rows(self.export_wifi_data(device_id, start, end)?),
+            ),
+            "call_log_data" => (
+                json_rows(self.get_call_log_data(device_id, start, end)?),
+                json_rows(self.export_call_log_data(device_id, start, end)?),
+            ),
+            "notification_data" => (
+                json_rows(self.get_notification_data(device_id, start, end)?),
+                json_rows(self.export_notification_data(device_id, start, end)?),
+            ),
+            "app_usage_data" => (
+                json_rows(self.get_app_usage_data(device_id, start, end)?),
+                json_rows(self.export_app_usage_data(device_id, start, end)?),
+            ),
+            _ => return self.audit_export(table, device_id, start, end),
+        };
+
+        // Rows are unique per device and timestamp
+        let exported: HashMap<String, &Map<String, Value>> =
+            exported.iter().map(|row| (row["timestamp"].to_string(), row)).collect();
+        for row in &stored {
+            let timestamp = serde_json::from_value::<DateTime<Utc>>(row["timestamp"].clone()).unwrap().to_string();
+            let Some(shown) = exported.get(&row["timestamp"].to_string()) else {
+                self.conn.execute(
+                    &format!("DELETE FROM {} WHERE device_id = ? AND timestamp = ?", EXPORT_STAGE),
+                    [device_id, &timestamp],
+                )?;
+                continue;
+            };
+            let changed: BTreeSet<&String> = row.keys().chain(shown.keys()).filter(|key| row.get(*key) != shown.get(*key)).collect();
+            if changed.is_empty() {
+                continue;
+            }
+            let assignments = changed.iter().map(|column| format!("{} = ?", column)).collect::<Vec<_>>().join(", ");
+            let params: Vec<Box<dyn ToSql>> = changed
+                .iter()
+                .map(|column| sql_param(shown.get(*column)))
+                .chain([Box::new(device_id.to_string()) as Box<dyn ToSql>, Box::new(timestamp)])
+                .collect();
+            self.conn.execute(
+                &format!("UPDATE {} SET {} WHERE device_id = ? AND timestamp = ?", EXPORT_STAGE, assignments),
+                duckdb::params_from_iter(params),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Each column of `table` with its DuckDB type and whether it's nullable.
+    pub(crate) fn column_types(&self, table: &str) -> Result<HashMap<String, (String, bool)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT column_name, data_type, is_nullable = 'YES'
+             FROM information_schema.columns
+             WHERE table_schema = 'main' AND table_name = ?
+             ORDER BY ordinal_position"
+        )?;
+        let columns = stmt
+            .query_map([table], |row| Ok((row.get::<_,String>(0)?, (row.get::<_,String>(1)?, row.get::<_,bool>(2)?))))?
+            .collect::<Result<HashMap<_, _>>>()?;
+        Ok(columns)
+    }
+
+    /// The columns of `files`, after checking they fit `table`.
+    fn file_columns(&self, table: &str, files: &str) -> Result<Vec<(String, String)>> {
+        let expected = self.column_types(table)?;
+        let mut stmt = self.conn.prepare(&format!("DESCRIBE SELECT * FROM {}", files))?;
+        let found = stmt
+            .query_map([], |row| Ok((row.get::<_,String>(0)?, row.get::<_,String>(1)?)))?
+            .collect::<Result<Vec<_>>>()?;
+
+        let incompatible = |reason: String| {
+            Err(duckdb::Error::ToSqlConversionFailure(
+                format!("Parquet files do not fit {}: {}", table, reason).into(),
+            ))
+        };
+        let mut columns = Vec::with_capacity(found.len());
+        for (name, file_type) in found {
+            let Some((data_type, _)) = expected.get(&name) else {
+                return incompatible(format!("unknown column {}", name));
+            };
+            let as_string = file_type == "VARCHAR" && (data_type == "JSON" || data_type.starts_with("ENUM("));
+            if file_type != *data_type && !as_string {
+                return incompatible(format!("{} is {}, expected {}", name, file_type, data_type));
+            }
+            columns.push((name, data_type.clone()));
+        }
+        let mut missing: Vec<&String> = expected
+            .iter()
+            .filter(|(name, (_, nullable))| !nullable && !columns.iter().any(|(found, _)| found == *name))
+            .map(|(name, _)| name)
+            .collect();
+        missing.sort();
+        if let Some(name) = missing.first() {
+            return incompatible(format!("required column {} is missing", name));
+        }
+        Ok(columns)
+    }
+}
+
+/// `path` as a quoted SQL string; COPY and read_parquet don't take parameters.
+pub(super) fn sql_path(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "''"))
+}
+
+fn json_rows<T: Serialize>(rows: Vec<T>) -> Vec<Map<String, Value>> {
+    rows.into_iter()
+        .filter_map(|row| match serde_json::to_value(row) {
+            Ok(Value::Object(object)) => Some(object),
+            _ => None,
+        })
+        .collect()
+}
+
+fn sql_param(value: Option<&Value>) -> Box<dyn ToSql> {
+    match value {
+        None | Some(Value::Null) => Box::new(None::<String>),
+        Some(Value::Bool(b)) => Box::new(*b),
+        Some(Value::Number(n)) => match n.as_i64() {
+            Some(i) => Box::new(i),
+            None => Box::new(n.as_f64()),
+        },
+        Some(Value::String(s)) => Box::new(s.clone()),
+        Some(other) => Box::new(other.to_string()),
+    }
+}
+
+/// Selects `column` as text that `json_value` can turn back into JSON.
+/// Lists are rendered as JSON arrays, as DuckDB's own text for them isn't.
+fn json_expression(column: &str, data_type: &str) -> String {
+    if data_type == "VARCHAR[]" {
+        format!(
+            "CASE WHEN len({0}) = 0 THEN '[]' ELSE '[\"' || array_to_string(list_transform({0}, x -> replace(replace(x, '\\', '\\\\'), '\"', '\\\"')), '\",\"') || '\"]' END",
+            column
+        )
+    } else {
+        format!("CAST({} AS VARCHAR)", column)
+    }
+}
+
+fn json_value(text: &str, data_type: &str) -> Value {
+    match data_type {
+        "BOOLEAN" => Value::Bool(text == "true"),
+        "TINYINT" | "SMALLINT" | "INTEGER" | "BIGINT" => text.parse::<i64>().map_or(Value::Null, Value::from),
+        "FLOAT" | "DOUBLE" => text.parse::<f64>().map_or(Value::Null, Value::from),
+        "TIMESTAMP" | "TIMESTAMP WITH TIME ZONE" => match parse_stored_timestamp(text) {
+            Some(timestamp) => Value::String(timestamp.to_rfc3339()),
+            None => Value::String(text.to_string()),
+        },
+        "JSON" => serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_string())),
+        list if list.ends_with("[]") => serde_json::from_str(text).unwrap_or(Value::Null),
+        _ => Value::String(text.to_string()),
+    }
+}
+
+/// A timestamp as DuckDB renders it, with or without an offset.
+fn parse_stored_timestamp(text: &str) -> Option<DateTime<Utc>> {
+    text.parse::<DateTime<Utc>>()
+        .ok()
+        .or_else(|| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f").ok().map(|t| t.and_utc()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::collection::{CollectionError, CollectionService};
+    use crate::datatypes::{privacy::PrivacyZone, sensor::GpsData, types::{ConsentLevel, ZoneMode}};
+    use crate::db::{fixtures::{device, temp_database, user}, AccessError, UserContext, CONSENT_WILDCARD};
+
+    #[test]
+    fn test_parquet_export_is_shielded_and_import_skips_duplicates() -> std::result::Result<(), AccessError> {
+        let (dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_user(&user("bob"))?;
+        let alice = db.scoped(UserContext::new("alice"));
+        let bob = db.scoped(UserContext::new("bob"));
+        alice.insert_device(&device("alice_phone", "alice"))?;
+        bob.insert_device(&device("bob_phone", "bob"))?;
+
+        let fix = |day: u32, latitude: f64, longitude: f64| GpsData {
+            timestamp: Utc.with_ymd_and_hms(2024, 3, day, 9, 0, 0).unwrap(),
+            device_id: "alice_phone".to_string(),
+            latitude,
+            longitude,
+            altitude: None,
+            accuracy: Some(5.0),
+            speed: None,
+            bearing: None,
+            satellites: None,
+            provider: None,
+            metadata: None,
+        };
+        alice.insert_gps_data(&fix(1, 51.5080, -0.1270))?;
+        alice.insert_gps_data(&fix(2, 51.5200, -0.1000))?;
+
+        // Added without touching stored fixes, so only the export hides home
+        let zone = PrivacyZone {
+            id: "home".to_string(),
+            user_id: "alice".to_string(),
+            name: "Home".to_string(),
+            latitude: Some(51.5074),
+            longitude: Some(-0.1278),
+            radius_m: Some(200.0),
+            ssid: None,
+            bssid: None,
+            mode: ZoneMode::Drop,
+            noise_m: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        alice.set_privacy_zone(&zone, false)?;
+
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+        let end = Utc.with_ymd_and_hms(2024, 3, 3, 0, 0, 0).unwrap();
+        let out = dir.path().join("export");
+        let tables = vec!["gps_data".to_string()];
+        assert!(matches!(
+            alice.export_parquet(&tables, &["bob_phone".to_string()], start, end, &out),
+            Err(AccessError::Denied(_))
+        ));
+        let partitions = alice.export_parquet(&tables, &[], start, end, &out)?;
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].day, NaiveDate::from_ymd_opt(2024, 3, 2).unwrap());
+        assert_eq!(partitions[0].rows, 1);
+        assert_eq!(alice.get_gps_data("alice_phone", start, end)?.len(), 2);
+        let paths: Vec<_> = partitions.iter().map(|p| p.path.clone()).collect();
+
+        // Importing rows that are already stored adds nothing
+        let import = CollectionService::new(&db, UserContext::new("alice")).import_parquet("gps_data", &paths).unwrap();
+        assert_eq!((import.rows_read, import.duplicates, import.imported), (1, 1, 0));
+
+        let copy = Database::new(&dir.path().join("copy.db"))?;
+        copy.insert_user(&user("alice"))?;
+        copy.insert_user(&user("bob"))?;
+        let mut phone = device("alice_phone", "alice");
+        copy.scoped(UserContext::new("alice")).insert_device(&phone)?;
+        assert!(matches!(
+            CollectionService::new(&copy, UserContext::new("bob")).import_parquet("gps_data", &paths),
+            Err(CollectionError::Access(AccessError::Denied(_)))
+        ));
+        assert!(CollectionService::new(&copy, UserContext::new("alice")).import_parquet("heart_rate_data", &paths).is_err());
+        assert!(matches!(
+            CollectionService::new(&copy, UserContext::new("alice")).import_parquet("gps_data", &paths),
+            Err(CollectionError::Unsupported { capability: "has_gps", .. })
+        ));
+
+        // Consent holds for imports as for uploads, only the plan is skipped
+        let alice_copy = copy.scoped(UserContext::new("alice"));
+        phone.capabilities.has_gps = true;
+        alice_copy.update_device_capabilities("alice_phone", &phone.capabilities, &[])?;
+        alice_copy.set_consent(CONSENT_WILDCARD, "gps_data", ConsentLevel::LocalOnly, false)?;
+        assert!(matches!(
+            CollectionService::new(&copy, UserContext::new("alice")).import_parquet("gps_data", &paths),
+            Err(CollectionError::ConsentWithheld { level: ConsentLevel::LocalOnly, .. })
+        ));
+        alice_copy.set_consent(CONSENT_WILDCARD, "gps_data", ConsentLevel::Collect, false)?;
+        CollectionService::new(&copy, UserContext::new("alice")).set_preference("gps_data", false, None).unwrap();
+
+        let import = CollectionService::new(&copy, UserContext::new("alice")).import_parquet("gps_data", &paths).unwrap();
+        assert_eq!(import.imported, 1);
+        let fixes = copy.get_gps_data("alice_phone", start, end)?;
+        assert_eq!(fixes.len(), 1);
+        assert_eq!((fixes[0].latitude, fixes[0].accuracy), (51.5200, Some(5.0)));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+use chrono::{DateTime, Utc};
+use duckdb::{OptionalExt, Result};
+use crate::datatypes::{
+    privacy::PrivacyZone,
+    sensor::{GpsData, WifiData},
+    types::ZoneMode,
+};
+use crate::geo::bounding_box;
+use crate::privacy::{shield_gps, shield_wifi};
+use super::{deletion::{Removed, ID_CHUNK}, gps_from_row, Database};
+
+const ZONE_COLUMNS: &str =
+    "id, user_id, name, latitude, longitude, radius_m, ssid, bssid, CAST(mode AS VARCHAR), noise_m, created_at, updated_at";
+
+impl Database {
+    pub(crate) fn get_privacy_zones(&self, user_id: &str) -> Result<Vec<PrivacyZone>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM privacy_zones WHERE user_id = ? ORDER BY name", ZONE_COLUMNS
+        ))?;
+        let rows = stmt.query_map([user_id], zone_from_row)?;
+        rows.collect()
+    }
+
+    pub(crate) fn get_privacy_zone(&self, id: &str) -> Result<Option<PrivacyZone>> {
+        self.conn
+            .query_row(&format!("SELECT {} FROM privacy_zones WHERE id = ?", ZONE_COLUMNS), [id], zone_from_row)
+            .optional()
+    }
+
+    /// The zones of whoever owns `device_id`.
+    pub(crate) fn get_privacy_zones_for_device(&self, device_id: &str) -> Result<Vec<PrivacyZone>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT z.id, z.user_id, z.name, z.latitude, z.longitude, z.radius_m, z.ssid, z.bssid,
+                    CAST(z.mode AS VARCHAR), z.noise_m, z.created_at, z.updated_at
+             FROM privacy_zones z JOIN devices d ON d.user_id = z.user_id
+             WHERE d.device_id = ?"
+        )?;
+        let rows = stmt.query_map([device_id], zone_from_row)?;
+        rows.collect()
+    }
+
+    /// Creates or replaces a zone. New readings are shielded as they arrive;
+    /// with `apply_to_existing` the user's stored GPS and Wi-Fi rows inside
+    /// the zone are rewritten (or deleted, for `Drop`) as well. Returns the
+    /// number of stored rows that changed.
+    pub(crate) fn set_privacy_zone(&self, zone: &PrivacyZone, apply_to_existing: bool) -> Result<usize> {
+        validate_zone(zone)?;
+
+        self.in_transaction(|db| {
+            let created_at = db.get_privacy_zone(&zone.id)?.map_or(zone.created_at, |existing| existing.created_at);
+            db.conn.execute(
+                "INSERT OR REPLACE INTO privacy_zones (
+                    id, user_id, name, latitude, longitude, radius_m, ssid, bssid, mode, noise_m, created_at, updated_at
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                duckdb::params![
+                    &zone.id,
+                    &zone.user_id,
+                    &zone.name,
+                    zone.latitude,
+                    zone.longitude,
+                    zone.radius_m,
+                    zone.ssid.as_deref(),
+                    zone.bssid.as_deref(),
+                    zone.mode.as_str(),
+                    zone.noise_m,
+                    created_at.to_string(),
+                    Utc::now().to_string(),
+                ],
+            )?;
+
+            if !apply_to_existing {
+                return Ok(0);
+            }
+            let mut changed = 0;
+            for device in db.get_device_ids_for_user(&zone.user_id)? {
+                changed += db.shield_stored_gps(zone, &device)?;
+                changed += db.shield_stored_wifi(zone, &device)?;
+            }
+            Ok(changed)
+        })
+    }
+
+    pub(crate) fn delete_privacy_zone(&self, user_id: &str, id: &str) -> Result<usize> {
+        self.conn.execute("DELETE FROM privacy_zones WHERE user_id = ? AND id = ?", [user_id, id])
+    }
+
+    /// GPS fixes as they may leave the app: like `get_gps_data`, with the
+    /// owner's current zones applied. Fixes stored before a zone existed
+    /// are shielded here too.
+    pub(crate) fn export_gps_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<GpsData>> {
+        let zones = self.get_privacy_zones_for_device(device_id)?;
+        let mut rng = rand::thread_rng();
+        self.audit_export("gps_data", device_id, start, end)?;
+        Ok(self
+            .get_gps_data(device_id, start, end)?
+            .iter()
+            .filter_map(|fix| shield_gps(&zones, fix, &mut rng))
+            .collect())
+    }
+
+    /// Wi-Fi readings with the owner's current zones applied.
+    pub(crate) fn export_wifi_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<WifiData>> {
+        let zones = self.get_privacy_zones_for_device(device_id)?;
+        self.audit_export("wifi_data", device_id, start, end)?;
+        Ok(self
+            .get_wifi_data(device_id, start, end)?
+            .iter()
+            .filter_map(|reading| shield_wifi(&zones, reading))
+            .collect())
+    }
+
+    fn shield_stored_gps(&self, zone: &PrivacyZone, device_id: &str) -> Result<usize> {
+        let (Some(latitude), Some(longitude), Some(radius_m)) = (zone.latitude, zone.longitude, zone.radius_m) else {
+            return Ok(0);
+        };
+        let (min_lat, max_lat, min_lon, max_lon) = bounding_box(latitude, longitude, radius_m);
+        let mut stmt = self.conn.prepare(
+            "SELECT *, rowid FROM gps_data
+             WHERE device_id = ? AND latitude BETWEEN ? AND ? AND longitude BETWEEN ? AND ?"
+        )?;
+        let fixes = stmt
+            .query_map(duckdb::params![device_id, min_lat, max_lat, min_lon, max_lon], |row| {
+                Ok((row.get::<_,i64>(11)?, gps_from_row(row)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        let zones = std::slice::from_ref(zone);
+        let mut rng = rand::thread_rng();
+        let mut dropped = Vec::new();
+        let mut moved = Vec::new();
+        for (rowid, fix) in fixes {
+            match shield_gps(zones, &fix, &mut rng) {
+                None => dropped.push(rowid),
+                Some(shielded) if shielded.latitude != fix.latitude || shielded.longitude != fix.longitude => {
+                    moved.push((rowid, shielded));
+                }
+                Some(_) => {}
+            }
+        }
+
+        let mut changed = 0;
+        for chunk in moved.chunks(ID_CHUNK) {
+            let list = chunk.iter().map(|(rowid, _)| rowid.to_string()).collect::<Vec<_>>().join(", ");
+            let devices = [device_id.to_string()];
+            changed += self.audited("gps_data", &devices, &format!("rowid IN ({})", list), &[], |db| {
+                let mut changed = 0;
+                for (rowid, shielded) in chunk {
+                    changed += db.conn.execute(
+                        "UPDATE gps_data SET latitude = ?, longitude = ?, accuracy = ?,
+                                altitude = NULL, speed = NULL, bearing = NULL
+                         WHERE rowid = ?",
+                        duckdb::params![shielded.latitude, shielded.longitude, shielded.accuracy, rowid],
+                    )?;
+                }
+                Ok(changed)
+            })?;
+        }
+
+        let mut removed = Removed::new();
+        self.delete_rowids("gps_data", device_id, &dropped, &mut removed)?;
+        Ok(changed + removed.get("gps_data").copied().unwrap_or_default())
+    }
+
+    fn shield_stored_wifi(&self, zone: &PrivacyZone, device_id: &str) -> Result<usize> {
+        let (filter, params) = match (&zone.ssid, &zone.bssid) {
+            (Some(ssid), Some(bssid)) => ("ssid = ? OR lower(bssid) = lower(?)", vec![ssid.clone(), bssid.clone()]),
+            (Some(ssid), None) => ("ssid = ?", vec![ssid.clone()]),
+            (None, Some(bssid)) => ("lower(bssid) = lower(?)", vec![bssid.clone()]),
+            (None, None) => return Ok(0),
+        };
+
+        if zone.mode == ZoneMode::Drop {
+            let mut removed = Removed::new();
+            return self.delete_sensor_rows("wifi_data", device_id, filter, &params, &mut removed);
+        }
+        // Relabelled rows no longer match `filter`, so the zone name is
+        // included in what the audit compares
+        let audit_params: Vec<String> = params.iter().cloned().chain([zone.name.clone()]).collect();
+        self.audited("wifi_data", &[device_id.to_string()], &format!("({}) OR ssid = ?", filter), &audit_params, |db| {
+            db.conn.execute(
+                &format!(
+                    "UPDATE wifi_data SET ssid = ?, bssid = '', ip_address = '', nearby_networks = NULL
+                     WHERE device_id = ? AND ({})",
+                    filter
+                ),
+                duckdb::params_from_iter([zone.name.clone(), device_id.to_string()].into_iter().chain(params)),
+            )
+        })
+    }
+}
+
+fn validate_zone(zone: &PrivacyZone) -> Result<()> {
+    let invalid = |reason: &str| {
+        Err(duckdb::Error::ToSqlConversionFailure(
+            format!("invalid privacy zone {}: {}", zone.name, reason).into(),
+        ))
+    };
+    if zone.name.trim().is_empty() {
+        return invalid("name is empty");
+    }
+    match (zone.latitude, zone.longitude, zone.radius_m) {
+        (None, None, None) if zone.ssid.is_none() && zone.bssid.is_none() => {
+            invalid("needs a centre and radius or a Wi-Fi network")
+        }
+        (None, None, None) => Ok(()),
+        (Some(lat), Some(lon), Some(radius_m)) => {
+            if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+                invalid("centre is out of range")
+            } else if radius_m <= 0.0 {
+                invalid("radius must be positive")
+            } else if zone.noise_m.is_some_and(|noise_m| noise_m <= 0.0) {
+                invalid("noise must be positive")
+            } else {
+                Ok(())
+            }
+        }
+        _ => invalid("centre and radius must be given together"),
+    }
+}
+
+fn zone_from_row(row: &duckdb::Row<'_>) -> Result<PrivacyZone> {
+    Ok(PrivacyZone {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        name: row.get(2)?,
+        latitude: row.get(3)?,
+        longitude: row.get(4)?,
+        radius_m: row.get(5)?,
+        ssid: row.get(6)?,
+        bssid: row.get(7)?,
+        mode: serde_json::from_value(serde_json::Value::String(row.get(8)?)).unwrap(),
+        noise_m: row.get(9)?,
+        created_at: row.get::<_,String>(10)?.parse::<DateTime<Utc>>().unwrap(),
+        updated_at: row.get::<_,String>(11)?.parse::<DateTime<Utc>>().unwrap(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatypes::{privacy::PrivacyZone, types::ZoneMode};
+    use crate::db::{fixtures::{device, temp_database, user}, AccessError, UserContext};
+
+    #[test]
+    fn test_privacy_zones_shield_fixes() -> std::result::Result<(), AccessError> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_user(&user("bob"))?;
+
+        let alice = db.scoped(UserContext::new("alice"));
+        let bob = db.scoped(UserContext::new("bob"));
+        alice.insert_device(&device("alice_phone", "alice"))?;
+
+        let now = Utc::now();
+        let fix = |minutes: i64, latitude: f64, longitude: f64| GpsData {
+            timestamp: now - chrono::Duration::minutes(minutes),
+            device_id: "alice_phone".to_string(),
+            latitude,
+            longitude,
+            altitude: Some(20.0),
+            accuracy: Some(5.0),
+            speed: None,
+            bearing: None,
+            satellites: None,
+            provider: None,
+            metadata: None,
+        };
+        // Stored before the zone exists: one at home, one across town
+        alice.insert_gps_data(&fix(3, 51.5080, -0.1270))?;
+        alice.insert_gps_data(&fix(2, 51.5200, -0.1000))?;
+
+        let mut zone = PrivacyZone {
+            id: "home".to_string(),
+            user_id: "alice".to_string(),
+            name: "Home".to_string(),
+            latitude: Some(51.5074),
+            longitude: Some(-0.1278),
+            radius_m: Some(200.0),
+            ssid: None,
+            bssid: None,
+            mode: ZoneMode::Snap,
+            noise_m: None,
+            created_at: now,
+            updated_at: now,
+        };
+        assert!(matches!(bob.set_privacy_zone(&zone, false), Err(AccessError::Denied(_))));
+        assert_eq!(alice.set_privacy_zone(&zone, true)?, 1);
+
+        // Bob can't take over Alice's zone by claiming its id
+        let mut stolen = zone.clone();
+        stolen.user_id = "bob".to_string();
+        assert!(matches!(bob.set_privacy_zone(&stolen, false), Err(AccessError::Denied(_))));
+
+        let start = now - chrono::Duration::hours(1);
+        let fixes = alice.get_gps_data("alice_phone", start, now)?;
+        assert!(fixes.iter().any(|f| (f.latitude, f.longitude) == (51.5074, -0.1278) && f.altitude.is_none()));
+        assert!(fixes.iter().any(|f| f.latitude == 51.5200));
+
+        // New fixes inside a drop zone are never stored
+        zone.mode = ZoneMode::Drop;
+        alice.set_privacy_zone(&zone, false)?;
+        alice.insert_gps_data(&fix(1, 51.5075, -0.1279))?;
+        assert_eq!(alice.get_gps_data("alice_phone", start, now)?.len(), 2);
+        assert_eq!(alice.export_gps_data("alice_phone", start, now)?.len(), 1);
+
+        let zones = alice.get_privacy_zones()?;
+        assert_eq!(zones.len(), 1);
+        assert_eq!(zones[0].mode, ZoneMode::Drop);
+        assert!(bob.get_privacy_zones()?.is_empty());
+        assert_eq!(bob.delete_privacy_zone("home")?, 0);
+        assert_eq!(alice.delete_privacy_zone("home")?, 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::{BTreeMap, HashSet};
+use chrono::{DateTime, Utc};
+use duckdb::{OptionalExt, Result};
+use rand::RngCore;
+use crate::crypto::to_hex;
+use crate::datatypes::{
+    note::Note,
+    privacy::RedactionPolicy,
+    sensor::{AppUsageData, CallLogData, NotificationData},
+    types::{Metadata, PiiDetector},
+};
+use crate::redaction::Redactor;
+use super::Database;
+
+impl Database {
+    pub(crate) fn get_redaction_policy(&self, user_id: &str) -> Result<Option<RedactionPolicy>> {
+        Ok(self.get_redaction_settings(user_id)?.map(|(policy, _)| policy))
+    }
+
+    /// Stores the policy. The user's salt is created with the first policy
+    /// and kept afterwards, so pseudonyms stay the same across changes.
+    pub(crate) fn set_redaction_policy(&self, policy: &RedactionPolicy) -> Result<RedactionPolicy> {
+        let salt = match self.get_redaction_settings(&policy.user_id)? {
+            Some((_, salt)) => salt,
+            None => {
+                let mut salt = [0u8; 16];
+                rand::thread_rng().fill_bytes(&mut salt);
+                to_hex(&salt)
+            }
+        };
+        let mut stored = policy.clone();
+        stored.updated_at = Utc::now();
+        self.conn.execute(
+            "INSERT OR REPLACE INTO redaction_policies (user_id, salt, detectors, on_ingest, on_export, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?)",
+            duckdb::params![
+                &stored.user_id,
+                salt,
+                serde_json::to_string(&stored.detectors).unwrap(),
+                stored.on_ingest,
+                stored.on_export,
+                stored.updated_at.to_string(),
+            ],
+        )?;
+        Ok(stored)
+    }
+
+    fn get_redaction_settings(&self, user_id: &str) -> Result<Option<(RedactionPolicy, String)>> {
+        self.conn
+            .query_row(
+                "SELECT user_id, detectors, on_ingest, on_export, updated_at, salt
+                 FROM redaction_policies WHERE user_id = ?",
+                [user_id],
+                |row| {
+                    let policy = RedactionPolicy {
+                        user_id: row.get(0)?,
+                        detectors: serde_json::from_str(&row.get::<_,String>(1)?).unwrap(),
+                        on_ingest: row.get(2)?,
+                        on_export: row.get(3)?,
+                        updated_at: row.get::<_,String>(4)?.parse::<DateTime<Utc>>().unwrap(),
+                    };
+                    Ok((policy, row.get(5)?))
+                },
+            )
+            .optional()
+    }
+
+    /// The user's policy with a redactor built from it, if they have one.
+    pub(crate) fn get_redactor(&self, user_id: &str) -> Result<Option<(RedactionPolicy, Redactor)>> {
+        let Some((policy, salt)) = self.get_redaction_settings(user_id)? else {
+            return Ok(None);
+        };
+        let mut stmt = self.conn.prepare("SELECT digest FROM redaction_names WHERE user_id = ?")?;
+        let names = stmt.query_map([user_id], |row| row.get::<_,String>(0))?.collect::<Result<HashSet<_>>>()?;
+        let redactor = Redactor::new(&salt, &policy.detectors, names);
+        Ok(Some((policy, redactor)))
+    }
+
+    fn get_device_redactor(&self, device_id: &str) -> Result<Option<(RedactionPolicy, Redactor)>> {
+        match self.get_device_owner(device_id)? {
+            Some(user_id) => self.get_redactor(&user_id),
+            None => Ok(None),
+        }
+    }
+
+    /// Remembers a call-log contact's name, as salted digests only, so it
+    /// is recognised in notification titles and notes.
+    fn learn_contact_name(&self, user_id: &str, redactor: &Redactor, name: &str) -> Result<()> {
+        if !redactor.is_enabled(PiiDetector::ContactName) || name.trim().is_empty() || crate::redaction::is_pseudonym(name) {
+            return Ok(());
+        }
+        // Known names are skipped rather than left to `OR IGNORE`, which
+        // DuckDB can trip over inside a transaction
+        for digest in redactor.name_digests(name).into_iter().filter(|d| !redactor.knows_name(d)) {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO redaction_names (user_id, digest) VALUES (?, ?)",
+                [user_id, &digest],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Applied by `insert_call_log_data`: learns the contact's name, then
+    /// redacts the row if the owner redacts on ingest.
+    pub(super) fn redact_call_log_on_ingest(&self, data: &CallLogData) -> Result<CallLogData> {
+        let Some((policy, redactor)) = self.get_device_redactor(&data.device_id)? else {
+            return Ok(data.clone());
+        };
+        if let Some(name) = &data.contact_name {
+            self.learn_contact_name(&policy.user_id, &redactor, name)?;
+        }
+        Ok(if policy.on_ingest { redactor.redact_call_log(data) } else { data.clone() })
+    }
+
+    pub(super) fn redact_on_ingest<T: Clone>(&self, device_id: &str, data: &T, redact: fn(&Redactor, &T) -> T) -> Result<T> {
+        Ok(match self.get_device_redactor(device_id)? {
+            Some((policy, redactor)) if policy.on_ingest => redact(&redactor, data),
+            _ => data.clone(),
+        })
+    }
+
+    pub(super) fn redact_note_on_ingest(&self, note: &Note) -> Result<Note> {
+        Ok(match self.get_redactor(&note.user_id)? {
+            Some((policy, redactor)) if policy.on_ingest => redactor.redact_note(note),
+            _ => note.clone(),
+        })
+    }
+
+    /// Call log rows as they may leave the app: redacted if the owner
+    /// redacts on export.
+    pub(crate) fn export_call_log_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<CallLogData>> {
+        let rows = self.get_call_log_data(device_id, start, end)?;
+        self.audit_export("call_log_data", device_id, start, end)?;
+        Ok(match self.get_device_redactor(device_id)? {
+            Some((policy, redactor)) if policy.on_export => rows.iter().map(|row| redactor.redact_call_log(row)).collect(),
+            _ => rows,
+        })
+    }
+
+    pub(crate) fn export_notification_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<NotificationData>> {
+        let rows = self.get_notification_data(device_id, start, end)?;
+        self.audit_export("notification_data", device_id, start, end)?;
+        Ok(match self.get_device_redactor(device_id)? {
+            Some((policy, redactor)) if policy.on_export => rows.iter().map(|row| redactor.redact_notification(row)).collect(),
+            _ => rows,
+        })
+    }
+
+    pub(crate) fn export_app_usage_data(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<AppUsageData>> {
+        let rows = self.get_app_usage_data(device_id, start, end)?;
+        self.audit_export("app_usage_data", device_id, start, end)?;
+        Ok(match self.get_device_redactor(device_id)? {
+            Some((policy, redactor)) if policy.on_export => rows.iter().map(|row| redactor.redact_app_usage(row)).collect(),
+            _ => rows,
+        })
+    }
+
+    pub(crate) fn export_notes(&self, user_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<Note>> {
+        let notes = self.get_notes(user_id, start, end)?;
+        self.redact_notes_on_export(user_id, notes)
+    }
+
+    pub(crate) fn export_all_notes(&self, user_id: &str) -> Result<Vec<Note>> {
+        let notes = self.get_all_notes(user_id)?;
+        self.redact_notes_on_export(user_id, notes)
+    }
+
+    fn redact_notes_on_export(&self, user_id: &str, notes: Vec<Note>) -> Result<Vec<Note>> {
+        Ok(match self.get_redactor(user_id)? {
+            Some((policy, redactor)) if policy.on_export => notes.iter().map(|note| redactor.redact_note(note)).collect(),
+            _ => notes,
+        })
+    }
+
+    /// Redacts what the user already has stored, whatever the policy's
+    /// stages, and rebuilds the search index so it doesn't keep the old
+    /// values. Contact names are learned from the whole call log first.
+    /// Encrypted values are decrypted and resealed, so this fails while
+    /// locked. Returns the number of rows changed per table.
+    pub(crate) fn redact_stored_data(&self, user_id: &str) -> Result<BTreeMap<String, usize>> {
+        let mut changed = BTreeMap::new();
+        self.in_transaction(|db| {
+            let Some((_, redactor)) = db.get_redactor(user_id)? else {
+                return Ok(());
+            };
+            let devices = db.get_device_ids_for_user(user_id)?;
+
+            let mut calls = Vec::new();
+            for device in &devices {
+                let mut stmt = db.conn.prepare(
+                    "SELECT rowid, phone_number, contact_name, metadata FROM call_log_data WHERE device_id = ?"
+                )?;
+                let rows = stmt
+                    .query_map([device], |row| Ok((row.get::<_,i64>(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+                    .collect::<Result<Vec<(i64, Option<String>, Option<String>, Option<String>)>>>()?;
+                for (rowid, phone_number, contact_name, metadata) in rows {
+                    let contact_name = db.open_optional_field(contact_name)?;
+                    if let Some(name) = &contact_name {
+                        db.learn_contact_name(user_id, &redactor, name)?;
+                    }
+                    calls.push((rowid, db.open_optional_field(phone_number)?, contact_name, metadata));
+                }
+            }
+            // Reloaded so it knows the names just learned
+            let (_, redactor) = db.get_redactor(user_id)?.unwrap();
+
+            db.audited("call_log_data", &devices, "true", &[], |db| {
+                for (rowid, phone_number, contact_name, metadata) in calls {
+                    let redacted_number = phone_number.as_deref().map(|n| redactor.redact_field(PiiDetector::PhoneNumber, n));
+                    let redacted_name = contact_name.as_deref().map(|n| redactor.redact_field(PiiDetector::ContactName, n));
+                    let redacted_metadata = redact_stored_metadata(&redactor, metadata.as_deref());
+                    if redacted_number == phone_number && redacted_name == contact_name && redacted_metadata.is_none() {
+                        continue;
+                    }
+                    db.conn.execute(
+                        "UPDATE call_log_data SET phone_number = ?, contact_name = ?, metadata = coalesce(?, metadata) WHERE rowid = ?",
+                        duckdb::params![
+                            redacted_number.map(|n| db.seal_field("call_log_data", "phone_number", &n)),
+                            redacted_name.map(|n| db.seal_field("call_log_data", "contact_name", &n)),
+                            redacted_metadata,
+                            rowid,
+                        ],
+                    )?;
+                    *changed.entry("call_log_data".to_string()).or_default() += 1;
+                }
+                Ok(())
+            })?;
+
+            for device in &devices {
+                db.audited("notification_data", std::slice::from_ref(device), "true", &[], |db| {
+                    let mut stmt = db.conn.prepare("SELECT rowid, title, metadata FROM notification_data WHERE device_id = ?")?;
+                    let rows = stmt
+                        .query_map([device], |row| Ok((row.get::<_,i64>(0)?, row.get(1)?, row.get(2)?)))?
+                        .collect::<Result<Vec<(i64, Option<String>, Option<String>)>>>()?;
+                    for (rowid, title, metadata) in rows {
+                        let title = db.open_optional_field(title)?;
+                        let redacted_title = title.as_deref().map(|t| redactor.redact_text(t));
+                        let redacted_metadata = redact_stored_metadata(&redactor, metadata.as_deref());
+                        if redacted_title == title && redacted_metadata.is_none() {
+                            continue;
+                        }
+                        db.conn.execute(
+                            "UPDATE notification_data SET title = ?, metadata = coalesce(?, metadata) WHERE rowid = ?",
+                            duckdb::params![
+                                redacted_title.map(|t| db.seal_field("notification_data", "title", &t)),
+                                redacted_metadata,
+                                rowid,
+                            ],
+                        )?;
+                        *changed.entry("notification_data".to_string()).or_default() += 1;
+                    }
+                    Ok(())
+                })?;
+
+                db.audited("app_usage_data", std::slice::from_ref(device), "true", &[], |db| {
+                    let mut stmt = db.conn.prepare(
+                        "SELECT rowid, metadata FROM app_usage_data WHERE device_id = ? AND metadata IS NOT NULL"
+                    )?;
+                    let rows = stmt
+                        .query_map([device], |row| Ok((row.get::<_,i64>(0)?, row.get::<_,String>(1)?)))?
+                        .collect::<Result<Vec<_>>>()?;
+                    for (rowid, metadata) in rows {
+                        if let Some(redacted) = redact_stored_metadata(&redactor, Some(&metadata)) {
+                            db.conn.execute("UPDATE app_usage_data SET metadata = ? WHERE rowid = ?", duckdb::params![redacted, rowid])?;
+                            *changed.entry("app_usage_data".to_string()).or_default() += 1;
+                        }
+                    }
+                    Ok(())
+                })?;
+            }
+
+            let mut stmt = db.conn.prepare("SELECT id, content FROM notes WHERE user_id = ?")?;
+            let notes = stmt
+                .query_map([user_id], |row| Ok((row.get::<_,String>(0)?, row.get::<_,String>(1)?)))?
+                .collect::<Result<Vec<_>>>()?;
+            for (id, content) in notes {
+                let content = db.open_field(content)?;
+                let redacted = redactor.redact_text(&content);
+                if redacted != content {
+                    db.conn.execute(
+                        "UPDATE notes SET content = ? WHERE id = ?",
+                        [&db.seal_field("notes", "content", &redacted), &id],
+                    )?;
+                    *changed.entry("notes".to_string()).or_default() += 1;
+                }
+            }
+            Ok(())
+        })?;
+
+        if !changed.is_empty() {
+            self.rebuild_search_index()?;
+        }
+        Ok(changed)
+    }
+}
+
+/// The redacted JSON for a stored metadata value, or `None` if nothing in
+/// it needed redacting.
+fn redact_stored_metadata(redactor: &Redactor, metadata: Option<&str>) -> Option<String> {
+    let metadata: Metadata = serde_json::from_str(metadata?).ok()?;
+    let redacted = redactor.redact_metadata(&metadata);
+    (redacted != metadata).then(|| serde_json::to_string(&redacted).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatypes::privacy::{DeletionScope, RedactionPolicy};
+    use crate::db::{fixtures::{device, temp_database, user}, AccessError, UserContext};
+
+    #[test]
+    fn test_redaction_policy_pseudonymises_contacts() -> std::result::Result<(), AccessError> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_user(&user("bob"))?;
+
+        let alice = db.scoped(UserContext::new("alice"));
+        let bob = db.scoped(UserContext::new("bob"));
+        alice.insert_device(&device("alice_phone", "alice"))?;
+
+        let policy = RedactionPolicy {
+            user_id: "alice".to_string(),
+            detectors: vec![PiiDetector::PhoneNumber, PiiDetector::ContactName],
+            on_ingest: false,
+            on_export: true,
+            updated_at: Utc::now(),
+        };
+        assert!(matches!(bob.set_redaction_policy(&policy), Err(AccessError::Denied(_))));
+        alice.set_redaction_policy(&policy)?;
+
+        let now = Utc::now();
+        let call = |minutes: i64, number: &str| CallLogData {
+            timestamp: now - chrono::Duration::minutes(minutes),
+            device_id: "alice_phone".to_string(),
+            call_type: "incoming".to_string(),
+            phone_number: Some(number.to_string()),
+            contact_name: Some("Sam Jones".to_string()),
+            duration_seconds: 60,
+            is_missed: false,
+            is_blocked: false,
+            sim_slot: None,
+            metadata: None,
+        };
+        alice.insert_call_log_data(&call(2, "+44 7700 900123"))?;
+        alice.insert_call_log_data(&call(1, "07700 900123"))?;
+        alice.insert_notification_data(&NotificationData {
+            timestamp: now,
+            device_id: "alice_phone".to_string(),
+            package_name: Some("org.example.chat".to_string()),
+            title: Some("Sam: call me on 07700 900123".to_string()),
+            priority: Some(0),
+            category: Some("msg".to_string()),
+            posted_at: Some(now),
+            removed_at: Some(now),
+            metadata: None,
+        })?;
+
+        // Stored as sent, redacted on the way out
+        let start = now - chrono::Duration::hours(1);
+        let calls = alice.get_call_log_data("alice_phone", start, now)?;
+        assert!(calls.iter().all(|c| c.contact_name.as_deref() == Some("Sam Jones")));
+        let exported = alice.export_call_log_data("alice_phone", start, now)?;
+        assert_eq!(exported[0].phone_number, exported[1].phone_number);
+        assert!(exported[0].phone_number.as_deref().unwrap().starts_with("[phone:"));
+        let title = alice.export_notification_data("alice_phone", start, now)?[0].title.clone().unwrap();
+        assert!(title.starts_with("[name:") && !title.contains("07700"), "{}", title);
+
+        let changed = alice.redact_stored_data()?;
+        assert_eq!(changed.get("call_log_data"), Some(&2));
+        assert_eq!(changed.get("notification_data"), Some(&1));
+        assert!(alice.redact_stored_data()?.is_empty());
+        assert!(alice.get_call_log_data("alice_phone", start, now)?.iter().all(|c| c.contact_name.as_deref() != Some("Sam Jones")));
+
+        // Forgetting the contact still finds the pseudonymised rows
+        let receipt = alice.erase(&DeletionScope::Contact { contact: "07700 900123".to_string() })?;
+        assert_eq!(receipt.total_rows, 3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use duckdb::{Connection, Result};
+
+/// The schema's history, oldest first. The first entry creates a new
+/// database and each later one migrates from the version before it, so a
+/// database's version is the number of entries applied to it. Entries are
+/// only ever appended: changing one that has shipped would leave databases
+/// that ran the old text on a schema nothing describes.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../../db-setup/init.sql"),
+    include_str!("../../db-setup/migrations/002_rebuild_for_ownership_and_privacy.sql"),
+    include_str!("../../db-setup/migrations/003_device_tokens.sql"),
+    include_str!("../../db-setup/migrations/004_encrypt_oauth_tokens.sql"),
+];
+
+/// The schema version this build creates and migrates databases to.
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+/// Every migration's SQL, oldest first.
+pub(super) fn migrations() -> &'static [&'static str] {
+    MIGRATIONS
+}
+
+/// Applies the migrations the database hasn't had yet, each in its own
+/// transaction, and returns the version it ends up at. A database from a
+/// newer build is left as it is.
+pub(super) fn migrate(conn: &Connection) -> Result<i64> {
+    migrate_to(conn, SCHEMA_VERSION)
+}
+
+/// Like `migrate`, but stops at `target`, so a backup can be restored into
+/// the schema it was taken with before being migrated like any database.
+pub(super) fn migrate_to(conn: &Connection, target: i64) -> Result<i64> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            applied_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+    )?;
+    let mut version = current_version(conn)?;
+    if version == 0 && has_unversioned_schema(conn)? {
+        conn.execute("INSERT INTO schema_version (version) VALUES (1)", [])?;
+        version = 1;
+    }
+
+    for sql in MIGRATIONS.iter().take(target.max(0) as usize).skip(version as usize) {
+        conn.execute_batch("BEGIN TRANSACTION")?;
+        let applied = conn
+            .execute_batch(sql)
+            .and_then(|_| conn.execute("INSERT INTO schema_version (version) VALUES (?)", [version + 1]));
+        if let Err(e) = applied {
+            let _ = conn.execute_batch("ROLLBACK");
+            return Err(e);
+        }
+        conn.execute_batch("COMMIT")?;
+        version += 1;
+    }
+    Ok(version)
+}
+
+pub(super) fn current_version(conn: &Connection) -> Result<i64> {
+    conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", [], |row| row.get(0))
+}
+
+/// Databases created before schema_version existed ran init.sql on every
+/// open and so hold version 1's tables without a record of it.
+fn has_unversioned_schema(conn: &Connection) -> Result<bool> {
+    let tables: i64 = conn.query_row(
+        "SELECT count(*) FROM information_schema.tables WHERE table_schema = 'main' AND table_name = 'users'",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(tables > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use crate::db::Database;
+
+    #[test]
+    fn test_reopening_leaves_the_schema_alone() -> Result<()> {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let db = Database::new(&path)?;
+        db.conn.execute("INSERT INTO users (id, email, encrypted_password) VALUES ('alice', 'a@example.com', 'x')", [])?;
+        drop(db);
+
+        let db = Database::new(&path)?;
+        assert_eq!(current_version(&db.conn)?, SCHEMA_VERSION);
+        let users: i64 = db.conn.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))?;
+        assert_eq!(users, 1);
+        let versions: i64 = db.conn.query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0))?;
+        assert_eq!(versions, SCHEMA_VERSION);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unversioned_version_1_databases_are_migrated() -> Result<()> {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("loom.db");
+        let conn = Connection::open(&path)?;
+        conn.execute_batch("INSTALL vss; LOAD vss; SET hnsw_enable_experimental_persistence = true;")?;
+        conn.execute_batch(MIGRATIONS[0])?;
+        conn.execute_batch(
+            "INSERT INTO users (id, email, encrypted_password) VALUES ('alice', 'a@example.com', 'x');
+            INSERT INTO devices (device_id, user_id, device_type, os_type, os_version, app_version, available_sensors)
+                VALUES ('phone', 'alice', 'phone', 'android', '14', '1.0', ['gps_data']);
+            INSERT INTO gps_data (timestamp, device_id, latitude, longitude)
+                VALUES ('2024-01-01 00:00:00', 'phone', 51.5, -0.1);
+            INSERT INTO known_entities (entity_id, type, label) VALUES ('bob', 'FACE', 'Bob');
+            INSERT INTO notes (id, user_id, timestamp, content, embedding)
+                VALUES ('n1', 'alice', '2024-01-01 00:00:00', 'hello', list_transform(range(1024), x -> 0.5)::FLOAT[1024]);",
+        )?;
+        drop(conn);
+
+        let db = Database::new(&path)?;
+        assert_eq!(current_version(&db.conn)?, SCHEMA_VERSION);
+        assert!(db.is_admin("alice")?);
+        let count = |sql: &str| -> Result<i64> { db.conn.query_row(sql, [], |row| row.get(0)) };
+        assert_eq!(count("SELECT count(*) FROM gps_data WHERE device_id = 'phone'")?, 1);
+        assert_eq!(count("SELECT count(*) FROM known_entities WHERE user_id = 'alice'")?, 1);
+        assert_eq!(count("SELECT count(*) FROM note_embeddings WHERE note_id = 'n1'")?, 1);
+        let sensors: String =
+            db.conn.query_row("SELECT available_sensors::VARCHAR FROM devices", [], |row| row.get(0))?;
+        assert_eq!(sensors, r#"["gps_data"]"#);
+        Ok(())
+    }
+}
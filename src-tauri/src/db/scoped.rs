@@ -0,0 +1,614 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use chrono::{DateTime, Utc};
+use duckdb::OptionalExt;
+use crate::datatypes::{
+    audit::{AuditEntry, AuditPolicy, AuditProof, AuditReport, AuditRoot},
+    config::{Consent, ConsentChange},
+    device::{Device, DeviceCapabilities},
+    note::{EntitySighting, KnownEntity, Note, NoteReference, NoteTarget},
+    privacy::{DeletionReceipt, DeletionScope, PrivacyZone, RedactionPolicy, Tombstone},
+    sensor::{AccelerometerData, GyroscopeData, MagnetometerData, GpsData, HeartRateData, ECGData, BloodOxygenData, StressData, ProximityData, LightData, PressureData, TemperatureData, HumidityData, StepCountData, AudioLevelData, BatteryData, NetworkData, ScreenStateData, NotificationData, AppUsageData, WifiData, CallLogData, TodosData},
+    types::{ConsentLevel, EntityType, Metadata},
+    user::{Session, User},
+};
+use crate::entities::EntityService;
+use super::{ConsentWithheld, Database, CONSENT_WILDCARD, KeywordQuery, NoteMatch, ParquetPartition, ResolvedReference, SearchHit, VectorQuery};
+
+/// The authenticated user a request runs as.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserContext {
+    pub user_id: String,
+}
+
+impl UserContext {
+    pub fn new(user_id: &str) -> Self {
+        Self { user_id: user_id.to_string() }
+    }
+}
+
+impl From<&User> for UserContext {
+    fn from(user: &User) -> Self {
+        Self::new(&user.id)
+    }
+}
+
+#[derive(Debug)]
+pub enum AccessError {
+    /// The device or note is unknown or belongs to another user. The two
+    /// cases are not told apart so ids of other users' data can't be probed.
+    Denied(String),
+    /// The user hasn't consented to rows from the device being stored in the
+    /// table.
+    ConsentWithheld(ConsentWithheld),
+    Database(duckdb::Error),
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessError::Denied(id) => write!(f, "access denied to {}", id),
+            AccessError::ConsentWithheld(e) => write!(f, "{}", e),
+            AccessError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AccessError {}
+
+/// Sensor inserts refuse rows the user withheld consent for inside the
+/// database layer, so the refusal arrives as a `duckdb::Error` and is picked
+/// out here.
+impl From<duckdb::Error> for AccessError {
+    fn from(e: duckdb::Error) -> Self {
+        match e {
+            duckdb::Error::ToSqlConversionFailure(inner) => match inner.downcast::<ConsentWithheld>() {
+                Ok(withheld) => AccessError::ConsentWithheld(*withheld),
+                Err(inner) => AccessError::Database(duckdb::Error::ToSqlConversionFailure(inner)),
+            },
+            e => AccessError::Database(e),
+        }
+    }
+}
+
+type ScopedResult<T> = std::result::Result<T, AccessError>;
+
+impl Database {
+    /// A view of the database limited to `user`'s devices and notes. Anything
+    /// acting on behalf of a signed-in user should go through this rather
+    /// than calling the unscoped methods directly.
+    pub fn scoped(&self, user: UserContext) -> ScopedDatabase<'_> {
+        ScopedDatabase { db: self, user }
+    }
+
+    pub(crate) fn get_device_owner(&self, device_id: &str) -> duckdb::Result<Option<String>> {
+        self.conn
+            .query_row("SELECT user_id FROM devices WHERE device_id = ?", [device_id], |row| row.get(0))
+            .optional()
+    }
+
+    pub(crate) fn get_device_ids_for_user(&self, user_id: &str) -> duckdb::Result<Vec<String>> {
+        let mut stmt = self.conn.prepare("SELECT device_id FROM devices WHERE user_id = ? ORDER BY device_id")?;
+        let rows = stmt.query_map([user_id], |row| row.get(0))?;
+        rows.collect()
+    }
+}
+
+pub struct ScopedDatabase<'a> {
+    db: &'a Database,
+    user: UserContext,
+}
+
+impl ScopedDatabase<'_> {
+    pub fn user(&self) -> &UserContext {
+        &self.user
+    }
+
+    pub fn authorize_device(&self, device_id: &str) -> ScopedResult<()> {
+        match self.db.get_device_owner(device_id)? {
+            Some(owner) if owner == self.user.user_id => Ok(()),
+            _ => Err(AccessError::Denied(device_id.to_string())),
+        }
+    }
+
+    fn authorize_note(&self, note_id: &str) -> ScopedResult<Note> {
+        match self.db.get_note(note_id).optional()? {
+            Some(note) if note.user_id == self.user.user_id => Ok(note),
+            _ => Err(AccessError::Denied(note_id.to_string())),
+        }
+    }
+
+    fn authorize_entity(&self, entity_id: &str) -> ScopedResult<KnownEntity> {
+        match self.db.get_known_entity(entity_id).optional()? {
+            Some(entity) if entity.user_id == self.user.user_id => Ok(entity),
+            _ => Err(AccessError::Denied(entity_id.to_string())),
+        }
+    }
+
+    /// The user's signed-in sessions. Tokens are stored hashed, so these
+    /// can't be used to sign in.
+    pub fn get_sessions(&self) -> ScopedResult<Vec<Session>> {
+        Ok(self.db.get_user_sessions(&self.user.user_id)?)
+    }
+
+    pub fn device_ids(&self) -> ScopedResult<Vec<String>> {
+        Ok(self.db.get_device_ids_for_user(&self.user.user_id)?)
+    }
+
+    pub fn get_device(&self, device_id: &str) -> ScopedResult<Device> {
+        self.authorize_device(device_id)?;
+        Ok(self.db.get_device(device_id)?)
+    }
+
+    pub fn insert_device(&self, device: &Device) -> ScopedResult<()> {
+        if device.user_id != self.user.user_id {
+            return Err(AccessError::Denied(device.device_id.clone()));
+        }
+        Ok(self.db.insert_device(device)?)
+    }
+
+    pub fn get_devices(&self, include_retired: bool) -> ScopedResult<Vec<Device>> {
+        Ok(self.db.get_devices_for_user(&self.user.user_id, include_retired)?)
+    }
+
+    pub fn update_device_capabilities(
+        &self,
+        device_id: &str,
+        capabilities: &DeviceCapabilities,
+        available_sensors: &[String],
+    ) -> ScopedResult<()> {
+        self.authorize_device(device_id)?;
+        Ok(self.db.update_device_capabilities(device_id, capabilities, available_sensors)?)
+    }
+
+    pub fn update_device_software(&self, device_id: &str, os_version: &str, app_version: &str) -> ScopedResult<()> {
+        self.authorize_device(device_id)?;
+        Ok(self.db.update_device_software(device_id, os_version, app_version)?)
+    }
+
+    pub fn heartbeat_device(&self, device_id: &str, at: DateTime<Utc>) -> ScopedResult<()> {
+        self.authorize_device(device_id)?;
+        Ok(self.db.heartbeat_device(device_id, at)?)
+    }
+
+    pub fn rename_device(&self, device_id: &str, name: Option<&str>) -> ScopedResult<()> {
+        self.authorize_device(device_id)?;
+        Ok(self.db.rename_device(device_id, name)?)
+    }
+
+    pub fn retire_device(&self, device_id: &str) -> ScopedResult<()> {
+        self.authorize_device(device_id)?;
+        Ok(self.db.retire_device(device_id, Utc::now())?)
+    }
+
+    /// Both devices have to belong to the user.
+    pub fn merge_devices(&self, old_id: &str, new_id: &str) -> ScopedResult<usize> {
+        self.authorize_device(old_id)?;
+        self.authorize_device(new_id)?;
+        Ok(self.db.merge_devices(old_id, new_id)?)
+    }
+
+    pub fn get_consents(&self) -> ScopedResult<Vec<Consent>> {
+        Ok(self.db.get_consents(&self.user.user_id)?)
+    }
+
+    pub fn get_consent_history(&self) -> ScopedResult<Vec<ConsentChange>> {
+        Ok(self.db.get_consent_history(&self.user.user_id)?)
+    }
+
+    /// `device_id` is either one of the user's devices or `*` for all of them.
+    pub fn set_consent(&self, device_id: &str, table: &str, level: ConsentLevel, purge: bool) -> ScopedResult<ConsentChange> {
+        if device_id != CONSENT_WILDCARD {
+            self.authorize_device(device_id)?;
+        }
+        Ok(self.db.set_consent(&self.user.user_id, device_id, table, level, purge)?)
+    }
+
+    /// Device ids named by the scope must belong to the user.
+    pub fn erase(&self, scope: &DeletionScope) -> ScopedResult<DeletionReceipt> {
+        match scope {
+            DeletionScope::TimeRange { device_id: Some(device_id), .. } | DeletionScope::Device { device_id } => {
+                self.authorize_device(device_id)?
+            }
+            _ => {}
+        }
+        Ok(self.db.erase(&self.user.user_id, scope)?)
+    }
+
+    pub fn get_deletion_receipts(&self) -> ScopedResult<Vec<DeletionReceipt>> {
+        Ok(self.db.get_deletion_receipts(&self.user.user_id)?)
+    }
+
+    pub fn get_tombstones(&self, since: Option<DateTime<Utc>>) -> ScopedResult<Vec<Tombstone>> {
+        Ok(self.db.get_tombstones(&self.user.user_id, since)?)
+    }
+
+    pub fn get_privacy_zones(&self) -> ScopedResult<Vec<PrivacyZone>> {
+        Ok(self.db.get_privacy_zones(&self.user.user_id)?)
+    }
+
+    /// The zone must be the user's, and so must any zone it replaces.
+    pub fn set_privacy_zone(&self, zone: &PrivacyZone, apply_to_existing: bool) -> ScopedResult<usize> {
+        let replaces_other = self.db.get_privacy_zone(&zone.id)?.is_some_and(|existing| existing.user_id != self.user.user_id);
+        if zone.user_id != self.user.user_id || replaces_other {
+            return Err(AccessError::Denied(zone.id.clone()));
+        }
+        Ok(self.db.set_privacy_zone(zone, apply_to_existing)?)
+    }
+
+    pub fn delete_privacy_zone(&self, id: &str) -> ScopedResult<usize> {
+        Ok(self.db.delete_privacy_zone(&self.user.user_id, id)?)
+    }
+
+    pub fn get_redaction_policy(&self) -> ScopedResult<Option<RedactionPolicy>> {
+        Ok(self.db.get_redaction_policy(&self.user.user_id)?)
+    }
+
+    pub fn set_redaction_policy(&self, policy: &RedactionPolicy) -> ScopedResult<RedactionPolicy> {
+        if policy.user_id != self.user.user_id {
+            return Err(AccessError::Denied(policy.user_id.clone()));
+        }
+        Ok(self.db.set_redaction_policy(policy)?)
+    }
+
+    pub fn redact_stored_data(&self) -> ScopedResult<BTreeMap<String, usize>> {
+        Ok(self.db.redact_stored_data(&self.user.user_id)?)
+    }
+
+    pub fn get_audit_policy(&self) -> ScopedResult<Option<AuditPolicy>> {
+        Ok(self.db.get_audit_policy(&self.user.user_id)?)
+    }
+
+    pub fn set_audit_policy(&self, policy: &AuditPolicy) -> ScopedResult<AuditPolicy> {
+        if policy.user_id != self.user.user_id {
+            return Err(AccessError::Denied(policy.user_id.clone()));
+        }
+        Ok(self.db.set_audit_policy(policy)?)
+    }
+
+    pub fn get_audit_log(&self) -> ScopedResult<Vec<AuditEntry>> {
+        Ok(self.db.get_audit_log(&self.user.user_id)?)
+    }
+
+    pub fn get_audit_roots(&self) -> ScopedResult<Vec<AuditRoot>> {
+        Ok(self.db.get_audit_roots(&self.user.user_id)?)
+    }
+
+    pub fn verify_audit_log(&self) -> ScopedResult<AuditReport> {
+        Ok(self.db.verify_audit_log(&self.user.user_id)?)
+    }
+
+    pub fn export_audit_proofs(
+        &self,
+        table: &str,
+        device_id: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> ScopedResult<Vec<AuditProof>> {
+        self.authorize_device(device_id)?;
+        Ok(self.db.export_audit_proofs(&self.user.user_id, table, device_id, start, end)?)
+    }
+
+    /// Exports `device_ids`, or all of the user's devices when empty.
+    pub fn export_parquet(
+        &self,
+        tables: &[String],
+        device_ids: &[String],
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        dir: &Path,
+    ) -> ScopedResult<Vec<ParquetPartition>> {
+        let device_ids = if device_ids.is_empty() {
+            self.device_ids()?
+        } else {
+            for device_id in device_ids {
+                self.authorize_device(device_id)?;
+            }
+            device_ids.to_vec()
+        };
+        Ok(self.db.export_parquet(tables, &device_ids, start, end, dir)?)
+    }
+
+    /// The todos as they stand on the given devices, or all the user's if
+    /// none are given: the newest row for each `todo_id`, whichever device
+    /// wrote it.
+    pub fn get_latest_todos(&self, device_ids: &[String]) -> ScopedResult<Vec<TodosData>> {
+        let device_ids = if device_ids.is_empty() {
+            self.device_ids()?
+        } else {
+            for device_id in device_ids {
+                self.authorize_device(device_id)?;
+            }
+            device_ids.to_vec()
+        };
+        let mut latest: BTreeMap<String, TodosData> = BTreeMap::new();
+        for device_id in &device_ids {
+            for todo in self.db.get_latest_todos(device_id)? {
+                if latest.get(&todo.todo_id).is_none_or(|current| current.timestamp < todo.timestamp) {
+                    latest.insert(todo.todo_id.clone(), todo);
+                }
+            }
+        }
+        Ok(latest.into_values().collect())
+    }
+
+    pub fn insert_note(&self, note: &Note) -> ScopedResult<()> {
+        if note.user_id != self.user.user_id {
+            return Err(AccessError::Denied(note.id.clone()));
+        }
+        Ok(self.db.insert_note(note)?)
+    }
+
+    pub fn get_note(&self, note_id: &str) -> ScopedResult<Note> {
+        self.authorize_note(note_id)
+    }
+
+    pub fn get_notes(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> ScopedResult<Vec<Note>> {
+        Ok(self.db.get_notes(&self.user.user_id, start, end)?)
+    }
+
+    pub fn export_notes(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> ScopedResult<Vec<Note>> {
+        Ok(self.db.export_notes(&self.user.user_id, start, end)?)
+    }
+
+    pub fn get_all_notes(&self) -> ScopedResult<Vec<Note>> {
+        Ok(self.db.get_all_notes(&self.user.user_id)?)
+    }
+
+    pub fn export_all_notes(&self) -> ScopedResult<Vec<Note>> {
+        Ok(self.db.export_all_notes(&self.user.user_id)?)
+    }
+
+    /// Rewrites one of the user's notes. The new parent has to be theirs too.
+    pub fn update_note(&self, note: &Note) -> ScopedResult<()> {
+        self.authorize_note(&note.id)?;
+        if note.user_id != self.user.user_id {
+            return Err(AccessError::Denied(note.id.clone()));
+        }
+        if let Some(parent_id) = &note.parent_id {
+            self.authorize_note(parent_id)?;
+        }
+        self.db.update_note(note)?;
+        Ok(())
+    }
+
+    pub fn get_note_references(&self, note_id: &str) -> ScopedResult<Vec<NoteReference>> {
+        self.authorize_note(note_id)?;
+        Ok(self.db.get_note_references(note_id)?)
+    }
+
+    pub fn get_notes_mentioning(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> ScopedResult<Vec<Note>> {
+        Ok(self.db.get_notes_mentioning(&self.user.user_id, start, end)?)
+    }
+
+    /// Sensor rows can only be attached if they come from one of the user's
+    /// devices, and entities if they are the user's.
+    pub fn attach_note(&self, note_id: &str, target: &NoteTarget, metadata: Option<&Metadata>) -> ScopedResult<NoteReference> {
+        self.authorize_note(note_id)?;
+        match target {
+            NoteTarget::SensorEvent { device_id, .. } => self.authorize_device(device_id)?,
+            NoteTarget::Entity { entity_id } => {
+                self.authorize_entity(entity_id)?;
+            }
+            _ => {}
+        }
+        Ok(self.db.attach_note(note_id, target, metadata)?)
+    }
+
+    pub fn detach_note(&self, note_id: &str, target: &NoteTarget) -> ScopedResult<usize> {
+        self.authorize_note(note_id)?;
+        Ok(self.db.detach_note(note_id, target)?)
+    }
+
+    /// Like `Database::resolve_note_references`, but sensor rows, notes and
+    /// entities that belong to someone else resolve as missing.
+    pub fn resolve_note_references(&self, note_id: &str) -> ScopedResult<Vec<(NoteReference, ResolvedReference)>> {
+        self.authorize_note(note_id)?;
+
+        let mut resolved = Vec::new();
+        for (reference, target) in self.db.resolve_note_references(note_id)? {
+            let visible = match &reference.target() {
+                Some(NoteTarget::SensorEvent { device_id, .. }) => self.authorize_device(device_id).is_ok(),
+                Some(NoteTarget::Note { note_id }) => self.authorize_note(note_id).is_ok(),
+                Some(NoteTarget::Entity { entity_id }) => self.authorize_entity(entity_id).is_ok(),
+                _ => true,
+            };
+            let target = if visible {
+                target
+            } else {
+                ResolvedReference::Missing {
+                    reference_type: reference.reference_type.clone(),
+                    reference_id: reference.reference_id.clone(),
+                }
+            };
+            resolved.push((reference, target));
+        }
+        Ok(resolved)
+    }
+
+    pub fn search_keyword(&self, query: &KeywordQuery) -> ScopedResult<Vec<SearchHit>> {
+        Ok(self.db.search_keyword(&self.restrict_keyword(query))?)
+    }
+
+    pub fn search_hybrid(&self, query: &KeywordQuery, vector: &[f32], alpha: f64) -> ScopedResult<Vec<SearchHit>> {
+        Ok(self.db.search_hybrid(&self.restrict_keyword(query), vector, alpha)?)
+    }
+
+    pub fn search_notes_by_vector(&self, query: &VectorQuery) -> ScopedResult<Vec<NoteMatch>> {
+        let mut query = query.clone();
+        query.user_id = Some(self.user.user_id.clone());
+        Ok(self.db.search_notes_by_vector(&query)?)
+    }
+
+    /// "When did I last see X", counting only sightings from the user's devices.
+    pub fn last_seen(&self, label: &str) -> ScopedResult<Option<(KnownEntity, EntitySighting)>> {
+        Ok(self.db.get_last_sighting_by_label(label, Some(&self.user.user_id))?)
+    }
+
+    pub fn get_known_entities(&self, entity_type: Option<&EntityType>) -> ScopedResult<Vec<KnownEntity>> {
+        Ok(self.db.get_known_entities(&self.user.user_id, entity_type)?)
+    }
+
+    pub fn rename_entity(&self, entity_id: &str, label: &str) -> ScopedResult<()> {
+        self.authorize_entity(entity_id)?;
+        Ok(self.db.rename_known_entity(entity_id, label)?)
+    }
+
+    pub fn delete_entity(&self, entity_id: &str) -> ScopedResult<()> {
+        self.authorize_entity(entity_id)?;
+        Ok(self.db.delete_known_entity(entity_id)?)
+    }
+
+    pub fn get_entity_sightings(&self, entity_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> ScopedResult<Vec<EntitySighting>> {
+        self.authorize_entity(entity_id)?;
+        Ok(self.db.get_entity_sightings(entity_id, start, end)?)
+    }
+
+    pub fn get_last_sighting(&self, entity_id: &str) -> ScopedResult<Option<EntitySighting>> {
+        self.authorize_entity(entity_id)?;
+        Ok(self.db.get_last_sighting(entity_id)?)
+    }
+
+    /// Folds one of the user's entities into another of theirs.
+    pub fn merge_entities(&self, kept_id: &str, merged_id: &str) -> ScopedResult<KnownEntity> {
+        self.authorize_entity(kept_id)?;
+        self.authorize_entity(merged_id)?;
+        Ok(EntityService::new(self.db).merge(kept_id, merged_id)?)
+    }
+
+    /// Splits sightings off one of the user's entities into a new one they own.
+    pub fn split_entity(&self, entity_id: &str, sighting_ids: &[String], label: &str) -> ScopedResult<KnownEntity> {
+        self.authorize_entity(entity_id)?;
+        Ok(EntityService::new(self.db).split(entity_id, sighting_ids, label)?)
+    }
+
+    fn restrict_keyword(&self, query: &KeywordQuery) -> KeywordQuery {
+        let mut query = query.clone();
+        query.user_id = Some(self.user.user_id.clone());
+        query
+    }
+}
+
+/// Wraps per-device sensor reads so they fail unless the device belongs to
+/// the scoped user.
+macro_rules! scoped_reads {
+    ($($get:ident => $data:ty,)*) => {
+        impl ScopedDatabase<'_> {
+            $(
+                pub fn $get(&self, device_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> ScopedResult<Vec<$data>> {
+                    self.authorize_device(device_id)?;
+                    Ok(self.db.$get(device_id, start, end)?)
+                }
+            )*
+        }
+    };
+}
+
+/// Same for inserts, checked against the row's own device_id.
+macro_rules! scoped_writes {
+    ($($insert:ident => $data:ty,)*) => {
+        impl ScopedDatabase<'_> {
+            $(
+                pub fn $insert(&self, data: &$data) -> ScopedResult<()> {
+                    self.authorize_device(&data.device_id)?;
+                    Ok(self.db.$insert(data)?)
+                }
+            )*
+        }
+    };
+}
+
+scoped_reads! {
+    get_accelerometer_data => AccelerometerData,
+    get_gyroscope_data => GyroscopeData,
+    get_magnetometer_data => MagnetometerData,
+    get_gps_data => GpsData,
+    get_heart_rate_data => HeartRateData,
+    get_ecg_data => ECGData,
+    get_blood_oxygen_data => BloodOxygenData,
+    get_stress_data => StressData,
+    get_proximity_data => ProximityData,
+    get_light_data => LightData,
+    get_pressure_data => PressureData,
+    get_temperature_data => TemperatureData,
+    get_humidity_data => HumidityData,
+    get_step_count_data => StepCountData,
+    get_call_log_data => CallLogData,
+    get_todos_data => TodosData,
+    get_audio_level_data => AudioLevelData,
+    get_battery_data => BatteryData,
+    get_screen_state_data => ScreenStateData,
+    get_notification_data => NotificationData,
+    get_app_usage_data => AppUsageData,
+    get_wifi_data => WifiData,
+    export_gps_data => GpsData,
+    export_wifi_data => WifiData,
+    export_call_log_data => CallLogData,
+    export_notification_data => NotificationData,
+    export_app_usage_data => AppUsageData,
+}
+
+scoped_writes! {
+    insert_accelerometer_data => AccelerometerData,
+    insert_gyroscope_data => GyroscopeData,
+    insert_magnetometer_data => MagnetometerData,
+    insert_gps_data => GpsData,
+    insert_heart_rate_data => HeartRateData,
+    insert_ecg_data => ECGData,
+    insert_blood_oxygen_data => BloodOxygenData,
+    insert_stress_data => StressData,
+    insert_light_data => LightData,
+    insert_pressure_data => PressureData,
+    insert_temperature_data => TemperatureData,
+    insert_humidity_data => HumidityData,
+    insert_step_count_data => StepCountData,
+    insert_call_log_data => CallLogData,
+    insert_todos_data => TodosData,
+    insert_audio_level_data => AudioLevelData,
+    insert_battery_data => BatteryData,
+    insert_network_data => NetworkData,
+    insert_screen_state_data => ScreenStateData,
+    insert_notification_data => NotificationData,
+    insert_app_usage_data => AppUsageData,
+    insert_wifi_data => WifiData,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::fixtures::{device, temp_database, user};
+
+    #[test]
+    fn test_scoped_access_is_limited_to_own_devices() -> ScopedResult<()> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_user(&user("bob"))?;
+
+        let alice = db.scoped(UserContext::new("alice"));
+        let bob = db.scoped(UserContext::new("bob"));
+        alice.insert_device(&device("alice_phone", "alice"))?;
+        assert!(matches!(bob.insert_device(&device("sneaky", "alice")), Err(AccessError::Denied(_))));
+
+        let reading = AccelerometerData {
+            timestamp: Utc::now(),
+            device_id: "alice_phone".to_string(),
+            x: 0.0,
+            y: 0.0,
+            z: 9.8,
+            accuracy: None,
+            metadata: None,
+        };
+        alice.insert_accelerometer_data(&reading)?;
+        assert!(matches!(bob.insert_accelerometer_data(&reading), Err(AccessError::Denied(_))));
+
+        let start = Utc::now() - chrono::Duration::hours(1);
+        let end = Utc::now() + chrono::Duration::hours(1);
+        assert_eq!(alice.get_accelerometer_data("alice_phone", start, end)?.len(), 1);
+        assert!(matches!(bob.get_accelerometer_data("alice_phone", start, end), Err(AccessError::Denied(_))));
+        assert!(matches!(bob.get_accelerometer_data("no_such_device", start, end), Err(AccessError::Denied(_))));
+        assert!(bob.device_ids()?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use chrono::{DateTime, Utc};
+use duckdb::{OptionalExt, Result};
+use crate::datatypes::user::{DeviceToken, Session, User};
+use super::Database;
+
+const USER_COLUMNS: &str = "id, email, name, encrypted_password, created_at, updated_at";
+const SESSION_COLUMNS: &str = "id, user_id, token, expires_at, created_at";
+const DEVICE_TOKEN_COLUMNS: &str = "id, user_id, device_id, token, created_at, last_used_at";
+
+impl Database {
+    pub(crate) fn get_user_by_email(&self, email: &str) -> Result<Option<User>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM users WHERE lower(email) = lower(?)", USER_COLUMNS
+        ))?;
+        stmt.query_row([email], user_from_row).optional()
+    }
+
+    pub(crate) fn update_user_password(&self, user_id: &str, encrypted_password: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE users SET encrypted_password = ?, updated_at = ? WHERE id = ?",
+            [encrypted_password, &Utc::now().to_string(), user_id],
+        )?;
+        Ok(())
+    }
+
+    /// Makes `user_id` the administrator if it is the only account so far.
+    pub(crate) fn claim_admin_if_first(&self, user_id: &str) -> Result<bool> {
+        let rows = self.conn.execute(
+            "UPDATE users SET is_admin = true WHERE id = ? AND (SELECT count(*) FROM users) = 1",
+            [user_id],
+        )?;
+        Ok(rows > 0)
+    }
+
+    pub(crate) fn is_admin(&self, user_id: &str) -> Result<bool> {
+        let admin = self.conn
+            .query_row("SELECT is_admin FROM users WHERE id = ?", [user_id], |row| row.get(0))
+            .optional()?;
+        Ok(admin.unwrap_or(false))
+    }
+
+    /// `session.token` must already be hashed; raw tokens never reach the database.
+    pub(crate) fn insert_session(&self, session: &Session) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO sessions (id, user_id, token, expires_at, created_at) VALUES (?, ?, ?, ?, ?)",
+            [
+                &session.id,
+                &session.user_id,
+                &session.token,
+                &session.expires_at.to_string(),
+                &session.created_at.to_string(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn get_session_by_token(&self, token_hash: &str) -> Result<Option<Session>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM sessions WHERE token = ?", SESSION_COLUMNS
+        ))?;
+        stmt.query_row([token_hash], session_from_row).optional()
+    }
+
+    pub(crate) fn get_user_sessions(&self, user_id: &str) -> Result<Vec<Session>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM sessions WHERE user_id = ? ORDER BY created_at", SESSION_COLUMNS
+        ))?;
+        let rows = stmt.query_map([user_id], session_from_row)?;
+        rows.collect()
+    }
+
+    pub(crate) fn set_session_expiry(&self, session_id: &str, expires_at: DateTime<Utc>) -> Result<()> {
+        self.conn.execute(
+            "UPDATE sessions SET expires_at = ? WHERE id = ?",
+            [&expires_at.to_string(), session_id],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn delete_session(&self, session_id: &str) -> Result<()> {
+        self.conn.execute("DELETE FROM sessions WHERE id = ?", [session_id])?;
+        Ok(())
+    }
+
+    /// Revokes every session of a user, optionally sparing one (the caller's own).
+    pub(crate) fn delete_user_sessions(&self, user_id: &str, except_session_id: Option<&str>) -> Result<usize> {
+        self.conn.execute(
+            "DELETE FROM sessions WHERE user_id = ? AND id IS DISTINCT FROM ?",
+            duckdb::params![user_id, except_session_id],
+        )
+    }
+
+    pub(crate) fn delete_expired_sessions(&self, now: DateTime<Utc>) -> Result<usize> {
+        self.conn.execute("DELETE FROM sessions WHERE expires_at <= ?", [now.to_string()])
+    }
+
+    /// `device_token.token` must already be hashed, as for sessions.
+    pub(crate) fn insert_device_token(&self, device_token: &DeviceToken) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO device_tokens (id, user_id, device_id, token, created_at) VALUES (?, ?, ?, ?, ?)",
+            [
+                &device_token.id,
+                &device_token.user_id,
+                &device_token.device_id,
+                &device_token.token,
+                &device_token.created_at.to_string(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn get_device_token_by_token(&self, token_hash: &str) -> Result<Option<DeviceToken>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM device_tokens WHERE token = ?", DEVICE_TOKEN_COLUMNS
+        ))?;
+        stmt.query_row([token_hash], device_token_from_row).optional()
+    }
+
+    pub(crate) fn get_device_tokens(&self, user_id: &str) -> Result<Vec<DeviceToken>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM device_tokens WHERE user_id = ? ORDER BY device_id, created_at", DEVICE_TOKEN_COLUMNS
+        ))?;
+        let rows = stmt.query_map([user_id], device_token_from_row)?;
+        rows.collect()
+    }
+
+    pub(crate) fn set_device_token_used(&self, id: &str, at: DateTime<Utc>) -> Result<()> {
+        self.conn.execute("UPDATE device_tokens SET last_used_at = ? WHERE id = ?", [&at.to_string(), id])?;
+        Ok(())
+    }
+
+    pub(crate) fn delete_device_token(&self, user_id: &str, id: &str) -> Result<usize> {
+        self.conn.execute("DELETE FROM device_tokens WHERE user_id = ? AND id = ?", [user_id, id])
+    }
+}
+
+fn user_from_row(row: &duckdb::Row<'_>) -> Result<User> {
+    Ok(User {
+        id: row.get(0)?,
+        email: row.get(1)?,
+        name: row.get(2)?,
+        encrypted_password: row.get(3)?,
+        created_at: row.get::<_,String>(4)?.parse::<DateTime<Utc>>().unwrap(),
+        updated_at: row.get::<_,String>(5)?.parse::<DateTime<Utc>>().unwrap(),
+    })
+}
+
+fn session_from_row(row: &duckdb::Row<'_>) -> Result<Session> {
+    Ok(Session {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        token: row.get(2)?,
+        expires_at: row.get::<_,String>(3)?.parse::<DateTime<Utc>>().unwrap(),
+        created_at: row.get::<_,String>(4)?.parse::<DateTime<Utc>>().unwrap(),
+    })
+}
+
+fn device_token_from_row(row: &duckdb::Row<'_>) -> Result<DeviceToken> {
+    Ok(DeviceToken {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        device_id: row.get(2)?,
+        token: row.get(3)?,
+        created_at: row.get::<_,String>(4)?.parse::<DateTime<Utc>>().unwrap(),
+        last_used_at: row.get::<_,Option<String>>(5)?.map(|s| s.parse::<DateTime<Utc>>().unwrap()),
+    })
+}
@@ -0,0 +1,298 @@
+use chrono::{DateTime, Utc};
+use duckdb::Result;
+use serde::{Deserialize, Serialize};
+use crate::datatypes::{note::{KnownEntity, Note}, types::EntityType};
+use crate::embedding::EMBEDDING_DIMENSIONS;
+use super::{entities::{entity_from_row, ENTITY_COLUMNS}, notes::{note_from_row, NOTE_COLUMNS}, Database};
+
+/// Distance function used to rank neighbours. The HNSW indexes in init.sql
+/// are built with the vss default (`l2sq`), so `L2` is the one that can use
+/// them; the others fall back to a scan.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DistanceMetric {
+    #[default]
+    L2,
+    Cosine,
+    InnerProduct,
+}
+
+impl DistanceMetric {
+    fn sql_function(&self) -> &'static str {
+        match self {
+            DistanceMetric::L2 => "array_distance",
+            DistanceMetric::Cosine => "array_cosine_distance",
+            DistanceMetric::InnerProduct => "array_negative_inner_product",
+        }
+    }
+}
+
+/// A k-nearest-neighbour query. Filters left as `None`/empty are not applied.
+#[derive(Debug, Clone)]
+pub struct VectorQuery {
+    pub vector: Vec<f32>,
+    pub k: usize,
+    pub metric: DistanceMetric,
+    pub user_id: Option<String>,
+    /// Match notes carrying any of these tags.
+    pub tags: Vec<String>,
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+}
+
+impl VectorQuery {
+    pub fn new(vector: Vec<f32>, k: usize) -> Self {
+        Self {
+            vector,
+            k,
+            metric: DistanceMetric::default(),
+            user_id: None,
+            tags: Vec::new(),
+            start: None,
+            end: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct NoteMatch {
+    pub note: Note,
+    pub distance: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntityMatch {
+    pub entity: KnownEntity,
+    pub distance: f32,
+}
+
+impl Database {
+    /// Stores or replaces a note's embedding. The note itself isn't touched,
+    /// so embedding it doesn't count as an edit. DuckDB can't re-insert a key
+    /// deleted in the same transaction, so this must not run inside one that
+    /// already embedded the note.
+    pub(crate) fn set_note_embedding(&self, note_id: &str, embedding: &[f32]) -> Result<()> {
+        let vector = vector_literal(embedding)?;
+        self.conn.execute("DELETE FROM note_embeddings WHERE note_id = ?", [note_id])?;
+        self.conn.execute(
+            &format!("INSERT INTO note_embeddings (note_id, embedding, embedded_at) VALUES (?, ?::FLOAT[{}], ?)", EMBEDDING_DIMENSIONS),
+            [note_id, &vector, &Utc::now().to_string()],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn set_entity_embedding(&self, entity_id: &str, embedding: &[f32]) -> Result<()> {
+        self.conn.execute(
+            &format!("UPDATE known_entities SET embedding = ?::FLOAT[{}], updated_at = ? WHERE entity_id = ?", EMBEDDING_DIMENSIONS),
+            [&vector_literal(embedding)?, &Utc::now().to_string(), entity_id],
+        )?;
+        Ok(())
+    }
+
+    /// (id, content) of notes still waiting for an embedding, oldest first.
+    /// Encrypted notes are skipped while locked and picked up once unlocked.
+    pub(crate) fn get_notes_without_embedding(&self, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut sql = String::from(
+            "SELECT id, content FROM notes WHERE NOT EXISTS (SELECT 1 FROM note_embeddings e WHERE e.note_id = notes.id)",
+        );
+        if self.is_locked() {
+            sql.push_str(" AND content NOT LIKE 'enc:v1:%'");
+        }
+        sql.push_str(" ORDER BY created_at LIMIT ?");
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map([limit as i64], |row| Ok((row.get(0)?, self.open_field(row.get(1)?)?)))?;
+        rows.collect()
+    }
+
+    pub(crate) fn search_notes_by_vector(&self, query: &VectorQuery) -> Result<Vec<NoteMatch>> {
+        let mut sql = format!(
+            "SELECT {}, {}(e.embedding, ?::FLOAT[{}]) AS distance
+             FROM notes JOIN note_embeddings e ON e.note_id = notes.id WHERE true",
+            NOTE_COLUMNS, query.metric.sql_function(), EMBEDDING_DIMENSIONS
+        );
+        let mut params = vec![vector_literal(&query.vector)?];
+
+        if let Some(user_id) = &query.user_id {
+            sql.push_str(" AND user_id = ?");
+            params.push(user_id.clone());
+        }
+        if let Some(start) = query.start {
+            sql.push_str(" AND timestamp >= ?");
+            params.push(start.to_string());
+        }
+        if let Some(end) = query.end {
+            sql.push_str(" AND timestamp <= ?");
+            params.push(end.to_string());
+        }
+        if !query.tags.is_empty() {
+            let placeholders = vec!["?"; query.tags.len()].join(", ");
+            sql.push_str(&format!(" AND list_has_any(tags, [{}])", placeholders));
+            params.extend(query.tags.iter().cloned());
+        }
+        sql.push_str(&format!(" ORDER BY distance LIMIT {}", query.k));
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(duckdb::params_from_iter(params), |row| {
+            Ok(NoteMatch {
+                note: self.open_note(note_from_row(row)?)?,
+                distance: row.get(10)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Nearest known entities to `query.vector`, optionally of one type.
+    /// Of the filters, only `user_id` applies to entities.
+    pub(crate) fn search_entities_by_vector(&self, query: &VectorQuery, entity_type: Option<&EntityType>) -> Result<Vec<EntityMatch>> {
+        let mut sql = format!(
+            "SELECT {}, {}(embedding, ?::FLOAT[{}]) AS distance FROM known_entities WHERE embedding IS NOT NULL",
+            ENTITY_COLUMNS, query.metric.sql_function(), EMBEDDING_DIMENSIONS
+        );
+        let mut params = vec![vector_literal(&query.vector)?];
+
+        if let Some(user_id) = &query.user_id {
+            sql.push_str(" AND user_id = ?");
+            params.push(user_id.clone());
+        }
+        if let Some(entity_type) = entity_type {
+            sql.push_str(" AND type = ?");
+            params.push(serde_json::to_value(entity_type).unwrap().as_str().unwrap().to_string());
+        }
+        sql.push_str(&format!(" ORDER BY distance LIMIT {}", query.k));
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(duckdb::params_from_iter(params), |row| {
+            Ok(EntityMatch {
+                entity: entity_from_row(row)?,
+                distance: row.get(7)?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+/// Renders an embedding as a DuckDB list literal, rejecting vectors that
+/// don't fit the FLOAT[1024] columns.
+pub(super) fn vector_literal(vector: &[f32]) -> Result<String> {
+    if vector.len() != EMBEDDING_DIMENSIONS {
+        return Err(duckdb::Error::ToSqlConversionFailure(
+            format!("expected a {}-dimensional vector, got {}", EMBEDDING_DIMENSIONS, vector.len()).into(),
+        ));
+    }
+    Ok(serde_json::to_string(vector).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use std::sync::{Arc, Mutex};
+    use crate::datatypes::{note::NoteTarget, types::NotePriority, user::User};
+    use crate::embedding::{embed_pending_notes, Embedder, HashEmbedder};
+
+    #[test]
+    fn test_search_notes_by_vector() -> Result<()> {
+        let dir = tempdir().unwrap();
+        let db = Database::new(&dir.path().join("test.db"))?;
+        db.insert_user(&User {
+            id: "test_user".to_string(),
+            email: "test@example.com".to_string(),
+            name: None,
+            encrypted_password: "x".to_string(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+
+        let embedder = HashEmbedder::default();
+        for (id, content) in [("n1", "dentist appointment tuesday"), ("n2", "ran 5k along the river")] {
+            db.insert_note(&Note {
+                id: id.to_string(),
+                user_id: "test_user".to_string(),
+                timestamp: Utc::now(),
+                content: content.to_string(),
+                priority: NotePriority::Medium,
+                parent_id: None,
+                tags: None,
+                embedding: Some(embedder.embed(content).unwrap()),
+                metadata: None,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })?;
+        }
+
+        let mut query = VectorQuery::new(embedder.embed("dentist").unwrap(), 1);
+        query.user_id = Some("test_user".to_string());
+        let matches = db.search_notes_by_vector(&query)?;
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].note.id, "n1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_embedding_referenced_notes() -> Result<()> {
+        let dir = tempdir().unwrap();
+        let db = Database::new(&dir.path().join("test.db"))?;
+        db.insert_user(&User {
+            id: "test_user".to_string(),
+            email: "test@example.com".to_string(),
+            name: None,
+            encrypted_password: "x".to_string(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+
+        let note = |id: &str, content: &str, parent_id: Option<&str>| Note {
+            id: id.to_string(),
+            user_id: "test_user".to_string(),
+            timestamp: Utc::now(),
+            content: content.to_string(),
+            priority: NotePriority::Medium,
+            parent_id: parent_id.map(str::to_string),
+            tags: None,
+            embedding: None,
+            metadata: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        db.insert_note(&note("n1", "dentist appointment tuesday", None))?;
+        db.insert_note(&note("n2", "bring the insurance card", Some("n1")))?;
+        db.attach_note("n2", &NoteTarget::Note { note_id: "n1".to_string() }, None)?;
+
+        // n1 is both a parent and a reference target; embedding it, twice,
+        // must not touch the row those point at.
+        let embedder = HashEmbedder::default();
+        db.set_note_embedding("n1", &embedder.embed("something else").unwrap())?;
+        db.set_note_embedding("n1", &embedder.embed("dentist appointment tuesday").unwrap())?;
+        let before = db.get_note("n1")?;
+
+        let mut query = VectorQuery::new(embedder.embed("dentist").unwrap(), 1);
+        query.user_id = Some("test_user".to_string());
+        let matches = db.search_notes_by_vector(&query)?;
+        assert_eq!(matches[0].note.id, "n1");
+        assert_eq!(matches[0].note.updated_at, before.updated_at);
+
+        // Editing drops the embedding, and moving a reply is allowed.
+        db.update_note(&Note { content: "orthodontist appointment tuesday".to_string(), ..before })?;
+        db.update_note(&note("n2", "bring the insurance card", None))?;
+        let pending: Vec<String> = db.get_notes_without_embedding(10)?.into_iter().map(|(id, _)| id).collect();
+        assert_eq!(pending, ["n1", "n2"]);
+        assert_eq!(db.get_note_references("n2")?.len(), 1);
+
+        let db = Arc::new(Mutex::new(db));
+        assert_eq!(embed_pending_notes(&db, &embedder, 10).unwrap(), 2);
+        let db = db.lock().unwrap();
+        assert!(db.get_notes_without_embedding(10)?.is_empty());
+        query.vector = embedder.embed("orthodontist").unwrap();
+        assert_eq!(db.search_notes_by_vector(&query)?[0].note.id, "n1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vector_literal_rejects_wrong_dimensions() {
+        assert!(vector_literal(&[0.0; 3]).is_err());
+        assert!(vector_literal(&[0.0; EMBEDDING_DIMENSIONS]).is_ok());
+    }
+}
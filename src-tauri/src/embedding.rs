@@ -0,0 +1,249 @@
+use std::fmt;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::PoisonError;
+use std::thread;
+use std::time::Duration;
+use crate::db::SharedDatabase;
+
+/// Width of the `embedding FLOAT[1024]` columns on note_embeddings and
+/// known_entities.
+pub const EMBEDDING_DIMENSIONS: usize = 1024;
+
+#[derive(Debug)]
+pub enum EmbeddingError {
+    Model(String),
+    Database(duckdb::Error),
+}
+
+impl fmt::Display for EmbeddingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmbeddingError::Model(message) => write!(f, "embedding model failed: {}", message),
+            EmbeddingError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EmbeddingError {}
+
+impl From<duckdb::Error> for EmbeddingError {
+    fn from(e: duckdb::Error) -> Self {
+        EmbeddingError::Database(e)
+    }
+}
+
+/// Turns text into vectors that can be stored in and searched against the
+/// HNSW indexes. Implementations must return `dimensions()`-long vectors.
+pub trait Embedder: Send + Sync {
+    fn dimensions(&self) -> usize {
+        EMBEDDING_DIMENSIONS
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>, EmbeddingError>;
+
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, EmbeddingError> {
+        texts.iter().map(|text| self.embed(text)).collect()
+    }
+}
+
+/// Deterministic bag-of-words embedder using the hashing trick. It has no
+/// notion of meaning beyond shared words, but it needs no model files and
+/// gives stable vectors, which is what tests and first runs need.
+pub struct HashEmbedder {
+    dimensions: usize,
+}
+
+impl HashEmbedder {
+    pub fn new(dimensions: usize) -> Self {
+        Self { dimensions }
+    }
+}
+
+impl Default for HashEmbedder {
+    fn default() -> Self {
+        Self::new(EMBEDDING_DIMENSIONS)
+    }
+}
+
+impl Embedder for HashEmbedder {
+    fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>, EmbeddingError> {
+        let mut vector = vec![0.0f32; self.dimensions];
+
+        for token in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|t| !t.is_empty())
+            .map(|t| t.to_lowercase())
+        {
+            let hash = fnv1a(token.as_bytes());
+            let index = (hash % self.dimensions as u64) as usize;
+            let sign = if (hash >> 63) == 0 { 1.0 } else { -1.0 };
+            vector[index] += sign;
+        }
+
+        let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            vector.iter_mut().for_each(|v| *v /= norm);
+        }
+        Ok(vector)
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// multilingual-e5-large running on the CPU through ONNX Runtime. The model
+/// (about 2 GB) is downloaded on first use into the fastembed cache.
+#[cfg(feature = "local-embeddings")]
+pub struct LocalEmbedder {
+    model: fastembed::TextEmbedding,
+}
+
+#[cfg(feature = "local-embeddings")]
+impl LocalEmbedder {
+    pub fn new() -> Result<Self, EmbeddingError> {
+        let options = fastembed::InitOptions::new(fastembed::EmbeddingModel::MultilingualE5Large);
+        let model = fastembed::TextEmbedding::try_new(options)
+            .map_err(|e| EmbeddingError::Model(e.to_string()))?;
+        Ok(Self { model })
+    }
+}
+
+#[cfg(feature = "local-embeddings")]
+impl Embedder for LocalEmbedder {
+    fn embed(&self, text: &str) -> Result<Vec<f32>, EmbeddingError> {
+        let mut vectors = self.embed_batch(&[text])?;
+        vectors.pop().ok_or_else(|| EmbeddingError::Model("model returned no embedding".to_string()))
+    }
+
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, EmbeddingError> {
+        // e5 models expect a "passage: "/"query: " prefix; notes and queries
+        // are short enough that treating both as queries works well.
+        let inputs: Vec<String> = texts.iter().map(|t| format!("query: {}", t)).collect();
+        self.model.embed(inputs, None).map_err(|e| EmbeddingError::Model(e.to_string()))
+    }
+}
+
+/// The local model when built with `local-embeddings`, otherwise the hashing
+/// embedder.
+pub fn default_embedder() -> Result<Box<dyn Embedder>, EmbeddingError> {
+    #[cfg(feature = "local-embeddings")]
+    {
+        Ok(Box::new(LocalEmbedder::new()?))
+    }
+    #[cfg(not(feature = "local-embeddings"))]
+    {
+        Ok(Box::new(HashEmbedder::default()))
+    }
+}
+
+/// Embeds up to `limit` notes that don't have an embedding yet. Returns how
+/// many were stored.
+///
+/// The database is only locked to fetch the notes and to store their
+/// vectors, not while the model runs. Notes edited or deleted in between
+/// are left for the next run rather than given a stale embedding.
+pub fn embed_pending_notes(db: &SharedDatabase, embedder: &dyn Embedder, limit: usize) -> Result<usize, EmbeddingError> {
+    let pending = db.lock().unwrap_or_else(PoisonError::into_inner).get_notes_without_embedding(limit)?;
+    if pending.is_empty() {
+        return Ok(0);
+    }
+    let texts: Vec<&str> = pending.iter().map(|(_, content)| content.as_str()).collect();
+    let vectors = embedder.embed_batch(&texts)?;
+
+    let db = db.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut stored = 0;
+    for ((note_id, content), vector) in pending.iter().zip(vectors) {
+        if matches!(db.get_note(note_id), Ok(note) if note.content == *content) {
+            db.set_note_embedding(note_id, &vector)?;
+            stored += 1;
+        }
+    }
+    Ok(stored)
+}
+
+/// Embeds new and edited notes in the background: a batch at a time until
+/// none are left, then again every `every`.
+pub struct EmbeddingWorker {
+    stop: mpsc::Sender<()>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl EmbeddingWorker {
+    const BATCH: usize = 32;
+
+    /// Starts the worker. The embedder is loaded on the worker's thread,
+    /// since the local model may have to be downloaded first.
+    pub fn start(db: SharedDatabase, every: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let embedder = match default_embedder() {
+                Ok(embedder) => embedder,
+                Err(e) => {
+                    log::error!("Note embedding disabled: {}", e);
+                    return;
+                }
+            };
+            loop {
+                loop {
+                    match embed_pending_notes(&db, embedder.as_ref(), Self::BATCH) {
+                        Ok(n) if n == Self::BATCH => {}
+                        Ok(_) => break,
+                        Err(e) => {
+                            log::warn!("Embedding notes failed: {}", e);
+                            break;
+                        }
+                    }
+                    if stopped.try_recv().is_ok() {
+                        return;
+                    }
+                }
+                match stopped.recv_timeout(every) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+            }
+        });
+        Self { stop, handle }
+    }
+
+    /// Stops the worker, waiting for the batch in progress to finish.
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.handle.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_embedder_is_deterministic_and_normalized() {
+        let embedder = HashEmbedder::default();
+        let a = embedder.embed("Dentist appointment on Tuesday").unwrap();
+        let b = embedder.embed("dentist APPOINTMENT on tuesday").unwrap();
+
+        assert_eq!(a.len(), EMBEDDING_DIMENSIONS);
+        assert_eq!(a, b);
+        let norm: f32 = a.iter().map(|v| v * v).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_hash_embedder_similarity() {
+        let embedder = HashEmbedder::new(256);
+        let query = embedder.embed("parking meter").unwrap();
+        let close = embedder.embed("paid the parking meter downtown").unwrap();
+        let far = embedder.embed("heart rate was high after the run").unwrap();
+
+        let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+        assert!(dot(&query, &close) > dot(&query, &far));
+    }
+}
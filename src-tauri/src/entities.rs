@@ -0,0 +1,527 @@
+use chrono::{DateTime, Utc};
+use duckdb::Result;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+use crate::datatypes::{note::{EntitySighting, KnownEntity}, types::{EntityType, Metadata}};
+use crate::db::{detection_source, Database, DistanceMetric, EntityMatch, VectorQuery};
+
+/// Largest cosine distance at which a detection is taken to be a known
+/// entity, per kind. Faces vary more with lighting and angle than objects.
+#[derive(Debug, Clone)]
+pub struct MatchThresholds {
+    pub face: f32,
+    pub object: f32,
+    pub pose: f32,
+    pub audio: f32,
+}
+
+impl MatchThresholds {
+    pub fn for_type(&self, entity_type: &EntityType) -> f32 {
+        match entity_type {
+            EntityType::Face => self.face,
+            EntityType::Object => self.object,
+            EntityType::Pose => self.pose,
+            EntityType::Audio => self.audio,
+        }
+    }
+}
+
+impl Default for MatchThresholds {
+    fn default() -> Self {
+        Self {
+            face: 0.35,
+            object: 0.25,
+            pose: 0.30,
+            audio: 0.30,
+        }
+    }
+}
+
+/// One entry of the `faces`/`objects`/`poses` arrays written by the capture
+/// pipeline. Entries without an embedding can't be matched and are skipped.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Detection {
+    pub embedding: Vec<f32>,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub confidence: Option<f32>,
+    #[serde(default)]
+    pub bounding_box: Option<Value>,
+}
+
+impl Detection {
+    pub fn parse_frame(detections: &Value) -> Vec<Detection> {
+        detections
+            .as_array()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter_map(|entry| serde_json::from_value(entry.clone()).ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn metadata(&self) -> Metadata {
+        serde_json::from_value(json!({
+            "label": self.label,
+            "confidence": self.confidence,
+            "bounding_box": self.bounding_box,
+        }))
+        .unwrap()
+    }
+}
+
+/// Matches detections against labelled entities and keeps their sighting
+/// history.
+pub struct EntityService<'a> {
+    db: &'a Database,
+    pub thresholds: MatchThresholds,
+}
+
+impl<'a> EntityService<'a> {
+    pub fn new(db: &'a Database) -> Self {
+        Self {
+            db,
+            thresholds: MatchThresholds::default(),
+        }
+    }
+
+    pub fn with_thresholds(db: &'a Database, thresholds: MatchThresholds) -> Self {
+        Self { db, thresholds }
+    }
+
+    /// Stores a new labelled entity owned by `user_id`, enrolled with
+    /// `embedding` if given.
+    pub fn register(
+        &self,
+        user_id: &str,
+        entity_type: EntityType,
+        label: &str,
+        embedding: Option<Vec<f32>>,
+        metadata: Option<Metadata>,
+    ) -> Result<KnownEntity> {
+        self.register_as(Uuid::new_v4().to_string(), user_id, entity_type, label, embedding, metadata)
+    }
+
+    fn register_as(
+        &self,
+        entity_id: String,
+        user_id: &str,
+        entity_type: EntityType,
+        label: &str,
+        embedding: Option<Vec<f32>>,
+        metadata: Option<Metadata>,
+    ) -> Result<KnownEntity> {
+        let now = Utc::now();
+        let entity = KnownEntity {
+            entity_id,
+            user_id: user_id.to_string(),
+            entity_type,
+            label: label.to_string(),
+            embedding,
+            metadata,
+            created_at: now,
+            updated_at: now,
+        };
+        self.db.insert_known_entity(&entity)?;
+        Ok(entity)
+    }
+
+    /// The closest of `user_id`'s entities of this kind, if it is within the
+    /// threshold.
+    pub fn best_match(&self, user_id: &str, entity_type: &EntityType, embedding: &[f32]) -> Result<Option<EntityMatch>> {
+        let mut query = VectorQuery::new(embedding.to_vec(), 1);
+        query.metric = DistanceMetric::Cosine;
+        query.user_id = Some(user_id.to_string());
+
+        let threshold = self.thresholds.for_type(entity_type);
+        let best = self.db.search_entities_by_vector(&query, Some(entity_type))?.into_iter().next();
+        Ok(best.filter(|m| m.distance <= threshold))
+    }
+
+    /// Records a sighting if the detection matches one of the device owner's
+    /// known entities. Detections from unknown devices match nothing.
+    pub fn observe(
+        &self,
+        entity_type: &EntityType,
+        detection: &Detection,
+        device_id: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Option<EntitySighting>> {
+        self.observe_as(Uuid::new_v4().to_string(), entity_type, detection, device_id, timestamp)
+    }
+
+    fn observe_as(
+        &self,
+        sighting_id: String,
+        entity_type: &EntityType,
+        detection: &Detection,
+        device_id: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Option<EntitySighting>> {
+        let Some(owner) = self.db.get_device_owner(device_id)? else {
+            return Ok(None);
+        };
+        let Some(best) = self.best_match(&owner, entity_type, &detection.embedding)? else {
+            return Ok(None);
+        };
+
+        let sighting = EntitySighting {
+            sighting_id,
+            entity_id: best.entity.entity_id,
+            device_id: device_id.to_string(),
+            timestamp,
+            source: source_name(entity_type).to_string(),
+            distance: Some(best.distance),
+            embedding: Some(detection.embedding.clone()),
+            metadata: Some(detection.metadata()),
+        };
+        self.db.insert_entity_sighting(&sighting)?;
+        Ok(Some(sighting))
+    }
+
+    /// Matches every detection a device captured in `[start, end]` and records
+    /// the sightings. Sighting ids are derived from the frame, so running this
+    /// twice over the same range doesn't duplicate anything. Returns the
+    /// number of detections that matched.
+    pub fn process_detections(
+        &self,
+        entity_type: &EntityType,
+        device_id: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<usize> {
+        let mut matched = 0;
+        for (timestamp, frame) in self.db.get_detection_frames(entity_type, device_id, start, end)? {
+            for (index, detection) in Detection::parse_frame(&frame).iter().enumerate() {
+                let sighting_id = format!(
+                    "{}:{}@{}#{}",
+                    source_name(entity_type), device_id, timestamp.to_rfc3339(), index
+                );
+                if self.observe_as(sighting_id, entity_type, detection, device_id, timestamp)?.is_some() {
+                    matched += 1;
+                }
+            }
+        }
+        Ok(matched)
+    }
+
+    /// Answers "when did I last see X" for an entity label, across all
+    /// devices or only `user_id`'s.
+    pub fn last_seen(&self, label: &str, user_id: Option<&str>) -> Result<Option<(KnownEntity, EntitySighting)>> {
+        self.db.get_last_sighting_by_label(label, user_id)
+    }
+
+    /// Folds `merged_id` into `kept_id`, for when one person or thing was
+    /// enrolled twice. Both must belong to the same user; callers acting for
+    /// a user check that through `ScopedDatabase::merge_entities`. Returns
+    /// the surviving entity.
+    pub fn merge(&self, kept_id: &str, merged_id: &str) -> Result<KnownEntity> {
+        self.db.in_transaction(|db| {
+            db.merge_known_entities(kept_id, merged_id)?;
+            self.recenter(kept_id)
+        })?;
+        self.db.get_known_entity(kept_id)
+    }
+
+    /// Moves the given sightings of `entity_id` onto a new entity labelled
+    /// `label` with the same owner, for when two people or things were
+    /// matched as one. Both entities are re-centred on the sightings they end
+    /// up with.
+    pub fn split(&self, entity_id: &str, sighting_ids: &[String], label: &str) -> Result<KnownEntity> {
+        let original = self.db.get_known_entity(entity_id)?;
+        self.db.in_transaction(|db| {
+            // The sightings move first so the new entity is inserted with its
+            // centroid: updating the embedding of a row inserted in the same
+            // transaction would make DuckDB re-insert a key it just deleted.
+            let split_id = Uuid::new_v4().to_string();
+            db.reassign_sightings(entity_id, sighting_ids, &split_id)?;
+            let center = centroid(&db.get_sighting_embeddings(&split_id)?);
+            self.register_as(split_id, &original.user_id, original.entity_type.clone(), label, center, None)?;
+            self.recenter(entity_id)?;
+            db.get_known_entity(&split_id)
+        })
+    }
+
+    /// Sets the entity's embedding to the centroid of its sightings. Entities
+    /// without stored sighting embeddings keep the one they were enrolled with.
+    fn recenter(&self, entity_id: &str) -> Result<()> {
+        let embeddings = self.db.get_sighting_embeddings(entity_id)?;
+        if let Some(center) = centroid(&embeddings) {
+            self.db.set_entity_embedding(entity_id, &center)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where sightings of this kind come from: the detection table, or "audio".
+fn source_name(entity_type: &EntityType) -> &'static str {
+    detection_source(entity_type).map_or("audio", |(table, _)| table)
+}
+
+/// Mean of the vectors, scaled back to unit length so cosine distances stay
+/// comparable with freshly enrolled embeddings.
+pub fn centroid(vectors: &[Vec<f32>]) -> Option<Vec<f32>> {
+    let first = vectors.first()?;
+    let mut sum = vec![0.0f32; first.len()];
+    for vector in vectors {
+        sum.iter_mut().zip(vector).for_each(|(s, v)| *s += v);
+    }
+
+    let norm = sum.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        sum.iter_mut().for_each(|v| *v /= norm);
+    }
+    Some(sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+    use crate::datatypes::{note::NoteTarget, privacy::DeletionScope};
+    use crate::db::{AccessError, ResolvedReference, UserContext};
+    use crate::db::fixtures::{device, insert_detections, note, temp_database, user};
+    use crate::embedding::EMBEDDING_DIMENSIONS;
+
+    /// A unit vector mostly along axis `main`, tilted slightly towards
+    /// `toward`: within every default threshold of `axis(main)`.
+    fn near(main: usize, toward: usize) -> Vec<f32> {
+        let mut vector = vec![0.0; EMBEDDING_DIMENSIONS];
+        vector[main] = 0.99;
+        vector[toward] = (1.0f32 - 0.99 * 0.99).sqrt();
+        vector
+    }
+
+    fn axis(i: usize) -> Vec<f32> {
+        let mut vector = vec![0.0; EMBEDDING_DIMENSIONS];
+        vector[i] = 1.0;
+        vector
+    }
+
+    fn detection(embedding: Vec<f32>) -> Detection {
+        Detection { embedding, label: None, confidence: Some(0.9), bounding_box: None }
+    }
+
+    fn setup() -> Result<(tempfile::TempDir, Database)> {
+        let (dir, db) = temp_database()?;
+        for (user_id, device_id) in [("alice", "alice_phone"), ("bob", "bob_phone")] {
+            db.insert_user(&user(user_id))?;
+            db.insert_device(&device(device_id, user_id))?;
+        }
+        Ok((dir, db))
+    }
+
+    fn sightings(db: &Database, entity_id: &str) -> Result<Vec<EntitySighting>> {
+        let start = Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap();
+        db.get_entity_sightings(entity_id, start, Utc::now())
+    }
+
+    #[test]
+    fn test_observe_and_last_seen() -> Result<()> {
+        let (_dir, db) = setup()?;
+        let entities = EntityService::new(&db);
+        let sam = entities.register("alice", EntityType::Face, "Sam", Some(axis(0)), None)?;
+        let mug = entities.register("alice", EntityType::Object, "Mug", Some(axis(0)), None)?;
+
+        let morning = Utc.with_ymd_and_hms(2024, 5, 1, 9, 0, 0).unwrap();
+        let seen = entities.observe(&EntityType::Face, &detection(near(0, 1)), "alice_phone", morning)?.unwrap();
+        assert_eq!(seen.entity_id, sam.entity_id);
+        assert_eq!(seen.source, "face_recognition_data");
+        assert!(seen.distance.unwrap() < 0.05);
+        assert!(entities.observe(&EntityType::Face, &detection(axis(2)), "alice_phone", morning)?.is_none());
+
+        // Same vector, but only entities of the detection's kind match
+        let seen = entities.observe(&EntityType::Object, &detection(near(0, 1)), "alice_phone", morning)?.unwrap();
+        assert_eq!(seen.entity_id, mug.entity_id);
+
+        // Bob's phone only matches Bob's entities, and unknown devices nothing
+        let evening = morning + Duration::hours(10);
+        assert!(entities.observe(&EntityType::Face, &detection(near(0, 2)), "bob_phone", evening)?.is_none());
+        assert!(entities.observe(&EntityType::Face, &detection(near(0, 2)), "stolen_phone", evening)?.is_none());
+        let bobs_sam = entities.register("bob", EntityType::Face, "Sam", Some(axis(0)), None)?;
+        let seen = entities.observe(&EntityType::Face, &detection(near(0, 2)), "bob_phone", evening)?.unwrap();
+        assert_eq!(seen.entity_id, bobs_sam.entity_id);
+
+        let (entity, sighting) = entities.last_seen("sam", Some("alice"))?.unwrap();
+        assert_eq!(entity.entity_id, sam.entity_id);
+        assert_eq!((sighting.device_id.as_str(), sighting.timestamp), ("alice_phone", morning));
+        let (entity, sighting) = entities.last_seen("SAM", None)?.unwrap();
+        assert_eq!(entity.entity_id, bobs_sam.entity_id);
+        assert_eq!((sighting.device_id.as_str(), sighting.timestamp), ("bob_phone", evening));
+        assert!(entities.last_seen("nobody", None)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_detections_is_idempotent() -> Result<()> {
+        let (_dir, db) = setup()?;
+        let entities = EntityService::new(&db);
+        let sam = entities.register("alice", EntityType::Face, "Sam", Some(axis(0)), None)?;
+
+        let first = Utc.with_ymd_and_hms(2024, 5, 1, 9, 0, 0).unwrap();
+        let second = first + Duration::seconds(30);
+        insert_detections(&db, &EntityType::Face, "alice_phone", first, &json!([
+            { "embedding": near(0, 1), "confidence": 0.9 },
+            { "embedding": axis(2), "confidence": 0.8 },
+            { "label": "blurred" },
+        ]))?;
+        insert_detections(&db, &EntityType::Face, "alice_phone", second, &json!([{ "embedding": near(0, 3) }]))?;
+        insert_detections(&db, &EntityType::Face, "bob_phone", second, &json!([{ "embedding": near(0, 3) }]))?;
+
+        let (start, end) = (first - Duration::minutes(1), second + Duration::minutes(1));
+        assert_eq!(entities.process_detections(&EntityType::Face, "alice_phone", start, end)?, 2);
+        assert_eq!(entities.process_detections(&EntityType::Face, "alice_phone", start, end)?, 2);
+        assert_eq!(sightings(&db, &sam.entity_id)?.len(), 2);
+
+        assert_eq!(entities.process_detections(&EntityType::Object, "alice_phone", start, end)?, 0);
+        assert_eq!(entities.process_detections(&EntityType::Audio, "alice_phone", start, end)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_moves_sightings_and_note_references() -> Result<()> {
+        let (_dir, db) = setup()?;
+        let entities = EntityService::new(&db);
+        let sam = entities.register("alice", EntityType::Face, "Sam", Some(axis(0)), None)?;
+        let samuel = entities.register("alice", EntityType::Face, "Samuel", Some(axis(1)), None)?;
+
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 9, 0, 0).unwrap();
+        entities.observe(&EntityType::Face, &detection(near(0, 2)), "alice_phone", now)?.unwrap();
+        entities.observe(&EntityType::Face, &detection(near(1, 2)), "alice_phone", now + Duration::hours(1))?.unwrap();
+
+        db.insert_note(&note("both", "alice", "Sam and Samuel, or just Sam?"))?;
+        db.insert_note(&note("one", "alice", "Samuel's birthday"))?;
+        for (note_id, entity) in [("both", &sam), ("both", &samuel), ("one", &samuel)] {
+            db.attach_note(note_id, &NoteTarget::Entity { entity_id: entity.entity_id.clone() }, None)?;
+        }
+
+        let kept = entities.merge(&sam.entity_id, &samuel.entity_id)?;
+        assert_eq!(kept.entity_id, sam.entity_id);
+        assert!(db.get_known_entity(&samuel.entity_id).is_err());
+        assert_eq!(sightings(&db, &sam.entity_id)?.len(), 2);
+        assert!(sightings(&db, &samuel.entity_id)?.is_empty());
+
+        for note_id in ["both", "one"] {
+            let references: Vec<String> = db.get_note_references(note_id)?.into_iter().map(|r| r.reference_id).collect();
+            assert_eq!(references, [sam.entity_id.clone()], "{}", note_id);
+        }
+
+        // Re-centred between the two: either enrolment's face now finds Sam
+        let found = entities.best_match("alice", &EntityType::Face, &axis(1))?.unwrap();
+        assert_eq!(found.entity.entity_id, sam.entity_id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_moves_only_the_given_sightings() -> Result<()> {
+        let (_dir, db) = setup()?;
+        let thresholds = MatchThresholds { face: 1.5, ..MatchThresholds::default() };
+        let entities = EntityService::with_thresholds(&db, thresholds);
+        let sam = entities.register("alice", EntityType::Face, "Sam", Some(axis(0)), None)?;
+        let other = entities.register("alice", EntityType::Face, "Other", Some(axis(5)), None)?;
+
+        // Closer to Sam than to Other, but far enough from Sam's other two
+        // sightings to tell apart once split off
+        let mut lookalike = vec![0.0; EMBEDDING_DIMENSIONS];
+        lookalike[0] = 0.6;
+        lookalike[1] = 0.8;
+
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 9, 0, 0).unwrap();
+        let mut ids = Vec::new();
+        for (i, embedding) in [near(0, 2), near(0, 3), lookalike.clone()].into_iter().enumerate() {
+            let seen = entities.observe(&EntityType::Face, &detection(embedding), "alice_phone", now + Duration::minutes(i as i64))?;
+            ids.push(seen.unwrap().sighting_id);
+        }
+        let elsewhere = entities.observe(&EntityType::Face, &detection(axis(5)), "alice_phone", now)?.unwrap();
+        assert_eq!(elsewhere.entity_id, other.entity_id);
+
+        let alex = entities.split(&sam.entity_id, &[ids[2].clone(), elsewhere.sighting_id.clone()], "Alex")?;
+        assert_eq!(alex.label, "Alex");
+        assert_eq!(alex.user_id, "alice");
+        assert_eq!(alex.entity_type, EntityType::Face);
+        assert_eq!(sightings(&db, &sam.entity_id)?.len(), 2);
+        let moved = sightings(&db, &alex.entity_id)?;
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[0].sighting_id, ids[2]);
+        assert_eq!(sightings(&db, &other.entity_id)?.len(), 1);
+
+        // Each side is re-centred on the sightings it kept
+        assert_eq!(entities.best_match("alice", &EntityType::Face, &lookalike)?.unwrap().entity.entity_id, alex.entity_id);
+        assert_eq!(entities.best_match("alice", &EntityType::Face, &axis(0))?.unwrap().entity.entity_id, sam.entity_id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scoped_entity_commands_need_the_owner() -> std::result::Result<(), AccessError> {
+        let (_dir, db) = setup()?;
+        let entities = EntityService::new(&db);
+        let sam = entities.register("alice", EntityType::Face, "Sam", Some(axis(0)), None)?;
+        let samuel = entities.register("alice", EntityType::Face, "Samuel", Some(axis(1)), None)?;
+        let rex = entities.register("bob", EntityType::Object, "Rex", Some(axis(2)), None)?;
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 9, 0, 0).unwrap();
+        let seen = entities.observe(&EntityType::Face, &detection(near(1, 3)), "alice_phone", now)?.unwrap();
+
+        let alice = db.scoped(UserContext::new("alice"));
+        let bob = db.scoped(UserContext::new("bob"));
+        assert!(matches!(bob.merge_entities(&rex.entity_id, &sam.entity_id), Err(AccessError::Denied(_))));
+        assert!(matches!(alice.merge_entities(&sam.entity_id, &rex.entity_id), Err(AccessError::Denied(_))));
+        assert!(matches!(bob.split_entity(&samuel.entity_id, &[seen.sighting_id.clone()], "Sammy"), Err(AccessError::Denied(_))));
+        assert!(matches!(bob.rename_entity(&sam.entity_id, "Not Sam"), Err(AccessError::Denied(_))));
+        assert_eq!(bob.get_known_entities(None)?.len(), 1);
+
+        let sammy = alice.split_entity(&samuel.entity_id, &[seen.sighting_id.clone()], "Sammy")?;
+        assert_eq!(sammy.user_id, "alice");
+        assert_eq!(alice.merge_entities(&sam.entity_id, &sammy.entity_id)?.entity_id, sam.entity_id);
+        assert_eq!(alice.get_last_sighting(&sam.entity_id)?.unwrap().sighting_id, seen.sighting_id);
+
+        // Notes can only point at the user's own entities, and references
+        // that slipped in some other way resolve as missing
+        alice.insert_note(&note("n1", "alice", "Sam and Rex"))?;
+        let rex_target = NoteTarget::Entity { entity_id: rex.entity_id.clone() };
+        assert!(matches!(alice.attach_note("n1", &rex_target, None), Err(AccessError::Denied(_))));
+        alice.attach_note("n1", &NoteTarget::Entity { entity_id: sam.entity_id.clone() }, None)?;
+        db.attach_note("n1", &rex_target, None)?;
+        let resolved = alice.resolve_note_references("n1")?;
+        let missing = resolved.iter().filter(|(_, target)| matches!(target, ResolvedReference::Missing { .. })).count();
+        assert_eq!((resolved.len(), missing), (2, 1));
+
+        // Erasing everything takes the user's entities and references to them
+        bob.erase(&DeletionScope::Everything)?;
+        assert!(db.get_known_entity(&rex.entity_id).is_err());
+        assert_eq!(alice.get_note_references("n1")?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_centroid_is_normalized_mean() {
+        let center = centroid(&[vec![1.0, 0.0], vec![0.0, 1.0]]).unwrap();
+        let expected = std::f32::consts::FRAC_1_SQRT_2;
+        assert!((center[0] - expected).abs() < 1e-6);
+        assert!((center[1] - expected).abs() < 1e-6);
+        assert!(centroid(&[]).is_none());
+    }
+
+    #[test]
+    fn test_parse_frame_skips_entries_without_embedding() {
+        let frame = json!([
+            { "embedding": [0.1, 0.2], "confidence": 0.9, "bounding_box": [0, 0, 10, 10] },
+            { "label": "cup", "confidence": 0.5 },
+        ]);
+        let detections = Detection::parse_frame(&frame);
+        assert_eq!(detections.len(), 1);
+        assert_eq!(detections[0].confidence, Some(0.9));
+
+        assert!(Detection::parse_frame(&json!({ "faces": [] })).is_empty());
+    }
+
+    #[test]
+    fn test_thresholds_per_type() {
+        let thresholds = MatchThresholds { face: 0.1, ..MatchThresholds::default() };
+        assert_eq!(thresholds.for_type(&EntityType::Face), 0.1);
+        assert_eq!(thresholds.for_type(&EntityType::Object), 0.25);
+    }
+}
@@ -0,0 +1,66 @@
+// Small geodesy helpers shared by anything that works with gps_data.
+
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+
+/// Great-circle distance in metres between two WGS84 coordinates.
+pub fn haversine_m(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let d_phi = (lat2 - lat1).to_radians();
+    let d_lambda = (lon2 - lon1).to_radians();
+
+    let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+}
+
+/// A (min_lat, max_lat, min_lon, max_lon) box that contains every point
+/// within `radius_m` of the centre. Used to narrow SQL scans before an exact
+/// haversine check.
+pub fn bounding_box(latitude: f64, longitude: f64, radius_m: f64) -> (f64, f64, f64, f64) {
+    let d_lat = (radius_m / EARTH_RADIUS_M).to_degrees();
+    let cos_lat = latitude.to_radians().cos().abs().max(1e-6);
+    let d_lon = (radius_m / (EARTH_RADIUS_M * cos_lat)).to_degrees().min(180.0);
+
+    (latitude - d_lat, latitude + d_lat, longitude - d_lon, longitude + d_lon)
+}
+
+/// The point `distance_m` from a coordinate along `bearing` (radians
+/// clockwise from north). Flat-earth approximation, fine for the few
+/// kilometres it's used over.
+pub fn offset(latitude: f64, longitude: f64, distance_m: f64, bearing: f64) -> (f64, f64) {
+    let d_lat = (distance_m * bearing.cos() / EARTH_RADIUS_M).to_degrees();
+    let cos_lat = latitude.to_radians().cos().abs().max(1e-6);
+    let d_lon = (distance_m * bearing.sin() / (EARTH_RADIUS_M * cos_lat)).to_degrees();
+
+    ((latitude + d_lat).clamp(-90.0, 90.0), (longitude + d_lon + 540.0) % 360.0 - 180.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_haversine() {
+        // Paris to London is roughly 344 km
+        let d = haversine_m(48.8566, 2.3522, 51.5074, -0.1278);
+        assert!((d - 343_500.0).abs() < 1_500.0);
+        assert_eq!(haversine_m(10.0, 10.0, 10.0, 10.0), 0.0);
+    }
+
+    #[test]
+    fn test_bounding_box_contains_radius() {
+        let (min_lat, max_lat, min_lon, max_lon) = bounding_box(37.7749, -122.4194, 1_000.0);
+        assert!(haversine_m(37.7749, -122.4194, max_lat, -122.4194) >= 999.0);
+        assert!(haversine_m(37.7749, -122.4194, 37.7749, min_lon) >= 999.0);
+        assert!(min_lat < 37.7749 && max_lon > -122.4194);
+    }
+
+    #[test]
+    fn test_offset_moves_the_given_distance() {
+        let (lat, lon) = offset(51.5074, -0.1278, 500.0, std::f64::consts::FRAC_PI_4);
+        assert!((haversine_m(51.5074, -0.1278, lat, lon) - 500.0).abs() < 1.0);
+        assert!(lat > 51.5074 && lon > -0.1278);
+
+        let (_, lon) = offset(0.0, 179.9999, 1_000.0, std::f64::consts::FRAC_PI_2);
+        assert!(lon < -179.0);
+    }
+}
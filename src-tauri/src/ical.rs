@@ -0,0 +1,629 @@
+// Todos in and out of iCalendar (RFC 5545) files, as VTODOs, for syncing
+// with task apps.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+use serde_json::Value;
+use crate::datatypes::{sensor::TodosData, types::Metadata};
+use crate::db::{AccessError, Database, UserContext};
+use crate::import::{ensure_device, ImportError, RowError};
+
+const PRODID: &str = "-//Loom//Todos//EN";
+
+/// Longest content line in octets before it's folded.
+const MAX_LINE_OCTETS: usize = 75;
+
+const DATE_TIME_FORMAT: &str = "%Y%m%dT%H%M%S";
+
+/// What a todo import stored, or would have on a dry run.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TodoImportReport {
+    /// The device changed todos were stored under.
+    pub device_id: String,
+    pub dry_run: bool,
+    pub todos_read: usize,
+    /// New todos, and ones changed since Loom last had them.
+    pub imported: usize,
+    /// Todos the same as Loom already has them.
+    pub unchanged: usize,
+    /// Todos Loom has a newer version of, which were left alone.
+    pub stale: usize,
+    pub failed: usize,
+    /// By position among the file's VTODOs.
+    pub errors: Vec<RowError>,
+}
+
+/// Exchanges todos with task apps as `.ics` files. Each VTODO's UID is its
+/// `todo_id`, so a todo keeps its identity through an export and back.
+/// todos_data keeps a row per version of a todo, so an import only adds a
+/// row when a todo has changed, and an export writes each todo's newest.
+pub struct TodoCalendar<'a> {
+    db: &'a Database,
+    user: UserContext,
+}
+
+impl<'a> TodoCalendar<'a> {
+    pub fn new(db: &'a Database, user: UserContext) -> Self {
+        Self { db, user }
+    }
+
+    /// The device imported todos are stored under for the user.
+    pub fn device_id(&self) -> String {
+        format!("ical-{}", self.user.user_id)
+    }
+
+    /// A VCALENDAR of the todos on the given devices, or on all the user's
+    /// if none are given.
+    pub fn export(&self, device_ids: &[String]) -> Result<String, AccessError> {
+        let todos = self.db.scoped(self.user.clone()).get_latest_todos(device_ids)?;
+        let mut ics = String::new();
+        for line in ["BEGIN:VCALENDAR", "VERSION:2.0", &format!("PRODID:{}", PRODID)] {
+            fold(line, &mut ics);
+        }
+        for todo in &todos {
+            write_todo(todo, &mut ics);
+        }
+        fold("END:VCALENDAR", &mut ics);
+        Ok(ics)
+    }
+
+    /// Imports the VTODOs of the `.ics` file at `path`. A todo is stored
+    /// when it's new or differs from Loom's newest version of it, timed by
+    /// its LAST-MODIFIED; where Loom's version is newer, Loom's is kept.
+    /// With `dry_run` nothing is stored and the device isn't created.
+    pub fn import(&self, path: &Path, dry_run: bool) -> Result<TodoImportReport, ImportError> {
+        let components = parse_todos(&fs::read_to_string(path)?).map_err(ImportError::Calendar)?;
+        let device_id = self.device_id();
+        let mut report = TodoImportReport { device_id: device_id.clone(), dry_run, ..Default::default() };
+
+        let mut latest: HashMap<String, TodosData> = HashMap::new();
+        for device_id in self.db.get_device_ids_for_user(&self.user.user_id)? {
+            for todo in self.db.get_latest_todos(&device_id)? {
+                if latest.get(&todo.todo_id).is_none_or(|current| current.timestamp < todo.timestamp) {
+                    latest.insert(todo.todo_id.clone(), todo);
+                }
+            }
+        }
+
+        let now = Utc::now();
+        let mut changed = Vec::new();
+        for (i, properties) in components.iter().enumerate() {
+            report.todos_read += 1;
+            let todo = match todo_from_properties(properties, &device_id, now) {
+                Ok(todo) => todo,
+                Err(message) => {
+                    report.failed += 1;
+                    report.errors.push(RowError { line: i as u64 + 1, message });
+                    continue;
+                }
+            };
+            if let Some(current) = latest.get(&todo.todo_id) {
+                if same_content(current, &todo) {
+                    report.unchanged += 1;
+                    continue;
+                }
+                if current.timestamp >= todo.timestamp {
+                    report.stale += 1;
+                    continue;
+                }
+            }
+            report.imported += 1;
+            latest.insert(todo.todo_id.clone(), todo.clone());
+            changed.push(todo);
+        }
+
+        if !dry_run && !changed.is_empty() {
+            ensure_device(self.db, &self.user, &device_id, "iCalendar", &["todos"])?;
+            self.db.in_transaction(|db| changed.iter().try_for_each(|todo| db.insert_todos_data(todo)))?;
+        }
+        Ok(report)
+    }
+}
+
+/// A content line: `NAME;PARAM=value:value`, names upper-cased.
+#[derive(Debug, Clone, PartialEq)]
+struct Property {
+    name: String,
+    params: Vec<(String, String)>,
+    value: String,
+}
+
+impl Property {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// The properties of each VTODO in a calendar, leaving out those of
+/// components nested in it such as VALARMs.
+fn parse_todos(text: &str) -> Result<Vec<Vec<Property>>, String> {
+    let lines = unfold(text);
+    if !lines.first().is_some_and(|line| line.eq_ignore_ascii_case("BEGIN:VCALENDAR")) {
+        return Err("no VCALENDAR".to_string());
+    }
+
+    let mut todos = Vec::new();
+    let mut stack: Vec<String> = Vec::new();
+    for line in &lines {
+        let Some(property) = parse_line(line) else {
+            continue;
+        };
+        match property.name.as_str() {
+            "BEGIN" => {
+                let component = property.value.to_ascii_uppercase();
+                if component == "VTODO" && stack.last().is_some_and(|parent| parent == "VCALENDAR") {
+                    todos.push(Vec::new());
+                }
+                stack.push(component);
+            }
+            "END" => {
+                stack.pop();
+            }
+            _ if stack.last().is_some_and(|component| component == "VTODO") => {
+                if let Some(todo) = todos.last_mut() {
+                    todo.push(property);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(todos)
+}
+
+/// Joins folded lines back up: a line starting with a space or tab
+/// continues the one before.
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.split('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if let Some(rest) = line.strip_prefix([' ', '\t']) {
+            if let Some(last) = lines.last_mut() {
+                last.push_str(rest);
+                continue;
+            }
+        }
+        if !line.is_empty() {
+            lines.push(line.to_string());
+        }
+    }
+    lines
+}
+
+fn parse_line(line: &str) -> Option<Property> {
+    let mut in_quotes = false;
+    let colon = line.char_indices().find_map(|(i, c)| {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ':' if !in_quotes => return Some(i),
+            _ => {}
+        }
+        None
+    })?;
+    let mut parts = split_unquoted(&line[..colon], ';').into_iter();
+    let name = parts.next()?.trim().to_ascii_uppercase();
+    let params = parts
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, value)| (key.trim().to_ascii_uppercase(), value.trim_matches('"').to_string()))
+        .collect();
+    Some(Property { name, params, value: line[colon + 1..].to_string() })
+}
+
+fn split_unquoted(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut in_quotes = false;
+    for (i, c) in text.char_indices() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        } else if c == separator && !in_quotes {
+            parts.push(&text[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Reads a TEXT value, or a comma-separated list of them when `list`.
+fn unescape(value: &str, list: bool) -> Vec<String> {
+    let mut items = vec![String::new()];
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == ',' && list {
+            items.push(String::new());
+            continue;
+        }
+        let item = items.last_mut().unwrap();
+        match c {
+            '\\' => match chars.next() {
+                Some('n' | 'N') => item.push('\n'),
+                Some(escaped) => item.push(escaped),
+                None => {}
+            },
+            _ => item.push(c),
+        }
+    }
+    items
+}
+
+fn unescape_text(value: &str) -> String {
+    unescape(value, false).remove(0)
+}
+
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes a content line, folded at 75 octets without splitting a
+/// character.
+fn fold(line: &str, out: &mut String) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > MAX_LINE_OCTETS {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+/// A DATE-TIME in UTC, in its TZID, or floating (read as UTC), or a DATE
+/// as midnight UTC. The flag is set for DATEs.
+fn parse_time(property: &Property) -> Result<(DateTime<Utc>, bool), String> {
+    let value = property.value.trim();
+    let invalid = || format!("invalid {} {:?}", property.name, value);
+    if property.param("VALUE").is_some_and(|kind| kind.eq_ignore_ascii_case("DATE")) || value.len() == 8 {
+        let date = NaiveDate::parse_from_str(value, "%Y%m%d").map_err(|_| invalid())?;
+        return Ok((date.and_hms_opt(0, 0, 0).unwrap().and_utc(), true));
+    }
+    if let Some(utc) = value.strip_suffix(['Z', 'z']) {
+        let time = NaiveDateTime::parse_from_str(utc, DATE_TIME_FORMAT).map_err(|_| invalid())?;
+        return Ok((time.and_utc(), false));
+    }
+    let local = NaiveDateTime::parse_from_str(value, DATE_TIME_FORMAT).map_err(|_| invalid())?;
+    match property.param("TZID") {
+        Some(tzid) => {
+            let tz = timezone(tzid).ok_or_else(|| format!("unknown TZID {:?}", tzid))?;
+            let time = tz.from_local_datetime(&local).earliest().ok_or_else(invalid)?;
+            Ok((time.with_timezone(&Utc), false))
+        }
+        None => Ok((local.and_utc(), false)),
+    }
+}
+
+/// A TZID as an IANA zone. Some apps prefix the name, as in
+/// `/mozilla.org/20050126_1/Europe/Berlin`, so each suffix after a `/` is
+/// tried too.
+fn timezone(tzid: &str) -> Option<Tz> {
+    std::iter::once(tzid)
+        .chain(tzid.match_indices('/').map(|(i, _)| &tzid[i + 1..]))
+        .find_map(|name| name.parse().ok())
+}
+
+fn format_time(t: DateTime<Utc>) -> String {
+    t.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// iCalendar priorities run from 1, highest, to 9, with 0 for none.
+fn from_ical_priority(value: u32) -> Option<i32> {
+    match value {
+        0 => None,
+        1 => Some(3),
+        2..=4 => Some(2),
+        5 => Some(1),
+        _ => Some(0),
+    }
+}
+
+fn to_ical_priority(priority: i32) -> u32 {
+    match priority {
+        p if p >= 3 => 1,
+        2 => 3,
+        1 => 5,
+        _ => 9,
+    }
+}
+
+fn todo_from_properties(properties: &[Property], device_id: &str, now: DateTime<Utc>) -> Result<TodosData, String> {
+    let get = |name: &str| properties.iter().find(|property| property.name == name);
+    let uid = get("UID").map(|uid| uid.value.trim()).filter(|uid| !uid.is_empty()).ok_or("VTODO has no UID")?;
+
+    let mut metadata = Metadata::new();
+    metadata.insert("source".to_string(), Value::from("ical"));
+
+    let due_date = match get("DUE") {
+        Some(due) => {
+            let (due, all_day) = parse_time(due)?;
+            if all_day {
+                metadata.insert("due_all_day".to_string(), Value::Bool(true));
+            }
+            Some(due)
+        }
+        None => None,
+    };
+    let completed_at = get("COMPLETED").map(parse_time).transpose()?.map(|(time, _)| time);
+    let status = get("STATUS").map(|status| status.value.trim().to_ascii_uppercase());
+    let percent = get("PERCENT-COMPLETE").and_then(|percent| percent.value.trim().parse::<u32>().ok());
+    let completed = status.as_deref() == Some("COMPLETED") || completed_at.is_some() || percent == Some(100);
+    if let Some(status) = status {
+        metadata.insert("ical_status".to_string(), Value::from(status));
+    }
+
+    let priority = match get("PRIORITY") {
+        Some(priority) => {
+            let value: u32 = priority.value.trim().parse().map_err(|_| format!("invalid PRIORITY {:?}", priority.value))?;
+            metadata.insert("ical_priority".to_string(), Value::from(value));
+            from_ical_priority(value)
+        }
+        None => None,
+    };
+
+    let timestamp = match get("LAST-MODIFIED").or_else(|| get("DTSTAMP")) {
+        Some(modified) => parse_time(modified)?.0,
+        None => now,
+    };
+    let tags = properties
+        .iter()
+        .filter(|property| property.name == "CATEGORIES")
+        .flat_map(|categories| unescape(&categories.value, true))
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+
+    Ok(TodosData {
+        timestamp,
+        device_id: device_id.to_string(),
+        todo_id: uid.to_string(),
+        title: get("SUMMARY").map(|summary| unescape_text(&summary.value)).unwrap_or_default(),
+        description: get("DESCRIPTION").map(|description| unescape_text(&description.value)),
+        due_date,
+        completed,
+        completed_at,
+        priority,
+        tags,
+        metadata: Some(metadata),
+    })
+}
+
+/// Whether two versions of a todo say the same, to the second as an
+/// `.ics` file keeps times.
+fn same_content(a: &TodosData, b: &TodosData) -> bool {
+    let seconds = |time: Option<DateTime<Utc>>| time.map(|t| t.timestamp());
+    a.title == b.title
+        && a.description.as_deref().unwrap_or_default() == b.description.as_deref().unwrap_or_default()
+        && seconds(a.due_date) == seconds(b.due_date)
+        && a.completed == b.completed
+        && seconds(a.completed_at) == seconds(b.completed_at)
+        && a.priority == b.priority
+        && a.tags == b.tags
+}
+
+/// Writes a todo as a VTODO. The STATUS and PRIORITY it was imported with
+/// are written back where they still fit, so a round trip through Loom
+/// doesn't turn IN-PROCESS into NEEDS-ACTION or priority 2 into 3.
+fn write_todo(todo: &TodosData, out: &mut String) {
+    let metadata = todo.metadata.as_ref();
+    let imported = |key: &str| metadata.and_then(|metadata| metadata.get(key));
+    let mut line = |line: String| fold(&line, out);
+
+    line("BEGIN:VTODO".to_string());
+    line(format!("UID:{}", escape_text(&todo.todo_id)));
+    line(format!("DTSTAMP:{}", format_time(todo.timestamp)));
+    line(format!("LAST-MODIFIED:{}", format_time(todo.timestamp)));
+    line(format!("SUMMARY:{}", escape_text(&todo.title)));
+    if let Some(description) = todo.description.as_deref().filter(|description| !description.is_empty()) {
+        line(format!("DESCRIPTION:{}", escape_text(description)));
+    }
+    if let Some(due) = todo.due_date {
+        if imported("due_all_day") == Some(&Value::Bool(true)) {
+            line(format!("DUE;VALUE=DATE:{}", due.format("%Y%m%d")));
+        } else {
+            line(format!("DUE:{}", format_time(due)));
+        }
+    }
+
+    let status = match (todo.completed, imported("ical_status").and_then(Value::as_str)) {
+        (true, _) => "COMPLETED",
+        (false, Some(status)) if status != "COMPLETED" => status,
+        _ => "NEEDS-ACTION",
+    };
+    line(format!("STATUS:{}", status));
+    if todo.completed {
+        line("PERCENT-COMPLETE:100".to_string());
+    }
+    if let Some(completed_at) = todo.completed_at.filter(|_| todo.completed) {
+        line(format!("COMPLETED:{}", format_time(completed_at)));
+    }
+
+    if let Some(priority) = todo.priority {
+        let value = imported("ical_priority")
+            .and_then(Value::as_u64)
+            .map(|value| value as u32)
+            .filter(|&value| from_ical_priority(value) == Some(priority))
+            .unwrap_or_else(|| to_ical_priority(priority));
+        line(format!("PRIORITY:{}", value));
+    }
+    if !todo.tags.is_empty() {
+        let tags: Vec<String> = todo.tags.iter().map(|tag| escape_text(tag)).collect();
+        line(format!("CATEGORIES:{}", tags.join(",")));
+    }
+    line("END:VTODO".to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::fixtures::{device, temp_database, user};
+
+    const TASKS: &str = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//Example//Tasks//EN\r\n\
+        BEGIN:VTODO\r\nUID:groceries@example.com\r\nDTSTAMP:20240601T080000Z\r\nLAST-MODIFIED:20240601T090000Z\r\n\
+        SUMMARY:Buy milk\\, eggs\r\nDESCRIPTION:From the corner shop\\nnot the market\r\n\
+        DUE;TZID=/mozilla.org/20050126_1/Europe/Berlin:20240602T180000\r\nPRIORITY:2\r\nSTATUS:IN-PROCESS\r\n\
+        CATEGORIES:errands,home\r\nBEGIN:VALARM\r\nACTION:DISPLAY\r\nDESCRIPTION:Reminder\r\nEND:VALARM\r\nEND:VTODO\r\n\
+        BEGIN:VTODO\r\nUID:taxes@example.com\r\nDTSTAMP:20240501T080000Z\r\nSUMMARY:File ta\r\n xes\r\n\
+        DUE;VALUE=DATE:20240531\r\nSTATUS:COMPLETED\r\nCOMPLETED:20240530T170000Z\r\nEND:VTODO\r\n\
+        BEGIN:VTODO\r\nSUMMARY:No uid\r\nEND:VTODO\r\nEND:VCALENDAR\r\n";
+
+    fn todos() -> Vec<TodosData> {
+        let now = Utc.with_ymd_and_hms(2024, 6, 10, 0, 0, 0).unwrap();
+        parse_todos(TASKS)
+            .unwrap()
+            .iter()
+            .filter_map(|properties| todo_from_properties(properties, "ical-alice", now).ok())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_todos() {
+        let components = parse_todos(TASKS).unwrap();
+        assert_eq!(components.len(), 3);
+        assert_eq!(todo_from_properties(&components[2], "ical-alice", Utc::now()).unwrap_err(), "VTODO has no UID");
+
+        let todos = todos();
+        let groceries = &todos[0];
+        assert_eq!(groceries.todo_id, "groceries@example.com");
+        assert_eq!(groceries.title, "Buy milk, eggs");
+        // The VALARM's DESCRIPTION isn't the todo's
+        assert_eq!(groceries.description.as_deref(), Some("From the corner shop\nnot the market"));
+        assert_eq!(groceries.due_date, Some(Utc.with_ymd_and_hms(2024, 6, 2, 16, 0, 0).unwrap()));
+        assert_eq!(groceries.timestamp, Utc.with_ymd_and_hms(2024, 6, 1, 9, 0, 0).unwrap());
+        assert_eq!((groceries.priority, groceries.completed), (Some(2), false));
+        assert_eq!(groceries.tags, vec!["errands", "home"]);
+
+        let taxes = &todos[1];
+        assert_eq!(taxes.title, "File taxes");
+        assert!(taxes.completed);
+        assert_eq!(taxes.completed_at, Some(Utc.with_ymd_and_hms(2024, 5, 30, 17, 0, 0).unwrap()));
+        assert_eq!(taxes.due_date, Some(Utc.with_ymd_and_hms(2024, 5, 31, 0, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn test_written_todos_read_back_the_same() {
+        let mut ics = String::new();
+        fold("BEGIN:VCALENDAR", &mut ics);
+        for todo in todos() {
+            write_todo(&todo, &mut ics);
+        }
+        fold("END:VCALENDAR", &mut ics);
+
+        assert!(ics.contains("PRIORITY:2\r\n"));
+        assert!(ics.contains("STATUS:IN-PROCESS\r\n"));
+        assert!(ics.contains("DUE;VALUE=DATE:20240531\r\n"));
+        assert!(ics.lines().all(|line| line.len() <= MAX_LINE_OCTETS));
+
+        let now = Utc::now();
+        let again: Vec<TodosData> = parse_todos(&ics)
+            .unwrap()
+            .iter()
+            .map(|properties| todo_from_properties(properties, "ical-alice", now).unwrap())
+            .collect();
+        for (before, after) in todos().iter().zip(&again) {
+            assert!(same_content(before, after), "{:?} != {:?}", before, after);
+            assert_eq!(before.timestamp, after.timestamp);
+        }
+    }
+
+    #[test]
+    fn test_fold_and_escape() {
+        let mut out = String::new();
+        let long = format!("SUMMARY:{}", "é".repeat(40));
+        fold(&long, &mut out);
+        assert!(out.split("\r\n").all(|line| line.len() <= MAX_LINE_OCTETS));
+        assert_eq!(unfold(&out), vec![long]);
+
+        assert_eq!(escape_text("a;b,c\\d\ne"), "a\\;b\\,c\\\\d\\ne");
+        assert_eq!(unescape_text(&escape_text("a;b,c\\d\ne")), "a;b,c\\d\ne");
+        assert_eq!(unescape("a\\,b,c", true), vec!["a,b", "c"]);
+    }
+
+    #[test]
+    fn test_priority_mapping() {
+        assert_eq!((1..=9).map(|p| from_ical_priority(p).unwrap()).collect::<Vec<_>>(), vec![3, 2, 2, 2, 1, 0, 0, 0, 0]);
+        assert_eq!(from_ical_priority(0), None);
+        for priority in 0..=3 {
+            assert_eq!(from_ical_priority(to_ical_priority(priority)), Some(priority));
+        }
+    }
+
+    #[test]
+    fn test_todo_ics_round_trips_through_the_calendar_device() -> Result<(), AccessError> {
+        let (dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_user(&user("bob"))?;
+        let alice = db.scoped(UserContext::new("alice"));
+        alice.insert_device(&device("alice_phone", "alice"))?;
+        // Edited on the phone after the task app last saw it
+        alice.insert_todos_data(&TodosData {
+            timestamp: Utc.with_ymd_and_hms(2024, 6, 5, 0, 0, 0).unwrap(),
+            device_id: "alice_phone".to_string(),
+            todo_id: "taxes@example.com".to_string(),
+            title: "File taxes today".to_string(),
+            description: None,
+            due_date: None,
+            completed: false,
+            completed_at: None,
+            priority: Some(3),
+            tags: vec![],
+            metadata: None,
+        })?;
+
+        let path = dir.path().join("tasks.ics");
+        std::fs::write(
+            &path,
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\n\
+             BEGIN:VTODO\r\nUID:groceries@example.com\r\nLAST-MODIFIED:20240601T090000Z\r\nSUMMARY:Buy milk\r\n\
+             PRIORITY:2\r\nCATEGORIES:errands\r\nEND:VTODO\r\n\
+             BEGIN:VTODO\r\nUID:taxes@example.com\r\nLAST-MODIFIED:20240501T090000Z\r\nSUMMARY:File taxes\r\nEND:VTODO\r\n\
+             BEGIN:VTODO\r\nSUMMARY:No uid\r\nEND:VTODO\r\nEND:VCALENDAR\r\n",
+        )
+        .unwrap();
+
+        let calendar = TodoCalendar::new(&db, UserContext::new("alice"));
+        let preview = calendar.import(&path, true).unwrap();
+        assert_eq!((preview.imported, preview.stale, preview.failed), (1, 1, 1));
+        assert_eq!(preview.errors[0].line, 3);
+        assert_eq!(db.get_device_owner(&preview.device_id)?, None);
+
+        let report = calendar.import(&path, false).unwrap();
+        assert_eq!(report.device_id, "ical-alice");
+        assert_eq!(report.imported, 1);
+
+        let ics = calendar.export(&[]).unwrap();
+        assert!(ics.contains("SUMMARY:File taxes today\r\n"));
+        assert!(ics.contains("PRIORITY:2\r\n"));
+        let exported = dir.path().join("export.ics");
+        std::fs::write(&exported, &ics).unwrap();
+        let again = calendar.import(&exported, false).unwrap();
+        assert_eq!((again.unchanged, again.imported), (2, 0));
+
+        std::fs::write(&exported, ics.replace("SUMMARY:Buy milk", "SUMMARY:Buy oat milk").replace("20240601T090000Z", "20240607T090000Z"))
+            .unwrap();
+        let edited = calendar.import(&exported, false).unwrap();
+        assert_eq!((edited.imported, edited.unchanged), (1, 1));
+        let latest = alice.get_latest_todos(&[])?;
+        assert_eq!(latest.iter().map(|todo| todo.title.as_str()).collect::<Vec<_>>(), vec!["Buy oat milk", "File taxes today"]);
+
+        let bob = TodoCalendar::new(&db, UserContext::new("bob"));
+        assert!(matches!(bob.export(&["alice_phone".to_string()]), Err(AccessError::Denied(_))));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,729 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use crate::collection::SensorRow;
+use crate::datatypes::{
+    device::{Device, DeviceCapabilities, ScreenDetails},
+    types::{ConsentLevel, DeviceType},
+};
+use crate::db::{AccessError, Database, UserContext};
+
+mod apple_health;
+mod fit;
+mod takeout;
+mod tcx;
+mod workout;
+
+pub use apple_health::{AppleHealthImporter, AppleHealthReport};
+pub use takeout::{TakeoutImporter, TakeoutReport};
+pub use workout::{WorkoutImporter, WorkoutLap, WorkoutReport, WorkoutSession};
+
+/// Rows stored per transaction, so a file never has to fit in memory.
+const BATCH_SIZE: usize = 500;
+
+/// Row errors kept in a report. Past this only `failed` keeps counting.
+const MAX_REPORTED_ERRORS: usize = 1000;
+
+/// Tried in order when a mapping doesn't list its own formats.
+const DEFAULT_TIMESTAMP_FORMATS: &[&str] = &["rfc3339", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f", "unix"];
+
+#[derive(Debug)]
+pub enum ImportError {
+    /// The mapping doesn't fit the table, or the file's header.
+    InvalidMapping(String),
+    /// Not a sensor table, or one that can't be imported yet.
+    UnknownTable(String),
+    Csv(csv::Error),
+    /// The file isn't valid JSON, or not shaped as expected.
+    Json(serde_json::Error),
+    Xml(quick_xml::Error),
+    /// The file isn't a FIT file, or is cut short or corrupt.
+    Fit(String),
+    /// The file isn't an iCalendar file.
+    Calendar(String),
+    Database(duckdb::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::InvalidMapping(reason) => write!(f, "invalid import mapping: {}", reason),
+            ImportError::UnknownTable(table) => write!(f, "unknown sensor table: {}", table),
+            ImportError::Csv(e) => write!(f, "{}", e),
+            ImportError::Json(e) => write!(f, "invalid JSON: {}", e),
+            ImportError::Xml(e) => write!(f, "invalid XML: {}", e),
+            ImportError::Fit(reason) => write!(f, "invalid FIT file: {}", reason),
+            ImportError::Calendar(reason) => write!(f, "invalid iCalendar file: {}", reason),
+            ImportError::Database(e) => write!(f, "{}", e),
+            ImportError::Io(e) => write!(f, "import i/o error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<csv::Error> for ImportError {
+    fn from(e: csv::Error) -> Self {
+        ImportError::Csv(e)
+    }
+}
+
+impl From<serde_json::Error> for ImportError {
+    fn from(e: serde_json::Error) -> Self {
+        ImportError::Json(e)
+    }
+}
+
+impl From<quick_xml::Error> for ImportError {
+    fn from(e: quick_xml::Error) -> Self {
+        ImportError::Xml(e)
+    }
+}
+
+impl From<duckdb::Error> for ImportError {
+    fn from(e: duckdb::Error) -> Self {
+        ImportError::Database(e)
+    }
+}
+
+impl From<io::Error> for ImportError {
+    fn from(e: io::Error) -> Self {
+        ImportError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileFormat {
+    /// With a header row.
+    Csv,
+    /// One JSON object per line.
+    Jsonl,
+}
+
+/// How the rows of a CSV or JSON lines file become rows of a sensor table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportMapping {
+    pub table_name: String,
+    pub format: FileFormat,
+    /// CSV only, a comma unless set.
+    #[serde(default)]
+    pub delimiter: Option<char>,
+    /// Field of the table's `datatypes::sensor` struct to the CSV column or
+    /// JSON key it is read from. JSON keys may be dotted paths into nested
+    /// objects. Fields not listed are read from a column of the same name,
+    /// if there is one.
+    #[serde(default)]
+    pub columns: BTreeMap<String, String>,
+    /// Fields given the same value on every row, such as `device_id` for a
+    /// dump from a single device.
+    #[serde(default)]
+    pub constants: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub timestamps: TimestampFormat,
+}
+
+/// How timestamps are written in the file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TimestampFormat {
+    /// Tried in order: `rfc3339`, `unix` (seconds), `unix_ms`, or a
+    /// `strftime` pattern such as `%d/%m/%Y %H:%M`.
+    #[serde(default)]
+    pub formats: Vec<String>,
+    /// IANA zone of timestamps without an offset, UTC if unset.
+    #[serde(default)]
+    pub timezone: Option<String>,
+}
+
+/// A row that was skipped, by its line in the file or, for formats that
+/// aren't line based, its position among the file's records.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RowError {
+    pub line: u64,
+    pub message: String,
+}
+
+/// What an import stored, or would have on a dry run.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ImportReport {
+    pub table_name: String,
+    pub dry_run: bool,
+    pub rows_read: usize,
+    pub imported: usize,
+    /// Rows already stored, or repeated in the file.
+    pub duplicates: usize,
+    pub failed: usize,
+    /// The first `MAX_REPORTED_ERRORS` failed rows.
+    pub errors: Vec<RowError>,
+    pub earliest: Option<DateTime<Utc>>,
+    pub latest: Option<DateTime<Utc>>,
+}
+
+/// Imports CSV and JSON lines dumps from other trackers into the sensor
+/// tables of a user's devices.
+pub struct FileImporter<'a> {
+    db: &'a Database,
+    user: UserContext,
+}
+
+impl<'a> FileImporter<'a> {
+    pub fn new(db: &'a Database, user: UserContext) -> Self {
+        Self { db, user }
+    }
+
+    /// Streams `path` into `mapping.table_name`. Rows that can't be parsed,
+    /// come from a device the user doesn't own or have consent withheld are
+    /// skipped and reported by line. Rows already stored count as
+    /// duplicates. The rest are stored `BATCH_SIZE` at a time, so an I/O or
+    /// database error part way through leaves the earlier batches stored.
+    /// With `dry_run` every check is made but nothing is stored.
+    pub fn import(&self, path: &Path, mapping: &ImportMapping, dry_run: bool) -> Result<ImportReport, ImportError> {
+        if !SensorRow::supports(&mapping.table_name) {
+            return Err(ImportError::UnknownTable(mapping.table_name.clone()));
+        }
+        let columns = self.db.column_types(&mapping.table_name)?;
+        let parser = RowParser::new(mapping, &columns)?;
+        let mut sink = RowSink::new(self.db, self.user.clone(), &mapping.table_name, dry_run);
+        let file = BufReader::new(File::open(path)?);
+
+        match mapping.format {
+            FileFormat::Csv => {
+                let delimiter = mapping.delimiter.unwrap_or(',');
+                if !delimiter.is_ascii() {
+                    return Err(ImportError::InvalidMapping(format!("delimiter {:?} is not ASCII", delimiter)));
+                }
+                let mut reader = csv::ReaderBuilder::new().delimiter(delimiter as u8).from_reader(file);
+                let headers: HashMap<String, usize> = reader
+                    .headers()?
+                    .iter()
+                    .enumerate()
+                    .map(|(i, header)| (header.trim().to_string(), i))
+                    .collect();
+                if let Some(missing) = mapping.columns.values().find(|column| !headers.contains_key(*column)) {
+                    return Err(ImportError::InvalidMapping(format!("the file has no column {}", missing)));
+                }
+
+                for record in reader.records() {
+                    let line = match &record {
+                        Ok(record) => record.position(),
+                        Err(e) => e.position(),
+                    }
+                    .map_or(0, |position| position.line());
+                    let row = match record {
+                        Ok(record) => parser.parse(|column| {
+                            let cell = record.get(*headers.get(column)?)?;
+                            Some(Value::String(cell.to_string()))
+                        }),
+                        Err(e) if e.is_io_error() => return Err(e.into()),
+                        Err(e) => Err(e.to_string()),
+                    };
+                    sink.push(line, row)?;
+                }
+            }
+            FileFormat::Jsonl => {
+                for (i, line) in file.lines().enumerate() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let row = serde_json::from_str::<Value>(&line)
+                        .map_err(|e| e.to_string())
+                        .and_then(|object| parser.parse(|key| lookup(&object, key).cloned()));
+                    sink.push(i as u64 + 1, row)?;
+                }
+            }
+        }
+        sink.finish()
+    }
+}
+
+/// `key` in `object`, or the value at `key` as a dotted path.
+fn lookup<'v>(object: &'v Value, key: &str) -> Option<&'v Value> {
+    object.get(key).or_else(|| key.split('.').try_fold(object, |value, part| value.get(part)))
+}
+
+/// Turns the cells of one row in the file into a `SensorRow`.
+struct RowParser<'m> {
+    mapping: &'m ImportMapping,
+    /// Each field read from the file, with its source column and DuckDB type.
+    fields: Vec<(String, String, String)>,
+    formats: Vec<String>,
+    timezone: Tz,
+}
+
+impl<'m> RowParser<'m> {
+    fn new(mapping: &'m ImportMapping, columns: &HashMap<String, (String, bool)>) -> Result<Self, ImportError> {
+        let table = &mapping.table_name;
+        if let Some(field) = mapping.columns.keys().chain(mapping.constants.keys()).find(|field| !columns.contains_key(*field)) {
+            return Err(ImportError::InvalidMapping(format!("{} has no field {}", table, field)));
+        }
+        let timezone = match &mapping.timestamps.timezone {
+            Some(name) => name
+                .parse::<Tz>()
+                .map_err(|_| ImportError::InvalidMapping(format!("unknown time zone {}", name)))?,
+            None => Tz::UTC,
+        };
+        let formats = if mapping.timestamps.formats.is_empty() {
+            DEFAULT_TIMESTAMP_FORMATS.iter().map(|format| format.to_string()).collect()
+        } else {
+            mapping.timestamps.formats.clone()
+        };
+
+        let mut fields: Vec<(String, String, String)> = columns
+            .iter()
+            .filter(|(field, _)| !mapping.constants.contains_key(*field))
+            .map(|(field, (data_type, _))| {
+                let source = mapping.columns.get(field).unwrap_or(field);
+                (field.clone(), source.clone(), data_type.clone())
+            })
+            .collect();
+        fields.sort();
+        Ok(Self { mapping, fields, formats, timezone })
+    }
+
+    /// Builds a row from the value `cell` finds for each source column.
+    fn parse(&self, cell: impl Fn(&str) -> Option<Value>) -> Result<SensorRow, String> {
+        let mut object: Map<String, Value> = self.mapping.constants.clone().into_iter().collect();
+        for (field, source, data_type) in &self.fields {
+            let Some(raw) = cell(source) else {
+                continue;
+            };
+            if let Some(value) = self.convert(field, raw, data_type)? {
+                object.insert(field.clone(), value);
+            }
+        }
+        SensorRow::parse(&self.mapping.table_name, Value::Object(object)).map_err(|e| e.to_string())
+    }
+
+    /// `raw` as the JSON the table's struct expects for a column of
+    /// `data_type`. CSV cells are all strings, so they're parsed by type;
+    /// JSON values are only converted where a tracker's choice of type is
+    /// likely to differ from ours. Empty cells and nulls are left out.
+    fn convert(&self, field: &str, raw: Value, data_type: &str) -> Result<Option<Value>, String> {
+        let is_timestamp = data_type.starts_with("TIMESTAMP");
+        let text = match raw {
+            Value::Null => return Ok(None),
+            Value::String(text) => text,
+            Value::Number(n) if is_timestamp || data_type == "VARCHAR" => n.to_string(),
+            Value::Bool(b) if data_type == "VARCHAR" => b.to_string(),
+            other => return Ok(Some(other)),
+        };
+        let text = text.trim();
+        if text.is_empty() {
+            return Ok(None);
+        }
+
+        let invalid = |expected: &str| format!("{}: {:?} is not {}", field, text, expected);
+        let value = match data_type {
+            _ if is_timestamp => match self.formats.iter().find_map(|format| parse_timestamp(text, format, self.timezone)) {
+                Some(timestamp) => Value::String(timestamp.to_rfc3339()),
+                None => return Err(invalid("in a known timestamp format")),
+            },
+            "BOOLEAN" => match text.to_ascii_lowercase().as_str() {
+                "true" | "yes" | "1" => Value::Bool(true),
+                "false" | "no" | "0" => Value::Bool(false),
+                _ => return Err(invalid("a boolean")),
+            },
+            "TINYINT" | "SMALLINT" | "INTEGER" | "BIGINT" => {
+                text.parse::<i64>().map(Value::from).map_err(|_| invalid("an integer"))?
+            }
+            "FLOAT" | "DOUBLE" => text
+                .parse::<f64>()
+                .ok()
+                .and_then(serde_json::Number::from_f64)
+                .map(Value::Number)
+                .ok_or_else(|| invalid("a number"))?,
+            "JSON" => serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_string())),
+            list if list.ends_with("[]") => serde_json::from_str(text).map_err(|_| invalid("a JSON list"))?,
+            _ => Value::String(text.to_string()),
+        };
+        Ok(Some(value))
+    }
+}
+
+/// `text` read as `format`, one of the formats `TimestampFormat` takes.
+/// Times without an offset are in `timezone`: ones in a daylight saving gap
+/// there don't exist, and ones in an overlap are taken as the earlier.
+fn parse_timestamp(text: &str, format: &str, timezone: Tz) -> Option<DateTime<Utc>> {
+    let from_millis = |millis: f64| {
+        if millis.is_finite() {
+            DateTime::<Utc>::from_timestamp_millis(millis.round() as i64)
+        } else {
+            None
+        }
+    };
+    match format {
+        "rfc3339" => DateTime::parse_from_rfc3339(text).ok().map(|t| t.with_timezone(&Utc)),
+        "unix" => from_millis(text.parse::<f64>().ok()? * 1000.0),
+        "unix_ms" => from_millis(text.parse::<f64>().ok()?),
+        pattern => match DateTime::parse_from_str(text, pattern) {
+            Ok(t) => Some(t.with_timezone(&Utc)),
+            Err(_) => {
+                let local = NaiveDateTime::parse_from_str(text, pattern)
+                    .or_else(|_| NaiveDate::parse_from_str(text, pattern).map(|day| day.and_time(NaiveTime::MIN)))
+                    .ok()?;
+                timezone.from_local_datetime(&local).earliest().map(|t| t.with_timezone(&Utc))
+            }
+        },
+    }
+}
+
+/// Creates the device rows are stored under when their source doesn't
+/// register one itself, such as a file that doesn't say which device
+/// recorded it, unless it exists already. `sensors` are the tables it
+/// fills, without the `_data` suffix.
+pub(crate) fn ensure_device(db: &Database, user: &UserContext, device_id: &str, name: &str, sensors: &[&str]) -> duckdb::Result<()> {
+    if db.get_device_owner(device_id)?.is_some() {
+        return Ok(());
+    }
+    let has = |sensor: &str| sensors.contains(&sensor);
+    let now = Utc::now();
+    let device = Device {
+        device_id: device_id.to_string(),
+        user_id: user.user_id.clone(),
+        device_type: DeviceType::Unknown,
+        os_type: name.to_string(),
+        os_version: String::new(),
+        app_version: String::new(),
+        available_sensors: sensors.iter().map(|sensor| sensor.to_string()).collect(),
+        capabilities: DeviceCapabilities {
+            has_camera: false,
+            has_microphone: false,
+            has_gps: has("gps"),
+            has_accelerometer: has("accelerometer"),
+            has_gyroscope: has("gyroscope"),
+            has_magnetometer: has("magnetometer"),
+            has_proximity: has("proximity"),
+            has_light: has("light"),
+            has_pressure: has("pressure"),
+            has_temperature: has("temperature"),
+            has_humidity: has("humidity"),
+            has_step_counter: has("step_count"),
+            has_heart_rate: has("heart_rate"),
+            has_ecg: has("ecg"),
+            has_blood_oxygen: has("blood_oxygen"),
+            has_stress: has("stress"),
+            has_compass: has("compass"),
+            screen_details: ScreenDetails { width: 0, height: 0, density: 1.0, refresh_rate: 0 },
+        },
+        name: Some(name.to_string()),
+        created_at: now,
+        last_seen: now,
+        updated_at: now,
+        retired_at: None,
+    };
+    db.insert_device(&device)
+}
+
+/// Checks rows for the table an importer is filling and stores them in
+/// batches, keeping the tally for its `ImportReport`.
+pub(crate) struct RowSink<'a> {
+    db: &'a Database,
+    user: UserContext,
+    /// Whether rows from each device seen so far may be stored, and if not why.
+    devices: HashMap<String, Result<(), String>>,
+    /// Rows not stored yet: the current batch, or on a dry run every row.
+    pending: HashSet<(String, DateTime<Utc>)>,
+    batch: Vec<SensorRow>,
+    report: ImportReport,
+}
+
+impl<'a> RowSink<'a> {
+    pub(crate) fn new(db: &'a Database, user: UserContext, table: &str, dry_run: bool) -> Self {
+        Self {
+            db,
+            user,
+            devices: HashMap::new(),
+            pending: HashSet::new(),
+            batch: Vec::new(),
+            report: ImportReport { table_name: table.to_string(), dry_run, ..Default::default() },
+        }
+    }
+
+    /// Treats `device_id` as the user's and consenting without looking it
+    /// up, for a device the importer creates once the dry run is over.
+    pub(crate) fn expect_device(&mut self, device_id: &str) {
+        self.devices.insert(device_id.to_string(), Ok(()));
+    }
+
+    /// Takes the row read from `line`, or why it couldn't be read.
+    pub(crate) fn push(&mut self, line: u64, row: Result<SensorRow, String>) -> Result<(), ImportError> {
+        self.report.rows_read += 1;
+        let row = match row {
+            Ok(row) => row,
+            Err(message) => {
+                self.fail(line, message);
+                return Ok(());
+            }
+        };
+        if let Err(message) = self.device_allowed(row.device_id())? {
+            self.fail(line, message);
+            return Ok(());
+        }
+
+        let key = (row.device_id().to_string(), row.timestamp());
+        if self.pending.contains(&key) || self.db.row_exists(&self.report.table_name, &key.0, key.1)? {
+            self.report.duplicates += 1;
+            return Ok(());
+        }
+        self.report.imported += 1;
+        self.report.earliest = Some(self.report.earliest.map_or(key.1, |t| t.min(key.1)));
+        self.report.latest = Some(self.report.latest.map_or(key.1, |t| t.max(key.1)));
+        self.pending.insert(key);
+        if !self.report.dry_run {
+            self.batch.push(row);
+            if self.batch.len() >= BATCH_SIZE {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<ImportReport, ImportError> {
+        self.flush()?;
+        Ok(self.report)
+    }
+
+    fn flush(&mut self) -> Result<(), ImportError> {
+        let batch = std::mem::take(&mut self.batch);
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.db.in_transaction(|db| batch.iter().try_for_each(|row| row.insert(db)))?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn fail(&mut self, line: u64, message: String) {
+        self.report.failed += 1;
+        if self.report.errors.len() < MAX_REPORTED_ERRORS {
+            self.report.errors.push(RowError { line, message });
+        }
+    }
+
+    fn device_allowed(&mut self, device_id: &str) -> Result<Result<(), String>, ImportError> {
+        if let Some(allowed) = self.devices.get(device_id) {
+            return Ok(allowed.clone());
+        }
+        let table = &self.report.table_name;
+        let allowed = match self.db.scoped(self.user.clone()).authorize_device(device_id) {
+            Ok(()) => match self.db.get_consent_level(device_id, table)? {
+                level @ ConsentLevel::Never => Err(format!("consent for {} from {} is {}", table, device_id, level.as_str())),
+                _ => Ok(()),
+            },
+            Err(AccessError::Denied(id)) => Err(format!("access denied to {}", id)),
+            Err(e @ AccessError::ConsentWithheld(_)) => Err(e.to_string()),
+            Err(AccessError::Database(e)) => return Err(e.into()),
+        };
+        self.devices.insert(device_id.to_string(), allowed.clone());
+        Ok(allowed)
+    }
+}
+
+/// A `RowSink` per table, made when its first row turns up, for importers
+/// that fill several tables under a device of their own.
+pub(crate) struct Sinks<'a> {
+    db: &'a Database,
+    user: UserContext,
+    device_id: String,
+    dry_run: bool,
+    sinks: BTreeMap<&'static str, RowSink<'a>>,
+}
+
+impl<'a> Sinks<'a> {
+    pub(crate) fn new(db: &'a Database, user: UserContext, device_id: &str, dry_run: bool) -> Self {
+        Self { db, user, device_id: device_id.to_string(), dry_run, sinks: BTreeMap::new() }
+    }
+
+    pub(crate) fn push(&mut self, table: &'static str, position: u64, row: Result<SensorRow, String>) -> Result<(), ImportError> {
+        let (db, user, device_id, dry_run) = (self.db, &self.user, &self.device_id, self.dry_run);
+        let sink = self.sinks.entry(table).or_insert_with(|| {
+            let mut sink = RowSink::new(db, user.clone(), table, dry_run);
+            if dry_run {
+                sink.expect_device(device_id);
+            }
+            sink
+        });
+        sink.push(position, row)
+    }
+
+    pub(crate) fn finish(self) -> Result<BTreeMap<String, ImportReport>, ImportError> {
+        self.sinks
+            .into_iter()
+            .map(|(table, sink)| sink.finish().map(|report| (table.to_string(), report)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::db::fixtures::{device, temp_database, user};
+
+    fn heart_rate_columns() -> HashMap<String, (String, bool)> {
+        [
+            ("timestamp", "TIMESTAMP", false),
+            ("device_id", "VARCHAR", false),
+            ("bpm", "INTEGER", true),
+            ("confidence", "FLOAT", true),
+            ("rr_intervals", "FLOAT[]", true),
+            ("metadata", "JSON", true),
+        ]
+        .into_iter()
+        .map(|(name, data_type, nullable)| (name.to_string(), (data_type.to_string(), nullable)))
+        .collect()
+    }
+
+    fn heart_rate_mapping() -> ImportMapping {
+        ImportMapping {
+            table_name: "heart_rate_data".to_string(),
+            format: FileFormat::Csv,
+            delimiter: None,
+            columns: [("timestamp", "time"), ("bpm", "HR")]
+                .into_iter()
+                .map(|(field, column)| (field.to_string(), column.to_string()))
+                .collect(),
+            constants: [("device_id".to_string(), json!("watch"))].into_iter().collect(),
+            timestamps: TimestampFormat {
+                formats: vec!["%d/%m/%Y %H:%M".to_string()],
+                timezone: Some("Europe/Berlin".to_string()),
+            },
+        }
+    }
+
+    #[test]
+    fn test_parse_timestamp_formats_and_zones() {
+        let berlin: Tz = "Europe/Berlin".parse().unwrap();
+        let utc = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+
+        assert_eq!(parse_timestamp("01/03/2024 09:30", "%d/%m/%Y %H:%M", berlin), Some(utc("2024-03-01T08:30:00Z")));
+        assert_eq!(parse_timestamp("2024-07-01", "%Y-%m-%d", berlin), Some(utc("2024-06-30T22:00:00Z")));
+        assert_eq!(parse_timestamp("2024-03-01T09:30:00-05:00", "rfc3339", berlin), Some(utc("2024-03-01T14:30:00Z")));
+        assert_eq!(parse_timestamp("1709285400.5", "unix", berlin), Some(utc("2024-03-01T09:30:00.500Z")));
+        assert_eq!(parse_timestamp("1709285400000", "unix_ms", berlin), Some(utc("2024-03-01T09:30:00Z")));
+        // Clocks in Berlin went from 02:00 to 03:00 that night
+        assert_eq!(parse_timestamp("31/03/2024 02:30", "%d/%m/%Y %H:%M", berlin), None);
+        assert_eq!(parse_timestamp("yesterday", "unix", berlin), None);
+    }
+
+    #[test]
+    fn test_row_parser_converts_cells_by_column_type() {
+        let mapping = heart_rate_mapping();
+        let parser = RowParser::new(&mapping, &heart_rate_columns()).unwrap();
+        let cells = |bpm: &'static str| {
+            move |column: &str| {
+                let cell = match column {
+                    "time" => "01/03/2024 09:30",
+                    "HR" => bpm,
+                    "confidence" => "0.75",
+                    "rr_intervals" => "[0.8, 0.82]",
+                    "metadata" => "",
+                    _ => return None,
+                };
+                Some(Value::String(cell.to_string()))
+            }
+        };
+
+        let Ok(SensorRow::HeartRate(data)) = parser.parse(cells("72")) else {
+            panic!("row should parse");
+        };
+        assert_eq!(data.timestamp, "2024-03-01T08:30:00Z".parse::<DateTime<Utc>>().unwrap());
+        assert_eq!((data.device_id.as_str(), data.bpm, data.confidence), ("watch", 72, Some(0.75)));
+        assert_eq!(data.rr_intervals, Some(vec![0.8, 0.82]));
+        assert!(data.metadata.is_none());
+
+        let error = parser.parse(cells("fast")).err().unwrap();
+        assert!(error.starts_with("bpm:"), "{}", error);
+    }
+
+    #[test]
+    fn test_mapping_must_name_fields_of_the_table() {
+        let mut mapping = heart_rate_mapping();
+        mapping.columns.insert("heart_rate".to_string(), "HR".to_string());
+        assert!(matches!(RowParser::new(&mapping, &heart_rate_columns()), Err(ImportError::InvalidMapping(_))));
+
+        let mut mapping = heart_rate_mapping();
+        mapping.timestamps.timezone = Some("Mars/Olympus_Mons".to_string());
+        assert!(matches!(RowParser::new(&mapping, &heart_rate_columns()), Err(ImportError::InvalidMapping(_))));
+    }
+
+    #[test]
+    fn test_lookup_follows_dotted_paths() {
+        let object = json!({ "location": { "lat": 51.5 }, "a.b": 1 });
+        assert_eq!(lookup(&object, "location.lat"), Some(&json!(51.5)));
+        assert_eq!(lookup(&object, "a.b"), Some(&json!(1)));
+        assert_eq!(lookup(&object, "location.lon"), None);
+    }
+
+    #[test]
+    fn test_file_import_reports_bad_rows_and_skips_duplicates() -> Result<(), AccessError> {
+        let (dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_user(&user("bob"))?;
+        let alice = db.scoped(UserContext::new("alice"));
+        alice.insert_device(&device("alice_phone", "alice"))?;
+        alice.insert_device(&device("alice_watch", "alice"))?;
+        db.scoped(UserContext::new("bob")).insert_device(&device("bob_phone", "bob"))?;
+        alice.set_consent("alice_watch", "heart_rate_data", ConsentLevel::Never, false)?;
+
+        let path = dir.path().join("heart_rate.csv");
+        std::fs::write(
+            &path,
+            "time;device;HR\n\
+             01/03/2024 09:30;alice_phone;72\n\
+             01/03/2024 09:31;alice_phone;fast\n\
+             01/03/2024 09:32;bob_phone;80\n\
+             01/03/2024 09:33;alice_watch;65\n\
+             01/03/2024 09:30;alice_phone;72\n\
+             01/03/2024 09:34;alice_phone;75\n",
+        )
+        .unwrap();
+        let mapping = ImportMapping {
+            table_name: "heart_rate_data".to_string(),
+            format: FileFormat::Csv,
+            delimiter: Some(';'),
+            columns: [("timestamp", "time"), ("device_id", "device"), ("bpm", "HR")]
+                .into_iter()
+                .map(|(field, column)| (field.to_string(), column.to_string()))
+                .collect(),
+            constants: Default::default(),
+            timestamps: TimestampFormat {
+                formats: vec!["%d/%m/%Y %H:%M".to_string()],
+                timezone: Some("Europe/Berlin".to_string()),
+            },
+        };
+        let importer = FileImporter::new(&db, UserContext::new("alice"));
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+        let end = Utc.with_ymd_and_hms(2024, 3, 2, 0, 0, 0).unwrap();
+
+        let preview = importer.import(&path, &mapping, true).unwrap();
+        assert!(db.get_heart_rate_data("alice_phone", start, end)?.is_empty());
+        assert_eq!((preview.rows_read, preview.imported, preview.duplicates, preview.failed), (6, 2, 1, 3));
+        let lines: Vec<u64> = preview.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![3, 4, 5]);
+        assert!(preview.errors[0].message.starts_with("bpm:"));
+        assert_eq!(preview.earliest, Some(Utc.with_ymd_and_hms(2024, 3, 1, 8, 30, 0).unwrap()));
+        assert_eq!(preview.latest, Some(Utc.with_ymd_and_hms(2024, 3, 1, 8, 34, 0).unwrap()));
+
+        let report = importer.import(&path, &mapping, false).unwrap();
+        assert_eq!(report, ImportReport { dry_run: false, ..preview });
+        let stored = db.get_heart_rate_data("alice_phone", start, end)?;
+        let mut bpm: Vec<i32> = stored.iter().map(|r| r.bpm).collect();
+        bpm.sort();
+        assert_eq!(bpm, vec![72, 75]);
+
+        let again = importer.import(&path, &mapping, false).unwrap();
+        assert_eq!((again.imported, again.duplicates, again.failed), (0, 3, 3));
+        assert_eq!(again.errors[1], RowError { line: 4, message: "access denied to bob_phone".to_string() });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,472 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde::Serialize;
+use serde_json::Value;
+use crate::collection::SensorRow;
+use crate::datatypes::{
+    sensor::{BloodOxygenData, ECGData, HeartRateData, StepCountData, StressData},
+    types::Metadata,
+};
+use crate::db::{Database, UserContext};
+use super::{ensure_device, ImportError, ImportReport, RowError, Sinks};
+
+/// Each `HKQuantityTypeIdentifier` imported from `export.xml`, with the
+/// table its records go to.
+const RECORD_TABLES: &[(&str, &str)] = &[
+    ("HKQuantityTypeIdentifierHeartRate", "heart_rate_data"),
+    ("HKQuantityTypeIdentifierOxygenSaturation", "blood_oxygen_data"),
+    ("HKQuantityTypeIdentifierStepCount", "step_count_data"),
+    ("HKQuantityTypeIdentifierHeartRateVariabilitySDNN", "stress_data"),
+];
+
+/// Where an export keeps its ECG recordings, next to `export.xml`.
+const ECG_DIR: &str = "electrocardiograms";
+
+/// How Health writes every date, e.g. `2024-03-01 09:30:00 +0100`.
+const HEALTH_DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S %z";
+
+/// What an Apple Health import stored, or would have on a dry run.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AppleHealthReport {
+    /// The device the export was stored under.
+    pub device_id: String,
+    /// By table, for each table the export had rows for.
+    pub tables: BTreeMap<String, ImportReport>,
+    /// Records of types with no table, such as sleep analysis, by type.
+    pub skipped_types: BTreeMap<String, usize>,
+    /// Records that couldn't be read far enough to tell their table.
+    pub errors: Vec<RowError>,
+}
+
+/// Imports an unzipped Apple Health export: heart rate, blood oxygen, step
+/// count and HRV records from `export.xml`, and the recordings in
+/// `electrocardiograms`. Rows go under a device made for the import, as
+/// the export mixes the user's iPhone, Watch and any apps writing to Health.
+pub struct AppleHealthImporter<'a> {
+    db: &'a Database,
+    user: UserContext,
+}
+
+impl<'a> AppleHealthImporter<'a> {
+    pub fn new(db: &'a Database, user: UserContext) -> Self {
+        Self { db, user }
+    }
+
+    /// The device Apple Health data is stored under for the user.
+    pub fn device_id(&self) -> String {
+        format!("apple-health-{}", self.user.user_id)
+    }
+
+    /// Imports the export at `path`, its `export.xml` or the directory
+    /// holding it. `export.xml` is read a record at a time, so exports of
+    /// several gigabytes import in constant memory, and rows are stored in
+    /// batches as they're read. Rows already stored are skipped; where two
+    /// sources recorded the same moment the first in the export is kept.
+    /// With `dry_run` nothing is stored and the device isn't created.
+    pub fn import(&self, path: &Path, dry_run: bool) -> Result<AppleHealthReport, ImportError> {
+        let (xml, dir) = if path.is_dir() {
+            (path.join("export.xml"), path.to_path_buf())
+        } else {
+            (path.to_path_buf(), path.parent().map_or_else(PathBuf::new, Path::to_path_buf))
+        };
+        let device_id = self.device_id();
+        if !dry_run {
+            ensure_device(self.db, &self.user, &device_id, "Apple Health", &["heart_rate", "blood_oxygen", "step_count", "stress", "ecg"])?;
+        }
+        let mut sinks = Sinks::new(self.db, self.user.clone(), &device_id, dry_run);
+        let mut report = AppleHealthReport { device_id: device_id.clone(), ..Default::default() };
+
+        let mut reader = Reader::from_reader(BufReader::new(File::open(&xml)?));
+        let mut buf = Vec::new();
+        let mut open: Option<(u64, Result<HealthRecord, String>)> = None;
+        let mut position = 0;
+        loop {
+            match reader.read_event_into(&mut buf)? {
+                Event::Start(e) if e.name().as_ref() == b"Record" => {
+                    position += 1;
+                    open = Some((position, HealthRecord::from_element(&e)));
+                }
+                Event::Empty(e) if e.name().as_ref() == b"Record" => {
+                    position += 1;
+                    take(&mut sinks, &device_id, position, HealthRecord::from_element(&e), &mut report)?;
+                }
+                Event::Empty(e) if e.name().as_ref() == b"MetadataEntry" => {
+                    if let Some((_, Ok(record))) = &mut open {
+                        record.add_metadata(&e);
+                    }
+                }
+                Event::End(e) if e.name().as_ref() == b"Record" => {
+                    if let Some((position, record)) = open.take() {
+                        take(&mut sinks, &device_id, position, record, &mut report)?;
+                    }
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+            buf.clear();
+        }
+
+        let ecg_dir = dir.join(ECG_DIR);
+        if ecg_dir.is_dir() {
+            let mut files: Vec<PathBuf> = fs::read_dir(&ecg_dir)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<_, _>>()?;
+            files.retain(|file| file.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("csv")));
+            files.sort();
+            for (i, file) in files.iter().enumerate() {
+                let name = file.file_name().unwrap_or_default().to_string_lossy();
+                let row = parse_ecg(&fs::read_to_string(file)?, &device_id)
+                    .map(SensorRow::Ecg)
+                    .map_err(|message| format!("{}: {}", name, message));
+                sinks.push("ecg_data", i as u64 + 1, row)?;
+            }
+        }
+
+        report.tables = sinks.finish()?;
+        Ok(report)
+    }
+}
+
+/// Routes a record from `export.xml` to its table's sink, or tallies it in
+/// `report` if it has none or couldn't be read.
+fn take(
+    sinks: &mut Sinks<'_>,
+    device_id: &str,
+    position: u64,
+    record: Result<HealthRecord, String>,
+    report: &mut AppleHealthReport,
+) -> Result<(), ImportError> {
+    let record = match record {
+        Ok(record) => record,
+        Err(message) => {
+            report.errors.push(RowError { line: position, message });
+            return Ok(());
+        }
+    };
+    let Some(&(_, table)) = RECORD_TABLES.iter().find(|(record_type, _)| *record_type == record.record_type) else {
+        *report.skipped_types.entry(record.record_type).or_default() += 1;
+        return Ok(());
+    };
+    sinks.push(table, position, record.to_row(device_id))
+}
+
+/// A `<Record>` from `export.xml` with its `<MetadataEntry>` children.
+struct HealthRecord {
+    record_type: String,
+    source_name: Option<String>,
+    unit: Option<String>,
+    start_date: Option<String>,
+    end_date: Option<String>,
+    value: Option<String>,
+    metadata: Metadata,
+}
+
+impl HealthRecord {
+    fn from_element(element: &BytesStart<'_>) -> Result<Self, String> {
+        let mut record = HealthRecord {
+            record_type: String::new(),
+            source_name: None,
+            unit: None,
+            start_date: None,
+            end_date: None,
+            value: None,
+            metadata: Metadata::new(),
+        };
+        for attribute in element.attributes() {
+            let attribute = attribute.map_err(|e| e.to_string())?;
+            let value = attribute.unescape_value().map_err(|e| e.to_string())?.into_owned();
+            match attribute.key.as_ref() {
+                b"type" => record.record_type = value,
+                b"sourceName" => record.source_name = Some(value),
+                b"unit" => record.unit = Some(value),
+                b"startDate" => record.start_date = Some(value),
+                b"endDate" => record.end_date = Some(value),
+                b"value" => record.value = Some(value),
+                _ => {}
+            }
+        }
+        if record.record_type.is_empty() {
+            return Err("record has no type".to_string());
+        }
+        Ok(record)
+    }
+
+    /// Keeps a `<MetadataEntry key=".." value=".."/>` under its key.
+    /// Entries that can't be read are dropped rather than failing the record.
+    fn add_metadata(&mut self, element: &BytesStart<'_>) {
+        let (mut key, mut value) = (None, None);
+        for attribute in element.attributes().flatten() {
+            let Ok(text) = attribute.unescape_value() else {
+                continue;
+            };
+            match attribute.key.as_ref() {
+                b"key" => key = Some(text.into_owned()),
+                b"value" => value = Some(text.into_owned()),
+                _ => {}
+            }
+        }
+        if let (Some(key), Some(value)) = (key, value) {
+            self.metadata.insert(key, Value::String(value));
+        }
+    }
+
+    fn to_row(self, device_id: &str) -> Result<SensorRow, String> {
+        let timestamp = health_date(self.start_date.as_deref().ok_or("record has no startDate")?)?;
+        let text = self.value.as_deref().ok_or("record has no value")?;
+        let value: f64 = text.parse().map_err(|_| format!("value {:?} is not a number", text))?;
+        let device_id = device_id.to_string();
+
+        let mut metadata = self.metadata;
+        metadata.insert("source".to_string(), Value::from("apple_health"));
+        for (key, field) in [("source_name", self.source_name), ("unit", self.unit), ("end_date", self.end_date)] {
+            if let Some(field) = field {
+                metadata.insert(key.to_string(), Value::String(field));
+            }
+        }
+        let metadata = Some(metadata);
+
+        let row = match self.record_type.as_str() {
+            "HKQuantityTypeIdentifierHeartRate" => SensorRow::HeartRate(HeartRateData {
+                timestamp,
+                device_id,
+                bpm: value.round() as i32,
+                confidence: None,
+                rr_intervals: None,
+                metadata,
+            }),
+            // A fraction in `%` units, though some apps have written percentages
+            "HKQuantityTypeIdentifierOxygenSaturation" => SensorRow::BloodOxygen(BloodOxygenData {
+                timestamp,
+                device_id,
+                spo2: (if value <= 1.0 { value * 100.0 } else { value }).round() as i32,
+                confidence: None,
+                raw_values: None,
+                metadata,
+            }),
+            "HKQuantityTypeIdentifierStepCount" => SensorRow::StepCount(StepCountData {
+                timestamp,
+                device_id,
+                steps: value.round() as i32,
+                activity_type: None,
+                confidence: None,
+                metadata,
+            }),
+            "HKQuantityTypeIdentifierHeartRateVariabilitySDNN" => SensorRow::Stress(StressData {
+                timestamp,
+                device_id,
+                stress_score: None,
+                stress_level: None,
+                hrv: Some(value as f32),
+                metadata,
+            }),
+            other => return Err(format!("no table for {}", other)),
+        };
+        Ok(row)
+    }
+}
+
+fn health_date(text: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_str(text, HEALTH_DATE_FORMAT)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|_| format!("invalid date {:?}", text))
+}
+
+/// An ECG recording as Health exports it: `key,value` header lines, then
+/// one sample per line. The header's name and date of birth are left out.
+fn parse_ecg(text: &str, device_id: &str) -> Result<ECGData, String> {
+    let mut recorded = None;
+    let mut classification = None;
+    let mut sample_rate = None;
+    let mut metadata = Metadata::new();
+    let mut voltage = Vec::new();
+
+    for line in text.lines() {
+        let line = line.trim().trim_end_matches(',');
+        if line.is_empty() {
+            continue;
+        }
+        if let Ok(sample) = line.parse::<f32>() {
+            voltage.push(sample);
+            continue;
+        }
+        let Some((key, value)) = line.split_once(',') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"');
+        match key.trim() {
+            "Recorded Date" => recorded = Some(health_date(value)?),
+            "Classification" => classification = Some(value.to_string()),
+            "Sample Rate" => {
+                let hertz = value.split_whitespace().next().and_then(|rate| rate.parse::<f32>().ok());
+                sample_rate = Some(hertz.filter(|rate| *rate > 0.0).ok_or_else(|| format!("invalid sample rate {:?}", value))?);
+            }
+            "Unit" => {
+                metadata.insert("unit".to_string(), Value::from(value));
+            }
+            "Lead" => {
+                metadata.insert("lead".to_string(), Value::from(value));
+            }
+            "Device" => {
+                metadata.insert("source_name".to_string(), Value::from(value));
+            }
+            "Software Version" => {
+                metadata.insert("software_version".to_string(), Value::from(value));
+            }
+            _ => {}
+        }
+    }
+
+    let timestamp = recorded.ok_or("no Recorded Date")?;
+    let sample_rate = sample_rate.ok_or("no Sample Rate")?;
+    if voltage.is_empty() {
+        return Err("no samples".to_string());
+    }
+    metadata.insert("source".to_string(), Value::from("apple_health"));
+    metadata.insert("sample_rate_hz".to_string(), Value::from(sample_rate));
+    Ok(ECGData {
+        timestamp,
+        device_id: device_id.to_string(),
+        time: (0..voltage.len()).map(|i| i as f32 / sample_rate).collect(),
+        voltage,
+        rhythm_classification: classification.filter(|c| !c.is_empty()),
+        heart_rate: None,
+        metadata: Some(metadata),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::db::{fixtures::{temp_database, user}, AccessError};
+
+    fn first_record(xml: &str) -> HealthRecord {
+        let mut reader = Reader::from_str(xml);
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Start(e) | Event::Empty(e) if e.name().as_ref() == b"Record" => {
+                    return HealthRecord::from_element(&e).unwrap();
+                }
+                Event::Eof => panic!("no record"),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_records_map_to_their_tables() {
+        let record = first_record(
+            r#"<Record type="HKQuantityTypeIdentifierOxygenSaturation" sourceName="Alice&apos;s Watch" unit="%"
+                startDate="2024-03-01 09:30:00 +0100" endDate="2024-03-01 09:30:00 +0100" value="0.97"/>"#,
+        );
+        let Ok(SensorRow::BloodOxygen(data)) = record.to_row("health") else {
+            panic!("should be a blood oxygen row");
+        };
+        assert_eq!(data.spo2, 97);
+        assert_eq!(data.timestamp, "2024-03-01T08:30:00Z".parse::<DateTime<Utc>>().unwrap());
+        assert_eq!(data.metadata.unwrap()["source_name"], Value::from("Alice's Watch"));
+
+        let record = first_record(
+            r#"<Record type="HKQuantityTypeIdentifierHeartRateVariabilitySDNN" unit="ms"
+                startDate="2024-03-01 09:30:00 +0100" value="48.2"/>"#,
+        );
+        let Ok(SensorRow::Stress(data)) = record.to_row("health") else {
+            panic!("should be a stress row");
+        };
+        assert_eq!((data.stress_score, data.hrv), (None, Some(48.2)));
+
+        let record = first_record(r#"<Record type="HKQuantityTypeIdentifierHeartRate" startDate="yesterday" value="60"/>"#);
+        assert!(record.to_row("health").is_err());
+    }
+
+    #[test]
+    fn test_parse_ecg_csv() {
+        let csv = "Name,Alice\n\
+                   Date of Birth,\"Jan 1, 1980\"\n\
+                   Recorded Date,2024-03-01 09:30:00 +0100\n\
+                   Classification,Sinus Rhythm\n\
+                   Symptoms,\n\
+                   Sample Rate,512 hertz\n\
+                   Lead,Lead I\n\
+                   Unit,µV\n\
+                   \n\
+                   -12.5\n\
+                   3.25\n\
+                   40\n";
+        let ecg = parse_ecg(csv, "health").unwrap();
+        assert_eq!(ecg.voltage, vec![-12.5, 3.25, 40.0]);
+        assert_eq!(ecg.time, vec![0.0, 1.0 / 512.0, 2.0 / 512.0]);
+        assert_eq!(ecg.rhythm_classification.as_deref(), Some("Sinus Rhythm"));
+        let metadata = ecg.metadata.unwrap();
+        assert_eq!(metadata["unit"], Value::from("µV"));
+        assert!(!metadata.values().any(|value| value == "Alice"));
+
+        assert!(parse_ecg("Recorded Date,2024-03-01 09:30:00 +0100\nSample Rate,512 hertz\n", "health").is_err());
+    }
+
+    #[test]
+    fn test_apple_health_import_fills_health_tables() -> Result<(), AccessError> {
+        let (dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        let export = dir.path().join("apple_health_export");
+        std::fs::create_dir_all(export.join("electrocardiograms")).unwrap();
+        std::fs::write(
+            export.join("export.xml"),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE HealthData [
+<!ELEMENT HealthData (Record*)>
+]>
+<HealthData locale="en_GB">
+ <Record type="HKQuantityTypeIdentifierHeartRate" sourceName="Watch" unit="count/min" startDate="2024-03-01 09:30:00 +0100" endDate="2024-03-01 09:30:00 +0100" value="72">
+  <MetadataEntry key="HKMetadataKeyHeartRateMotionContext" value="1"/>
+ </Record>
+ <Record type="HKQuantityTypeIdentifierHeartRate" sourceName="iPhone" unit="count/min" startDate="2024-03-01 09:30:00 +0100" endDate="2024-03-01 09:30:00 +0100" value="75"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" sourceName="Watch" unit="count/min" startDate="2024-03-01 09:31:00 +0100" value="fast"/>
+ <Record type="HKQuantityTypeIdentifierOxygenSaturation" sourceName="Watch" unit="%" startDate="2024-03-01 09:32:00 +0100" value="0.98"/>
+ <Record type="HKQuantityTypeIdentifierStepCount" sourceName="iPhone" unit="count" startDate="2024-03-01 10:00:00 +0100" endDate="2024-03-01 10:10:00 +0100" value="812"/>
+ <Record type="HKQuantityTypeIdentifierHeartRateVariabilitySDNN" sourceName="Watch" unit="ms" startDate="2024-03-01 09:33:00 +0100" value="51.5"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" sourceName="Watch" startDate="2024-03-01 00:30:00 +0100" endDate="2024-03-01 07:00:00 +0100" value="HKCategoryValueSleepAnalysisAsleepCore"/>
+</HealthData>"#,
+        )
+        .unwrap();
+        std::fs::write(
+            export.join("electrocardiograms/ecg_2024-03-01.csv"),
+            "Recorded Date,2024-03-01 09:40:00 +0100\nClassification,Sinus Rhythm\nSample Rate,512 hertz\nUnit,µV\n\n-1.5\n2.5\n",
+        )
+        .unwrap();
+
+        let importer = AppleHealthImporter::new(&db, UserContext::new("alice"));
+        let device_id = importer.device_id();
+        let preview = importer.import(&export, true).unwrap();
+        assert_eq!(db.get_device_owner(&device_id)?, None);
+        let heart_rate = &preview.tables["heart_rate_data"];
+        assert_eq!((heart_rate.imported, heart_rate.duplicates, heart_rate.failed), (1, 1, 1));
+        assert_eq!(heart_rate.errors[0].line, 3);
+        assert_eq!(preview.skipped_types["HKCategoryTypeIdentifierSleepAnalysis"], 1);
+        assert_eq!(preview.tables.keys().collect::<Vec<_>>(), vec!["blood_oxygen_data", "ecg_data", "heart_rate_data", "step_count_data", "stress_data"]);
+
+        importer.import(&export, false).unwrap();
+        let alice = db.scoped(UserContext::new("alice"));
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+        let end = Utc.with_ymd_and_hms(2024, 3, 2, 0, 0, 0).unwrap();
+        let heart_rate = alice.get_heart_rate_data(&device_id, start, end)?;
+        assert_eq!(heart_rate.len(), 1);
+        assert_eq!(heart_rate[0].bpm, 72);
+        assert_eq!(heart_rate[0].metadata.as_ref().unwrap()["HKMetadataKeyHeartRateMotionContext"], "1");
+        assert_eq!(alice.get_blood_oxygen_data(&device_id, start, end)?[0].spo2, 98);
+        assert_eq!(alice.get_step_count_data(&device_id, start, end)?[0].steps, 812);
+        assert_eq!(alice.get_stress_data(&device_id, start, end)?[0].hrv, Some(51.5));
+        let ecg = alice.get_ecg_data(&device_id, start, end)?;
+        assert_eq!((ecg[0].voltage.clone(), ecg[0].rhythm_classification.as_deref()), (vec![-1.5, 2.5], Some("Sinus Rhythm")));
+
+        let again = importer.import(&export, false).unwrap();
+        assert!(again.tables.values().all(|table| table.imported == 0));
+
+        Ok(())
+    }
+}
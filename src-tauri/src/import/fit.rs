@@ -0,0 +1,418 @@
+use chrono::{DateTime, Utc};
+use super::workout::{Sample, Workout, WorkoutLap, WorkoutSession};
+use super::ImportError;
+
+/// Seconds from the Unix epoch to FIT's, 1989-12-31T00:00:00Z.
+const FIT_EPOCH: i64 = 631_065_600;
+
+/// FIT gives latitude and longitude in semicircles, 2^31 to 180 degrees.
+const DEGREES_PER_SEMICIRCLE: f64 = 180.0 / 2_147_483_648.0;
+
+// Global message numbers from the FIT profile
+const FILE_ID: u16 = 0;
+const SESSION: u16 = 18;
+const LAP: u16 = 19;
+const RECORD: u16 = 20;
+const HRV: u16 = 78;
+
+/// The field every message keeps its timestamp in.
+const TIMESTAMP: u8 = 253;
+
+const CRC_TABLE: [u16; 16] = [
+    0x0000, 0xCC01, 0xD801, 0x1400, 0xF001, 0x3C00, 0x2800, 0xE401,
+    0xA001, 0x6C00, 0x7800, 0xB401, 0x5000, 0x9C01, 0x8801, 0x4400,
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum FitValue {
+    Numbers(Vec<f64>),
+    Text(String),
+}
+
+/// A data message with its valid fields, unscaled. Fields holding FIT's
+/// invalid value are left out.
+#[derive(Debug)]
+struct Message {
+    global: u16,
+    fields: Vec<(u8, FitValue)>,
+}
+
+impl Message {
+    fn numbers(&self, field: u8) -> &[f64] {
+        match self.fields.iter().find(|(number, _)| *number == field) {
+            Some((_, FitValue::Numbers(values))) => values.as_slice(),
+            _ => &[],
+        }
+    }
+
+    fn number(&self, field: u8) -> Option<f64> {
+        self.numbers(field).first().copied()
+    }
+
+    fn text(&self, field: u8) -> Option<&str> {
+        match self.fields.iter().find(|(number, _)| *number == field) {
+            Some((_, FitValue::Text(text))) => Some(text.as_str()),
+            _ => None,
+        }
+    }
+
+    fn time(&self, field: u8) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp(FIT_EPOCH + self.number(field)? as i64, 0)
+    }
+}
+
+/// A definition message: how the data messages of one local type are laid
+/// out until it's redefined.
+struct Definition {
+    global: u16,
+    big_endian: bool,
+    /// Field number, size in bytes and base type.
+    fields: Vec<[u8; 3]>,
+    /// Bytes of developer fields after the profile ones, which are skipped.
+    developer_size: usize,
+}
+
+pub(super) fn is_fit(bytes: &[u8]) -> bool {
+    bytes.get(8..12) == Some(b".FIT".as_slice())
+}
+
+/// Reads the records, HRV, laps and sessions of an activity file, and the
+/// watch from its file id.
+pub(super) fn read_workout(bytes: &[u8]) -> Result<Workout, ImportError> {
+    let mut workout = Workout::default();
+    let mut identified = false;
+    for message in decode(bytes).map_err(ImportError::Fit)? {
+        match message.global {
+            FILE_ID if !identified => {
+                identified = true;
+                workout.make = message.number(1).map(manufacturer);
+                workout.model = message.text(8).map(str::to_string).or_else(|| message.number(2).map(|product| format!("product {}", product)));
+                workout.serial = message.number(3).map(|serial| serial.to_string());
+            }
+            RECORD => {
+                let Some(time) = message.time(TIMESTAMP) else {
+                    workout.skipped += 1;
+                    continue;
+                };
+                workout.samples.push(Sample {
+                    time,
+                    latitude: message.number(0).map(|semicircles| semicircles * DEGREES_PER_SEMICIRCLE),
+                    longitude: message.number(1).map(|semicircles| semicircles * DEGREES_PER_SEMICIRCLE),
+                    // enhanced_altitude and enhanced_speed, where the watch logs them
+                    altitude: message.number(78).or(message.number(2)).map(|altitude| altitude / 5.0 - 500.0),
+                    speed: message.number(73).or(message.number(6)).map(|speed| (speed / 1000.0) as f32),
+                    distance_m: message.number(5).map(|distance| distance / 100.0),
+                    heart_rate: message.number(3).map(|bpm| bpm as i32),
+                    rr_intervals: Vec::new(),
+                    cadence: message.number(4).map(|cadence| cadence + message.number(53).unwrap_or(0.0) / 128.0),
+                    temperature: message.number(13).map(|celsius| celsius as f32),
+                });
+            }
+            // Beat intervals logged since the last record, in milliseconds
+            HRV => {
+                if let Some(sample) = workout.samples.last_mut() {
+                    sample.rr_intervals.extend(message.numbers(0).iter().map(|ms| (ms / 1000.0) as f32));
+                }
+            }
+            LAP => {
+                if let Some(start) = message.time(2) {
+                    workout.laps.push(WorkoutLap { start, end: message.time(TIMESTAMP) });
+                }
+            }
+            SESSION => {
+                if let Some(start) = message.time(2) {
+                    workout.sessions.push(WorkoutSession {
+                        start,
+                        end: message.time(TIMESTAMP),
+                        sport: message.number(5).map(sport),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    workout.finish();
+    Ok(workout)
+}
+
+/// The data messages of a file, or of each file in a chained one.
+fn decode(bytes: &[u8]) -> Result<Vec<Message>, String> {
+    let mut messages = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let header_size = usize::from(rest[0]);
+        if header_size < 12 || rest.len() < header_size || !is_fit(rest) {
+            return Err("no FIT header".to_string());
+        }
+        let data_size = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        let end = header_size + data_size;
+        if rest.len() < end + 2 {
+            return Err(format!("cut short at {} of {} bytes", rest.len(), end + 2));
+        }
+        let checksum = u16::from_le_bytes([rest[end], rest[end + 1]]);
+        if checksum != 0 && crc(&rest[..end]) != checksum {
+            return Err("checksum mismatch".to_string());
+        }
+        decode_records(&rest[header_size..end], &mut messages)?;
+        rest = &rest[end + 2..];
+    }
+    Ok(messages)
+}
+
+fn decode_records(data: &[u8], messages: &mut Vec<Message>) -> Result<(), String> {
+    let mut definitions: [Option<Definition>; 16] = Default::default();
+    let mut timestamp = 0u32;
+    let mut pos = 0;
+    while pos < data.len() {
+        let header = take(data, &mut pos, 1)?[0];
+        if header & 0x80 != 0 {
+            // Compressed timestamp header: the low five bits of the time,
+            // counted on from the last full timestamp
+            let offset = u32::from(header & 0x1F);
+            timestamp = (timestamp & !0x1F) + offset + if offset < timestamp & 0x1F { 0x20 } else { 0 };
+            let definition = definitions[usize::from((header >> 5) & 0x03)].as_ref().ok_or("data before its definition")?;
+            let mut message = read_message(definition, data, &mut pos)?;
+            message.fields.retain(|(number, _)| *number != TIMESTAMP);
+            message.fields.push((TIMESTAMP, FitValue::Numbers(vec![f64::from(timestamp)])));
+            messages.push(message);
+        } else if header & 0x40 != 0 {
+            let fixed = take(data, &mut pos, 5)?;
+            let big_endian = fixed[1] == 1;
+            let global = if big_endian {
+                u16::from_be_bytes([fixed[2], fixed[3]])
+            } else {
+                u16::from_le_bytes([fixed[2], fixed[3]])
+            };
+            let count = usize::from(fixed[4]);
+            let fields = take(data, &mut pos, count * 3)?.chunks(3).map(|field| [field[0], field[1], field[2]]).collect();
+            let mut developer_size = 0;
+            if header & 0x20 != 0 {
+                let count = usize::from(take(data, &mut pos, 1)?[0]);
+                developer_size = take(data, &mut pos, count * 3)?.chunks(3).map(|field| usize::from(field[1])).sum();
+            }
+            definitions[usize::from(header & 0x0F)] = Some(Definition { global, big_endian, fields, developer_size });
+        } else {
+            let definition = definitions[usize::from(header & 0x0F)].as_ref().ok_or("data before its definition")?;
+            let message = read_message(definition, data, &mut pos)?;
+            if let Some(time) = message.number(TIMESTAMP) {
+                timestamp = time as u32;
+            }
+            messages.push(message);
+        }
+    }
+    Ok(())
+}
+
+fn read_message(definition: &Definition, data: &[u8], pos: &mut usize) -> Result<Message, String> {
+    let mut fields = Vec::with_capacity(definition.fields.len());
+    for &[number, size, base] in &definition.fields {
+        let raw = take(data, pos, usize::from(size))?;
+        if let Some(value) = decode_value(raw, base, definition.big_endian) {
+            fields.push((number, value));
+        }
+    }
+    take(data, pos, definition.developer_size)?;
+    Ok(Message { global: definition.global, fields })
+}
+
+fn take<'d>(data: &'d [u8], pos: &mut usize, len: usize) -> Result<&'d [u8], String> {
+    let bytes = data.get(*pos..*pos + len).ok_or("record runs past the end of the data")?;
+    *pos += len;
+    Ok(bytes)
+}
+
+/// A field as numbers, or text for strings. Byte arrays and fields that
+/// don't fit their base type are dropped.
+fn decode_value(raw: &[u8], base: u8, big_endian: bool) -> Option<FitValue> {
+    let base = base & 0x1F;
+    if base == 0x07 {
+        let text = raw.split(|&byte| byte == 0).next().unwrap_or_default();
+        return (!text.is_empty()).then(|| FitValue::Text(String::from_utf8_lossy(text).into_owned()));
+    }
+    let size = match base {
+        0x00 | 0x01 | 0x02 | 0x0A => 1,
+        0x03 | 0x04 | 0x0B => 2,
+        0x05 | 0x06 | 0x08 | 0x0C => 4,
+        0x09 | 0x0E | 0x0F | 0x10 => 8,
+        _ => return None,
+    };
+    if raw.len() % size != 0 {
+        return None;
+    }
+    let values: Vec<f64> = raw.chunks(size).filter_map(|chunk| decode_number(chunk, base, big_endian)).collect();
+    (!values.is_empty()).then_some(FitValue::Numbers(values))
+}
+
+/// One element of a numeric field, or `None` for the base type's invalid
+/// value.
+fn decode_number(chunk: &[u8], base: u8, big_endian: bool) -> Option<f64> {
+    let mut bytes = [0u8; 8];
+    bytes[..chunk.len()].copy_from_slice(chunk);
+    if big_endian {
+        bytes[..chunk.len()].reverse();
+    }
+    let bits = u64::from_le_bytes(bytes);
+    match base {
+        0x00 | 0x02 => (bits != 0xFF).then_some(bits as f64),
+        0x01 => (bits != 0x7F).then_some(bits as u8 as i8 as f64),
+        0x03 => (bits != 0x7FFF).then_some(bits as u16 as i16 as f64),
+        0x04 => (bits != 0xFFFF).then_some(bits as f64),
+        0x05 => (bits != 0x7FFF_FFFF).then_some(bits as u32 as i32 as f64),
+        0x06 => (bits != 0xFFFF_FFFF).then_some(bits as f64),
+        0x08 => Some(f64::from(f32::from_bits(bits as u32))).filter(|value| value.is_finite()),
+        0x09 => Some(f64::from_bits(bits)).filter(|value| value.is_finite()),
+        0x0A | 0x0B | 0x0C | 0x10 => (bits != 0).then_some(bits as f64),
+        0x0E => (bits != 0x7FFF_FFFF_FFFF_FFFF).then_some(bits as i64 as f64),
+        0x0F => (bits != u64::MAX).then_some(bits as f64),
+        _ => None,
+    }
+}
+
+fn crc(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &byte| {
+        let crc = (crc >> 4) ^ CRC_TABLE[usize::from(crc & 0xF)] ^ CRC_TABLE[usize::from(byte & 0xF)];
+        (crc >> 4) ^ CRC_TABLE[usize::from(crc & 0xF)] ^ CRC_TABLE[usize::from(byte >> 4)]
+    })
+}
+
+fn manufacturer(value: f64) -> String {
+    match value as u16 {
+        1 => "Garmin".to_string(),
+        15 => "Dynastream".to_string(),
+        23 => "Suunto".to_string(),
+        32 => "Wahoo".to_string(),
+        123 => "Polar".to_string(),
+        260 => "Zwift".to_string(),
+        294 => "Coros".to_string(),
+        other => format!("Manufacturer {}", other),
+    }
+}
+
+fn sport(value: f64) -> String {
+    let name = match value as u8 {
+        0 => "generic",
+        1 => "running",
+        2 => "cycling",
+        3 => "transition",
+        4 => "fitness_equipment",
+        5 => "swimming",
+        10 => "training",
+        11 => "walking",
+        15 => "rowing",
+        17 => "hiking",
+        other => return format!("sport_{}", other),
+    };
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// 2024-06-01T07:00:00Z in FIT time.
+    const START: u32 = 1_717_225_200 - FIT_EPOCH as u32;
+
+    fn definition(local: u8, global: u16, fields: &[(u8, u8, u8)]) -> Vec<u8> {
+        let mut bytes = vec![0x40 | local, 0, 0];
+        bytes.extend(global.to_le_bytes());
+        bytes.push(fields.len() as u8);
+        for &(number, size, base) in fields {
+            bytes.extend([number, size, base]);
+        }
+        bytes
+    }
+
+    fn file(records: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![14, 0x20, 0x54, 0x08];
+        bytes.extend((records.len() as u32).to_le_bytes());
+        bytes.extend(b".FIT");
+        bytes.extend([0, 0]);
+        bytes.extend(records);
+        bytes.extend(crc(&bytes).to_le_bytes());
+        bytes
+    }
+
+    fn activity() -> Vec<u8> {
+        let mut records = definition(0, FILE_ID, &[(1, 2, 0x84), (3, 4, 0x8C)]);
+        records.push(0);
+        records.extend(1u16.to_le_bytes());
+        records.extend(3_312_345_678u32.to_le_bytes());
+
+        // timestamp, lat, long, heart rate, cadence, enhanced altitude, temperature
+        records.extend(definition(1, RECORD, &[(253, 4, 0x86), (0, 4, 0x85), (1, 4, 0x85), (3, 1, 0x02), (4, 1, 0x02), (78, 4, 0x86), (13, 1, 0x01)]));
+        let semicircles = |degrees: f64| ((degrees / DEGREES_PER_SEMICIRCLE) as i32).to_le_bytes();
+        records.push(1);
+        records.extend(START.to_le_bytes());
+        records.extend(semicircles(52.0));
+        records.extend(semicircles(4.0));
+        records.extend([150, 88]);
+        records.extend(2_600u32.to_le_bytes());
+        records.push(21);
+        // A record with no position or heart rate, by compressed timestamp 2s on
+        records.push(0x80 | (1 << 5) | ((START + 2) & 0x1F) as u8);
+        records.extend((START + 2).to_le_bytes());
+        records.extend(i32::MAX.to_le_bytes());
+        records.extend(i32::MAX.to_le_bytes());
+        records.extend([0xFF, 90]);
+        records.extend(2_605u32.to_le_bytes());
+        records.push(0x7F);
+
+        records.extend(definition(2, HRV, &[(0, 4, 0x84)]));
+        records.push(2);
+        records.extend(400u16.to_le_bytes());
+        records.extend(410u16.to_le_bytes());
+
+        records.extend(definition(3, LAP, &[(253, 4, 0x86), (2, 4, 0x86)]));
+        records.push(3);
+        records.extend((START + 2).to_le_bytes());
+        records.extend(START.to_le_bytes());
+
+        records.extend(definition(4, SESSION, &[(253, 4, 0x86), (2, 4, 0x86), (5, 1, 0x00)]));
+        records.push(4);
+        records.extend((START + 2).to_le_bytes());
+        records.extend(START.to_le_bytes());
+        records.push(1);
+        file(&records)
+    }
+
+    #[test]
+    fn test_crc() {
+        assert_eq!(crc(b"123456789"), 0xBB3D);
+        let bytes = activity();
+        assert_eq!(crc(&bytes), 0);
+    }
+
+    #[test]
+    fn test_read_workout() {
+        let workout = read_workout(&activity()).unwrap();
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 7, 0, 0).unwrap();
+        assert_eq!((workout.make.as_deref(), workout.serial.as_deref()), (Some("Garmin"), Some("3312345678")));
+        assert_eq!(workout.samples.len(), 2);
+
+        let first = &workout.samples[0];
+        assert_eq!(first.time, start);
+        assert!((first.latitude.unwrap() - 52.0).abs() < 1e-6 && (first.longitude.unwrap() - 4.0).abs() < 1e-6);
+        assert_eq!((first.heart_rate, first.cadence, first.altitude, first.temperature), (Some(150), Some(88.0), Some(20.0), Some(21.0)));
+
+        let second = &workout.samples[1];
+        assert_eq!(second.time, start + chrono::Duration::seconds(2));
+        assert_eq!((second.latitude, second.heart_rate, second.temperature), (None, None, None));
+        assert_eq!(second.rr_intervals, vec![0.4, 0.41]);
+
+        assert_eq!(workout.laps, vec![WorkoutLap { start, end: Some(second.time) }]);
+        assert_eq!(workout.sessions[0].sport.as_deref(), Some("running"));
+    }
+
+    #[test]
+    fn test_rejects_corrupt_files() {
+        let mut bytes = activity();
+        let last = bytes.len() - 3;
+        bytes[last] ^= 0xFF;
+        assert!(matches!(read_workout(&bytes), Err(ImportError::Fit(reason)) if reason == "checksum mismatch"));
+
+        let bytes = activity();
+        assert!(matches!(read_workout(&bytes[..bytes.len() - 10]), Err(ImportError::Fit(_))));
+        assert!(!is_fit(b"<?xml version=\"1.0\"?>"));
+    }
+}
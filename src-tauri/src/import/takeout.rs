@@ -0,0 +1,634 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+use duckdb::OptionalExt;
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::collection::SensorRow;
+use crate::datatypes::{
+    note::{Note, NoteTarget},
+    sensor::GpsData,
+    types::{Metadata, NotePriority},
+};
+use crate::db::{Database, UserContext};
+use super::{ensure_device, ImportError, ImportReport, RowError, RowSink};
+
+/// Tag on every note made from the timeline, so they can be found and
+/// told apart from the user's own.
+const TAKEOUT_TAG: &str = "google_takeout";
+
+/// Radius given to the places a timeline note is attached to. Takeout
+/// only has a point for them.
+const PLACE_RADIUS_M: f64 = 100.0;
+
+/// What a Takeout import stored, or would have on a dry run.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TakeoutReport {
+    /// The device the history was stored under.
+    pub device_id: String,
+    /// Fixes from `Records.json` and from the raw paths of activity segments.
+    pub gps: ImportReport,
+    pub place_visits: usize,
+    pub activity_segments: usize,
+    /// Visits and segments already imported.
+    pub duplicate_notes: usize,
+    /// Timeline objects that were skipped, by position in their file.
+    pub errors: Vec<RowError>,
+}
+
+/// Imports Google Takeout location history: the fixes in `Records.json`
+/// and the place visits and activity segments of Semantic Location
+/// History. Fixes go to `gps_data` under a device made for the import, as
+/// Takeout doesn't say which phone took them; visits and segments become
+/// notes attached to their time window and places.
+pub struct TakeoutImporter<'a> {
+    db: &'a Database,
+    user: UserContext,
+}
+
+impl<'a> TakeoutImporter<'a> {
+    pub fn new(db: &'a Database, user: UserContext) -> Self {
+        Self { db, user }
+    }
+
+    /// The device Takeout history is stored under for the user.
+    pub fn device_id(&self) -> String {
+        format!("google-takeout-{}", self.user.user_id)
+    }
+
+    /// Imports `path`, either one Takeout JSON file or a directory such as
+    /// `Takeout/Location History`, whose JSON files are read in name order.
+    /// Files are streamed, so `Records.json` is never held in memory whole.
+    /// Fixes and notes already imported are skipped. With `dry_run`
+    /// nothing is stored and the device isn't created.
+    pub fn import(&self, path: &Path, dry_run: bool) -> Result<TakeoutReport, ImportError> {
+        let mut files = Vec::new();
+        collect_json_files(path, &mut files)?;
+        files.sort();
+
+        let device_id = self.device_id();
+        if !dry_run {
+            ensure_device(self.db, &self.user, &device_id, "Google Takeout", &["gps"])?;
+        }
+        let mut sink = RowSink::new(self.db, self.user.clone(), "gps_data", dry_run);
+        if dry_run {
+            sink.expect_device(&device_id);
+        }
+        let mut report = TakeoutReport { device_id: device_id.clone(), ..Default::default() };
+
+        for file in &files {
+            let name = file.file_name().map_or_else(|| file.display().to_string(), |name| name.to_string_lossy().into_owned());
+            walk(file, |section, position, element| match section {
+                Section::Records => {
+                    let row = serde_json::from_value::<Record>(element)
+                        .map_err(|e| e.to_string())
+                        .and_then(|record| record.to_gps(&device_id))
+                        .map(SensorRow::Gps)
+                        .map_err(|message| format!("{}: {}", name, message));
+                    sink.push(position, row)
+                }
+                Section::Timeline => {
+                    let object = match serde_json::from_value::<TimelineObject>(element) {
+                        Ok(object) => object,
+                        Err(e) => {
+                            report.errors.push(RowError { line: position, message: format!("{}: {}", name, e) });
+                            return Ok(());
+                        }
+                    };
+                    let (note, targets) = match object.to_note(&self.user.user_id) {
+                        Some(Ok(note)) => note,
+                        Some(Err(message)) => {
+                            report.errors.push(RowError { line: position, message: format!("{}: {}", name, message) });
+                            return Ok(());
+                        }
+                        None => return Ok(()),
+                    };
+                    for point in object.raw_path() {
+                        let row = point.to_gps(&device_id).map(SensorRow::Gps).map_err(|message| format!("{}: {}", name, message));
+                        sink.push(position, row)?;
+                    }
+                    if !self.store_note(&note, &targets, dry_run)? {
+                        report.duplicate_notes += 1;
+                    } else if object.place_visit.is_some() {
+                        report.place_visits += 1;
+                    } else {
+                        report.activity_segments += 1;
+                    }
+                    Ok(())
+                }
+            })?;
+        }
+        report.gps = sink.finish()?;
+        Ok(report)
+    }
+
+    /// Stores `note` attached to `targets` unless it was imported before.
+    /// Returns whether it's new.
+    fn store_note(&self, note: &Note, targets: &[NoteTarget], dry_run: bool) -> Result<bool, ImportError> {
+        if self.db.get_note(&note.id).optional()?.is_some() {
+            return Ok(false);
+        }
+        if !dry_run {
+            self.db.in_transaction(|db| {
+                db.insert_note(note)?;
+                for target in targets {
+                    db.attach_note(&note.id, target, None)?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(true)
+    }
+}
+
+fn collect_json_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), ImportError> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_json_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json")) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Degrees from Takeout's E7 fixed point. Some exports wrote coordinates
+/// past the int32 range wrapped around, so those are unwrapped first.
+fn from_e7(value: i64) -> f64 {
+    let value = if value > 1_800_000_000 { value - (1 << 32) } else { value };
+    value as f64 / 1e7
+}
+
+/// Takeout has written times as RFC 3339 `timestamp`s and, in older
+/// exports, as `timestampMs` strings of Unix milliseconds.
+fn takeout_time(timestamp: Option<DateTime<Utc>>, millis: Option<&str>) -> Result<DateTime<Utc>, String> {
+    if let Some(timestamp) = timestamp {
+        return Ok(timestamp);
+    }
+    let millis = millis.ok_or("no timestamp")?;
+    millis
+        .parse::<i64>()
+        .ok()
+        .and_then(DateTime::<Utc>::from_timestamp_millis)
+        .ok_or_else(|| format!("invalid timestampMs {:?}", millis))
+}
+
+fn metadata(entries: impl IntoIterator<Item = (&'static str, Option<Value>)>) -> Option<Metadata> {
+    let metadata: Metadata = entries
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), value?)))
+        .chain([("source".to_string(), json!(TAKEOUT_TAG))])
+        .collect();
+    Some(metadata)
+}
+
+/// A fix in `Records.json`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Record {
+    latitude_e7: i64,
+    longitude_e7: i64,
+    accuracy: Option<f32>,
+    altitude: Option<f64>,
+    vertical_accuracy: Option<f32>,
+    velocity: Option<f32>,
+    heading: Option<f32>,
+    source: Option<String>,
+    device_tag: Option<i64>,
+    platform_type: Option<String>,
+    timestamp: Option<DateTime<Utc>>,
+    timestamp_ms: Option<String>,
+}
+
+impl Record {
+    fn to_gps(&self, device_id: &str) -> Result<GpsData, String> {
+        Ok(GpsData {
+            timestamp: takeout_time(self.timestamp, self.timestamp_ms.as_deref())?,
+            device_id: device_id.to_string(),
+            latitude: from_e7(self.latitude_e7),
+            longitude: from_e7(self.longitude_e7),
+            altitude: self.altitude,
+            accuracy: self.accuracy,
+            speed: self.velocity,
+            bearing: self.heading,
+            satellites: None,
+            provider: self.source.as_ref().map(|source| source.to_lowercase()),
+            metadata: metadata([
+                ("vertical_accuracy", self.vertical_accuracy.map(Value::from)),
+                ("device_tag", self.device_tag.map(Value::from)),
+                ("platform_type", self.platform_type.clone().map(Value::from)),
+            ]),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TimelineObject {
+    place_visit: Option<PlaceVisit>,
+    activity_segment: Option<ActivitySegment>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaceVisit {
+    location: Place,
+    duration: Span,
+    place_confidence: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Place {
+    latitude_e7: Option<i64>,
+    longitude_e7: Option<i64>,
+    place_id: Option<String>,
+    name: Option<String>,
+    address: Option<String>,
+    semantic_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ActivitySegment {
+    start_location: Option<Place>,
+    end_location: Option<Place>,
+    duration: Span,
+    /// Metres.
+    distance: Option<f64>,
+    activity_type: Option<String>,
+    confidence: Option<String>,
+    simplified_raw_path: Option<RawPath>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Span {
+    start_timestamp: Option<DateTime<Utc>>,
+    end_timestamp: Option<DateTime<Utc>>,
+    start_timestamp_ms: Option<String>,
+    end_timestamp_ms: Option<String>,
+}
+
+impl Span {
+    fn bounds(&self) -> Result<(DateTime<Utc>, DateTime<Utc>), String> {
+        let start = takeout_time(self.start_timestamp, self.start_timestamp_ms.as_deref())?;
+        let end = takeout_time(self.end_timestamp, self.end_timestamp_ms.as_deref())?;
+        if end < start {
+            return Err(format!("duration ends at {} before it starts", end.to_rfc3339()));
+        }
+        Ok((start, end))
+    }
+}
+
+#[derive(Deserialize)]
+struct RawPath {
+    #[serde(default)]
+    points: Vec<RawPoint>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawPoint {
+    lat_e7: i64,
+    lng_e7: i64,
+    accuracy_meters: Option<f32>,
+    timestamp: Option<DateTime<Utc>>,
+    timestamp_ms: Option<String>,
+}
+
+impl RawPoint {
+    fn to_gps(&self, device_id: &str) -> Result<GpsData, String> {
+        Ok(GpsData {
+            timestamp: takeout_time(self.timestamp, self.timestamp_ms.as_deref())?,
+            device_id: device_id.to_string(),
+            latitude: from_e7(self.lat_e7),
+            longitude: from_e7(self.lng_e7),
+            altitude: None,
+            accuracy: self.accuracy_meters,
+            speed: None,
+            bearing: None,
+            satellites: None,
+            provider: None,
+            metadata: metadata([("path", Some(json!("activity_segment")))]),
+        })
+    }
+}
+
+impl Place {
+    fn target(&self) -> Option<NoteTarget> {
+        Some(NoteTarget::Location {
+            latitude: from_e7(self.latitude_e7?),
+            longitude: from_e7(self.longitude_e7?),
+            radius_m: PLACE_RADIUS_M,
+        })
+    }
+}
+
+impl TimelineObject {
+    /// The note for a place visit or activity segment, with what it should
+    /// be attached to. Its id is derived from the start time, so importing
+    /// the same month twice finds the notes already there.
+    fn to_note(&self, user_id: &str) -> Option<Result<(Note, Vec<NoteTarget>), String>> {
+        let (kind, span) = match (&self.place_visit, &self.activity_segment) {
+            (Some(visit), _) => ("place_visit", &visit.duration),
+            (None, Some(segment)) => ("activity_segment", &segment.duration),
+            (None, None) => return None,
+        };
+        let (start, end) = match span.bounds() {
+            Ok(bounds) => bounds,
+            Err(message) => return Some(Err(message)),
+        };
+        let mut targets = vec![NoteTarget::TimeWindow { start, end }];
+
+        let (content, metadata) = match (&self.place_visit, &self.activity_segment) {
+            (Some(visit), _) => {
+                let place = &visit.location;
+                targets.extend(place.target());
+                let content = match (&place.name, &place.address) {
+                    (Some(name), Some(address)) => format!("Visited {}\n{}", name, address),
+                    (Some(name), None) => format!("Visited {}", name),
+                    (None, Some(address)) => format!("Visited {}", address),
+                    (None, None) => "Visited an unnamed place".to_string(),
+                };
+                let metadata = metadata([
+                    ("place_id", place.place_id.clone().map(Value::from)),
+                    ("semantic_type", place.semantic_type.clone().map(Value::from)),
+                    ("confidence", visit.place_confidence.clone().map(Value::from)),
+                ]);
+                (content, metadata)
+            }
+            (None, Some(segment)) => {
+                targets.extend(segment.start_location.as_ref().and_then(Place::target));
+                targets.extend(segment.end_location.as_ref().and_then(Place::target));
+                let activity = activity_label(segment.activity_type.as_deref().unwrap_or("UNKNOWN_ACTIVITY_TYPE"));
+                let content = match segment.distance {
+                    Some(metres) => format!("{} for {:.1} km", activity, metres / 1000.0),
+                    None => activity,
+                };
+                let metadata = metadata([
+                    ("activity_type", segment.activity_type.clone().map(Value::from)),
+                    ("distance_m", segment.distance.map(Value::from)),
+                    ("confidence", segment.confidence.clone().map(Value::from)),
+                ]);
+                (content, metadata)
+            }
+            (None, None) => unreachable!(),
+        };
+
+        let now = Utc::now();
+        let note = Note {
+            id: format!("takeout-{}-{}-{}", kind, user_id, start.timestamp_millis()),
+            user_id: user_id.to_string(),
+            timestamp: start,
+            content,
+            priority: NotePriority::Low,
+            parent_id: None,
+            tags: Some(vec![TAKEOUT_TAG.to_string(), kind.to_string()]),
+            embedding: None,
+            metadata,
+            created_at: now,
+            updated_at: now,
+        };
+        Some(Ok((note, targets)))
+    }
+
+    fn raw_path(&self) -> &[RawPoint] {
+        self.activity_segment
+            .as_ref()
+            .and_then(|segment| segment.simplified_raw_path.as_ref())
+            .map_or(&[][..], |path| path.points.as_slice())
+    }
+}
+
+/// `IN_PASSENGER_VEHICLE` as "In passenger vehicle".
+fn activity_label(activity_type: &str) -> String {
+    let words = activity_type.to_lowercase().replace('_', " ");
+    let mut chars = words.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => words,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Section {
+    /// `locations` in `Records.json`.
+    Records,
+    /// `timelineObjects` in a Semantic Location History month.
+    Timeline,
+}
+
+/// Streams the top-level object of a Takeout file, handing each element of
+/// the arrays it knows to `on_element` with its 1-based position as soon as
+/// it is parsed. Other keys are skipped.
+fn walk(path: &Path, on_element: impl FnMut(Section, u64, Value) -> Result<(), ImportError>) -> Result<(), ImportError> {
+    let mut walker = Walker { on_element, failure: None };
+    let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(File::open(path)?));
+    let result = (&mut deserializer).deserialize_map(&mut walker).and_then(|_| deserializer.end());
+    match walker.failure {
+        Some(e) => Err(e),
+        None => Ok(result?),
+    }
+}
+
+struct Walker<F> {
+    on_element: F,
+    /// Why `on_element` stopped the walk, which serde can only report as
+    /// its own error.
+    failure: Option<ImportError>,
+}
+
+impl<'de, F> Visitor<'de> for &mut Walker<F>
+where
+    F: FnMut(Section, u64, Value) -> Result<(), ImportError>,
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a Google Takeout location history object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            let section = match key.as_str() {
+                "locations" => Section::Records,
+                "timelineObjects" => Section::Timeline,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                    continue;
+                }
+            };
+            map.next_value_seed(Elements { walker: &mut *self, section })?;
+        }
+        Ok(())
+    }
+}
+
+struct Elements<'w, F> {
+    walker: &'w mut Walker<F>,
+    section: Section,
+}
+
+impl<'de, F> DeserializeSeed<'de> for Elements<'_, F>
+where
+    F: FnMut(Section, u64, Value) -> Result<(), ImportError>,
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, F> Visitor<'de> for Elements<'_, F>
+where
+    F: FnMut(Section, u64, Value) -> Result<(), ImportError>,
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("an array")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let mut position = 0;
+        while let Some(element) = seq.next_element::<Value>()? {
+            position += 1;
+            if let Err(e) = (self.walker.on_element)(self.section, position, element) {
+                self.walker.failure = Some(e);
+                return Err(de::Error::custom("import stopped"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::db::{fixtures::{temp_database, user}, AccessError};
+
+    #[test]
+    fn test_records_become_fixes() {
+        let record: Record = serde_json::from_value(json!({
+            "latitudeE7": 515074000,
+            "longitudeE7": -1278000,
+            "accuracy": 12,
+            "velocity": 3,
+            "source": "WIFI",
+            "deviceTag": 1234,
+            "timestampMs": "1709285400000"
+        }))
+        .unwrap();
+        let fix = record.to_gps("takeout").unwrap();
+        assert_eq!((fix.latitude, fix.longitude), (51.5074, -0.1278));
+        assert_eq!(fix.timestamp, "2024-03-01T09:30:00Z".parse::<DateTime<Utc>>().unwrap());
+        assert_eq!((fix.accuracy, fix.speed, fix.provider.as_deref()), (Some(12.0), Some(3.0), Some("wifi")));
+        assert_eq!(fix.metadata.unwrap()["device_tag"], json!(1234));
+
+        // Wrapped past the int32 range by some exports
+        assert!((from_e7(4_233_478_000) - (-6.1489296)).abs() < 1e-7);
+    }
+
+    #[test]
+    fn test_timeline_objects_become_notes() {
+        let visit: TimelineObject = serde_json::from_value(json!({
+            "placeVisit": {
+                "location": { "latitudeE7": 515074000, "longitudeE7": -1278000, "name": "Café", "placeId": "ChIJ" },
+                "duration": { "startTimestamp": "2024-03-01T09:30:00Z", "endTimestamp": "2024-03-01T10:15:00Z" }
+            }
+        }))
+        .unwrap();
+        let (note, targets) = visit.to_note("alice").unwrap().unwrap();
+        assert_eq!(note.content, "Visited Café");
+        assert_eq!(note.id, "takeout-place_visit-alice-1709285400000");
+        assert_eq!(targets.len(), 2);
+        assert!(matches!(targets[1], NoteTarget::Location { radius_m, .. } if radius_m == PLACE_RADIUS_M));
+
+        let segment: TimelineObject = serde_json::from_value(json!({
+            "activitySegment": {
+                "duration": { "startTimestampMs": "1709285400000", "endTimestampMs": "1709287200000" },
+                "distance": 2400,
+                "activityType": "IN_PASSENGER_VEHICLE",
+                "simplifiedRawPath": { "points": [{ "latE7": 515074000, "lngE7": -1278000, "timestamp": "2024-03-01T09:40:00Z" }] }
+            }
+        }))
+        .unwrap();
+        let (note, targets) = segment.to_note("alice").unwrap().unwrap();
+        assert_eq!(note.content, "In passenger vehicle for 2.4 km");
+        assert_eq!(targets.len(), 1);
+        assert_eq!(segment.raw_path().len(), 1);
+
+        let empty: TimelineObject = serde_json::from_value(json!({})).unwrap();
+        assert!(empty.to_note("alice").is_none());
+    }
+
+    #[test]
+    fn test_takeout_import_adds_fixes_and_timeline_notes_once() -> Result<(), AccessError> {
+        let (dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        let history = dir.path().join("Location History");
+        std::fs::create_dir_all(history.join("Semantic Location History/2024")).unwrap();
+        std::fs::write(
+            history.join("Records.json"),
+            r#"{"locations": [
+                {"latitudeE7": 515074000, "longitudeE7": -1278000, "accuracy": 20, "source": "GPS", "timestamp": "2024-03-01T09:00:00Z"},
+                {"longitudeE7": -1278000, "timestamp": "2024-03-01T09:01:00Z"},
+                {"latitudeE7": 515200000, "longitudeE7": -1000000, "timestampMs": "1709283720000"}
+            ]}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            history.join("Semantic Location History/2024/2024_MARCH.json"),
+            r#"{"timelineObjects": [
+                {"placeVisit": {
+                    "location": {"latitudeE7": 515074000, "longitudeE7": -1278000, "name": "Office"},
+                    "duration": {"startTimestamp": "2024-03-01T09:05:00Z", "endTimestamp": "2024-03-01T12:00:00Z"}
+                }},
+                {"activitySegment": {
+                    "duration": {"startTimestamp": "2024-03-01T12:00:00Z", "endTimestamp": "2024-03-01T12:20:00Z"},
+                    "distance": 1500,
+                    "activityType": "WALKING",
+                    "simplifiedRawPath": {"points": [{"latE7": 515100000, "lngE7": -1200000, "timestamp": "2024-03-01T12:10:00Z"}]}
+                }}
+            ]}"#,
+        )
+        .unwrap();
+
+        let importer = TakeoutImporter::new(&db, UserContext::new("alice"));
+        let device_id = importer.device_id();
+        let preview = importer.import(&history, true).unwrap();
+        assert_eq!(db.get_device_owner(&device_id)?, None);
+        assert_eq!((preview.gps.imported, preview.gps.failed, preview.place_visits, preview.activity_segments), (3, 1, 1, 1));
+        assert_eq!(preview.gps.errors[0].line, 2);
+
+        let report = importer.import(&history, false).unwrap();
+        assert_eq!(TakeoutReport { gps: ImportReport { dry_run: true, ..report.gps.clone() }, ..report.clone() }, preview);
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+        let end = Utc.with_ymd_and_hms(2024, 3, 2, 0, 0, 0).unwrap();
+        let alice = db.scoped(UserContext::new("alice"));
+        let fixes = alice.get_gps_data(&device_id, start, end)?;
+        assert_eq!(fixes.len(), 3);
+        assert!(fixes.iter().any(|fix| fix.latitude == 51.5074 && fix.provider.as_deref() == Some("gps")));
+        let notes = db.get_notes("alice", start, end)?;
+        let mut contents: Vec<&str> = notes.iter().map(|note| note.content.as_str()).collect();
+        contents.sort();
+        assert_eq!(contents, vec!["Visited Office", "Walking for 1.5 km"]);
+
+        let again = importer.import(&history, false).unwrap();
+        assert_eq!((again.gps.imported, again.gps.duplicates, again.duplicate_notes), (0, 3, 2));
+        assert_eq!(db.get_notes("alice", start, end)?.len(), 2);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use chrono::{DateTime, Duration, Utc};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use super::workout::{Sample, Workout, WorkoutLap, WorkoutSession};
+use super::ImportError;
+
+/// Reads the activities, laps and trackpoints of a Training Center file,
+/// and the watch from its `<Creator>`. Elements are matched by local name,
+/// as exporters disagree on the prefix for Garmin's `TPX` extension.
+pub(super) fn read_workout(bytes: &[u8]) -> Result<Workout, ImportError> {
+    let mut reader = Reader::from_reader(bytes);
+    let mut buf = Vec::new();
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    let mut workout = Workout::default();
+    let mut sport = None;
+    // The trackpoint being read, and its time once seen
+    let mut point: Option<(Option<DateTime<Utc>>, Sample)> = None;
+
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) => {
+                let name = e.local_name().as_ref().to_vec();
+                match name.as_slice() {
+                    b"Activity" => {
+                        sport = None;
+                        for attr in e.attributes().flatten() {
+                            if attr.key.local_name().as_ref() == b"Sport" {
+                                sport = tcx_sport(&attr.unescape_value()?);
+                            }
+                        }
+                    }
+                    b"Lap" => {
+                        for attr in e.attributes().flatten() {
+                            if attr.key.local_name().as_ref() == b"StartTime" {
+                                if let Some(start) = tcx_time(&attr.unescape_value()?) {
+                                    workout.laps.push(WorkoutLap { start, end: None });
+                                }
+                            }
+                        }
+                    }
+                    b"Trackpoint" => point = Some((None, Sample::default())),
+                    _ => {}
+                }
+                path.push(name);
+            }
+            Event::End(e) => {
+                if e.local_name().as_ref() == b"Trackpoint" {
+                    match point.take() {
+                        Some((Some(time), sample)) => workout.samples.push(Sample { time, ..sample }),
+                        _ => workout.skipped += 1,
+                    }
+                }
+                path.pop();
+            }
+            Event::Text(e) => {
+                let text = e.unescape()?;
+                let text = text.trim();
+                if let [.., parent, name] = path.as_slice() {
+                    match (parent.as_slice(), name.as_slice()) {
+                        (b"Activity", b"Id") => {
+                            if let Some(start) = tcx_time(text) {
+                                workout.sessions.push(WorkoutSession { start, end: None, sport: sport.clone() });
+                            }
+                        }
+                        (b"Lap", b"TotalTimeSeconds") => {
+                            if let (Some(lap), Ok(seconds)) = (workout.laps.last_mut(), text.parse::<f64>()) {
+                                lap.end = Some(lap.start + Duration::milliseconds((seconds * 1000.0) as i64));
+                            }
+                        }
+                        (b"Creator", b"Name") => workout.model = Some(text.to_string()),
+                        (b"Creator", b"UnitId") => workout.serial = Some(text.to_string()),
+                        _ => {
+                            if let Some((time, sample)) = &mut point {
+                                read_trackpoint_field(parent, name, text, time, sample);
+                            }
+                        }
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    workout.finish();
+    Ok(workout)
+}
+
+fn read_trackpoint_field(parent: &[u8], name: &[u8], text: &str, time: &mut Option<DateTime<Utc>>, sample: &mut Sample) {
+    match (parent, name) {
+        (b"Trackpoint", b"Time") => *time = tcx_time(text),
+        (b"Position", b"LatitudeDegrees") => sample.latitude = text.parse().ok(),
+        (b"Position", b"LongitudeDegrees") => sample.longitude = text.parse().ok(),
+        (b"Trackpoint", b"AltitudeMeters") => sample.altitude = text.parse().ok(),
+        (b"Trackpoint", b"DistanceMeters") => sample.distance_m = text.parse().ok(),
+        (b"HeartRateBpm", b"Value") => sample.heart_rate = text.parse().ok(),
+        (b"TPX", b"Speed") => sample.speed = text.parse().ok(),
+        // Trackpoint's own <Cadence> is pedal rate, so only TPX's counts
+        (b"TPX", b"RunCadence") => sample.cadence = text.parse().ok(),
+        _ => {}
+    }
+}
+
+fn tcx_time(text: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(text.trim()).ok().map(|t| t.with_timezone(&Utc))
+}
+
+/// TCX's `Running`, `Biking` and `Other`, as FIT names them.
+fn tcx_sport(text: &str) -> Option<String> {
+    match text {
+        "Running" => Some("running".to_string()),
+        "Biking" => Some("cycling".to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const RUN: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<TrainingCenterDatabase xmlns="http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2" xmlns:ns3="http://www.garmin.com/xmlschemas/ActivityExtension/v2">
+  <Activities>
+    <Activity Sport="Running">
+      <Id>2024-06-01T07:00:00Z</Id>
+      <Lap StartTime="2024-06-01T07:00:00Z">
+        <TotalTimeSeconds>600.5</TotalTimeSeconds>
+        <AverageHeartRateBpm><Value>120</Value></AverageHeartRateBpm>
+        <Track>
+          <Trackpoint>
+            <Time>2024-06-01T07:00:00Z</Time>
+            <Position><LatitudeDegrees>52.37</LatitudeDegrees><LongitudeDegrees>4.89</LongitudeDegrees></Position>
+            <AltitudeMeters>1.5</AltitudeMeters>
+            <HeartRateBpm><Value>131</Value></HeartRateBpm>
+            <Extensions><ns3:TPX><ns3:Speed>3.1</ns3:Speed><ns3:RunCadence>84</ns3:RunCadence></ns3:TPX></Extensions>
+          </Trackpoint>
+          <Trackpoint><Position><LatitudeDegrees>52.38</LatitudeDegrees><LongitudeDegrees>4.9</LongitudeDegrees></Position></Trackpoint>
+        </Track>
+      </Lap>
+      <Creator xsi:type="Device_t" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+        <Name>Forerunner 265</Name>
+        <UnitId>3456789012</UnitId>
+      </Creator>
+    </Activity>
+  </Activities>
+</TrainingCenterDatabase>"#;
+
+    #[test]
+    fn test_read_workout() {
+        let workout = read_workout(RUN.as_bytes()).unwrap();
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 7, 0, 0).unwrap();
+        assert_eq!((workout.model.as_deref(), workout.serial.as_deref()), (Some("Forerunner 265"), Some("3456789012")));
+        assert_eq!(workout.sessions[0].sport.as_deref(), Some("running"));
+        assert_eq!(workout.laps, vec![WorkoutLap { start, end: Some(start + Duration::milliseconds(600_500)) }]);
+
+        // The second trackpoint has no time
+        assert_eq!((workout.samples.len(), workout.skipped), (1, 1));
+        let sample = &workout.samples[0];
+        assert_eq!((sample.latitude, sample.longitude, sample.altitude), (Some(52.37), Some(4.89), Some(1.5)));
+        assert_eq!((sample.heart_rate, sample.speed, sample.cadence), (Some(131), Some(3.1), Some(84.0)));
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use crate::collection::SensorRow;
+use crate::datatypes::{
+    sensor::{GpsData, HeartRateData, StepCountData, TemperatureData},
+    types::Metadata,
+};
+use crate::db::{Database, UserContext};
+use super::{ensure_device, fit, tcx, ImportError, ImportReport, Sinks};
+
+/// Longest gap between samples counted towards a lap's steps. Longer ones
+/// are the watch pausing, not running on at the last cadence.
+const MAX_STEP_GAP_SECS: f64 = 30.0;
+
+/// A session in a workout file: the whole activity, or one leg of a
+/// multisport one.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WorkoutSession {
+    pub start: DateTime<Utc>,
+    pub end: Option<DateTime<Utc>>,
+    /// FIT's sport names, e.g. `running` or `cycling`.
+    pub sport: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WorkoutLap {
+    pub start: DateTime<Utc>,
+    pub end: Option<DateTime<Utc>>,
+}
+
+/// A workout as read from a FIT or TCX file, before it's split into rows.
+#[derive(Debug, Default)]
+pub(super) struct Workout {
+    /// The watch's maker, its model, and its serial number or unit id.
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub sessions: Vec<WorkoutSession>,
+    pub laps: Vec<WorkoutLap>,
+    pub samples: Vec<Sample>,
+    /// Samples left out for having no time.
+    pub skipped: usize,
+}
+
+/// One FIT record or TCX trackpoint.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(super) struct Sample {
+    pub time: DateTime<Utc>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub altitude: Option<f64>,
+    /// Metres per second.
+    pub speed: Option<f32>,
+    pub distance_m: Option<f64>,
+    pub heart_rate: Option<i32>,
+    /// Seconds between beats, for the beats since the previous sample.
+    pub rr_intervals: Vec<f32>,
+    /// Strides per minute, each stride being a step of either foot as
+    /// Garmin counts running cadence.
+    pub cadence: Option<f64>,
+    pub temperature: Option<f32>,
+}
+
+/// What a workout import stored, or would have on a dry run.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct WorkoutReport {
+    /// The device the watch's data was stored under.
+    pub device_id: String,
+    pub sessions: Vec<WorkoutSession>,
+    pub laps: Vec<WorkoutLap>,
+    /// By table, for each table the workout had rows for.
+    pub tables: BTreeMap<String, ImportReport>,
+    /// Samples left out for having no time.
+    pub skipped_samples: usize,
+}
+
+/// Imports workouts recorded by Garmin, Wahoo and similar watches from
+/// `.fit` or `.tcx` files into gps_data, heart_rate_data (with RR intervals
+/// where the watch logged HRV), temperature_data, and step_count_data with
+/// a row per lap. Every row's metadata names its session and lap.
+pub struct WorkoutImporter<'a> {
+    db: &'a Database,
+    user: UserContext,
+}
+
+impl<'a> WorkoutImporter<'a> {
+    pub fn new(db: &'a Database, user: UserContext) -> Self {
+        Self { db, user }
+    }
+
+    /// Imports the workout at `path`, told apart as FIT or TCX by its
+    /// header. Rows go under a device for the watch that recorded it, made
+    /// on the first import from that watch; with `dry_run` nothing is
+    /// stored and the device isn't created. Workout files are small, so
+    /// they're read whole.
+    pub fn import(&self, path: &Path, dry_run: bool) -> Result<WorkoutReport, ImportError> {
+        let bytes = fs::read(path)?;
+        let (source, workout) = if fit::is_fit(&bytes) {
+            ("fit", fit::read_workout(&bytes)?)
+        } else {
+            ("tcx", tcx::read_workout(&bytes)?)
+        };
+        let file = path.file_name().unwrap_or_default().to_string_lossy();
+
+        let device_id = self.device_id(&workout);
+        if !dry_run {
+            let name = [&workout.make, &workout.model].into_iter().flatten().cloned().collect::<Vec<_>>().join(" ");
+            let name = if name.is_empty() { "Workout watch" } else { &name };
+            ensure_device(self.db, &self.user, &device_id, name, &["gps", "heart_rate", "step_count", "temperature"])?;
+        }
+        let mut sinks = Sinks::new(self.db, self.user.clone(), &device_id, dry_run);
+        for (table, position, row) in workout.rows(&device_id, source, &file) {
+            sinks.push(table, position, Ok(row))?;
+        }
+
+        Ok(WorkoutReport {
+            device_id,
+            tables: sinks.finish()?,
+            sessions: workout.sessions,
+            laps: workout.laps,
+            skipped_samples: workout.skipped,
+        })
+    }
+
+    /// `<make or model>-<serial>-<user>`, so each watch gets a device of
+    /// its own without clashing with another user's identical one.
+    fn device_id(&self, workout: &Workout) -> String {
+        let watch = [workout.make.as_ref().or(workout.model.as_ref()), workout.serial.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|part| slug(part))
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>();
+        if watch.is_empty() {
+            format!("workout-{}", self.user.user_id)
+        } else {
+            format!("{}-{}", watch.join("-"), self.user.user_id)
+        }
+    }
+}
+
+impl Workout {
+    /// Sorts what was read by time and ends sessions the file left open at
+    /// their last sample.
+    pub(super) fn finish(&mut self) {
+        self.samples.sort_by_key(|sample| sample.time);
+        self.sessions.sort_by_key(|session| session.start);
+        self.laps.sort_by_key(|lap| lap.start);
+        for i in 0..self.sessions.len() {
+            if self.sessions[i].end.is_none() {
+                let next = self.sessions.get(i + 1).map(|session| session.start);
+                self.sessions[i].end = self
+                    .samples
+                    .iter()
+                    .map(|sample| sample.time)
+                    .filter(|&time| next.is_none_or(|next| time < next))
+                    .max();
+            }
+        }
+    }
+
+    /// The session and lap a sample falls in, by start time. Samples from
+    /// before the first start go in the first.
+    fn position(&self, time: DateTime<Utc>) -> (Option<usize>, Option<usize>) {
+        let session = self.sessions.iter().rposition(|session| session.start <= time);
+        let lap = self.laps.iter().rposition(|lap| lap.start <= time);
+        (
+            session.or((!self.sessions.is_empty()).then_some(0)),
+            lap.or((!self.laps.is_empty()).then_some(0)),
+        )
+    }
+
+    fn metadata(&self, source: &str, file: &str, session: Option<usize>, lap: Option<usize>) -> Metadata {
+        let mut metadata = Metadata::new();
+        metadata.insert("source".to_string(), Value::from(source));
+        metadata.insert("file".to_string(), Value::from(file));
+        if let Some(i) = session {
+            let session = &self.sessions[i];
+            metadata.insert("session".to_string(), Value::from(i + 1));
+            metadata.insert("session_start".to_string(), Value::from(time(session.start)));
+            if let Some(sport) = &session.sport {
+                metadata.insert("sport".to_string(), Value::from(sport.as_str()));
+            }
+        }
+        if let Some(i) = lap {
+            metadata.insert("lap".to_string(), Value::from(i + 1));
+            metadata.insert("lap_start".to_string(), Value::from(time(self.laps[i].start)));
+        }
+        metadata
+    }
+
+    /// Every row the workout makes, with the number of the sample it came
+    /// from. Steps are summed from cadence over each lap of a foot sport
+    /// and stored at the lap's start.
+    fn rows(&self, device_id: &str, source: &str, file: &str) -> Vec<(&'static str, u64, SensorRow)> {
+        let mut rows = Vec::new();
+        let mut steps: BTreeMap<Option<usize>, (u64, DateTime<Utc>, Option<usize>, f64)> = BTreeMap::new();
+        for (i, sample) in self.samples.iter().enumerate() {
+            let position = i as u64 + 1;
+            let (session, lap) = self.position(sample.time);
+            let metadata = self.metadata(source, file, session, lap);
+            let device_id = device_id.to_string();
+
+            if let (Some(latitude), Some(longitude)) = (sample.latitude, sample.longitude) {
+                let mut metadata = metadata.clone();
+                if let Some(distance) = sample.distance_m {
+                    metadata.insert("distance_m".to_string(), Value::from(distance));
+                }
+                rows.push(("gps_data", position, SensorRow::Gps(GpsData {
+                    timestamp: sample.time,
+                    device_id: device_id.clone(),
+                    latitude,
+                    longitude,
+                    altitude: sample.altitude,
+                    accuracy: None,
+                    speed: sample.speed,
+                    bearing: None,
+                    satellites: None,
+                    provider: None,
+                    metadata: Some(metadata),
+                })));
+            }
+            if let Some(bpm) = sample.heart_rate {
+                rows.push(("heart_rate_data", position, SensorRow::HeartRate(HeartRateData {
+                    timestamp: sample.time,
+                    device_id: device_id.clone(),
+                    bpm,
+                    confidence: None,
+                    rr_intervals: (!sample.rr_intervals.is_empty()).then(|| sample.rr_intervals.clone()),
+                    metadata: Some(metadata.clone()),
+                })));
+            }
+            if let Some(celsius) = sample.temperature {
+                rows.push(("temperature_data", position, SensorRow::Temperature(TemperatureData {
+                    timestamp: sample.time,
+                    device_id,
+                    celsius,
+                    metadata: Some(metadata),
+                })));
+            }
+
+            let sport = session.and_then(|i| self.sessions[i].sport.as_deref());
+            if let (Some(cadence), Some(next)) = (sample.cadence, self.samples.get(i + 1)) {
+                let gap = (next.time - sample.time).num_milliseconds() as f64 / 1000.0;
+                if counts_steps(sport) && gap <= MAX_STEP_GAP_SECS {
+                    let start = lap.map_or(sample.time, |i| self.laps[i].start);
+                    steps.entry(lap).or_insert((position, start, session, 0.0)).3 += 2.0 * cadence * gap / 60.0;
+                }
+            }
+        }
+
+        for (lap, (position, start, session, count)) in steps {
+            let mut metadata = self.metadata(source, file, session, lap);
+            if let Some(end) = lap.and_then(|i| self.laps[i].end) {
+                metadata.insert("lap_end".to_string(), Value::from(time(end)));
+            }
+            rows.push(("step_count_data", position, SensorRow::StepCount(StepCountData {
+                timestamp: start,
+                device_id: device_id.to_string(),
+                steps: count.round() as i32,
+                activity_type: session.and_then(|i| self.sessions[i].sport.clone()),
+                confidence: None,
+                metadata: Some(metadata),
+            })));
+        }
+        rows
+    }
+}
+
+/// Cadence is strides on foot but pedal or stroke rate otherwise, so only
+/// foot sports, or workouts that don't say, are counted as steps.
+fn counts_steps(sport: Option<&str>) -> bool {
+    matches!(sport, None | Some("generic" | "running" | "walking" | "hiking"))
+}
+
+fn slug(text: &str) -> String {
+    text.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(|part| part.to_ascii_lowercase())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn time(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+    use crate::db::{fixtures::{temp_database, user}, AccessError};
+
+    fn sample(seconds: i64, cadence: Option<f64>) -> Sample {
+        Sample {
+            time: Utc.with_ymd_and_hms(2024, 6, 1, 7, 0, 0).unwrap() + Duration::seconds(seconds),
+            latitude: Some(52.0),
+            longitude: Some(4.0),
+            heart_rate: Some(140),
+            cadence,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rows_carry_laps_and_sum_steps() {
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 7, 0, 0).unwrap();
+        let mut workout = Workout {
+            sessions: vec![WorkoutSession { start, end: None, sport: Some("running".to_string()) }],
+            laps: vec![
+                WorkoutLap { start, end: Some(start + Duration::seconds(20)) },
+                WorkoutLap { start: start + Duration::seconds(20), end: None },
+            ],
+            // 90 strides a minute for 20s, then a 5 minute pause that isn't counted
+            samples: vec![sample(20, Some(90.0)), sample(0, Some(90.0)), sample(10, Some(90.0)), sample(320, Some(90.0))],
+            ..Default::default()
+        };
+        workout.finish();
+        assert_eq!(workout.sessions[0].end, Some(start + Duration::seconds(320)));
+
+        let rows = workout.rows("watch", "fit", "run.fit");
+        let count = |table| rows.iter().filter(|(t, _, _)| *t == table).count();
+        assert_eq!((count("gps_data"), count("heart_rate_data"), count("step_count_data")), (4, 4, 1));
+
+        let Some((_, _, SensorRow::StepCount(steps))) = rows.iter().find(|(table, _, _)| *table == "step_count_data") else {
+            panic!("no step row");
+        };
+        assert_eq!((steps.steps, steps.timestamp), (60, start));
+        let metadata = steps.metadata.as_ref().unwrap();
+        assert_eq!(metadata["lap"], Value::from(1));
+        assert_eq!(metadata["lap_end"], Value::from("2024-06-01T07:00:20Z"));
+
+        let Some((_, position, SensorRow::Gps(gps))) = rows.iter().rfind(|(table, _, _)| *table == "gps_data") else {
+            panic!("no gps row");
+        };
+        assert_eq!(*position, 4);
+        assert_eq!(gps.metadata.as_ref().unwrap()["lap"], Value::from(2));
+        assert_eq!(gps.metadata.as_ref().unwrap()["sport"], Value::from("running"));
+    }
+
+    #[test]
+    fn test_no_steps_from_cycling_cadence() {
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 7, 0, 0).unwrap();
+        let mut workout = Workout {
+            sessions: vec![WorkoutSession { start, end: None, sport: Some("cycling".to_string()) }],
+            samples: vec![sample(0, Some(85.0)), sample(1, Some(85.0))],
+            ..Default::default()
+        };
+        workout.finish();
+        let rows = workout.rows("watch", "tcx", "ride.tcx");
+        assert!(rows.iter().all(|(table, _, _)| *table != "step_count_data"));
+    }
+
+    #[test]
+    fn test_slug() {
+        assert_eq!(slug("Wahoo ELEMNT Rival"), "wahoo-elemnt-rival");
+        assert_eq!(slug("  "), "");
+    }
+
+    #[test]
+    fn test_workout_import_stores_rows_under_the_watch() -> Result<(), AccessError> {
+        let (dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        let path = dir.path().join("morning_run.tcx");
+        let trackpoint = |second: u32, latitude: f64, bpm: u32| {
+            format!(
+                "<Trackpoint><Time>2024-06-01T07:00:{:02}Z</Time><Position><LatitudeDegrees>{}</LatitudeDegrees><LongitudeDegrees>4.89</LongitudeDegrees></Position>\
+                 <HeartRateBpm><Value>{}</Value></HeartRateBpm><Extensions><TPX><RunCadence>90</RunCadence></TPX></Extensions></Trackpoint>",
+                second, latitude, bpm
+            )
+        };
+        std::fs::write(&path, format!(
+            "<TrainingCenterDatabase><Activities><Activity Sport=\"Running\"><Id>2024-06-01T07:00:00Z</Id>\
+             <Lap StartTime=\"2024-06-01T07:00:00Z\"><TotalTimeSeconds>20</TotalTimeSeconds><Track>{}{}</Track></Lap>\
+             <Lap StartTime=\"2024-06-01T07:00:20Z\"><TotalTimeSeconds>20</TotalTimeSeconds><Track>{}{}</Track></Lap>\
+             <Creator><Name>Forerunner 265</Name><UnitId>3456789012</UnitId></Creator></Activity></Activities></TrainingCenterDatabase>",
+            trackpoint(0, 52.370, 120),
+            trackpoint(10, 52.371, 130),
+            trackpoint(20, 52.372, 140),
+            trackpoint(30, 52.373, 150),
+        ))
+        .unwrap();
+
+        let importer = WorkoutImporter::new(&db, UserContext::new("alice"));
+        let preview = importer.import(&path, true).unwrap();
+        assert_eq!(preview.device_id, "forerunner-265-3456789012-alice");
+        assert_eq!(db.get_device_owner(&preview.device_id)?, None);
+        assert_eq!(preview.laps.len(), 2);
+        assert_eq!(preview.tables["gps_data"].imported, 4);
+
+        let report = importer.import(&path, false).unwrap();
+        assert_eq!(report.tables["heart_rate_data"].imported, 4);
+        assert_eq!(report.tables["step_count_data"].imported, 2);
+        let alice = db.scoped(UserContext::new("alice"));
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap();
+        let end = Utc.with_ymd_and_hms(2024, 6, 2, 0, 0, 0).unwrap();
+        let mut steps = alice.get_step_count_data(&report.device_id, start, end)?;
+        steps.sort_by_key(|row| row.timestamp);
+        // 90 strides a minute, so 30 steps per 10s between trackpoints
+        assert_eq!(steps.iter().map(|row| row.steps).collect::<Vec<_>>(), vec![60, 30]);
+        let fixes = alice.get_gps_data(&report.device_id, start, end)?;
+        let last = fixes.iter().find(|fix| fix.latitude == 52.373).unwrap();
+        assert_eq!(last.metadata.as_ref().unwrap()["lap"], serde_json::json!(2));
+        assert_eq!(last.metadata.as_ref().unwrap()["file"], serde_json::json!("morning_run.tcx"));
+
+        let again = importer.import(&path, false).unwrap();
+        assert!(again.tables.values().all(|table| table.imported == 0));
+
+        Ok(())
+    }
+}
@@ -8,21 +8,46 @@ fn greet(name: &str) -> String {
 
 use chrono::{DateTime, Duration, Utc};
 use serde_json::{json, Value};
-use crate::db::Database;
-use std::path::Path;
+use crate::auth::AuthService;
+use crate::backup::{BackupSchedule, BackupStore};
+use crate::collection::CollectionService;
+use crate::crypto::SecretBox;
+use crate::datatypes::audit::AuditPolicy;
+use crate::datatypes::types::ConsentLevel;
+use crate::datatypes::user::OAuthAccount;
+use crate::datatypes::privacy::{DeletionScope, PrivacyZone, RedactionPolicy};
+use crate::db::{AccessError, Database, KeywordQuery, SearchIndexReport, SharedDatabase, UserContext, VectorQuery};
+use crate::embedding::EmbeddingWorker;
+use crate::ical::TodoCalendar;
+use crate::import::{AppleHealthImporter, FileImporter, ImportMapping, TakeoutImporter, WorkoutImporter};
+use crate::oauth::{load_providers, OAuthError, OAuthProvider, OAuthStore, RefreshScheduler, TokenGrant};
+use crate::owntracks::OwnTracks;
+use crate::tracks::{TrackExporter, TrackOptions};
+use crate::vault::Vault;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tauri::{Manager, State};
+
+/// The database file, in the working directory.
+const DB_PATH: &str = "loom.db";
+/// Key sealing linked accounts' OAuth tokens.
+const OAUTH_KEY_PATH: &str = "oauth.key";
+/// Token endpoints accounts can be linked with; see `oauth::load_providers`.
+const OAUTH_PROVIDERS_PATH: &str = "oauth_providers.json";
 
 #[tauri::command]
-fn get_last_24h_events() -> Result<Value, String> {
-    // Initialize DB connection
-    let db_path = Path::new("data.db"); // You may need to adjust this path
-    let db = Database::new(db_path).map_err(|e| e.to_string())?;
+fn get_last_24h_events(db: State<'_, SharedDatabase>, token: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
 
     let end = Utc::now();
     let start = end - Duration::hours(24);
 
-    // Get data from each sensor table
-    let accelerometer = db.get_accelerometer_data("*", start, end)
-        .map_err(|e| e.to_string())?;
+    // Get data from each sensor table, across the user's devices
+    let mut accelerometer = Vec::new();
+    for device_id in db.device_ids().map_err(|e| e.to_string())? {
+        accelerometer.extend(db.get_accelerometer_data(&device_id, start, end).map_err(|e| e.to_string())?);
+    }
     
     // Build response JSON
     let events = json!({
@@ -47,21 +72,793 @@ fn get_last_24h_events() -> Result<Value, String> {
     Ok(events)
 }
 
+/// Locks the shared database for the rest of a command.
+fn lock(db: &SharedDatabase) -> Result<MutexGuard<'_, Database>, String> {
+    db.lock().map_err(|e| e.to_string())
+}
+
+/// Resolves the session token every data command is called with.
+fn user_context(db: &Database, token: &str) -> Result<UserContext, String> {
+    let user = AuthService::new(db).authenticate(token).map_err(|e| e.to_string())?;
+    Ok(UserContext::from(&user))
+}
+
+/// Like `user_context`, but only the administrator (the first account) may
+/// run commands that act on the whole database, such as encryption and
+/// backups.
+fn admin_context(db: &Database, token: &str) -> Result<UserContext, String> {
+    let user = user_context(db, token)?;
+    if !db.is_admin(&user.user_id).map_err(|e| e.to_string())? {
+        return Err("Only the administrator can do this".to_string());
+    }
+    Ok(user)
+}
+
+#[tauri::command]
+fn get_notes_for_moment(db: State<'_, SharedDatabase>, token: &str, timestamp: &str, window_minutes: i64) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+
+    let moment = DateTime::parse_from_rfc3339(timestamp)
+        .map_err(|e| e.to_string())?
+        .with_timezone(&Utc);
+    let window = Duration::minutes(window_minutes);
+
+    let notes = db.get_notes_mentioning(moment - window, moment + window)
+        .map_err(|e| e.to_string())?;
+
+    let mut results = Vec::new();
+    for note in notes {
+        let references = db.resolve_note_references(&note.id).map_err(|e| e.to_string())?;
+        let references: Vec<Value> = references
+            .into_iter()
+            .map(|(reference, resolved)| json!({ "reference": reference, "resolved": resolved }))
+            .collect();
+        results.push(json!({ "note": note, "references": references }));
+    }
+
+    Ok(Value::Array(results))
+}
+
+
+#[tauri::command]
+fn search_notes(db: State<'_, SharedDatabase>, token: &str, query: &str, k: usize) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let embedder = crate::embedding::default_embedder().map_err(|e| e.to_string())?;
+
+    let vector = embedder.embed(query).map_err(|e| e.to_string())?;
+    let query = VectorQuery::new(vector, k);
+
+    let matches = db.search_notes_by_vector(&query).map_err(|e| e.to_string())?;
+    Ok(json!(matches))
+}
+
+#[tauri::command]
+fn search(db: State<'_, SharedDatabase>, token: &str, query: &str, hybrid: bool, limit: usize) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+
+    let keyword_query = KeywordQuery::new(query, limit);
+
+    let hits = if hybrid {
+        let embedder = crate::embedding::default_embedder().map_err(|e| e.to_string())?;
+        let vector = embedder.embed(query).map_err(|e| e.to_string())?;
+        db.search_hybrid(&keyword_query, &vector, 0.5)
+    } else {
+        db.search_keyword(&keyword_query)
+    }
+    .map_err(|e| e.to_string())?;
+
+    Ok(json!(hits))
+}
+
+#[tauri::command]
+fn rebuild_search_index(db: State<'_, SharedDatabase>, token: &str) -> Result<SearchIndexReport, String> {
+    let db = lock(&db)?;
+    admin_context(&db, token)?;
+    db.rebuild_search_index().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn last_seen(db: State<'_, SharedDatabase>, token: &str, label: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let sighting = db.last_seen(label).map_err(|e| e.to_string())?;
+
+    Ok(match sighting {
+        Some((entity, sighting)) => json!({ "entity": entity, "sighting": sighting }),
+        None => Value::Null,
+    })
+}
+
+#[tauri::command]
+fn merge_entities(db: State<'_, SharedDatabase>, token: &str, kept_id: &str, merged_id: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let entity = db.merge_entities(kept_id, merged_id).map_err(|e| e.to_string())?;
+    Ok(json!(entity))
+}
+
+#[tauri::command]
+fn split_entity(db: State<'_, SharedDatabase>, token: &str, entity_id: &str, sighting_ids: Vec<String>, label: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let entity = db.split_entity(entity_id, &sighting_ids, label).map_err(|e| e.to_string())?;
+    Ok(json!(entity))
+}
+
+#[tauri::command]
+fn list_devices(db: State<'_, SharedDatabase>, token: &str, include_retired: bool) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let devices = db.get_devices(include_retired).map_err(|e| e.to_string())?;
+    Ok(json!(devices))
+}
+
+#[tauri::command]
+fn rename_device(db: State<'_, SharedDatabase>, token: &str, device_id: &str, name: Option<&str>) -> Result<(), String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    db.rename_device(device_id, name).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn retire_device(db: State<'_, SharedDatabase>, token: &str, device_id: &str) -> Result<(), String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    db.retire_device(device_id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn merge_devices(db: State<'_, SharedDatabase>, token: &str, old_device_id: &str, new_device_id: &str) -> Result<usize, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    db.merge_devices(old_device_id, new_device_id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_collection_plan(db: State<'_, SharedDatabase>, token: &str, device_id: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let plan = CollectionService::new(&db, user).plan(device_id).map_err(|e| e.to_string())?;
+    Ok(json!(plan))
+}
+
+#[tauri::command]
+fn get_sensor_preferences(db: State<'_, SharedDatabase>, token: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let preferences = CollectionService::new(&db, user).preferences().map_err(|e| e.to_string())?;
+    Ok(json!(preferences))
+}
+
+#[tauri::command]
+fn set_sensor_preference(db: State<'_, SharedDatabase>, token: &str, table: &str, enabled: bool, interval_ms: Option<i64>) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let preference = CollectionService::new(&db, user)
+        .set_preference(table, enabled, interval_ms)
+        .map_err(|e| e.to_string())?;
+    Ok(json!(preference))
+}
+
+#[tauri::command]
+fn get_consents(db: State<'_, SharedDatabase>, token: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let consents = db.get_consents().map_err(|e| e.to_string())?;
+    Ok(json!(consents))
+}
+
+#[tauri::command]
+fn get_consent_history(db: State<'_, SharedDatabase>, token: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let history = db.get_consent_history().map_err(|e| e.to_string())?;
+    Ok(json!(history))
+}
+
+#[tauri::command]
+fn set_consent(db: State<'_, SharedDatabase>, token: &str, device_id: &str, table: &str, level: ConsentLevel, purge: bool) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let change = db.set_consent(device_id, table, level, purge).map_err(|e| e.to_string())?;
+    Ok(json!(change))
+}
+
+#[tauri::command]
+fn erase_data(db: State<'_, SharedDatabase>, token: &str, scope: DeletionScope) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let receipt = db.erase(&scope).map_err(|e| e.to_string())?;
+    Ok(json!(receipt))
+}
+
+#[tauri::command]
+fn get_deletion_receipts(db: State<'_, SharedDatabase>, token: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let receipts = db.get_deletion_receipts().map_err(|e| e.to_string())?;
+    Ok(json!(receipts))
+}
+
+#[tauri::command]
+fn get_privacy_zones(db: State<'_, SharedDatabase>, token: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let zones = db.get_privacy_zones().map_err(|e| e.to_string())?;
+    Ok(json!(zones))
+}
+
+#[tauri::command]
+fn set_privacy_zone(db: State<'_, SharedDatabase>, token: &str, zone: PrivacyZone, apply_to_existing: bool) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let changed = db.set_privacy_zone(&zone, apply_to_existing).map_err(|e| e.to_string())?;
+    Ok(json!({ "changed_rows": changed }))
+}
+
+#[tauri::command]
+fn delete_privacy_zone(db: State<'_, SharedDatabase>, token: &str, id: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let deleted = db.delete_privacy_zone(id).map_err(|e| e.to_string())?;
+    Ok(json!({ "deleted": deleted > 0 }))
+}
+
+#[tauri::command]
+fn get_redaction_policy(db: State<'_, SharedDatabase>, token: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let policy = db.get_redaction_policy().map_err(|e| e.to_string())?;
+    Ok(json!(policy))
+}
+
+#[tauri::command]
+fn set_redaction_policy(db: State<'_, SharedDatabase>, token: &str, policy: RedactionPolicy) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let policy = db.set_redaction_policy(&policy).map_err(|e| e.to_string())?;
+    Ok(json!(policy))
+}
+
+#[tauri::command]
+fn redact_stored_data(db: State<'_, SharedDatabase>, token: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let changed = db.redact_stored_data().map_err(|e| e.to_string())?;
+    Ok(json!(changed))
+}
+
+#[tauri::command]
+fn get_audit_policy(db: State<'_, SharedDatabase>, token: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let policy = db.get_audit_policy().map_err(|e| e.to_string())?;
+    Ok(json!(policy))
+}
+
+#[tauri::command]
+fn set_audit_policy(db: State<'_, SharedDatabase>, token: &str, policy: AuditPolicy) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let policy = db.set_audit_policy(&policy).map_err(|e| e.to_string())?;
+    Ok(json!(policy))
+}
+
+#[tauri::command]
+fn get_audit_log(db: State<'_, SharedDatabase>, token: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let entries = db.get_audit_log().map_err(|e| e.to_string())?;
+    let roots = db.get_audit_roots().map_err(|e| e.to_string())?;
+    Ok(json!({ "entries": entries, "roots": roots }))
+}
+
+#[tauri::command]
+fn verify_audit_log(db: State<'_, SharedDatabase>, token: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let report = db.verify_audit_log().map_err(|e| e.to_string())?;
+    Ok(json!({ "intact": report.is_intact(), "report": report }))
+}
+
+#[tauri::command]
+fn export_audit_proofs(db: State<'_, SharedDatabase>, token: &str, table: &str, device_id: &str, start: &str, end: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let parse = |timestamp: &str| {
+        DateTime::parse_from_rfc3339(timestamp)
+            .map(|t| t.with_timezone(&Utc))
+            .map_err(|e| e.to_string())
+    };
+    let proofs = db
+        .export_audit_proofs(table, device_id, parse(start)?, parse(end)?)
+        .map_err(|e| e.to_string())?;
+    Ok(json!(proofs))
+}
+
+#[tauri::command]
+fn export_parquet(db: State<'_, SharedDatabase>, 
+    token: &str,
+    tables: Vec<String>,
+    device_ids: Vec<String>,
+    start: &str,
+    end: &str,
+    dir: &str,
+) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let db = db.scoped(user_context(&db, token)?);
+    let parse = |timestamp: &str| {
+        DateTime::parse_from_rfc3339(timestamp)
+            .map(|t| t.with_timezone(&Utc))
+            .map_err(|e| e.to_string())
+    };
+    let partitions = db
+        .export_parquet(&tables, &device_ids, parse(start)?, parse(end)?, Path::new(dir))
+        .map_err(|e| e.to_string())?;
+    Ok(json!(partitions))
+}
+
+#[tauri::command]
+fn export_track(db: State<'_, SharedDatabase>, token: &str, device_id: &str, start: &str, end: &str, options: TrackOptions) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let parse = |timestamp: &str| {
+        DateTime::parse_from_rfc3339(timestamp)
+            .map(|t| t.with_timezone(&Utc))
+            .map_err(|e| e.to_string())
+    };
+    let track = TrackExporter::new(&db, user)
+        .export(device_id, parse(start)?, parse(end)?, &options)
+        .map_err(|e| e.to_string())?;
+    Ok(json!(track))
+}
+
+#[tauri::command]
+fn import_parquet(db: State<'_, SharedDatabase>, token: &str, table: &str, paths: Vec<String>) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+    let import = CollectionService::new(&db, user).import_parquet(table, &paths).map_err(|e| e.to_string())?;
+    Ok(json!(import))
+}
+
+#[tauri::command]
+fn import_sensor_file(db: State<'_, SharedDatabase>, token: &str, path: &str, mapping: ImportMapping, dry_run: bool) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let report = FileImporter::new(&db, user).import(Path::new(path), &mapping, dry_run).map_err(|e| e.to_string())?;
+    Ok(json!(report))
+}
+
+#[tauri::command]
+fn import_apple_health(db: State<'_, SharedDatabase>, token: &str, path: &str, dry_run: bool) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let report = AppleHealthImporter::new(&db, user).import(Path::new(path), dry_run).map_err(|e| e.to_string())?;
+    Ok(json!(report))
+}
+
+#[tauri::command]
+fn import_workout(db: State<'_, SharedDatabase>, token: &str, path: &str, dry_run: bool) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let report = WorkoutImporter::new(&db, user).import(Path::new(path), dry_run).map_err(|e| e.to_string())?;
+    Ok(json!(report))
+}
+
+#[tauri::command]
+fn import_todos_ics(db: State<'_, SharedDatabase>, token: &str, path: &str, dry_run: bool) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let report = TodoCalendar::new(&db, user).import(Path::new(path), dry_run).map_err(|e| e.to_string())?;
+    Ok(json!(report))
+}
+
+#[tauri::command]
+fn export_todos_ics(db: State<'_, SharedDatabase>, token: &str, device_ids: Vec<String>) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let ics = TodoCalendar::new(&db, user).export(&device_ids).map_err(|e| e.to_string())?;
+    Ok(json!(ics))
+}
+
+#[tauri::command]
+fn export_vault(db: State<'_, SharedDatabase>, token: &str, dir: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let report = Vault::new(&db, user, Path::new(dir)).export().map_err(|e| e.to_string())?;
+    Ok(json!(report))
+}
+
+#[tauri::command]
+fn import_vault(db: State<'_, SharedDatabase>, token: &str, dir: &str, dry_run: bool) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let report = Vault::new(&db, user, Path::new(dir)).import(dry_run).map_err(|e| e.to_string())?;
+    Ok(json!(report))
+}
+
+#[tauri::command]
+fn sync_vault(db: State<'_, SharedDatabase>, token: &str, dir: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let report = Vault::new(&db, user, Path::new(dir)).sync().map_err(|e| e.to_string())?;
+    Ok(json!(report))
+}
+
+#[tauri::command]
+fn import_google_takeout(db: State<'_, SharedDatabase>, token: &str, path: &str, dry_run: bool) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let report = TakeoutImporter::new(&db, user).import(Path::new(path), dry_run).map_err(|e| e.to_string())?;
+    Ok(json!(report))
+}
+
+#[tauri::command]
+fn get_encryption_status(db: State<'_, SharedDatabase>, token: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    user_context(&db, token)?;
+    Ok(json!({
+        "enabled": db.is_field_encryption_enabled(),
+        "locked": db.is_locked(),
+        "key_created_at": db.get_field_key_created_at().map_err(|e| e.to_string())?,
+        "columns": db.get_encrypted_columns(),
+    }))
+}
+
+#[tauri::command]
+fn enable_field_encryption(db: State<'_, SharedDatabase>, token: &str, passphrase: &str) -> Result<usize, String> {
+    let db = lock(&db)?;
+    admin_context(&db, token)?;
+    db.enable_field_encryption(passphrase).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn unlock_fields(db: State<'_, SharedDatabase>, token: &str, passphrase: &str) -> Result<(), String> {
+    let db = lock(&db)?;
+    user_context(&db, token)?;
+    db.unlock(passphrase).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn lock_fields(db: State<'_, SharedDatabase>, token: &str) -> Result<(), String> {
+    let db = lock(&db)?;
+    admin_context(&db, token)?;
+    db.lock().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn change_encryption_passphrase(db: State<'_, SharedDatabase>, token: &str, passphrase: &str, new_passphrase: &str) -> Result<(), String> {
+    let db = lock(&db)?;
+    admin_context(&db, token)?;
+    db.change_passphrase(passphrase, new_passphrase).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn rotate_field_key(db: State<'_, SharedDatabase>, token: &str, passphrase: &str) -> Result<usize, String> {
+    let db = lock(&db)?;
+    admin_context(&db, token)?;
+    db.rotate_field_key(passphrase).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn set_column_encrypted(db: State<'_, SharedDatabase>, token: &str, table: &str, column: &str, encrypted: bool) -> Result<usize, String> {
+    let db = lock(&db)?;
+    admin_context(&db, token)?;
+    db.set_column_encrypted(table, column, encrypted).map_err(|e| e.to_string())
+}
+
+/// The running backup schedule, replaced whenever it is set again.
+type ScheduledBackups = Mutex<Option<BackupSchedule>>;
+
+#[tauri::command]
+fn backup_now(db: State<'_, SharedDatabase>, token: &str, dir: &str, passphrase: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    admin_context(&db, token)?;
+    let store = BackupStore::open(Path::new(dir), passphrase).map_err(|e| e.to_string())?;
+    let summary = store.backup(&db).map_err(|e| e.to_string())?;
+    Ok(json!(summary))
+}
+
+#[tauri::command]
+fn list_backups(db: State<'_, SharedDatabase>, token: &str, dir: &str, passphrase: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    admin_context(&db, token)?;
+    let store = BackupStore::open(Path::new(dir), passphrase).map_err(|e| e.to_string())?;
+    let snapshots = store.snapshots().map_err(|e| e.to_string())?;
+    Ok(json!(snapshots))
+}
+
+#[tauri::command]
+fn verify_backup(db: State<'_, SharedDatabase>, token: &str, dir: &str, passphrase: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    admin_context(&db, token)?;
+    let store = BackupStore::open(Path::new(dir), passphrase).map_err(|e| e.to_string())?;
+    let check = store.verify().map_err(|e| e.to_string())?;
+    Ok(json!({ "ok": check.is_ok(), "check": check }))
+}
+
+/// Restores into a new file rather than over the open database. If the open
+/// database is the one lost, the first account registered on the fresh one is
+/// the administrator and can restore.
+#[tauri::command]
+fn restore_backup(db: State<'_, SharedDatabase>, token: &str, dir: &str, passphrase: &str, snapshot_id: &str, target: &str) -> Result<Value, String> {
+    admin_context(&*lock(&db)?, token)?;
+    let store = BackupStore::open(Path::new(dir), passphrase).map_err(|e| e.to_string())?;
+    let snapshot = store.restore(snapshot_id, Path::new(target)).map_err(|e| e.to_string())?;
+    Ok(json!(snapshot))
+}
+
+/// Backs up every `interval_hours`, or stops scheduled backups when it is 0.
+#[tauri::command]
+fn schedule_backups(db: State<'_, SharedDatabase>, scheduled: State<'_, ScheduledBackups>, token: &str, dir: &str, passphrase: &str, interval_hours: u64) -> Result<(), String> {
+    // Not held while stopping: a backup in progress needs the database
+    admin_context(&*lock(&db)?, token)?;
+    let mut schedule = scheduled.lock().map_err(|e| e.to_string())?;
+    if let Some(running) = schedule.take() {
+        running.stop();
+    }
+    if interval_hours > 0 {
+        let store = BackupStore::open(Path::new(dir), passphrase).map_err(|e| e.to_string())?;
+        let every = std::time::Duration::from_secs(interval_hours * 60 * 60);
+        *schedule = Some(BackupSchedule::start(db.inner().clone(), store, every));
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn register(db: State<'_, SharedDatabase>, email: &str, name: Option<&str>, password: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = AuthService::new(&db).register(email, name, password).map_err(|e| e.to_string())?;
+    Ok(json!({ "id": user.id, "email": user.email, "name": user.name }))
+}
+
+#[tauri::command]
+fn login(db: State<'_, SharedDatabase>, email: &str, password: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let (user, session) = AuthService::new(&db).login(email, password).map_err(|e| e.to_string())?;
+    Ok(json!({
+        "user": { "id": user.id, "email": user.email, "name": user.name },
+        "session": session,
+    }))
+}
+
+#[tauri::command]
+fn logout(db: State<'_, SharedDatabase>, token: &str) -> Result<(), String> {
+    let db = lock(&db)?;
+    AuthService::new(&db).logout(token).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_linked_accounts(db: State<'_, SharedDatabase>, token: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let accounts = db.get_oauth_accounts(&user.user_id).map_err(|e| e.to_string())?;
+    Ok(Value::Array(accounts.iter().map(account_summary).collect()))
+}
+
+/// Links a provider account with the tokens the frontend got at the end of
+/// the provider's authorization flow.
+#[tauri::command]
+fn link_account(
+    db: State<'_, SharedDatabase>,
+    token: &str,
+    provider: &str,
+    provider_user_id: &str,
+    grant: TokenGrant,
+) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let providers = load_providers(Path::new(OAUTH_PROVIDERS_PATH)).map_err(|e| e.to_string())?;
+    if !providers.iter().any(|p| p.name == provider) {
+        return Err(OAuthError::UnknownProvider(provider.to_string()).to_string());
+    }
+
+    let secrets = SecretBox::load_or_create(Path::new(OAUTH_KEY_PATH)).map_err(|e| e.to_string())?;
+    let account = OAuthStore::new(&db, &secrets)
+        .link(&user.user_id, provider, provider_user_id, &grant)
+        .map_err(|e| e.to_string())?;
+    Ok(account_summary(&account))
+}
+
+#[tauri::command]
+fn unlink_account(db: State<'_, SharedDatabase>, token: &str, account_id: &str) -> Result<(), String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let secrets = SecretBox::load_or_create(Path::new(OAUTH_KEY_PATH)).map_err(|e| e.to_string())?;
+    OAuthStore::new(&db, &secrets).unlink(&user.user_id, account_id).map_err(|e| e.to_string())
+}
+
+/// Issues a token for the device server, shown once. The device may be one
+/// the user hasn't registered yet, but not someone else's.
+#[tauri::command]
+fn issue_device_token(db: State<'_, SharedDatabase>, token: &str, device_id: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    if db.get_device_owner(device_id).map_err(|e| e.to_string())?.is_some_and(|owner| owner != user.user_id) {
+        return Err(AccessError::Denied(device_id.to_string()).to_string());
+    }
+    let device_token = AuthService::new(&db).issue_device_token(&user.user_id, device_id).map_err(|e| e.to_string())?;
+    Ok(json!({ "id": device_token.id, "device_id": device_token.device_id, "token": device_token.token }))
+}
+
+/// Issues a device token for an OwnTracks phone, which names itself
+/// `device` in OwnTracks' settings.
+#[tauri::command]
+fn issue_owntracks_token(db: State<'_, SharedDatabase>, token: &str, device: &str) -> Result<Value, String> {
+    let device_id = {
+        let db = lock(&db)?;
+        OwnTracks::new(&db, user_context(&db, token)?).device_id(device)
+    };
+    issue_device_token(db, token, &device_id)
+}
+
+#[tauri::command]
+fn list_device_tokens(db: State<'_, SharedDatabase>, token: &str) -> Result<Value, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    let device_tokens = db.get_device_tokens(&user.user_id).map_err(|e| e.to_string())?;
+    Ok(Value::Array(
+        device_tokens
+            .iter()
+            .map(|t| json!({ "id": t.id, "device_id": t.device_id, "created_at": t.created_at, "last_used_at": t.last_used_at }))
+            .collect(),
+    ))
+}
+
+#[tauri::command]
+fn revoke_device_token(db: State<'_, SharedDatabase>, token: &str, id: &str) -> Result<bool, String> {
+    let db = lock(&db)?;
+    let user = user_context(&db, token)?;
+    AuthService::new(&db).revoke_device_token(&user.user_id, id).map_err(|e| e.to_string())
+}
+
+/// A linked account as the frontend sees it. Tokens stay on this side of
+/// the IPC boundary.
+fn account_summary(account: &OAuthAccount) -> Value {
+    json!({
+        "id": account.id,
+        "provider": account.provider,
+        "provider_user_id": account.provider_user_id,
+        "expires_at": account.expires_at,
+        "updated_at": account.updated_at,
+    })
+}
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    // Start the networking service
-    crate::networking::start_networking_service();
+    let db: SharedDatabase = Arc::new(Mutex::new(
+        Database::new(Path::new(DB_PATH)).expect("Failed to open database"),
+    ));
 
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        .invoke_handler(tauri::generate_handler![greet])
+        .plugin(tauri_plugin_log::Builder::new().build())
+        .setup(|app| {
+            // Background work starts here, once logging is up
+            let db = app.state::<SharedDatabase>().inner().clone();
+            match AuthService::new(&lock(&db)?).purge_expired() {
+                Ok(0) => {}
+                Ok(purged) => log::info!("Removed {} expired sessions", purged),
+                Err(e) => log::warn!("Removing expired sessions failed: {}", e),
+            }
+
+            // Start the networking service
+            crate::networking::start_networking_service(db.clone());
+
+            app.manage(EmbeddingWorker::start(db.clone(), std::time::Duration::from_secs(30)));
+
+            match load_providers(Path::new(OAUTH_PROVIDERS_PATH)) {
+                Ok(providers) if !providers.is_empty() => {
+                    let providers = providers.into_iter().map(|p| Box::new(p) as Box<dyn OAuthProvider>).collect();
+                    app.manage(RefreshScheduler::start(
+                        db,
+                        PathBuf::from(OAUTH_KEY_PATH),
+                        providers,
+                        std::time::Duration::from_secs(5 * 60),
+                        Duration::minutes(10),
+                    ));
+                }
+                Ok(_) => {}
+                Err(e) => log::error!("OAuth token refresh disabled: {}", e),
+            }
+            Ok(())
+        })
+        .manage(db)
+        .manage(ScheduledBackups::default())
+        .invoke_handler(tauri::generate_handler![
+            greet,
+            get_notes_for_moment,
+            search_notes,
+            search,
+            rebuild_search_index,
+            last_seen,
+            merge_entities,
+            split_entity,
+            list_devices,
+            rename_device,
+            retire_device,
+            merge_devices,
+            get_collection_plan,
+            get_sensor_preferences,
+            set_sensor_preference,
+            get_consents,
+            get_consent_history,
+            set_consent,
+            erase_data,
+            get_deletion_receipts,
+            get_privacy_zones,
+            set_privacy_zone,
+            delete_privacy_zone,
+            get_redaction_policy,
+            set_redaction_policy,
+            redact_stored_data,
+            get_audit_policy,
+            set_audit_policy,
+            get_audit_log,
+            verify_audit_log,
+            export_audit_proofs,
+            export_parquet,
+            export_track,
+            import_parquet,
+            import_sensor_file,
+            import_google_takeout,
+            import_apple_health,
+            import_workout,
+            import_todos_ics,
+            export_todos_ics,
+            export_vault,
+            import_vault,
+            sync_vault,
+            get_encryption_status,
+            enable_field_encryption,
+            unlock_fields,
+            lock_fields,
+            change_encryption_passphrase,
+            rotate_field_key,
+            set_column_encrypted,
+            backup_now,
+            list_backups,
+            verify_backup,
+            restore_backup,
+            schedule_backups,
+            register,
+            login,
+            logout,
+            get_linked_accounts,
+            link_account,
+            unlink_account,
+            issue_device_token,
+            issue_owntracks_token,
+            list_device_tokens,
+            revoke_device_token,
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
 
+pub mod audit;
+pub mod auth;
+pub mod backup;
+pub mod collection;
+pub mod crypto;
 pub mod datatypes;
 pub mod db;
+pub mod embedding;
+pub mod entities;
+pub mod geo;
+pub mod ical;
+pub mod import;
+pub mod oauth;
+pub mod owntracks;
+pub mod privacy;
+pub mod redaction;
+pub mod server;
+pub mod tracks;
+pub mod vault;
 
 #[cfg(test)]
 mod tests {
@@ -1,28 +1,34 @@
 use mdns_sd::{ServiceDaemon, ServiceInfo};
+use crate::db::SharedDatabase;
 use std::thread;
 use std::time::Duration;
 
 const SERVICE_TYPE: &str = "_loom-app._tcp.local.";
 const SERVICE_NAME: &str = "Loom App";
 const SERVICE_PORT: u16 = 8080;
+/// Where the device server listens unless `LOOM_SERVER_HOST` says otherwise.
+/// It speaks plain HTTP, so it stays on this machine by default; set the
+/// variable to `0.0.0.0` only behind a TLS-terminating proxy.
+const DEFAULT_SERVER_HOST: &str = "127.0.0.1";
 
-pub fn start_networking_service() {
+pub fn start_networking_service(db: SharedDatabase) {
     let mdns = ServiceDaemon::new().expect("Failed to create daemon");
 
-    // let txt_properties = vec![("role", "master")];
-
-    // Register our service
-    let service_info = ServiceInfo::new(
-        SERVICE_TYPE,
-        SERVICE_NAME,
-        "loom.local.",
-        "",
-        SERVICE_PORT,
-        None,
-        // Some(&txt_properties)
-    ).expect("Failed to create service info");
-    
-    mdns.register(service_info).expect("Failed to register service");
+    // Devices fetch their collection plans and upload data here
+    let host = std::env::var("LOOM_SERVER_HOST").unwrap_or_else(|_| DEFAULT_SERVER_HOST.to_string());
+    match crate::server::start(db, &host, SERVICE_PORT) {
+        Ok(addr) => {
+            log::info!("Device server listening on {}", addr);
+            // Nothing else on the network can reach a loopback server, so
+            // there's nothing to advertise
+            if addr.ip().is_loopback() {
+                log::info!("Not advertising the device server over mDNS: it only listens on {}", addr.ip());
+            } else {
+                advertise(&mdns, addr.port());
+            }
+        }
+        Err(e) => log::error!("Failed to start HTTP server: {}", e),
+    }
 
     // Browse for other instances
     let receiver = mdns.browse(SERVICE_TYPE).expect("Failed to browse");
@@ -30,9 +36,26 @@ pub fn start_networking_service() {
     thread::spawn(move || {
         loop {
             if let Ok(event) = receiver.recv() {
-                println!("Service discovery event: {:?}", event);
+                log::debug!("Service discovery event: {:?}", event);
             }
             thread::sleep(Duration::from_secs(1));
         }
     });
 }
+
+/// Registers the device server on `port` for devices on the network to find.
+fn advertise(mdns: &ServiceDaemon, port: u16) {
+    // let txt_properties = vec![("role", "master")];
+
+    let service_info = ServiceInfo::new(
+        SERVICE_TYPE,
+        SERVICE_NAME,
+        "loom.local.",
+        "",
+        port,
+        None,
+        // Some(&txt_properties)
+    ).expect("Failed to create service info");
+
+    mdns.register(service_info).expect("Failed to register service");
+}
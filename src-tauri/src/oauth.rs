@@ -0,0 +1,489 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use uuid::Uuid;
+use crate::crypto::{CryptoError, SecretBox};
+use crate::datatypes::user::OAuthAccount;
+use crate::db::{Database, SharedDatabase};
+
+#[derive(Debug)]
+pub enum OAuthError {
+    /// The token endpoint answered with an error, e.g. a revoked refresh token.
+    Provider(String),
+    /// The token endpoint couldn't be reached or sent something unreadable.
+    Http(String),
+    UnknownProvider(String),
+    /// The provider account is already linked to another user.
+    AlreadyLinked,
+    NotFound,
+    /// The account has no refresh token, so it can't be renewed.
+    NoRefreshToken,
+    /// The providers file couldn't be read or parsed.
+    Config(String),
+    Crypto(CryptoError),
+    Database(duckdb::Error),
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OAuthError::Provider(message) => write!(f, "token endpoint rejected the request: {}", message),
+            OAuthError::Http(message) => write!(f, "token endpoint unreachable: {}", message),
+            OAuthError::UnknownProvider(name) => write!(f, "no OAuth provider named {}", name),
+            OAuthError::AlreadyLinked => write!(f, "account is already linked to another user"),
+            OAuthError::NotFound => write!(f, "OAuth account not found"),
+            OAuthError::NoRefreshToken => write!(f, "account has no refresh token"),
+            OAuthError::Config(message) => write!(f, "invalid OAuth provider configuration: {}", message),
+            OAuthError::Crypto(e) => write!(f, "{}", e),
+            OAuthError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OAuthError {}
+
+impl From<duckdb::Error> for OAuthError {
+    fn from(e: duckdb::Error) -> Self {
+        OAuthError::Database(e)
+    }
+}
+
+impl From<CryptoError> for OAuthError {
+    fn from(e: CryptoError) -> Self {
+        OAuthError::Crypto(e)
+    }
+}
+
+/// The token endpoint response (RFC 6749 section 5.1), trimmed to what we keep.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenGrant {
+    pub access_token: String,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    /// Lifetime of the access token in seconds.
+    #[serde(default)]
+    pub expires_in: Option<i64>,
+}
+
+impl TokenGrant {
+    pub fn expires_at(&self, issued_at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.expires_in.map(|seconds| issued_at + Duration::seconds(seconds))
+    }
+}
+
+/// A service importers authenticate against. `name` is what ends up in
+/// `oauth_accounts.provider`.
+pub trait OAuthProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn refresh(&self, refresh_token: &str) -> Result<TokenGrant, OAuthError>;
+}
+
+/// A standard OAuth 2.0 token endpoint using the refresh_token grant.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HttpTokenProvider {
+    pub name: String,
+    pub token_url: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+}
+
+/// Reads the token endpoints accounts can be linked with, a JSON array of
+/// `HttpTokenProvider`s. No file means no providers are configured.
+pub fn load_providers(path: &Path) -> Result<Vec<HttpTokenProvider>, OAuthError> {
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).map_err(|e| OAuthError::Config(e.to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(OAuthError::Config(e.to_string())),
+    }
+}
+
+impl OAuthProvider for HttpTokenProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn refresh(&self, refresh_token: &str) -> Result<TokenGrant, OAuthError> {
+        let mut form = vec![
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("client_id", self.client_id.as_str()),
+        ];
+        if let Some(secret) = &self.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+
+        match ureq::post(&self.token_url).send_form(&form) {
+            Ok(response) => response.into_json().map_err(|e| OAuthError::Http(e.to_string())),
+            Err(ureq::Error::Status(status, response)) => {
+                let body = response.into_string().unwrap_or_default();
+                Err(OAuthError::Provider(format!("{} {}", status, body)))
+            }
+            Err(e) => Err(OAuthError::Http(e.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RefreshReport {
+    pub refreshed: usize,
+    /// (account id, reason) for every account that couldn't be refreshed.
+    pub failed: Vec<(String, String)>,
+    /// The run was skipped because the database is locked, so the tokens
+    /// couldn't be read.
+    pub locked: bool,
+}
+
+/// Links provider accounts to users. Tokens are sealed with `secrets` on the
+/// way in and opened on the way out, so accounts returned here hold plaintext
+/// tokens and must not be serialized to the frontend as-is.
+pub struct OAuthStore<'a> {
+    db: &'a Database,
+    secrets: &'a SecretBox,
+}
+
+impl<'a> OAuthStore<'a> {
+    pub fn new(db: &'a Database, secrets: &'a SecretBox) -> Self {
+        Self { db, secrets }
+    }
+
+    /// Links (or re-links, with new tokens) a provider account to `user_id`.
+    pub fn link(&self, user_id: &str, provider: &str, provider_user_id: &str, grant: &TokenGrant) -> Result<OAuthAccount, OAuthError> {
+        let now = Utc::now();
+        let access_token = self.secrets.seal(&grant.access_token);
+        let refresh_token = grant.refresh_token.as_deref().map(|t| self.secrets.seal(t));
+
+        let id = match self.db.find_oauth_account(provider, provider_user_id)? {
+            Some(existing) if existing.user_id != user_id => return Err(OAuthError::AlreadyLinked),
+            Some(existing) => {
+                self.db.update_oauth_tokens(&existing.id, &access_token, refresh_token.as_deref(), grant.expires_at(now))?;
+                existing.id
+            }
+            None => {
+                let account = OAuthAccount {
+                    id: Uuid::new_v4().to_string(),
+                    user_id: user_id.to_string(),
+                    provider: provider.to_string(),
+                    provider_user_id: provider_user_id.to_string(),
+                    access_token,
+                    refresh_token,
+                    expires_at: grant.expires_at(now),
+                    created_at: now,
+                    updated_at: now,
+                };
+                self.db.insert_oauth_account(&account)?;
+                account.id
+            }
+        };
+        self.account(&id)
+    }
+
+    pub fn unlink(&self, user_id: &str, account_id: &str) -> Result<(), OAuthError> {
+        match self.db.get_oauth_account(account_id)? {
+            Some(account) if account.user_id == user_id => Ok(self.db.delete_oauth_account(account_id)?),
+            _ => Err(OAuthError::NotFound),
+        }
+    }
+
+    pub fn account(&self, account_id: &str) -> Result<OAuthAccount, OAuthError> {
+        let account = self.db.get_oauth_account(account_id)?.ok_or(OAuthError::NotFound)?;
+        self.open(account)
+    }
+
+    pub fn accounts(&self, user_id: &str) -> Result<Vec<OAuthAccount>, OAuthError> {
+        self.db.get_oauth_accounts(user_id)?.into_iter().map(|a| self.open(a)).collect()
+    }
+
+    /// Exchanges the account's refresh token for a new access token.
+    pub fn refresh(&self, account_id: &str, provider: &dyn OAuthProvider) -> Result<OAuthAccount, OAuthError> {
+        let account = self.account(account_id)?;
+        let refresh_token = account.refresh_token.as_deref().ok_or(OAuthError::NoRefreshToken)?;
+
+        let grant = provider.refresh(refresh_token)?;
+        self.store_grant(account_id, &grant)
+    }
+
+    /// Stores tokens the provider issued for an already linked account.
+    pub fn store_grant(&self, account_id: &str, grant: &TokenGrant) -> Result<OAuthAccount, OAuthError> {
+        self.db.update_oauth_tokens(
+            account_id,
+            &self.secrets.seal(&grant.access_token),
+            grant.refresh_token.as_deref().map(|t| self.secrets.seal(t)).as_deref(),
+            grant.expires_at(Utc::now()),
+        )?;
+        self.account(account_id)
+    }
+
+    /// Accounts with a refresh token whose access token expires within
+    /// `margin`.
+    pub fn expiring(&self, margin: Duration) -> Result<Vec<OAuthAccount>, OAuthError> {
+        self.db.get_oauth_accounts_expiring(Utc::now() + margin)?.into_iter().map(|a| self.open(a)).collect()
+    }
+
+    /// Fails while the database is locked if the token columns are
+    /// field-encrypted.
+    fn open(&self, mut account: OAuthAccount) -> Result<OAuthAccount, OAuthError> {
+        account.access_token = self.secrets.open(&self.db.open_field(account.access_token)?)?;
+        account.refresh_token = self
+            .db
+            .open_optional_field(account.refresh_token)?
+            .map(|t| self.secrets.open(&t))
+            .transpose()?;
+        Ok(account)
+    }
+}
+
+/// Refreshes every account whose access token expires within `margin`. One
+/// account failing doesn't stop the others. The database is only locked
+/// around reads and writes, not while the token endpoints are called.
+/// Nothing is refreshed while field encryption is locked.
+pub fn refresh_expiring(
+    db: &SharedDatabase,
+    secrets: &SecretBox,
+    providers: &[Box<dyn OAuthProvider>],
+    margin: Duration,
+) -> Result<RefreshReport, OAuthError> {
+    let lock = || db.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut report = RefreshReport::default();
+    let accounts = {
+        let db = lock();
+        if db.is_locked() {
+            report.locked = true;
+            return Ok(report);
+        }
+        OAuthStore::new(&db, secrets).expiring(margin)?
+    };
+
+    for account in accounts {
+        let result = providers
+            .iter()
+            .find(|p| p.name() == account.provider)
+            .ok_or_else(|| OAuthError::UnknownProvider(account.provider.clone()))
+            .and_then(|provider| provider.refresh(account.refresh_token.as_deref().ok_or(OAuthError::NoRefreshToken)?))
+            .and_then(|grant| OAuthStore::new(&lock(), secrets).store_grant(&account.id, &grant));
+
+        match result {
+            Ok(_) => report.refreshed += 1,
+            Err(e) => report.failed.push((account.id, e.to_string())),
+        }
+    }
+    Ok(report)
+}
+
+/// Background thread that keeps linked accounts' access tokens fresh. Stops
+/// when dropped.
+pub struct RefreshScheduler {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl RefreshScheduler {
+    /// Every `interval`, refreshes tokens expiring within `margin`.
+    pub fn start(
+        db: SharedDatabase,
+        key_path: PathBuf,
+        providers: Vec<Box<dyn OAuthProvider>>,
+        interval: std::time::Duration,
+        margin: Duration,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+
+        let handle = thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                let run = SecretBox::load_or_create(&key_path)
+                    .map_err(OAuthError::from)
+                    .and_then(|secrets| refresh_expiring(&db, &secrets, &providers, margin));
+                match run {
+                    Ok(report) if report.locked => log::debug!("OAuth refresh skipped: database is locked"),
+                    Ok(report) => {
+                        for (account_id, reason) in report.failed {
+                            log::warn!("OAuth refresh failed for {}: {}", account_id, reason);
+                        }
+                    }
+                    Err(e) => log::error!("OAuth refresh run failed: {}", e),
+                }
+
+                let next_run = Instant::now() + interval;
+                while !stopped.load(Ordering::Relaxed) && Instant::now() < next_run {
+                    thread::sleep(std::time::Duration::from_millis(200));
+                }
+            }
+        });
+
+        Self { stop, handle: Some(handle) }
+    }
+}
+
+impl Drop for RefreshScheduler {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Serves one request with `status` and `body`, handing back the raw
+    /// request it received.
+    fn mock_token_endpoint(status: &'static str, body: &'static str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            loop {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|l| l.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status, body.len(), body
+            )
+            .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, handle)
+    }
+
+    fn provider(token_url: String) -> HttpTokenProvider {
+        HttpTokenProvider {
+            name: "mock".to_string(),
+            token_url,
+            client_id: "loom".to_string(),
+            client_secret: Some("s3cret".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_http_provider_refresh() {
+        let (url, server) = mock_token_endpoint(
+            "200 OK",
+            r#"{"access_token":"new-access","expires_in":3600,"token_type":"Bearer"}"#,
+        );
+        let grant = provider(url).refresh("old-refresh").unwrap();
+        let request = server.join().unwrap();
+
+        assert!(request.starts_with("POST /token"));
+        assert!(request.contains("grant_type=refresh_token"));
+        assert!(request.contains("refresh_token=old-refresh"));
+        assert!(request.contains("client_secret=s3cret"));
+        assert_eq!(grant.access_token, "new-access");
+        assert_eq!(grant.refresh_token, None);
+
+        let issued = Utc::now();
+        assert_eq!(grant.expires_at(issued), Some(issued + Duration::seconds(3600)));
+    }
+
+    #[test]
+    fn test_refresh_expiring_renews_linked_accounts() -> Result<(), OAuthError> {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("test.db"))?;
+        db.insert_user(&crate::db::fixtures::user("alice"))?;
+        let secrets = SecretBox::load_or_create(&dir.path().join("oauth.key"))?;
+
+        let grant = |access: &str, expires_in: i64| TokenGrant {
+            access_token: access.to_string(),
+            refresh_token: Some("refresh".to_string()),
+            expires_in: Some(expires_in),
+        };
+        let store = OAuthStore::new(&db, &secrets);
+        let expiring = store.link("alice", "mock", "alice@mock", &grant("old", 60))?;
+        store.link("alice", "mock", "alice-work@mock", &grant("fresh", 86_400))?;
+        assert!(matches!(store.link("bob", "mock", "alice@mock", &grant("x", 60)), Err(OAuthError::AlreadyLinked)));
+
+        let (url, server) = mock_token_endpoint("200 OK", r#"{"access_token":"new-access","expires_in":3600}"#);
+        let providers: Vec<Box<dyn OAuthProvider>> = vec![Box::new(provider(url))];
+        let db = Arc::new(std::sync::Mutex::new(db));
+        let report = refresh_expiring(&db, &secrets, &providers, Duration::minutes(10))?;
+        server.join().unwrap();
+        assert_eq!(report.refreshed, 1);
+        assert!(report.failed.is_empty());
+
+        let db = db.lock().unwrap();
+        let store = OAuthStore::new(&db, &secrets);
+        let account = store.account(&expiring.id)?;
+        assert_eq!(account.access_token, "new-access");
+        assert_eq!(account.refresh_token.as_deref(), Some("refresh"));
+        assert!(store.expiring(Duration::minutes(10))?.is_empty());
+
+        store.unlink("alice", &account.id)?;
+        assert!(matches!(store.account(&account.id), Err(OAuthError::NotFound)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokens_are_field_encrypted_and_skipped_while_locked() -> Result<(), OAuthError> {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("test.db"))?;
+        db.insert_user(&crate::db::fixtures::user("alice"))?;
+        db.enable_field_encryption("correct horse")?;
+        let secrets = SecretBox::load_or_create(&dir.path().join("oauth.key"))?;
+
+        let grant = TokenGrant {
+            access_token: "old".to_string(),
+            refresh_token: Some("refresh".to_string()),
+            expires_in: Some(60),
+        };
+        let account = OAuthStore::new(&db, &secrets).link("alice", "mock", "alice@mock", &grant)?;
+        let stored = db.get_oauth_account(&account.id)?.unwrap();
+        assert!(stored.access_token.starts_with(crate::crypto::FIELD_PREFIX));
+        assert!(stored.refresh_token.unwrap().starts_with(crate::crypto::FIELD_PREFIX));
+
+        // Locked, the run stops before calling any provider
+        db.lock()?;
+        let db = Arc::new(std::sync::Mutex::new(db));
+        let providers: Vec<Box<dyn OAuthProvider>> = vec![Box::new(provider("http://127.0.0.1:9/token".to_string()))];
+        let report = refresh_expiring(&db, &secrets, &providers, Duration::minutes(10))?;
+        assert!(report.locked);
+        assert_eq!(report.refreshed, 0);
+        assert!(report.failed.is_empty());
+
+        let db = db.lock().unwrap();
+        assert!(OAuthStore::new(&db, &secrets).account(&account.id).is_err());
+        db.unlock("correct horse")?;
+        assert_eq!(OAuthStore::new(&db, &secrets).account(&account.id)?.access_token, "old");
+        Ok(())
+    }
+
+    #[test]
+    fn test_http_provider_reports_rejection() {
+        let (url, server) = mock_token_endpoint("400 Bad Request", r#"{"error":"invalid_grant"}"#);
+        let result = provider(url).refresh("revoked");
+        server.join().unwrap();
+
+        match result {
+            Err(OAuthError::Provider(message)) => assert!(message.contains("invalid_grant")),
+            other => panic!("expected a provider error, got {:?}", other),
+        }
+    }
+}
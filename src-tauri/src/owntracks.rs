@@ -0,0 +1,467 @@
+// Messages from OwnTracks in HTTP mode, so phones running it can record
+// locations before there's a Loom app for them.
+
+use std::collections::HashSet;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::collection::{CollectionError, CollectionService};
+use crate::datatypes::{
+    note::{Note, NoteTarget},
+    sensor::{BatteryData, GpsData},
+    types::{Metadata, NotePriority},
+};
+use crate::db::{AccessError, Database, UserContext};
+use crate::import::ensure_device;
+
+/// Radius of a waypoint that doesn't give one. OwnTracks waypoints without
+/// a radius are points of interest rather than regions.
+const DEFAULT_WAYPOINT_RADIUS_M: f64 = 50.0;
+
+/// A message as OwnTracks posts it, tagged by `_type`. Only the fields Loom
+/// keeps are read.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "_type", rename_all = "lowercase")]
+pub enum Message {
+    Location(Location),
+    /// Entering or leaving a waypoint's region.
+    Transition(Transition),
+    Waypoint(Waypoint),
+    /// The phone's waypoints, as sent when they're published in bulk.
+    Waypoints { waypoints: Vec<Waypoint> },
+    /// Cards, status, lwt and anything else.
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Location {
+    /// Unix seconds.
+    pub tst: i64,
+    pub lat: f64,
+    pub lon: f64,
+    pub alt: Option<f64>,
+    /// Metres.
+    pub acc: Option<f64>,
+    /// Vertical accuracy, metres.
+    pub vac: Option<f64>,
+    /// km/h.
+    pub vel: Option<f64>,
+    /// Course over ground, degrees.
+    pub cog: Option<f64>,
+    /// Battery percentage.
+    pub batt: Option<i32>,
+    /// Battery status: 0 unknown, 1 unplugged, 2 charging, 3 full.
+    pub bs: Option<u8>,
+    /// Tracker id, the two letters shown on the map.
+    pub tid: Option<String>,
+    /// What triggered the report, such as `p` for a ping or `u` for manual.
+    pub t: Option<String>,
+    /// Connectivity: `w` wifi, `m` mobile, `o` offline.
+    pub conn: Option<String>,
+    /// `owntracks/{user}/{device}`.
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub inregions: Vec<String>,
+    #[serde(rename = "SSID")]
+    pub ssid: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Transition {
+    pub tst: i64,
+    pub lat: f64,
+    pub lon: f64,
+    pub acc: Option<f64>,
+    /// `enter` or `leave`.
+    pub event: String,
+    /// The waypoint's name.
+    pub desc: Option<String>,
+    pub t: Option<String>,
+    pub tid: Option<String>,
+    pub topic: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Waypoint {
+    /// When the waypoint was created, which OwnTracks also uses to tell
+    /// waypoints apart.
+    pub tst: i64,
+    pub lat: f64,
+    pub lon: f64,
+    /// Metres.
+    pub rad: Option<f64>,
+    pub desc: String,
+    /// Region id, from newer versions of the app.
+    pub rid: Option<String>,
+}
+
+/// What was stored from a request.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct OwnTracksReceipt {
+    pub device_id: String,
+    /// GPS fixes from locations and transitions.
+    pub fixes: usize,
+    pub battery: usize,
+    /// Waypoints stored as location notes, new or changed.
+    pub waypoints: usize,
+    /// Fixes and battery readings stored already, as when the app resends
+    /// after a failed request.
+    pub duplicates: usize,
+    /// Rows for tables the user hasn't planned or consented to uploading,
+    /// and messages of types Loom doesn't keep.
+    pub skipped: usize,
+    /// Messages that couldn't be read. The rest of the request is stored
+    /// all the same.
+    pub errors: Vec<MessageError>,
+}
+
+/// A message in a request that couldn't be read, by its position in the
+/// request.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MessageError {
+    pub index: usize,
+    pub message: String,
+}
+
+/// Stores what OwnTracks posts for one of the user's phones. Each phone gets
+/// a device of its own, `owntracks-{user}-{device}`, made on its first
+/// message and named by the device token it posts with. Fixes and battery readings go through the device's collection
+/// plan like any upload, but ones the user doesn't want are dropped rather
+/// than refused, as OwnTracks would otherwise resend them forever.
+/// Waypoints become notes attached to their place.
+pub struct OwnTracks<'a> {
+    db: &'a Database,
+    user: UserContext,
+}
+
+impl<'a> OwnTracks<'a> {
+    pub fn new(db: &'a Database, user: UserContext) -> Self {
+        Self { db, user }
+    }
+
+    /// The device for the phone OwnTracks calls `device`.
+    pub fn device_id(&self, device: &str) -> String {
+        format!("owntracks-{}-{}", self.user.user_id, slug(device))
+    }
+
+    /// Stores a posted message, or an array of them, for `device_id`, the
+    /// device the request's token was issued for. The message's topic or
+    /// tracker id only names the device when it's first made. Messages that
+    /// can't be read are reported in the receipt rather than failing the
+    /// request; only a body that isn't JSON at all does.
+    pub fn receive_as(&self, device_id: &str, body: &str) -> Result<OwnTracksReceipt, CollectionError> {
+        let mut receipt = OwnTracksReceipt { device_id: device_id.to_string(), ..Default::default() };
+        let mut messages = Vec::new();
+        for (index, message) in parse_messages(body)?.into_iter().enumerate() {
+            match message {
+                Ok(message) => messages.push(message),
+                Err(e) => receipt.errors.push(MessageError { index, message: e.to_string() }),
+            }
+        }
+
+        let name = messages.iter().find_map(Message::device_name).unwrap_or_else(|| device_id.to_string());
+        ensure_device(self.db, &self.user, device_id, &format!("OwnTracks {}", name), &["gps", "battery"])?;
+        let scoped = self.db.scoped(self.user.clone());
+        scoped.heartbeat_device(device_id, Utc::now())?;
+
+        let mut fixes = Vec::new();
+        let mut battery = Vec::new();
+        for message in messages {
+            match message {
+                Message::Location(location) => {
+                    let Some(timestamp) = DateTime::from_timestamp(location.tst, 0) else {
+                        receipt.skipped += 1;
+                        continue;
+                    };
+                    if let Some(percentage) = location.batt {
+                        battery.push(location_battery(device_id, timestamp, percentage, location.bs));
+                    }
+                    fixes.push(location_fix(device_id, timestamp, location));
+                }
+                Message::Transition(transition) => match DateTime::from_timestamp(transition.tst, 0) {
+                    Some(timestamp) => fixes.push(transition_fix(device_id, timestamp, transition)),
+                    None => receipt.skipped += 1,
+                },
+                Message::Waypoint(waypoint) => receipt.waypoints += usize::from(self.store_waypoint(device_id, &waypoint)?),
+                Message::Waypoints { waypoints } => {
+                    for waypoint in &waypoints {
+                        receipt.waypoints += usize::from(self.store_waypoint(device_id, waypoint)?);
+                    }
+                }
+                Message::Other => receipt.skipped += 1,
+            }
+        }
+
+        receipt.fixes = self.ingest("gps_data", device_id, fixes, |fix| fix.timestamp, &mut receipt)?;
+        receipt.battery = self.ingest("battery_data", device_id, battery, |row| row.timestamp, &mut receipt)?;
+        Ok(receipt)
+    }
+
+    /// Stores the rows the device's plan takes that aren't stored already,
+    /// returning how many were.
+    fn ingest<T: Serialize>(
+        &self,
+        table: &str,
+        device_id: &str,
+        rows: Vec<T>,
+        timestamp: impl Fn(&T) -> DateTime<Utc>,
+        receipt: &mut OwnTracksReceipt,
+    ) -> Result<usize, CollectionError> {
+        if rows.is_empty() {
+            return Ok(0);
+        }
+        let collection = CollectionService::new(self.db, self.user.clone());
+        let planned = collection
+            .plan(device_id)?
+            .sensors
+            .iter()
+            .any(|schedule| schedule.table_name == table && !schedule.local_only);
+        if !planned {
+            receipt.skipped += rows.len();
+            return Ok(0);
+        }
+
+        let mut seen = HashSet::new();
+        let mut new = Vec::new();
+        for row in rows {
+            let at = timestamp(&row);
+            if !seen.insert(at) || self.db.row_exists(table, device_id, at)? {
+                receipt.duplicates += 1;
+                continue;
+            }
+            new.push(serde_json::to_value(row).map_err(CollectionError::InvalidRow)?);
+        }
+        if new.is_empty() {
+            return Ok(0);
+        }
+        collection.ingest(table, new)
+    }
+
+    /// Keeps a waypoint as a note on its place, named by its description.
+    /// Returns whether anything changed.
+    fn store_waypoint(&self, device_id: &str, waypoint: &Waypoint) -> Result<bool, CollectionError> {
+        let scoped = self.db.scoped(self.user.clone());
+        let key = waypoint.rid.clone().unwrap_or_else(|| waypoint.tst.to_string());
+        let id = format!("{}-waypoint-{}", device_id, slug(&key));
+        let place = NoteTarget::Location {
+            latitude: waypoint.lat,
+            longitude: waypoint.lon,
+            radius_m: waypoint.rad.filter(|rad| *rad > 0.0).unwrap_or(DEFAULT_WAYPOINT_RADIUS_M),
+        };
+
+        let now = Utc::now();
+        match scoped.get_note(&id) {
+            Ok(mut note) => {
+                let references = scoped.get_note_references(&id)?;
+                let located = references.iter().filter_map(|reference| reference.target()).any(|target| target == place);
+                if note.content == waypoint.desc && located {
+                    return Ok(false);
+                }
+                for old in references.iter().filter_map(|reference| reference.target()) {
+                    if matches!(old, NoteTarget::Location { .. }) && old != place {
+                        scoped.detach_note(&id, &old)?;
+                    }
+                }
+                note.content = waypoint.desc.clone();
+                note.updated_at = now;
+                scoped.update_note(&note)?;
+            }
+            Err(AccessError::Denied(_)) => {
+                let mut metadata = Metadata::new();
+                metadata.insert("source".to_string(), Value::from("owntracks"));
+                metadata.insert("device_id".to_string(), Value::from(device_id));
+                scoped.insert_note(&Note {
+                    id: id.clone(),
+                    user_id: self.user.user_id.clone(),
+                    timestamp: DateTime::from_timestamp(waypoint.tst, 0).unwrap_or(now),
+                    content: waypoint.desc.clone(),
+                    priority: NotePriority::Medium,
+                    parent_id: None,
+                    tags: Some(vec!["owntracks".to_string(), "waypoint".to_string()]),
+                    embedding: None,
+                    metadata: Some(metadata),
+                    created_at: now,
+                    updated_at: now,
+                })?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        scoped.attach_note(&id, &place, None)?;
+        Ok(true)
+    }
+}
+
+impl Message {
+    /// The phone named by the message's topic, or failing that its tracker id.
+    fn device_name(&self) -> Option<String> {
+        let (topic, tid) = match self {
+            Message::Location(location) => (&location.topic, &location.tid),
+            Message::Transition(transition) => (&transition.topic, &transition.tid),
+            _ => return None,
+        };
+        topic
+            .as_deref()
+            .and_then(|topic| topic.split('/').nth(2))
+            .filter(|device| !device.is_empty())
+            .or(tid.as_deref())
+            .map(str::to_string)
+    }
+}
+
+/// A posted message, or an array of them, each read on its own so one bad
+/// message doesn't lose the others. Types Loom doesn't keep read as
+/// `Message::Other`. Fails only if the body isn't JSON.
+fn parse_messages(body: &str) -> Result<Vec<serde_json::Result<Message>>, CollectionError> {
+    let messages = match serde_json::from_str::<Value>(body).map_err(CollectionError::InvalidRow)? {
+        Value::Array(messages) => messages,
+        message => vec![message],
+    };
+    Ok(messages.into_iter().map(serde_json::from_value).collect())
+}
+
+fn location_fix(device_id: &str, timestamp: DateTime<Utc>, location: Location) -> GpsData {
+    let mut metadata = Metadata::new();
+    metadata.insert("source".to_string(), Value::from("owntracks"));
+    for (key, value) in [("tid", location.tid), ("trigger", location.t), ("conn", location.conn), ("ssid", location.ssid)] {
+        if let Some(value) = value {
+            metadata.insert(key.to_string(), Value::from(value));
+        }
+    }
+    if let Some(vac) = location.vac {
+        metadata.insert("vertical_accuracy".to_string(), Value::from(vac));
+    }
+    if !location.inregions.is_empty() {
+        metadata.insert("regions".to_string(), Value::from(location.inregions));
+    }
+    GpsData {
+        timestamp,
+        device_id: device_id.to_string(),
+        latitude: location.lat,
+        longitude: location.lon,
+        altitude: location.alt,
+        accuracy: location.acc.map(|acc| acc as f32),
+        speed: location.vel.map(|kmh| (kmh / 3.6) as f32),
+        bearing: location.cog.map(|cog| cog as f32),
+        satellites: None,
+        provider: None,
+        metadata: Some(metadata),
+    }
+}
+
+fn transition_fix(device_id: &str, timestamp: DateTime<Utc>, transition: Transition) -> GpsData {
+    let mut metadata = Metadata::new();
+    metadata.insert("source".to_string(), Value::from("owntracks"));
+    metadata.insert("event".to_string(), Value::from(transition.event));
+    for (key, value) in [("region", transition.desc), ("trigger", transition.t), ("tid", transition.tid)] {
+        if let Some(value) = value {
+            metadata.insert(key.to_string(), Value::from(value));
+        }
+    }
+    GpsData {
+        timestamp,
+        device_id: device_id.to_string(),
+        latitude: transition.lat,
+        longitude: transition.lon,
+        altitude: None,
+        accuracy: transition.acc.map(|acc| acc as f32),
+        speed: None,
+        bearing: None,
+        satellites: None,
+        provider: None,
+        metadata: Some(metadata),
+    }
+}
+
+fn location_battery(device_id: &str, timestamp: DateTime<Utc>, percentage: i32, status: Option<u8>) -> BatteryData {
+    let status = match status {
+        Some(1) => "unplugged",
+        Some(2) => "charging",
+        Some(3) => "full",
+        _ => "unknown",
+    };
+    let mut metadata = Metadata::new();
+    metadata.insert("source".to_string(), Value::from("owntracks"));
+    metadata.insert("status".to_string(), Value::from(status));
+    BatteryData {
+        timestamp,
+        device_id: device_id.to_string(),
+        percentage,
+        charging: status == "charging",
+        power_source: None,
+        temperature: None,
+        voltage: None,
+        current: None,
+        metadata: Some(metadata),
+    }
+}
+
+fn slug(text: &str) -> String {
+    text.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(|part| part.to_ascii_lowercase())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_messages() {
+        let location: Message = serde_json::from_str(
+            r#"{"_type":"location","tid":"ph","lat":52.37,"lon":4.89,"tst":1717232400,"acc":12,"alt":3,"vel":36,
+                "cog":270,"batt":81,"bs":2,"conn":"w","topic":"owntracks/alice/pixel","inregions":["Home"],"created_at":1717232401}"#,
+        )
+        .unwrap();
+        let Message::Location(location) = location else {
+            panic!("not a location");
+        };
+        assert_eq!((location.acc, location.batt, location.bs), (Some(12.0), Some(81), Some(2)));
+        assert_eq!(Message::Location(location.clone()).device_name().as_deref(), Some("pixel"));
+
+        let fix = location_fix("d", DateTime::from_timestamp(location.tst, 0).unwrap(), location);
+        assert_eq!(fix.speed, Some(10.0));
+        assert_eq!(fix.metadata.unwrap()["regions"], serde_json::json!(["Home"]));
+
+        let waypoints: Message = serde_json::from_str(
+            r#"{"_type":"waypoints","waypoints":[{"_type":"waypoint","desc":"Home","lat":52.37,"lon":4.89,"rad":100,"tst":1700000000}]}"#,
+        )
+        .unwrap();
+        assert!(matches!(waypoints, Message::Waypoints { waypoints } if waypoints[0].desc == "Home"));
+
+        let card: Message = serde_json::from_str(r#"{"_type":"card","name":"Alice"}"#).unwrap();
+        assert_eq!(card, Message::Other);
+        assert!(serde_json::from_str::<Message>(r#"{"_type":"location","lat":1}"#).is_err());
+    }
+
+    #[test]
+    fn test_battery_status() {
+        let at = DateTime::from_timestamp(0, 0).unwrap();
+        assert!(location_battery("d", at, 50, Some(2)).charging);
+        assert!(!location_battery("d", at, 100, Some(3)).charging);
+        assert_eq!(location_battery("d", at, 50, None).metadata.unwrap()["status"], "unknown");
+    }
+
+    #[test]
+    fn test_parse_messages_reads_each_message_on_its_own() {
+        let messages = parse_messages(
+            r#"[
+                {"_type":"location","lat":52.37,"lon":4.89,"tst":1717232400},
+                {"_type":"location","lat":1},
+                {"_type":"steps","steps":1200},
+                42
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(messages.len(), 4);
+        assert!(matches!(messages[0], Ok(Message::Location(_))));
+        assert!(messages[1].is_err());
+        assert!(matches!(messages[2], Ok(Message::Other)));
+        assert!(messages[3].is_err());
+
+        assert_eq!(parse_messages(r#"{"_type":"lwt","tst":1}"#).unwrap().len(), 1);
+        assert!(matches!(parse_messages("not json"), Err(CollectionError::InvalidRow(_))));
+    }
+}
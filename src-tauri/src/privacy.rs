@@ -0,0 +1,206 @@
+// Privacy zones applied to GPS and Wi-Fi readings before they are stored,
+// exported or uploaded.
+
+use rand::Rng;
+use crate::datatypes::{
+    privacy::PrivacyZone,
+    sensor::{GpsData, WifiData},
+    types::ZoneMode,
+};
+use crate::geo::{haversine_m, offset};
+
+/// Where zones overlap the strictest one applies.
+fn strictness(mode: ZoneMode) -> u8 {
+    match mode {
+        ZoneMode::Drop => 2,
+        ZoneMode::Snap => 1,
+        ZoneMode::Fuzz => 0,
+    }
+}
+
+/// The zone whose circle contains a point, if any.
+pub fn zone_containing(zones: &[PrivacyZone], latitude: f64, longitude: f64) -> Option<&PrivacyZone> {
+    zones
+        .iter()
+        .filter(|zone| match (zone.latitude, zone.longitude, zone.radius_m) {
+            (Some(lat), Some(lon), Some(radius_m)) => haversine_m(lat, lon, latitude, longitude) <= radius_m,
+            _ => false,
+        })
+        .max_by_key(|zone| strictness(zone.mode))
+}
+
+/// The zone covering a Wi-Fi network, matched by SSID or BSSID.
+pub fn zone_for_network<'a>(zones: &'a [PrivacyZone], ssid: &str, bssid: &str) -> Option<&'a PrivacyZone> {
+    zones
+        .iter()
+        .filter(|zone| {
+            zone.ssid.as_deref().is_some_and(|s| !ssid.is_empty() && s == ssid)
+                || zone.bssid.as_deref().is_some_and(|b| !bssid.is_empty() && b.eq_ignore_ascii_case(bssid))
+        })
+        .max_by_key(|zone| strictness(zone.mode))
+}
+
+/// The fix as it may leave the device or be stored, or `None` if it must be
+/// dropped. Altitude, speed and bearing are cleared inside a zone since they
+/// narrow the position down again.
+pub fn shield_gps(zones: &[PrivacyZone], fix: &GpsData, rng: &mut impl Rng) -> Option<GpsData> {
+    let mut fix = fix.clone();
+    let Some(zone) = zone_containing(zones, fix.latitude, fix.longitude) else {
+        return Some(fix);
+    };
+    let radius_m = zone.radius_m.unwrap_or_default();
+
+    match zone.mode {
+        ZoneMode::Drop => return None,
+        ZoneMode::Snap => {
+            fix.latitude = zone.latitude.unwrap_or(fix.latitude);
+            fix.longitude = zone.longitude.unwrap_or(fix.longitude);
+            fix.accuracy = Some(radius_m as f32);
+        }
+        ZoneMode::Fuzz => {
+            let noise_m = zone.noise_m.unwrap_or(radius_m);
+            (fix.latitude, fix.longitude) = planar_laplace(fix.latitude, fix.longitude, noise_m, rng);
+            fix.accuracy = Some(fix.accuracy.unwrap_or_default().max(noise_m as f32));
+        }
+    }
+    fix.altitude = None;
+    fix.speed = None;
+    fix.bearing = None;
+    Some(fix)
+}
+
+/// The reading as it may be stored, or `None` if it must be dropped. Inside
+/// a zone the network is replaced by the zone's name, and the address and
+/// nearby networks are removed since they identify the place as well.
+pub fn shield_wifi(zones: &[PrivacyZone], reading: &WifiData) -> Option<WifiData> {
+    let mut reading = reading.clone();
+    let Some(zone) = zone_for_network(zones, &reading.ssid, &reading.bssid) else {
+        return Some(reading);
+    };
+    if zone.mode == ZoneMode::Drop {
+        return None;
+    }
+
+    reading.ssid = zone.name.clone();
+    reading.bssid = String::new();
+    reading.ip_address = String::new();
+    reading.nearby_networks = None;
+    Some(reading)
+}
+
+/// Moves a point by planar Laplace noise, as used for geo-indistinguishability.
+/// The distance is Gamma(2)-distributed, i.e. the sum of two exponentials,
+/// with mean `mean_m`; the direction is uniform.
+fn planar_laplace(latitude: f64, longitude: f64, mean_m: f64, rng: &mut impl Rng) -> (f64, f64) {
+    let scale = mean_m / 2.0;
+    let distance = -scale * ((1.0 - rng.gen::<f64>()).ln() + (1.0 - rng.gen::<f64>()).ln());
+    let bearing = rng.gen_range(0.0..std::f64::consts::TAU);
+    offset(latitude, longitude, distance, bearing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn zone(mode: ZoneMode) -> PrivacyZone {
+        PrivacyZone {
+            id: "home".to_string(),
+            user_id: "alice".to_string(),
+            name: "Home".to_string(),
+            latitude: Some(51.5074),
+            longitude: Some(-0.1278),
+            radius_m: Some(200.0),
+            ssid: Some("Skynet".to_string()),
+            bssid: None,
+            mode,
+            noise_m: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn fix(latitude: f64, longitude: f64) -> GpsData {
+        GpsData {
+            timestamp: Utc::now(),
+            device_id: "phone".to_string(),
+            latitude,
+            longitude,
+            altitude: Some(30.0),
+            accuracy: Some(5.0),
+            speed: Some(1.2),
+            bearing: Some(90.0),
+            satellites: None,
+            provider: None,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn test_zones_drop_and_snap_fixes() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let inside = fix(51.5080, -0.1270);
+        let outside = fix(51.5200, -0.1000);
+
+        assert!(shield_gps(&[zone(ZoneMode::Drop)], &inside, &mut rng).is_none());
+        assert_eq!(shield_gps(&[zone(ZoneMode::Drop)], &outside, &mut rng).unwrap().latitude, 51.5200);
+
+        let snapped = shield_gps(&[zone(ZoneMode::Snap)], &inside, &mut rng).unwrap();
+        assert_eq!((snapped.latitude, snapped.longitude), (51.5074, -0.1278));
+        assert_eq!(snapped.accuracy, Some(200.0));
+        assert!(snapped.altitude.is_none() && snapped.speed.is_none());
+
+        // Overlapping zones: the stricter one wins
+        assert!(shield_gps(&[zone(ZoneMode::Snap), zone(ZoneMode::Drop)], &inside, &mut rng).is_none());
+    }
+
+    #[test]
+    fn test_fuzz_noise_averages_to_noise_m() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut fuzz = zone(ZoneMode::Fuzz);
+        fuzz.noise_m = Some(300.0);
+        let inside = fix(51.5080, -0.1270);
+
+        let samples = 2_000;
+        let mean = (0..samples)
+            .map(|_| {
+                let moved = shield_gps(std::slice::from_ref(&fuzz), &inside, &mut rng).unwrap();
+                haversine_m(inside.latitude, inside.longitude, moved.latitude, moved.longitude)
+            })
+            .sum::<f64>()
+            / samples as f64;
+        assert!((mean - 300.0).abs() < 20.0, "mean displacement {}", mean);
+    }
+
+    #[test]
+    fn test_wifi_zone_relabels_network() {
+        let reading = WifiData {
+            timestamp: Utc::now(),
+            device_id: "phone".to_string(),
+            ssid: "Skynet".to_string(),
+            bssid: "aa:bb:cc:dd:ee:ff".to_string(),
+            strength: -40,
+            frequency: 5180,
+            ip_address: "192.168.1.20".to_string(),
+            link_speed: 866,
+            security_type: "WPA2".to_string(),
+            is_5ghz: true,
+            is_6ghz: false,
+            is_passpoint: false,
+            is_restricted: false,
+            nearby_networks: Some(serde_json::json!(["Neighbour"])),
+            metadata: None,
+        };
+
+        let shielded = shield_wifi(&[zone(ZoneMode::Fuzz)], &reading).unwrap();
+        assert_eq!(shielded.ssid, "Home");
+        assert!(shielded.bssid.is_empty() && shielded.ip_address.is_empty() && shielded.nearby_networks.is_none());
+        assert!(shield_wifi(&[zone(ZoneMode::Drop)], &reading).is_none());
+
+        let mut by_bssid = zone(ZoneMode::Drop);
+        by_bssid.ssid = None;
+        by_bssid.bssid = Some("AA:BB:CC:DD:EE:FF".to_string());
+        assert!(shield_wifi(&[by_bssid], &reading).is_none());
+    }
+}
@@ -0,0 +1,380 @@
+// Detects third-party personal details in free text and replaces them with
+// salted pseudonyms. The same value always maps to the same pseudonym under
+// one salt, so counts and joins over redacted data still work.
+
+use std::collections::HashSet;
+use sha2::{Digest, Sha256};
+use crate::crypto::to_hex;
+use crate::datatypes::{
+    note::Note,
+    sensor::{AppUsageData, CallLogData, NotificationData},
+    types::{Metadata, PiiDetector},
+};
+
+/// Hex digits of the digest kept in a pseudonym.
+const DIGEST_HEX: usize = 8;
+
+/// Longest contact name, in words, looked for in text.
+const MAX_NAME_WORDS: usize = 3;
+
+pub struct Redactor {
+    salt: String,
+    detectors: Vec<PiiDetector>,
+    /// Digests of known contact names; see `name_digests`.
+    names: HashSet<String>,
+}
+
+struct Span {
+    start: usize,
+    end: usize,
+    detector: PiiDetector,
+    normalized: String,
+}
+
+impl Redactor {
+    pub fn new(salt: &str, detectors: &[PiiDetector], names: HashSet<String>) -> Self {
+        Redactor { salt: salt.to_string(), detectors: detectors.to_vec(), names }
+    }
+
+    pub fn is_enabled(&self, detector: PiiDetector) -> bool {
+        self.detectors.contains(&detector)
+    }
+
+    fn digest(&self, detector: PiiDetector, normalized: &str) -> String {
+        let hash = Sha256::digest(format!("{}:{}:{}", self.salt, detector.label(), normalized).as_bytes());
+        to_hex(&hash)[..DIGEST_HEX].to_string()
+    }
+
+    /// The stable stand-in for `value`, e.g. `[phone:1f3a9c0e]`. Formatting
+    /// differences (spacing, country prefix, case) don't change it.
+    pub fn pseudonym(&self, detector: PiiDetector, value: &str) -> String {
+        format!("[{}:{}]", detector.label(), self.digest(detector, &normalize(detector, value)))
+    }
+
+    /// What to store for a contact name so it can be recognised in text
+    /// later: the full name and, for multi-word names, the first name.
+    pub fn name_digests(&self, name: &str) -> Vec<String> {
+        let normalized = normalize(PiiDetector::ContactName, name);
+        let mut digests = vec![self.digest(PiiDetector::ContactName, &normalized)];
+        match normalized.split_once(' ') {
+            Some((first, _)) if first.chars().count() >= 3 => {
+                digests.push(self.digest(PiiDetector::ContactName, first))
+            }
+            _ => {}
+        }
+        digests
+    }
+
+    /// Whether `digest`, from `name_digests`, is a known contact name.
+    pub fn knows_name(&self, digest: &str) -> bool {
+        self.names.contains(digest)
+    }
+
+    /// Replaces every detected value in `text` with its pseudonym.
+    pub fn redact_text(&self, text: &str) -> String {
+        let protected = pseudonym_spans(text);
+        let mut spans = Vec::new();
+        if self.is_enabled(PiiDetector::Email) {
+            spans.extend(find_emails(text));
+        }
+        if self.is_enabled(PiiDetector::PhoneNumber) || self.is_enabled(PiiDetector::CardNumber) {
+            spans.extend(find_numbers(text).into_iter().filter(|span| self.is_enabled(span.detector)));
+        }
+        if self.is_enabled(PiiDetector::ContactName) && !self.names.is_empty() {
+            spans.extend(self.find_names(text));
+        }
+        spans.sort_by_key(|span| (span.start, std::cmp::Reverse(span.end)));
+
+        let mut redacted = String::with_capacity(text.len());
+        let mut cursor = 0;
+        for span in spans {
+            if span.start < cursor || protected.iter().any(|&(start, end)| span.start < end && start < span.end) {
+                continue;
+            }
+            redacted.push_str(&text[cursor..span.start]);
+            redacted.push_str(&format!("[{}:{}]", span.detector.label(), self.digest(span.detector, &span.normalized)));
+            cursor = span.end;
+        }
+        redacted.push_str(&text[cursor..]);
+        redacted
+    }
+
+    /// For columns holding nothing but one value of a known kind, such as
+    /// `call_log_data.phone_number`.
+    pub fn redact_field(&self, detector: PiiDetector, value: &str) -> String {
+        if !self.is_enabled(detector) || value.trim().is_empty() || is_pseudonym(value) {
+            return value.to_string();
+        }
+        self.pseudonym(detector, value)
+    }
+
+    pub fn redact_metadata(&self, metadata: &Metadata) -> Metadata {
+        metadata.iter().map(|(key, value)| (key.clone(), self.redact_json(value))).collect()
+    }
+
+    fn redact_json(&self, value: &serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::String(s) => serde_json::Value::String(self.redact_text(s)),
+            serde_json::Value::Array(items) => items.iter().map(|item| self.redact_json(item)).collect(),
+            serde_json::Value::Object(fields) => serde_json::Value::Object(
+                fields.iter().map(|(key, value)| (key.clone(), self.redact_json(value))).collect(),
+            ),
+            other => other.clone(),
+        }
+    }
+
+    pub fn redact_call_log(&self, data: &CallLogData) -> CallLogData {
+        let mut data = data.clone();
+        data.phone_number = data.phone_number.map(|n| self.redact_field(PiiDetector::PhoneNumber, &n));
+        data.contact_name = data.contact_name.map(|n| self.redact_field(PiiDetector::ContactName, &n));
+        data.metadata = data.metadata.map(|m| self.redact_metadata(&m));
+        data
+    }
+
+    pub fn redact_notification(&self, data: &NotificationData) -> NotificationData {
+        let mut data = data.clone();
+        data.title = data.title.map(|t| self.redact_text(&t));
+        data.metadata = data.metadata.map(|m| self.redact_metadata(&m));
+        data
+    }
+
+    pub fn redact_app_usage(&self, data: &AppUsageData) -> AppUsageData {
+        let mut data = data.clone();
+        data.metadata = data.metadata.map(|m| self.redact_metadata(&m));
+        data
+    }
+
+    pub fn redact_note(&self, note: &Note) -> Note {
+        let mut note = note.clone();
+        note.content = self.redact_text(&note.content);
+        note
+    }
+
+    /// Runs of one to `MAX_NAME_WORDS` words whose digest is a known name,
+    /// longest first.
+    fn find_names(&self, text: &str) -> Vec<Span> {
+        let words = words(text);
+        let mut spans = Vec::new();
+        let mut i = 0;
+        while i < words.len() {
+            let found = (1..=MAX_NAME_WORDS.min(words.len() - i)).rev().find_map(|n| {
+                let (start, end) = (words[i].0, words[i + n - 1].1);
+                let phrase = &text[start..end];
+                if words[i..i + n].windows(2).any(|w| !text[w[0].1..w[1].0].chars().all(char::is_whitespace)) {
+                    return None;
+                }
+                let normalized = normalize(PiiDetector::ContactName, phrase);
+                self.names
+                    .contains(&self.digest(PiiDetector::ContactName, &normalized))
+                    .then_some((n, Span { start, end, detector: PiiDetector::ContactName, normalized }))
+            });
+            match found {
+                Some((n, span)) => {
+                    spans.push(span);
+                    i += n;
+                }
+                None => i += 1,
+            }
+        }
+        spans
+    }
+}
+
+/// Whether `value` is exactly one pseudonym.
+pub fn is_pseudonym(value: &str) -> bool {
+    pseudonym_spans(value) == [(0, value.len())]
+}
+
+/// The canonical form a value is hashed in. Phone numbers keep their last
+/// nine digits so national and international forms agree.
+fn normalize(detector: PiiDetector, value: &str) -> String {
+    match detector {
+        PiiDetector::PhoneNumber => {
+            let digits: Vec<char> = value.chars().filter(char::is_ascii_digit).collect();
+            digits[digits.len().saturating_sub(9)..].iter().collect()
+        }
+        PiiDetector::CardNumber => value.chars().filter(char::is_ascii_digit).collect(),
+        PiiDetector::Email => value.trim().to_lowercase(),
+        PiiDetector::ContactName => value.split_whitespace().map(str::to_lowercase).collect::<Vec<_>>().join(" "),
+    }
+}
+
+/// Byte ranges of `[label:digest]` pseudonyms already in `text`.
+fn pseudonym_spans(text: &str) -> Vec<(usize, usize)> {
+    let labels = [PiiDetector::PhoneNumber, PiiDetector::Email, PiiDetector::CardNumber, PiiDetector::ContactName];
+    let mut spans = Vec::new();
+    for (start, _) in text.match_indices('[') {
+        let rest = &text[start + 1..];
+        let Some((label, tail)) = rest.split_once(':') else { continue };
+        if !labels.iter().any(|d| d.label() == label) {
+            continue;
+        }
+        let digest = tail.get(..DIGEST_HEX);
+        if digest.is_some_and(|d| d.bytes().all(|b| b.is_ascii_hexdigit())) && tail[DIGEST_HEX..].starts_with(']') {
+            spans.push((start, start + 1 + label.len() + 1 + DIGEST_HEX + 1));
+        }
+    }
+    spans
+}
+
+fn find_emails(text: &str) -> Vec<Span> {
+    let bytes = text.as_bytes();
+    let is_local = |b: u8| b.is_ascii_alphanumeric() || b"._%+-".contains(&b);
+    let is_domain = |b: u8| b.is_ascii_alphanumeric() || b".-".contains(&b);
+
+    let mut spans = Vec::new();
+    for (at, _) in text.match_indices('@') {
+        let start = (0..at).rev().take_while(|&i| is_local(bytes[i])).last().unwrap_or(at);
+        let mut end = (at + 1..bytes.len()).take_while(|&i| is_domain(bytes[i])).last().map_or(at + 1, |i| i + 1);
+        while end > at + 1 && b".-".contains(&bytes[end - 1]) {
+            end -= 1;
+        }
+        let domain = &text[at + 1..end];
+        let tld = domain.rsplit('.').next().unwrap_or_default();
+        if start < at && domain.contains('.') && tld.len() >= 2 && tld.bytes().all(|b| b.is_ascii_alphabetic()) {
+            spans.push(Span { start, end, detector: PiiDetector::Email, normalized: normalize(PiiDetector::Email, &text[start..end]) });
+        }
+    }
+    spans
+}
+
+/// Digit runs, allowing the separators people type in phone and card
+/// numbers. 13–19 digits passing the Luhn check are cards; other runs of
+/// 7–15 digits are phone numbers.
+fn find_numbers(text: &str) -> Vec<Span> {
+    let bytes = text.as_bytes();
+    let mut spans = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let starts_number = bytes[i].is_ascii_digit()
+            || (bytes[i] == b'+' && bytes.get(i + 1).is_some_and(u8::is_ascii_digit))
+            || (bytes[i] == b'(' && bytes.get(i + 1).is_some_and(u8::is_ascii_digit));
+        let after_word = i > 0 && (bytes[i - 1].is_ascii_alphanumeric() || bytes[i - 1] == b'[' || bytes[i - 1] == b':');
+        if !starts_number || after_word {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        let mut end = i + 1;
+        while end < bytes.len() {
+            let b = bytes[end];
+            let separator = b" -()".contains(&b) && bytes.get(end + 1).is_some_and(|n| n.is_ascii_digit() || *n == b'(');
+            if b.is_ascii_digit() || separator || (b == b')' && end > start) {
+                end += 1;
+            } else {
+                break;
+            }
+        }
+        while end > start && !bytes[end - 1].is_ascii_digit() {
+            end -= 1;
+        }
+        i = end.max(start + 1);
+        if bytes.get(end).is_some_and(|b| b.is_ascii_alphanumeric()) {
+            continue;
+        }
+
+        let run = &text[start..end];
+        let digits: Vec<u32> = run.chars().filter_map(|c| c.to_digit(10)).collect();
+        let detector = if (13..=19).contains(&digits.len()) && luhn_valid(&digits) {
+            PiiDetector::CardNumber
+        } else if (7..=15).contains(&digits.len()) {
+            PiiDetector::PhoneNumber
+        } else {
+            continue;
+        };
+        spans.push(Span { start, end, detector, normalized: normalize(detector, run) });
+    }
+    spans
+}
+
+fn luhn_valid(digits: &[u32]) -> bool {
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| if i % 2 == 1 { if d * 2 > 9 { d * 2 - 9 } else { d * 2 } } else { d })
+        .sum();
+    sum % 10 == 0
+}
+
+/// Byte ranges of the words in `text`: letters, with inner apostrophes
+/// and hyphens.
+fn words(text: &str) -> Vec<(usize, usize)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let inner = (c == '\'' || c == '-') && start.is_some() && chars.peek().is_some_and(|(_, n)| n.is_alphabetic());
+        if c.is_alphabetic() || inner {
+            start.get_or_insert(i);
+        } else if let Some(s) = start.take() {
+            words.push((s, i));
+        }
+    }
+    if let Some(s) = start {
+        words.push((s, text.len()));
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor(detectors: &[PiiDetector]) -> Redactor {
+        let mut redactor = Redactor::new("salt", detectors, HashSet::new());
+        redactor.names = redactor.name_digests("Sam Jones").into_iter().collect();
+        redactor
+    }
+
+    #[test]
+    fn test_pseudonyms_are_stable_across_formats() {
+        let redactor = redactor(&[PiiDetector::PhoneNumber, PiiDetector::Email]);
+        assert_eq!(
+            redactor.pseudonym(PiiDetector::PhoneNumber, "+44 7700 900123"),
+            redactor.pseudonym(PiiDetector::PhoneNumber, "07700 900123"),
+        );
+        assert_eq!(
+            redactor.pseudonym(PiiDetector::Email, "Sam@Example.com"),
+            redactor.pseudonym(PiiDetector::Email, "sam@example.com"),
+        );
+        assert_ne!(
+            redactor.pseudonym(PiiDetector::PhoneNumber, "07700 900123"),
+            Redactor::new("pepper", &[], HashSet::new()).pseudonym(PiiDetector::PhoneNumber, "07700 900123"),
+        );
+
+        let field = redactor.redact_field(PiiDetector::PhoneNumber, "07700 900123");
+        assert!(is_pseudonym(&field));
+        assert_eq!(redactor.redact_field(PiiDetector::PhoneNumber, &field), field);
+        assert_eq!(redactor.redact_field(PiiDetector::CardNumber, "4111 1111 1111 1111"), "4111 1111 1111 1111");
+    }
+
+    #[test]
+    fn test_redact_text_finds_each_kind() {
+        let all = [PiiDetector::PhoneNumber, PiiDetector::Email, PiiDetector::CardNumber, PiiDetector::ContactName];
+        let redactor = redactor(&all);
+        let text = "Sam Jones (sam.jones@example.co.uk) paid with 4111-1111-1111-1111, call +44 7700 900123 at 5pm";
+        let redacted = redactor.redact_text(text);
+
+        assert!(!redacted.contains("Sam") && !redacted.contains("example") && !redacted.contains("4111") && !redacted.contains("7700"));
+        assert!(redacted.starts_with(&redactor.pseudonym(PiiDetector::ContactName, "sam jones")));
+        assert!(redacted.contains(&redactor.pseudonym(PiiDetector::Email, "sam.jones@example.co.uk")));
+        assert!(redacted.contains(&redactor.pseudonym(PiiDetector::CardNumber, "4111111111111111")));
+        assert!(redacted.contains(&redactor.pseudonym(PiiDetector::PhoneNumber, "07700900123")));
+        assert!(redacted.ends_with(" at 5pm"));
+
+        // First names alone are known too; already-redacted text is left as is
+        assert_eq!(redactor.redact_text("Sam: running late"), format!("{}: running late", redactor.pseudonym(PiiDetector::ContactName, "sam")));
+        assert_eq!(redactor.redact_text(&redacted), redacted);
+    }
+
+    #[test]
+    fn test_redact_text_leaves_other_numbers() {
+        let redactor = redactor(&[PiiDetector::PhoneNumber, PiiDetector::CardNumber]);
+        for text in ["Battery at 45%", "Meeting 10:30-11:00", "Order 1234", "Samsung update", "Card 4111 1111 1111 1112"] {
+            assert_eq!(redactor.redact_text(text), text);
+        }
+        assert!(luhn_valid(&[4, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1]));
+    }
+}
@@ -0,0 +1,323 @@
+use std::{io::{self, Read}, net::SocketAddr, thread};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+use crate::auth::AuthService;
+use crate::collection::{CollectionError, CollectionService};
+use crate::db::{AccessError, Database, SharedDatabase, UserContext};
+use crate::owntracks::OwnTracks;
+
+/// Endpoints devices talk to. Every request carries a device token from
+/// `AuthService::issue_device_token` as `Authorization: Bearer <token>`, or as
+/// the password of HTTP basic auth for clients that only support that. A
+/// token only acts for the device it was issued for.
+///
+/// - `GET /devices/{id}/plan` returns the device's collection plan
+/// - `POST /devices/{id}/heartbeat` bumps `last_seen` and returns the plan,
+///   so plan changes reach devices on their next heartbeat
+/// - `POST /ingest/{table}` stores a row or an array of rows for a sensor table
+/// - `GET /tombstones?since={rfc3339}` lists deletions the device should
+///   apply to its own copy of the data
+/// - `POST /owntracks` and `POST /owntracks/{device}` take messages from
+///   OwnTracks in HTTP mode. They are stored under the token's device
+///   whatever the path or OwnTracks' `X-Limit-D` header call it
+#[derive(Debug, PartialEq)]
+enum Route {
+    Plan(String),
+    Heartbeat(String),
+    Ingest(String),
+    Tombstones(Option<DateTime<Utc>>),
+    OwnTracks,
+}
+
+impl Route {
+    fn parse(method: &Method, url: &str) -> Option<Self> {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        match (method, segments.as_slice()) {
+            (Method::Get, ["devices", id, "plan"]) => Some(Route::Plan(id.to_string())),
+            (Method::Post, ["devices", id, "heartbeat"]) => Some(Route::Heartbeat(id.to_string())),
+            (Method::Post, ["ingest", table]) => Some(Route::Ingest(table.to_string())),
+            (Method::Get, ["tombstones"]) => {
+                let since = query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("since="))
+                    .map(|since| DateTime::parse_from_rfc3339(since).ok().map(|t| t.with_timezone(&Utc)));
+                match since {
+                    Some(None) => None,
+                    since => Some(Route::Tombstones(since.flatten())),
+                }
+            }
+            (Method::Post, ["owntracks"] | ["owntracks", _]) => Some(Route::OwnTracks),
+            _ => None,
+        }
+    }
+}
+
+/// The largest request body read, in bytes. Bigger ingest batches have to be
+/// split.
+const MAX_BODY: u64 = 8 * 1024 * 1024;
+
+/// Serves the device endpoints on `host:port` from a background thread, one
+/// request at a time, each holding the database while it's handled. The
+/// server speaks plain HTTP, so anything but a loopback `host` should sit
+/// behind a TLS-terminating proxy. Returns the address it's bound to.
+pub fn start(db: SharedDatabase, host: &str, port: u16) -> io::Result<SocketAddr> {
+    let server = Server::http((host, port)).map_err(io::Error::other)?;
+    let addr = server.server_addr().to_ip().ok_or_else(|| io::Error::other("not bound to an IP address"))?;
+    thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let (status, body) = match respond(&db, &mut request) {
+                Ok(body) => (200, body),
+                Err((status, message)) => (status, json!({ "error": message })),
+            };
+            let response = Response::from_string(body.to_string())
+                .with_status_code(status)
+                .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+            if let Err(e) = request.respond(response) {
+                log::warn!("Failed to send response: {}", e);
+            }
+        }
+    });
+    Ok(addr)
+}
+
+fn respond(db: &SharedDatabase, request: &mut Request) -> Result<Value, (u16, String)> {
+    let route = Route::parse(request.method(), request.url()).ok_or((404, "not found".to_string()))?;
+    let token = device_token(request).ok_or((401, "missing device token".to_string()))?;
+    let (user, device_token) = {
+        let db = db.lock().map_err(|e| (500, e.to_string()))?;
+        AuthService::new(&db).authenticate_device(&token).map_err(|e| (401, e.to_string()))?
+    };
+
+    let body = read_body(request)?;
+    let db = db.lock().map_err(|e| (500, e.to_string()))?;
+    handle(&db, UserContext::from(&user), &device_token.device_id, route, &body)
+        .map_err(|e| (status_for(&e), e.to_string()))
+}
+
+/// Reads the body once the request has authenticated, so only known devices
+/// get anything buffered, and then no more than `MAX_BODY` bytes of it.
+fn read_body(request: &mut Request) -> Result<String, (u16, String)> {
+    let too_large = || (413, format!("request body is larger than {} bytes", MAX_BODY));
+    if request.body_length().is_some_and(|length| length as u64 > MAX_BODY) {
+        return Err(too_large());
+    }
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY + 1)
+        .read_to_string(&mut body)
+        .map_err(|e| (400, e.to_string()))?;
+    if body.len() as u64 > MAX_BODY {
+        return Err(too_large());
+    }
+    Ok(body)
+}
+
+/// Handles `route` for `device`, the one device the request's token acts for.
+fn handle(db: &Database, user: UserContext, device: &str, route: Route, body: &str) -> Result<Value, CollectionError> {
+    let only_device = |device_id: &str| {
+        if device_id == device {
+            Ok(())
+        } else {
+            Err(CollectionError::Access(AccessError::Denied(device_id.to_string())))
+        }
+    };
+    let collection = CollectionService::new(db, user.clone());
+    match route {
+        Route::Plan(device_id) => {
+            only_device(&device_id)?;
+            Ok(json!(collection.plan(&device_id)?))
+        }
+        Route::Heartbeat(device_id) => {
+            only_device(&device_id)?;
+            db.scoped(user).heartbeat_device(&device_id, Utc::now())?;
+            Ok(json!(collection.plan(&device_id)?))
+        }
+        Route::Ingest(table) => {
+            let rows = match serde_json::from_str(body).map_err(CollectionError::InvalidRow)? {
+                Value::Array(rows) => rows,
+                row => vec![row],
+            };
+            for row in &rows {
+                only_device(row.get("device_id").and_then(Value::as_str).unwrap_or_default())?;
+            }
+            let inserted = collection.ingest(&table, rows)?;
+            Ok(json!({ "inserted": inserted }))
+        }
+        Route::Tombstones(since) => Ok(json!(db.scoped(user).get_tombstones(since)?)),
+        Route::OwnTracks => {
+            let receipt = OwnTracks::new(db, user).receive_as(device, body)?;
+            for error in &receipt.errors {
+                log::warn!("Unreadable OwnTracks message {} from {}: {}", error.index, device, error.message);
+            }
+            // OwnTracks shows whatever comes back as messages from friends,
+            // so it gets an empty list rather than the receipt.
+            Ok(json!([]))
+        }
+    }
+}
+
+fn header(request: &Request, name: &str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str().trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn device_token(request: &Request) -> Option<String> {
+    token_from_authorization(&header(request, "Authorization")?)
+}
+
+/// The device token from a bearer token, or from the password of basic
+/// auth, where the username is ignored.
+fn token_from_authorization(authorization: &str) -> Option<String> {
+    if let Some(token) = authorization.strip_prefix("Bearer ") {
+        return Some(token.trim().to_string());
+    }
+    let credentials = BASE64.decode(authorization.strip_prefix("Basic ")?.trim()).ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let (_, password) = credentials.split_once(':')?;
+    Some(password.to_string()).filter(|password| !password.is_empty())
+}
+
+fn status_for(e: &CollectionError) -> u16 {
+    match e {
+        CollectionError::Unsupported { .. } | CollectionError::NotPlanned(_) => 422,
+        CollectionError::ConsentWithheld { .. } => 403,
+        CollectionError::UnknownTable(_) => 404,
+        CollectionError::InvalidRow(_) | CollectionError::InvalidInterval(_) => 400,
+        CollectionError::Access(AccessError::Denied(_) | AccessError::ConsentWithheld(_)) => 403,
+        CollectionError::Access(AccessError::Database(_)) => 500,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatypes::{note::NoteTarget, types::ConsentLevel};
+    use crate::db::{fixtures::{device, temp_database, user}, ConsentWithheld, CONSENT_WILDCARD};
+
+    #[test]
+    fn test_route_parse() {
+        assert_eq!(Route::parse(&Method::Get, "/devices/phone-1/plan"), Some(Route::Plan("phone-1".to_string())));
+        assert_eq!(
+            Route::parse(&Method::Post, "/devices/phone-1/heartbeat?v=2"),
+            Some(Route::Heartbeat("phone-1".to_string()))
+        );
+        assert_eq!(Route::parse(&Method::Post, "/ingest/gps_data"), Some(Route::Ingest("gps_data".to_string())));
+        assert_eq!(Route::parse(&Method::Get, "/ingest/gps_data"), None);
+        assert_eq!(Route::parse(&Method::Get, "/devices/phone-1"), None);
+        assert_eq!(Route::parse(&Method::Get, "/tombstones"), Some(Route::Tombstones(None)));
+        assert_eq!(
+            Route::parse(&Method::Get, "/tombstones?since=2024-05-01T12:00:00Z"),
+            Some(Route::Tombstones(Some("2024-05-01T12:00:00Z".parse().unwrap())))
+        );
+        assert_eq!(Route::parse(&Method::Get, "/tombstones?since=yesterday"), None);
+        assert_eq!(Route::parse(&Method::Post, "/owntracks"), Some(Route::OwnTracks));
+        assert_eq!(Route::parse(&Method::Post, "/owntracks/pixel"), Some(Route::OwnTracks));
+        assert_eq!(Route::parse(&Method::Get, "/owntracks"), None);
+    }
+
+    #[test]
+    fn test_token_from_authorization() {
+        assert_eq!(token_from_authorization("Bearer abc123 ").as_deref(), Some("abc123"));
+        assert_eq!(token_from_authorization("Basic YWxpY2U6czNjcjN0").as_deref(), Some("s3cr3t"));
+        assert_eq!(token_from_authorization("Basic YWxpY2U6").as_deref(), None);
+        assert_eq!(token_from_authorization("Basic not base64!"), None);
+        assert_eq!(token_from_authorization("Digest abc"), None);
+    }
+
+    #[test]
+    fn test_bodies_over_the_limit_are_refused() {
+        let mut request: Request = tiny_http::TestRequest::new().with_method(Method::Post).with_body("{}").into();
+        assert_eq!(read_body(&mut request), Ok("{}".to_string()));
+
+        let oversized = "x".repeat(MAX_BODY as usize + 1);
+        let mut request: Request = tiny_http::TestRequest::new().with_method(Method::Post).with_body(&oversized).into();
+        assert_eq!(read_body(&mut request).unwrap_err().0, 413);
+    }
+
+    #[test]
+    fn test_inserts_refused_for_consent_are_forbidden() {
+        let refused = duckdb::Error::ToSqlConversionFailure(Box::new(ConsentWithheld {
+            device_id: "alice_phone".to_string(),
+            table: "gps_data".to_string(),
+            level: ConsentLevel::Never,
+        }));
+        let e = CollectionError::from(refused);
+        assert!(matches!(e, CollectionError::ConsentWithheld { level: ConsentLevel::Never, .. }));
+        assert_eq!(status_for(&e), 403);
+        assert_eq!(status_for(&CollectionError::from(duckdb::Error::InvalidQuery)), 500);
+    }
+
+    #[test]
+    fn test_requests_only_act_for_the_token_device() -> Result<(), CollectionError> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_device(&device("alice_phone", "alice"))?;
+        db.insert_device(&device("alice_watch", "alice"))?;
+        let alice = || UserContext::new("alice");
+        let denied = |result: Result<Value, CollectionError>| matches!(result, Err(CollectionError::Access(AccessError::Denied(_))));
+
+        assert!(denied(handle(&db, alice(), "alice_phone", Route::Plan("alice_watch".to_string()), "")));
+        assert!(denied(handle(&db, alice(), "alice_phone", Route::Heartbeat("alice_watch".to_string()), "")));
+        let rows = r#"[{ "timestamp": "2024-05-01T12:00:00Z", "device_id": "alice_watch", "x": 0, "y": 0, "z": 1 }]"#;
+        assert!(denied(handle(&db, alice(), "alice_phone", Route::Ingest("accelerometer_data".to_string()), rows)));
+        assert!(handle(&db, alice(), "alice_phone", Route::Heartbeat("alice_phone".to_string()), "").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_owntracks_posts_are_stored_for_the_token_device() -> Result<(), CollectionError> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        let issued = AuthService::new(&db).issue_device_token("alice", "owntracks-alice-pixel").unwrap();
+        let (owner, token) = AuthService::new(&db).authenticate_device(&issued.token).unwrap();
+        let post = |body: &str| handle(&db, UserContext::from(&owner), &token.device_id, Route::OwnTracks, body);
+        let at = |tst: i64| DateTime::from_timestamp(tst, 0).unwrap();
+        let (start, end) = (at(0), Utc::now());
+        let stored = || -> duckdb::Result<(usize, usize)> {
+            let device = "owntracks-alice-pixel";
+            Ok((db.get_gps_data(device, start, end)?.len(), db.get_battery_data(device, start, end)?.len()))
+        };
+
+        // The topic names another phone, but the token decides the device
+        let body = r#"[
+            {"_type":"location","tid":"px","lat":52.37,"lon":4.89,"tst":1717232400,"acc":10,"vel":18,"batt":80,"bs":1,
+             "topic":"owntracks/alice/tablet"},
+            {"_type":"transition","event":"enter","desc":"Home","lat":52.37,"lon":4.89,"tst":1717232460,"acc":10,"t":"c"},
+            {"_type":"waypoint","desc":"Home","lat":52.37,"lon":4.89,"rad":80,"tst":1700000000},
+            {"_type":"location","lat":1},
+            {"_type":"lwt","tst":1717232400}
+        ]"#;
+        assert_eq!(post(body)?, json!([]));
+        assert!(db.row_exists("gps_data", "owntracks-alice-pixel", at(1717232460))?);
+        assert!(db.row_exists("battery_data", "owntracks-alice-pixel", at(1717232400))?);
+        assert_eq!(stored()?, (2, 1));
+        let alice = db.scoped(UserContext::new("alice"));
+        assert!(alice.get_device("owntracks-alice-pixel")?.capabilities.has_gps);
+        assert!(alice.get_device("owntracks-alice-tablet").is_err());
+
+        // Resent after a dropped response, with the waypoint renamed: nothing
+        // is stored twice and the place note follows the new name
+        post(&body.replace(r#""desc":"Home","lat":52.37,"lon":4.89,"rad""#, r#""desc":"Flat","lat":52.37,"lon":4.89,"rad""#))?;
+        assert_eq!(stored()?, (2, 1));
+        let place = alice.get_note("owntracks-alice-pixel-waypoint-1700000000")?;
+        assert_eq!(place.content, "Flat");
+        let targets: Vec<NoteTarget> = alice.get_note_references(&place.id)?.iter().filter_map(|r| r.target()).collect();
+        assert_eq!(targets, vec![NoteTarget::Location { latitude: 52.37, longitude: 4.89, radius_m: 80.0 }]);
+
+        // Battery kept on the phone is dropped from uploads, fixes still stored
+        alice.set_consent(CONSENT_WILDCARD, "battery_data", ConsentLevel::LocalOnly, false)?;
+        post(r#"{"_type":"location","lat":51.5,"lon":-0.12,"tst":1717236000,"batt":40}"#)?;
+        assert_eq!(stored()?, (3, 1));
+
+        assert!(matches!(post("not json"), Err(CollectionError::InvalidRow(_))));
+        Ok(())
+    }
+}
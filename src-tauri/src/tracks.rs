@@ -0,0 +1,505 @@
+// Location tracks out of gps_data as GPX, KML or GeoJSON, for mapping tools.
+
+use std::fmt::Write;
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use quick_xml::escape::escape;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::datatypes::{
+    note::{Note, NoteReference, NoteTarget},
+    sensor::GpsData,
+};
+use crate::db::{AccessError, Database, UserContext};
+use crate::geo::haversine_m;
+
+/// Longest note title kept in a waypoint name; the full text goes in its
+/// description.
+const MAX_NAME_CHARS: usize = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackFormat {
+    Gpx,
+    Kml,
+    #[serde(alias = "geo_json")]
+    GeoJson,
+}
+
+impl TrackFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            TrackFormat::Gpx => "gpx",
+            TrackFormat::Kml => "kml",
+            TrackFormat::GeoJson => "geojson",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackOptions {
+    pub format: TrackFormat,
+    /// Starts a new trip after this many seconds without a fix. Unset
+    /// keeps the whole range as one track.
+    #[serde(default)]
+    pub trip_gap_secs: Option<i64>,
+    /// Adds the user's notes about times in the range as waypoints.
+    #[serde(default)]
+    pub include_notes: bool,
+}
+
+/// A run of fixes without a gap longer than the options allow.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TripSummary {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub fixes: usize,
+    pub distance_m: f64,
+}
+
+impl TripSummary {
+    fn of(trip: &[GpsData]) -> Self {
+        Self {
+            start: trip[0].timestamp,
+            end: trip[trip.len() - 1].timestamp,
+            fixes: trip.len(),
+            distance_m: distance_m(trip),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackExport {
+    pub format: TrackFormat,
+    pub device_id: String,
+    pub trips: Vec<TripSummary>,
+    pub waypoints: usize,
+    pub document: String,
+}
+
+/// A note placed on the track.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Waypoint {
+    pub note_id: String,
+    pub time: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub name: String,
+    pub description: String,
+}
+
+pub struct TrackExporter<'a> {
+    db: &'a Database,
+    user: UserContext,
+}
+
+impl<'a> TrackExporter<'a> {
+    pub fn new(db: &'a Database, user: UserContext) -> Self {
+        Self { db, user }
+    }
+
+    /// Renders the device's fixes in `[start, end]`. Fixes go through the
+    /// owner's privacy zones as any export does, and waypoints are placed at
+    /// the exported fix nearest the note's time rather than at a location
+    /// the note names, so the zones shield them too.
+    pub fn export(
+        &self,
+        device_id: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        options: &TrackOptions,
+    ) -> Result<TrackExport, AccessError> {
+        let scoped = self.db.scoped(self.user.clone());
+        let mut fixes = scoped.export_gps_data(device_id, start, end)?;
+        fixes.sort_by_key(|fix| fix.timestamp);
+        let trips = split_trips(&fixes, options.trip_gap_secs.map(Duration::seconds));
+
+        let mut waypoints = Vec::new();
+        if options.include_notes {
+            for note in scoped.get_notes_mentioning(start, end)? {
+                let references = self.db.get_note_references(&note.id)?;
+                if let Some(waypoint) = waypoint(&note, &references, &fixes, start, end) {
+                    waypoints.push(waypoint);
+                }
+            }
+        }
+
+        let title = format!("{} {} to {}", device_id, start.format("%Y-%m-%d"), end.format("%Y-%m-%d"));
+        let document = match options.format {
+            TrackFormat::Gpx => to_gpx(&title, &trips, &waypoints),
+            TrackFormat::Kml => to_kml(&title, &trips, &waypoints),
+            TrackFormat::GeoJson => to_geojson(&trips, &waypoints).to_string(),
+        };
+        Ok(TrackExport {
+            format: options.format,
+            device_id: device_id.to_string(),
+            trips: trips.iter().map(|trip| TripSummary::of(trip)).collect(),
+            waypoints: waypoints.len(),
+            document,
+        })
+    }
+}
+
+/// Splits time-ordered fixes wherever consecutive ones are more than `gap`
+/// apart.
+pub fn split_trips(fixes: &[GpsData], gap: Option<Duration>) -> Vec<&[GpsData]> {
+    match gap {
+        Some(gap) => fixes.chunk_by(|a, b| b.timestamp - a.timestamp <= gap).collect(),
+        None if fixes.is_empty() => Vec::new(),
+        None => vec![fixes],
+    }
+}
+
+pub fn distance_m(trip: &[GpsData]) -> f64 {
+    trip.windows(2)
+        .map(|pair| haversine_m(pair[0].latitude, pair[0].longitude, pair[1].latitude, pair[1].longitude))
+        .sum()
+}
+
+/// Where on the track a note goes: the earliest time it references inside
+/// the range, at the fix nearest that time. Notes about places alone have
+/// no time to place them by and are left out.
+fn waypoint(
+    note: &Note,
+    references: &[NoteReference],
+    fixes: &[GpsData],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Option<Waypoint> {
+    let time = references
+        .iter()
+        .filter_map(|reference| match reference.target()? {
+            NoteTarget::TimeWindow { start: from, end: to } if from <= end && to >= start => Some(from.max(start)),
+            NoteTarget::SensorEvent { timestamp, .. } if timestamp >= start && timestamp <= end => Some(timestamp),
+            _ => None,
+        })
+        .min()?;
+    let fix = nearest_fix(fixes, time)?;
+
+    let title = note.content.lines().find(|line| !line.trim().is_empty()).unwrap_or_default().trim();
+    let mut name: String = title.chars().take(MAX_NAME_CHARS).collect();
+    if name.len() < title.len() {
+        name.push('…');
+    }
+    Some(Waypoint {
+        note_id: note.id.clone(),
+        time,
+        latitude: fix.latitude,
+        longitude: fix.longitude,
+        name,
+        description: note.content.clone(),
+    })
+}
+
+fn nearest_fix(fixes: &[GpsData], time: DateTime<Utc>) -> Option<&GpsData> {
+    let i = fixes.partition_point(|fix| fix.timestamp < time);
+    [i.checked_sub(1), Some(i)]
+        .into_iter()
+        .flatten()
+        .filter_map(|i| fixes.get(i))
+        .min_by_key(|fix| (fix.timestamp - time).abs())
+}
+
+fn time(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+/// GPX 1.1, a `<trk>` per trip. Speed and course have no place in a 1.1
+/// track point, so they go in its extensions as GPX 1.0 named them.
+pub fn to_gpx(title: &str, trips: &[&[GpsData]], waypoints: &[Waypoint]) -> String {
+    let mut gpx = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    gpx.push_str("<gpx version=\"1.1\" creator=\"Loom\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n");
+    let _ = writeln!(gpx, "  <metadata><name>{}</name></metadata>", escape(title));
+
+    for waypoint in waypoints {
+        let _ = writeln!(
+            gpx,
+            "  <wpt lat=\"{}\" lon=\"{}\"><time>{}</time><name>{}</name><desc>{}</desc></wpt>",
+            waypoint.latitude,
+            waypoint.longitude,
+            time(waypoint.time),
+            escape(&waypoint.name),
+            escape(&waypoint.description),
+        );
+    }
+
+    for (i, trip) in trips.iter().enumerate() {
+        let _ = writeln!(gpx, "  <trk>\n    <name>Trip {}</name>\n    <trkseg>", i + 1);
+        for fix in trip.iter() {
+            let _ = write!(gpx, "      <trkpt lat=\"{}\" lon=\"{}\">", fix.latitude, fix.longitude);
+            if let Some(altitude) = fix.altitude {
+                let _ = write!(gpx, "<ele>{}</ele>", altitude);
+            }
+            let _ = write!(gpx, "<time>{}</time>", time(fix.timestamp));
+            if let Some(satellites) = fix.satellites {
+                let _ = write!(gpx, "<sat>{}</sat>", satellites);
+            }
+            if fix.speed.is_some() || fix.bearing.is_some() {
+                gpx.push_str("<extensions>");
+                if let Some(speed) = fix.speed {
+                    let _ = write!(gpx, "<speed>{}</speed>", speed);
+                }
+                if let Some(bearing) = fix.bearing {
+                    let _ = write!(gpx, "<course>{}</course>", bearing);
+                }
+                gpx.push_str("</extensions>");
+            }
+            gpx.push_str("</trkpt>\n");
+        }
+        gpx.push_str("    </trkseg>\n  </trk>\n");
+    }
+    gpx.push_str("</gpx>\n");
+    gpx
+}
+
+/// KML 2.2 with a `gx:Track` per trip, which keeps each fix's time. Speed
+/// rides along as a `gx:SimpleArrayData`, empty where a fix has none.
+pub fn to_kml(title: &str, trips: &[&[GpsData]], waypoints: &[Waypoint]) -> String {
+    let mut kml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    kml.push_str("<kml xmlns=\"http://www.opengis.net/kml/2.2\" xmlns:gx=\"http://www.google.com/kml/ext/2.2\">\n<Document>\n");
+    let _ = writeln!(kml, "  <name>{}</name>", escape(title));
+    kml.push_str("  <Schema id=\"fix\"><gx:SimpleArrayField name=\"speed\" type=\"float\"><displayName>Speed (m/s)</displayName></gx:SimpleArrayField></Schema>\n");
+
+    for waypoint in waypoints {
+        let _ = writeln!(
+            kml,
+            "  <Placemark><name>{}</name><description>{}</description><TimeStamp><when>{}</when></TimeStamp><Point><coordinates>{},{}</coordinates></Point></Placemark>",
+            escape(&waypoint.name),
+            escape(&waypoint.description),
+            time(waypoint.time),
+            waypoint.longitude,
+            waypoint.latitude,
+        );
+    }
+
+    for (i, trip) in trips.iter().enumerate() {
+        let altitude_mode = if trip.iter().all(|fix| fix.altitude.is_some()) { "absolute" } else { "clampToGround" };
+        let _ = writeln!(kml, "  <Placemark>\n    <name>Trip {}</name>", i + 1);
+        let _ = writeln!(
+            kml,
+            "    <TimeSpan><begin>{}</begin><end>{}</end></TimeSpan>",
+            time(trip[0].timestamp),
+            time(trip[trip.len() - 1].timestamp),
+        );
+        let _ = writeln!(kml, "    <gx:Track>\n      <altitudeMode>{}</altitudeMode>", altitude_mode);
+        for fix in trip.iter() {
+            let _ = writeln!(kml, "      <when>{}</when>", time(fix.timestamp));
+        }
+        for fix in trip.iter() {
+            let _ = writeln!(kml, "      <gx:coord>{} {} {}</gx:coord>", fix.longitude, fix.latitude, fix.altitude.unwrap_or(0.0));
+        }
+        kml.push_str("      <ExtendedData><SchemaData schemaUrl=\"#fix\"><gx:SimpleArrayData name=\"speed\">");
+        for fix in trip.iter() {
+            match fix.speed {
+                Some(speed) => {
+                    let _ = write!(kml, "<gx:value>{}</gx:value>", speed);
+                }
+                None => kml.push_str("<gx:value/>"),
+            }
+        }
+        kml.push_str("</gx:SimpleArrayData></SchemaData></ExtendedData>\n    </gx:Track>\n  </Placemark>\n");
+    }
+    kml.push_str("</Document>\n</kml>\n");
+    kml
+}
+
+/// A FeatureCollection with a LineString per trip, or a Point for a trip of
+/// a single fix, and a Point per waypoint. Per-fix times and speeds are
+/// arrays in the trip's properties, as GeoJSON has nowhere else for them.
+pub fn to_geojson(trips: &[&[GpsData]], waypoints: &[Waypoint]) -> Value {
+    let mut features = Vec::new();
+    for (i, trip) in trips.iter().enumerate() {
+        let with_altitude = trip.iter().all(|fix| fix.altitude.is_some());
+        let position = |fix: &GpsData| match fix.altitude {
+            Some(altitude) if with_altitude => json!([fix.longitude, fix.latitude, altitude]),
+            _ => json!([fix.longitude, fix.latitude]),
+        };
+        let geometry = match trip {
+            [fix] => json!({ "type": "Point", "coordinates": position(fix) }),
+            _ => json!({ "type": "LineString", "coordinates": trip.iter().map(position).collect::<Vec<_>>() }),
+        };
+        let summary = TripSummary::of(trip);
+        features.push(json!({
+            "type": "Feature",
+            "geometry": geometry,
+            "properties": {
+                "kind": "trip",
+                "trip": i + 1,
+                "start": time(summary.start),
+                "end": time(summary.end),
+                "fixes": summary.fixes,
+                "distance_m": summary.distance_m,
+                "times": trip.iter().map(|fix| time(fix.timestamp)).collect::<Vec<_>>(),
+                "speeds": trip.iter().map(|fix| fix.speed).collect::<Vec<_>>(),
+            },
+        }));
+    }
+    for waypoint in waypoints {
+        features.push(json!({
+            "type": "Feature",
+            "geometry": { "type": "Point", "coordinates": [waypoint.longitude, waypoint.latitude] },
+            "properties": {
+                "kind": "note",
+                "note_id": waypoint.note_id,
+                "name": waypoint.name,
+                "description": waypoint.description,
+                "time": time(waypoint.time),
+            },
+        }));
+    }
+    json!({ "type": "FeatureCollection", "features": features })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::datatypes::types::NotePriority;
+    use crate::db::fixtures::{device, temp_database, user};
+
+    fn fix(minute: u32, latitude: f64, longitude: f64) -> GpsData {
+        GpsData {
+            timestamp: Utc.with_ymd_and_hms(2024, 5, 1, 9, minute, 0).unwrap(),
+            device_id: "phone".to_string(),
+            latitude,
+            longitude,
+            altitude: Some(35.5),
+            accuracy: None,
+            speed: Some(1.4),
+            bearing: None,
+            satellites: None,
+            provider: None,
+            metadata: None,
+        }
+    }
+
+    fn waypoint() -> Waypoint {
+        Waypoint {
+            note_id: "n1".to_string(),
+            time: Utc.with_ymd_and_hms(2024, 5, 1, 9, 1, 0).unwrap(),
+            latitude: 51.5075,
+            longitude: -0.1279,
+            name: "Coffee & cake".to_string(),
+            description: "Coffee & cake <3".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_split_trips_on_gaps() {
+        let fixes = vec![fix(0, 51.5, -0.12), fix(1, 51.501, -0.12), fix(30, 51.6, -0.2), fix(31, 51.601, -0.2), fix(32, 51.602, -0.2)];
+        let trips = split_trips(&fixes, Some(Duration::minutes(10)));
+        assert_eq!(trips.iter().map(|trip| trip.len()).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(split_trips(&fixes, None).len(), 1);
+        assert!(split_trips(&[], None).is_empty());
+
+        let summary = TripSummary::of(trips[0]);
+        assert!((summary.distance_m - 111.2).abs() < 1.0);
+        assert_eq!(summary.end - summary.start, Duration::minutes(1));
+    }
+
+    #[test]
+    fn test_nearest_fix() {
+        let fixes = vec![fix(0, 1.0, 1.0), fix(10, 2.0, 2.0), fix(20, 3.0, 3.0)];
+        let at = |minute| Utc.with_ymd_and_hms(2024, 5, 1, 9, minute, 0).unwrap();
+        assert_eq!(nearest_fix(&fixes, at(4)).unwrap().latitude, 1.0);
+        assert_eq!(nearest_fix(&fixes, at(6)).unwrap().latitude, 2.0);
+        assert_eq!(nearest_fix(&fixes, at(59)).unwrap().latitude, 3.0);
+        assert!(nearest_fix(&[], at(0)).is_none());
+    }
+
+    #[test]
+    fn test_gpx_and_kml_documents() {
+        let fixes = vec![fix(0, 51.5074, -0.1278), fix(2, 51.5080, -0.1270)];
+        let trips = split_trips(&fixes, None);
+
+        let gpx = to_gpx("phone", &trips, &[waypoint()]);
+        assert!(gpx.contains("<trkpt lat=\"51.5074\" lon=\"-0.1278\"><ele>35.5</ele><time>2024-05-01T09:00:00Z</time><extensions><speed>1.4</speed></extensions></trkpt>"));
+        assert!(gpx.contains("<name>Coffee &amp; cake</name><desc>Coffee &amp; cake &lt;3</desc>"));
+        assert!(gpx.find("<wpt").unwrap() < gpx.find("<trk>").unwrap());
+
+        let kml = to_kml("phone", &trips, &[waypoint()]);
+        assert!(kml.contains("<gx:coord>-0.1278 51.5074 35.5</gx:coord>"));
+        assert!(kml.contains("<altitudeMode>absolute</altitudeMode>"));
+        assert!(kml.contains("<coordinates>-0.1279,51.5075</coordinates>"));
+        assert_eq!(kml.matches("<when>").count(), 3);
+    }
+
+    #[test]
+    fn test_geojson_features() {
+        let mut lone = fix(40, 51.6, -0.2);
+        lone.altitude = None;
+        let fixes = vec![fix(0, 51.5074, -0.1278), fix(2, 51.5080, -0.1270), lone];
+        let trips = split_trips(&fixes, Some(Duration::minutes(5)));
+
+        let geojson = to_geojson(&trips, &[waypoint()]);
+        let features = geojson["features"].as_array().unwrap();
+        assert_eq!(features.len(), 3);
+        assert_eq!(features[0]["geometry"]["type"], "LineString");
+        assert_eq!(features[0]["geometry"]["coordinates"][0], json!([-0.1278, 51.5074, 35.5]));
+        assert_eq!(features[0]["properties"]["times"][1], "2024-05-01T09:02:00Z");
+        assert_eq!(features[1]["geometry"], json!({ "type": "Point", "coordinates": [-0.2, 51.6] }));
+        assert_eq!(features[2]["properties"]["kind"], "note");
+    }
+
+    #[test]
+    fn test_track_export_splits_trips_and_places_notes() -> Result<(), AccessError> {
+        let (_dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        db.insert_user(&user("bob"))?;
+        let alice = db.scoped(UserContext::new("alice"));
+        alice.insert_device(&device("alice_phone", "alice"))?;
+
+        let at = |minute: u32| Utc.with_ymd_and_hms(2024, 5, 1, 9, minute, 0).unwrap();
+        let fix = |minute: u32, latitude: f64| GpsData {
+            timestamp: at(minute),
+            device_id: "alice_phone".to_string(),
+            latitude,
+            longitude: -0.1,
+            altitude: None,
+            accuracy: None,
+            speed: Some(1.5),
+            bearing: None,
+            satellites: None,
+            provider: None,
+            metadata: None,
+        };
+        // Inserted out of order; the export sorts by time
+        for (minute, latitude) in [(2, 51.52), (0, 51.50), (1, 51.51), (40, 51.60), (41, 51.61)] {
+            alice.insert_gps_data(&fix(minute, latitude))?;
+        }
+
+        alice.insert_note(&Note {
+            id: "lunch".to_string(),
+            user_id: "alice".to_string(),
+            timestamp: at(50),
+            content: "Lunch at the market\nThe queue was long".to_string(),
+            priority: NotePriority::Medium,
+            parent_id: None,
+            tags: None,
+            embedding: None,
+            metadata: None,
+            created_at: at(50),
+            updated_at: at(50),
+        })?;
+        alice.attach_note("lunch", &NoteTarget::TimeWindow { start: at(39), end: at(45) }, None)?;
+
+        let options = TrackOptions { format: TrackFormat::Gpx, trip_gap_secs: Some(600), include_notes: true };
+        let (start, end) = (at(0), at(59));
+        let bob = TrackExporter::new(&db, UserContext::new("bob"));
+        assert!(matches!(bob.export("alice_phone", start, end, &options), Err(AccessError::Denied(_))));
+
+        let exporter = TrackExporter::new(&db, UserContext::new("alice"));
+        let track = exporter.export("alice_phone", start, end, &options)?;
+        assert_eq!(track.trips.iter().map(|trip| trip.fixes).collect::<Vec<_>>(), vec![3, 2]);
+        assert_eq!(track.waypoints, 1);
+        assert!(track.document.find("lat=\"51.5\"").unwrap() < track.document.find("lat=\"51.51\"").unwrap());
+        assert!(track.document.contains("<wpt lat=\"51.6\" lon=\"-0.1\"><time>2024-05-01T09:39:00Z</time><name>Lunch at the market</name>"));
+
+        let options = TrackOptions { format: TrackFormat::GeoJson, trip_gap_secs: None, include_notes: false };
+        let track = exporter.export("alice_phone", start, end, &options)?;
+        let geojson: serde_json::Value = serde_json::from_str(&track.document).unwrap();
+        assert_eq!(geojson["features"].as_array().unwrap().len(), 1);
+        assert_eq!(geojson["features"][0]["geometry"]["coordinates"].as_array().unwrap().len(), 5);
+
+        Ok(())
+    }
+}
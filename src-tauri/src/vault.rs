@@ -0,0 +1,1017 @@
+// Notes as a folder of Markdown files that Obsidian and Logseq can open,
+// kept in sync both ways.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::crypto::to_hex;
+use crate::datatypes::{
+    note::{Note, NoteTarget},
+    types::{Metadata, NotePriority},
+};
+use crate::db::{AccessError, Database, ResolvedReference, ScopedDatabase, UserContext};
+
+/// Starts the list of references Loom adds below a note's content.
+const LINKS_MARKER: &str = "<!-- loom:links -->";
+
+/// Front-matter properties Loom doesn't know, such as Obsidian's `aliases`,
+/// are kept under this metadata key so they survive the trip through Loom.
+const PROPERTIES_KEY: &str = "vault_properties";
+
+/// Longest file name taken from a note's first line.
+const MAX_NAME_CHARS: usize = 60;
+
+#[derive(Debug)]
+pub enum VaultError {
+    Access(AccessError),
+    Io(io::Error),
+}
+
+impl fmt::Display for VaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VaultError::Access(e) => write!(f, "{}", e),
+            VaultError::Io(e) => write!(f, "vault i/o error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for VaultError {}
+
+impl From<AccessError> for VaultError {
+    fn from(e: AccessError) -> Self {
+        VaultError::Access(e)
+    }
+}
+
+impl From<io::Error> for VaultError {
+    fn from(e: io::Error) -> Self {
+        VaultError::Io(e)
+    }
+}
+
+/// A file that couldn't be read as a note.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VaultFileError {
+    /// Relative to the vault.
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct VaultExportReport {
+    pub written: usize,
+    /// Files moved to a new folder because the note's parent changed.
+    pub moved: usize,
+    pub unchanged: usize,
+    /// Files edited in the vault since they were written, which an export
+    /// leaves for the next import to pick up.
+    pub kept: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct VaultImportReport {
+    pub dry_run: bool,
+    pub files_read: usize,
+    /// Files without a note id, added as new notes.
+    pub created: usize,
+    /// Notes edited or moved in the vault.
+    pub updated: usize,
+    pub unchanged: usize,
+    /// Links between notes added or removed by edits.
+    pub links_added: usize,
+    pub links_removed: usize,
+    /// Files edited in the vault whose note was also changed in Loom since
+    /// the file was written. Loom's version is kept.
+    pub conflicts: Vec<String>,
+    /// Files for notes Loom doesn't have, such as ones since deleted.
+    pub orphaned: Vec<String>,
+    pub errors: Vec<VaultFileError>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct VaultSyncReport {
+    pub import: VaultImportReport,
+    pub export: VaultExportReport,
+}
+
+/// Syncs the user's notes with a folder of Markdown files.
+///
+/// Each note is a file with its priority, tags and metadata in YAML front
+/// matter. A note's children go in a folder named after it, next to its
+/// file, and its references are listed as wiki-links below its content,
+/// where links to other notes use their file names.
+///
+/// Every file records the hash of what was written and when the note was
+/// last updated, so an import can tell which files were edited in the vault
+/// and whether the note changed in Loom meanwhile. Such conflicts keep
+/// Loom's version and leave the file alone; deleting the file, or its
+/// `updated` property, settles them for Loom or the vault.
+pub struct Vault<'a> {
+    db: &'a Database,
+    user: UserContext,
+    dir: PathBuf,
+}
+
+impl<'a> Vault<'a> {
+    pub fn new(db: &'a Database, user: UserContext, dir: &Path) -> Self {
+        Self { db, user, dir: dir.to_path_buf() }
+    }
+
+    /// Imports the vault's changes, then writes Loom's notes back to it.
+    pub fn sync(&self) -> Result<VaultSyncReport, VaultError> {
+        let import = self.import(false)?;
+        let export = self.export()?;
+        Ok(VaultSyncReport { import, export })
+    }
+
+    /// Writes a file for each of the user's notes, redacted as for any
+    /// export. A note keeps its file name once it has one, even if its first
+    /// line changes, so links to it written in the vault keep working.
+    pub fn export(&self) -> Result<VaultExportReport, VaultError> {
+        let scoped = self.db.scoped(self.user.clone());
+        let mut report = VaultExportReport::default();
+        fs::create_dir_all(&self.dir)?;
+
+        let existing = self.read_pages()?;
+        let mut files: HashMap<String, (PathBuf, Page, String)> = HashMap::new();
+        let mut taken = HashSet::new();
+        for (rel, page, text) in existing.into_iter().filter_map(|(rel, page, text)| Some((rel, page.ok()?, text))) {
+            taken.insert(path_key(&rel));
+            if let Some(id) = page.front.id.clone() {
+                files.insert(id, (rel, page, text));
+            }
+        }
+
+        let notes = scoped.export_all_notes()?;
+        let by_id: HashMap<&str, &Note> = notes.iter().map(|note| (note.id.as_str(), note)).collect();
+        let mut order: Vec<&Note> = notes.iter().collect();
+        order.sort_by_key(|note| depth(note, &by_id));
+
+        let edited = |id: &str| files.get(id).is_some_and(|(_, page, _)| page.is_edited());
+        let mut paths: HashMap<&str, PathBuf> = HashMap::new();
+        for note in &order {
+            let rel = match files.get(&note.id) {
+                Some((rel, _, _)) if edited(&note.id) => rel.clone(),
+                current => {
+                    let folder = note
+                        .parent_id
+                        .as_deref()
+                        .and_then(|parent| paths.get(parent))
+                        .map(|parent| parent.with_extension(""))
+                        .unwrap_or_default();
+                    let current = current.map(|(rel, _, _)| rel);
+                    let name = match current.and_then(|rel| rel.file_stem()) {
+                        Some(stem) => stem.to_string_lossy().into_owned(),
+                        None => file_name(note),
+                    };
+                    let own = current.map(|rel| path_key(rel));
+                    free_path(&folder, &name, &mut taken, own.as_deref())
+                }
+            };
+            paths.insert(&note.id, rel);
+        }
+
+        let index = LinkIndex::new(paths.iter().map(|(id, rel)| (rel.as_path(), *id)));
+        let mut moved_from = Vec::new();
+        for note in &order {
+            let rel = &paths[note.id.as_str()];
+            if edited(&note.id) {
+                report.kept.push(display_path(rel));
+                continue;
+            }
+            let section = links_section(&scoped, note, &index)?;
+            let text = Page::for_note(note, section).render();
+            match files.get(&note.id) {
+                Some((current, _, current_text)) if current == rel && *current_text == text => report.unchanged += 1,
+                current => {
+                    let path = self.dir.join(rel);
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::write(&path, text)?;
+                    match current {
+                        Some((current, _, _)) if current != rel => {
+                            fs::remove_file(self.dir.join(current))?;
+                            moved_from.push(current.clone());
+                            report.moved += 1;
+                        }
+                        _ => report.written += 1,
+                    }
+                }
+            }
+        }
+
+        // Folders left empty by moves
+        for rel in moved_from {
+            for dir in rel.ancestors().skip(1).take_while(|dir| !dir.as_os_str().is_empty()) {
+                if fs::remove_dir(self.dir.join(dir)).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Reads the vault's edits into Loom. Files without an id become new
+    /// notes, and edited files update theirs, including which notes they
+    /// link to and, from the folder they're in, their parent. Imported files
+    /// get their front matter rewritten with the note's id and new hash;
+    /// with `dry_run` neither Loom nor the vault is changed.
+    pub fn import(&self, dry_run: bool) -> Result<VaultImportReport, VaultError> {
+        let scoped = self.db.scoped(self.user.clone());
+        let mut report = VaultImportReport { dry_run, ..Default::default() };
+
+        let mut pages = Vec::new();
+        for (rel, page, _) in self.read_pages()? {
+            report.files_read += 1;
+            match page {
+                Ok(page) => {
+                    let id = page.front.id.clone().unwrap_or_else(|| Uuid::new_v4().to_string());
+                    pages.push((rel, id, page));
+                }
+                Err(message) => report.errors.push(VaultFileError { path: display_path(&rel), message }),
+            }
+        }
+        // Parents first, so a new note's parent is stored before it is
+        pages.sort_by_key(|(rel, _, _)| rel.components().count());
+        let index = LinkIndex::new(pages.iter().map(|(rel, id, _)| (rel.as_path(), id.as_str())));
+
+        let now = Utc::now();
+        let mut orphaned = HashSet::new();
+        let mut changed = Vec::new();
+        for (rel, id, page) in &pages {
+            let parent_id = rel
+                .parent()
+                .and_then(|dir| index.by_path.get(&path_key(dir)))
+                .filter(|parent| !orphaned.contains(**parent))
+                .map(|parent| parent.to_string());
+
+            let stored = match &page.front.id {
+                Some(id) => match scoped.get_note(id) {
+                    Ok(note) => Some(note),
+                    Err(AccessError::Denied(_)) => {
+                        orphaned.insert(id.clone());
+                        report.orphaned.push(display_path(rel));
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                },
+                None => None,
+            };
+
+            let note = match stored {
+                Some(mut note) => {
+                    let edited = page.is_edited();
+                    if !edited && note.parent_id == parent_id {
+                        report.unchanged += 1;
+                        continue;
+                    }
+                    if page.front.updated.is_some_and(|updated| note.updated_at.timestamp() > updated.timestamp()) {
+                        report.conflicts.push(display_path(rel));
+                        continue;
+                    }
+                    if edited {
+                        page.apply_to(&mut note);
+                    }
+                    note.parent_id = parent_id;
+                    note.updated_at = now;
+                    if !dry_run {
+                        scoped.update_note(&note)?;
+                    }
+                    report.updated += 1;
+                    note
+                }
+                None => {
+                    let mut note = Note {
+                        id: id.clone(),
+                        user_id: self.user.user_id.clone(),
+                        timestamp: page.front.timestamp.or(page.front.created).unwrap_or(now),
+                        content: String::new(),
+                        priority: NotePriority::Medium,
+                        parent_id,
+                        tags: None,
+                        embedding: None,
+                        metadata: None,
+                        created_at: page.front.created.unwrap_or(now),
+                        updated_at: now,
+                    };
+                    page.apply_to(&mut note);
+                    if !dry_run {
+                        scoped.insert_note(&note)?;
+                    }
+                    report.created += 1;
+                    note
+                }
+            };
+            changed.push((rel, page, note));
+        }
+
+        for (rel, page, note) in changed {
+            let linked: BTreeSet<&str> = page
+                .note_links()
+                .iter()
+                .filter_map(|target| index.resolve(target))
+                .filter(|id| *id != note.id && !orphaned.contains(*id))
+                .collect();
+            let current: BTreeSet<String> = match &page.front.id {
+                Some(_) => scoped
+                    .get_note_references(&note.id)?
+                    .into_iter()
+                    .filter(|reference| reference.reference_type == "note")
+                    .map(|reference| reference.reference_id)
+                    .collect(),
+                None => BTreeSet::new(),
+            };
+
+            for id in linked.iter().filter(|id| !current.contains(**id)) {
+                if !dry_run {
+                    scoped.attach_note(&note.id, &NoteTarget::Note { note_id: id.to_string() }, None)?;
+                }
+                report.links_added += 1;
+            }
+            // Only links that could have been written to the file, so ones to
+            // notes the vault is missing stay
+            for id in current.iter().filter(|id| !linked.contains(id.as_str()) && index.ids.contains(id.as_str())) {
+                if !dry_run {
+                    scoped.detach_note(&note.id, &NoteTarget::Note { note_id: id.clone() })?;
+                }
+                report.links_removed += 1;
+            }
+
+            if !dry_run {
+                // The file's own content, which an export may have redacted
+                let mut written = Page::for_note(&note, page.section.clone());
+                written.content = page.content.clone();
+                fs::write(self.dir.join(rel), written.sealed().render())?;
+            }
+        }
+        Ok(report)
+    }
+
+    /// The vault's Markdown files, relative to it, with their text and how
+    /// they parsed. Hidden folders such as `.obsidian` and `.trash` are
+    /// skipped.
+    fn read_pages(&self) -> io::Result<Vec<(PathBuf, Result<Page, String>, String)>> {
+        let mut files = Vec::new();
+        if self.dir.is_dir() {
+            find_markdown(&self.dir, Path::new(""), &mut files)?;
+        }
+        files.sort();
+        files
+            .into_iter()
+            .map(|rel| {
+                let text = fs::read_to_string(self.dir.join(&rel))?.replace("\r\n", "\n");
+                Ok((rel, Page::parse(&text), text))
+            })
+            .collect()
+    }
+}
+
+fn find_markdown(root: &Path, rel: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(root.join(rel))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = rel.join(&name);
+        if entry.file_type()?.is_dir() {
+            find_markdown(root, &path, files)?;
+        } else if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("md")) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// How many ancestors a note has among the exported notes.
+fn depth(note: &Note, by_id: &HashMap<&str, &Note>) -> usize {
+    let mut depth = 0;
+    let mut parent = note.parent_id.as_deref();
+    // Bounded in case the parents loop
+    while let Some(note) = parent.and_then(|id| by_id.get(id)).filter(|_| depth < by_id.len()) {
+        depth += 1;
+        parent = note.parent_id.as_deref();
+    }
+    depth
+}
+
+/// `folder/name.md`, numbered if another file has the name already.
+/// `own` is the note's current file, which it can keep.
+fn free_path(folder: &Path, name: &str, taken: &mut HashSet<String>, own: Option<&str>) -> PathBuf {
+    let mut n = 1;
+    loop {
+        let candidate = match n {
+            1 => folder.join(format!("{}.md", name)),
+            _ => folder.join(format!("{} {}.md", name, n)),
+        };
+        let key = path_key(&candidate);
+        if own == Some(key.as_str()) || taken.insert(key) {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// A file name for a note, from its first line.
+fn file_name(note: &Note) -> String {
+    let line = note.content.lines().map(|line| line.trim_start_matches('#').trim()).find(|line| !line.is_empty());
+    let name: String = line
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control() && !"/\\:*?\"<>|[]#^".contains(*c))
+        .take(MAX_NAME_CHARS)
+        .collect();
+    let name = name.trim().trim_start_matches('.').trim();
+    match name {
+        "" => note.id.clone(),
+        name => name.to_string(),
+    }
+}
+
+/// A vault path without `.md`, lower-cased, as links and the folder of a
+/// note's children name it.
+fn path_key(rel: &Path) -> String {
+    let rel = match rel.extension() {
+        Some(ext) if ext.eq_ignore_ascii_case("md") => rel.with_extension(""),
+        _ => rel.to_path_buf(),
+    };
+    display_path(&rel).to_lowercase()
+}
+
+fn display_path(rel: &Path) -> String {
+    rel.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+/// Resolves wiki-links the way Obsidian does: by path within the vault, or
+/// by file name where only one file has it.
+struct LinkIndex<'p> {
+    by_path: HashMap<String, &'p str>,
+    by_name: HashMap<String, Vec<&'p str>>,
+    names: HashMap<&'p str, String>,
+    ids: HashSet<&'p str>,
+}
+
+impl<'p> LinkIndex<'p> {
+    fn new(files: impl Iterator<Item = (&'p Path, &'p str)>) -> Self {
+        let mut index = LinkIndex { by_path: HashMap::new(), by_name: HashMap::new(), names: HashMap::new(), ids: HashSet::new() };
+        let files: Vec<_> = files.collect();
+        for &(rel, id) in &files {
+            index.by_path.insert(path_key(rel), id);
+            if let Some(stem) = rel.file_stem() {
+                index.by_name.entry(stem.to_string_lossy().to_lowercase()).or_default().push(id);
+            }
+            index.ids.insert(id);
+        }
+        for (rel, id) in files {
+            let stem = rel.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+            let name = match index.by_name.get(&stem.to_lowercase()) {
+                Some(ids) if ids.len() == 1 => stem,
+                _ => display_path(&rel.with_extension("")),
+            };
+            index.names.insert(id, name);
+        }
+        index
+    }
+
+    fn resolve(&self, target: &str) -> Option<&'p str> {
+        let key = path_key(Path::new(target.trim()));
+        if let Some(id) = self.by_path.get(&key) {
+            return Some(*id);
+        }
+        match self.by_name.get(&key)?.as_slice() {
+            [id] => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+/// The targets of `[[wiki-links]]` in some text, without their aliases,
+/// headings or block ids.
+fn wiki_links(text: &str) -> Vec<String> {
+    let mut links = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("[[") {
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("]]") else {
+            break;
+        };
+        let target = after[..end].split(['|', '#', '^']).next().unwrap_or_default().trim();
+        if !target.is_empty() && !target.contains('\n') {
+            links.push(target.to_string());
+        }
+        rest = &after[end + 2..];
+    }
+    links
+}
+
+/// The list written below a note's content: links to the notes it
+/// references that its content doesn't link to already, then its other
+/// references, with times linked to daily notes.
+fn links_section(scoped: &ScopedDatabase<'_>, note: &Note, index: &LinkIndex<'_>) -> Result<String, AccessError> {
+    let in_content: HashSet<&str> = wiki_links(&note.content).iter().filter_map(|target| index.resolve(target)).collect();
+    let day = |t: DateTime<Utc>| format!("[[{}]]", t.format("%Y-%m-%d"));
+
+    let mut lines = Vec::new();
+    for (reference, resolved) in scoped.resolve_note_references(&note.id)? {
+        let line = match (resolved, reference.target()) {
+            (ResolvedReference::Note { note: target }, _) => match index.names.get(target.id.as_str()) {
+                Some(_) if in_content.contains(target.id.as_str()) => None,
+                Some(name) => Some(format!("- [[{}]]", name)),
+                None => None,
+            },
+            (ResolvedReference::Entity { entity }, _) => Some(format!("- entity [[{}]]", entity.label)),
+            (ResolvedReference::TimeWindow { start, end }, _) if start.date_naive() == end.date_naive() => {
+                Some(format!("- time {} {}–{} UTC", day(start), start.format("%H:%M"), end.format("%H:%M")))
+            }
+            (ResolvedReference::TimeWindow { start, end }, _) => Some(format!(
+                "- time {} {} – {} {} UTC",
+                day(start),
+                start.format("%H:%M"),
+                day(end),
+                end.format("%H:%M")
+            )),
+            (ResolvedReference::SensorEvent { table, .. }, Some(NoteTarget::SensorEvent { device_id, timestamp, .. })) => {
+                Some(format!("- {} from {} {} {} UTC", table, device_id, day(timestamp), timestamp.format("%H:%M:%S")))
+            }
+            (ResolvedReference::Location { latitude, longitude, .. }, _) => {
+                Some(format!("- place [{}, {}](geo:{},{})", latitude, longitude, latitude, longitude))
+            }
+            _ => None,
+        };
+        lines.extend(line);
+    }
+    Ok(lines.join("\n"))
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct FrontMatter {
+    id: Option<String>,
+    priority: Option<NotePriority>,
+    tags: Vec<String>,
+    timestamp: Option<DateTime<Utc>>,
+    created: Option<DateTime<Utc>>,
+    updated: Option<DateTime<Utc>>,
+    /// Other properties, in file order.
+    properties: Vec<(String, Value)>,
+    metadata: Option<Metadata>,
+    /// Of what Loom last wrote, to tell edits made in the vault.
+    hash: Option<String>,
+}
+
+/// A note's file: front matter, content, and the list of links Loom adds.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Page {
+    front: FrontMatter,
+    content: String,
+    section: String,
+}
+
+impl Page {
+    /// The page to write for a note, with its hash.
+    fn for_note(note: &Note, section: String) -> Page {
+        let mut metadata = note.metadata.clone().unwrap_or_default();
+        let properties = match metadata.remove(PROPERTIES_KEY) {
+            Some(Value::Object(properties)) => properties.into_iter().collect(),
+            Some(other) => {
+                metadata.insert(PROPERTIES_KEY.to_string(), other);
+                Vec::new()
+            }
+            None => Vec::new(),
+        };
+        let page = Page {
+            front: FrontMatter {
+                id: Some(note.id.clone()),
+                priority: Some(note.priority.clone()),
+                tags: note.tags.clone().unwrap_or_default(),
+                timestamp: Some(note.timestamp),
+                created: Some(note.created_at),
+                updated: Some(note.updated_at),
+                properties,
+                metadata: (!metadata.is_empty()).then_some(metadata),
+                hash: None,
+            },
+            content: canonical(&note.content),
+            section: canonical(&section),
+        };
+        page.sealed()
+    }
+
+    /// The page with its hash, taken from the page as it reads back so
+    /// values that don't print exactly, like some floats, don't look edited.
+    fn sealed(&self) -> Page {
+        let mut page = Page::parse(&self.render()).unwrap_or_else(|_| self.clone());
+        page.front.hash = Some(page.hash());
+        page
+    }
+
+    fn hash(&self) -> String {
+        let properties: serde_json::Map<String, Value> = self.front.properties.iter().cloned().collect();
+        let fields = json!([
+            self.front.priority.as_ref().map(priority_name),
+            self.front.tags,
+            properties,
+            self.front.metadata,
+            self.content,
+            self.section,
+        ]);
+        to_hex(&Sha256::digest(fields.to_string().as_bytes()))[..16].to_string()
+    }
+
+    /// Whether the page differs from what Loom last wrote.
+    fn is_edited(&self) -> bool {
+        self.front.hash.as_deref() != Some(self.hash().as_str())
+    }
+
+    /// The notes linked to in the content and the links list.
+    fn note_links(&self) -> Vec<String> {
+        let listed = self.section.lines().map(str::trim).filter(|line| line.starts_with("- [[") && line.ends_with("]]"));
+        wiki_links(&self.content).into_iter().chain(listed.flat_map(wiki_links)).collect()
+    }
+
+    fn apply_to(&self, note: &mut Note) {
+        note.content = self.content.clone();
+        note.priority = self.front.priority.clone().unwrap_or(NotePriority::Medium);
+        note.tags = (!self.front.tags.is_empty()).then(|| self.front.tags.clone());
+        let mut metadata = self.front.metadata.clone().unwrap_or_default();
+        if !self.front.properties.is_empty() {
+            metadata.insert(PROPERTIES_KEY.to_string(), Value::Object(self.front.properties.iter().cloned().collect()));
+        }
+        note.metadata = (!metadata.is_empty()).then_some(metadata);
+    }
+
+    fn render(&self) -> String {
+        let front = &self.front;
+        let time = |t: &DateTime<Utc>| t.to_rfc3339_opts(SecondsFormat::Secs, true);
+        let mut lines = Vec::new();
+        if let Some(id) = &front.id {
+            lines.push(format!("id: {}", yaml_value(&Value::from(id.as_str()))));
+        }
+        if let Some(priority) = &front.priority {
+            lines.push(format!("priority: {}", priority_name(priority)));
+        }
+        if !front.tags.is_empty() {
+            lines.push(format!("tags: {}", json!(front.tags)));
+        }
+        for (key, value) in [("timestamp", &front.timestamp), ("created", &front.created), ("updated", &front.updated)] {
+            if let Some(t) = value {
+                lines.push(format!("{}: {}", key, time(t)));
+            }
+        }
+        for (key, value) in &front.properties {
+            lines.push(format!("{}: {}", key, yaml_value(value)));
+        }
+        if let Some(metadata) = &front.metadata {
+            lines.push(format!("metadata: {}", json!(metadata)));
+        }
+        if let Some(hash) = &front.hash {
+            lines.push(format!("loom_hash: {}", hash));
+        }
+
+        let mut text = format!("---\n{}\n---\n{}\n", lines.join("\n"), self.content);
+        if !self.section.is_empty() {
+            text.push_str(&format!("\n{}\n{}\n", LINKS_MARKER, self.section));
+        }
+        text
+    }
+
+    fn parse(text: &str) -> Result<Page, String> {
+        let lines: Vec<&str> = text.split('\n').collect();
+        let (front, body) = match lines.first() {
+            Some(first) if first.trim_end() == "---" => {
+                let end = lines[1..]
+                    .iter()
+                    .position(|line| line.trim_end() == "---")
+                    .ok_or("front matter isn't closed")?;
+                (parse_front_matter(&lines[1..=end])?, &lines[end + 2..])
+            }
+            _ => (FrontMatter::default(), &lines[..]),
+        };
+        let (content, section) = match body.iter().position(|line| line.trim() == LINKS_MARKER) {
+            Some(marker) => (&body[..marker], &body[marker + 1..]),
+            None => (body, &[][..]),
+        };
+        Ok(Page { front, content: canonical(&content.join("\n")), section: canonical(&section.join("\n")) })
+    }
+}
+
+/// Text as compared and stored: without the blank lines editors leave
+/// after front matter or at the end.
+fn canonical(text: &str) -> String {
+    text.trim_start_matches('\n').trim_end().to_string()
+}
+
+fn priority_name(priority: &NotePriority) -> String {
+    serde_json::to_value(priority).unwrap().as_str().unwrap().to_lowercase()
+}
+
+/// Reads the subset of YAML that Obsidian and Logseq write as properties:
+/// `key: value` lines with scalars, flow lists and block lists.
+fn parse_front_matter(lines: &[&str]) -> Result<FrontMatter, String> {
+    let mut front = FrontMatter::default();
+    let mut lines = lines.iter().peekable();
+    while let Some(line) = lines.next() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) if !line.starts_with([' ', '\t']) => (key.trim(), value.trim()),
+            _ => return Err(format!("unsupported front matter line {:?}", line)),
+        };
+        let value = if value.is_empty() {
+            let mut items = Vec::new();
+            while let Some(item) = lines.peek().and_then(|line| line.trim_start().strip_prefix('-')) {
+                items.push(parse_scalar(item));
+                lines.next();
+            }
+            if items.is_empty() {
+                Value::Null
+            } else {
+                Value::Array(items)
+            }
+        } else {
+            parse_scalar(value)
+        };
+
+        let invalid = || format!("invalid {} {}", key, value);
+        match key {
+            "id" => {
+                front.id = match &value {
+                    Value::String(id) => Some(id.clone()),
+                    Value::Number(id) => Some(id.to_string()),
+                    _ => return Err(invalid()),
+                }
+            }
+            "priority" => {
+                let name = value.as_str().ok_or_else(invalid)?.to_uppercase();
+                front.priority = Some(serde_json::from_value(Value::String(name)).map_err(|_| invalid())?);
+            }
+            "tags" | "tag" => front.tags = tag_list(&value),
+            "timestamp" | "created" | "updated" => {
+                let time = Some(parse_time(&value).ok_or_else(invalid)?);
+                match key {
+                    "timestamp" => front.timestamp = time,
+                    "created" => front.created = time,
+                    _ => front.updated = time,
+                }
+            }
+            "metadata" => match &value {
+                Value::Object(metadata) => front.metadata = Some(metadata.clone().into_iter().collect()),
+                Value::Null => {}
+                _ => return Err(invalid()),
+            },
+            "loom_hash" => front.hash = value.as_str().map(str::to_string),
+            _ => front.properties.push((key.to_string(), value)),
+        }
+    }
+    Ok(front)
+}
+
+fn parse_scalar(text: &str) -> Value {
+    let text = text.trim();
+    if text.starts_with(['"', '[', '{']) {
+        if let Ok(value) = serde_json::from_str(text) {
+            return value;
+        }
+    }
+    if let Some(items) = text.strip_prefix('[').and_then(|text| text.strip_suffix(']')) {
+        return Value::Array(items.split(',').map(str::trim).filter(|item| !item.is_empty()).map(parse_scalar).collect());
+    }
+    if let Some(quoted) = text.strip_prefix('\'').and_then(|text| text.strip_suffix('\'')) {
+        return Value::String(quoted.replace("''", "'"));
+    }
+    match text {
+        "" | "~" | "null" => Value::Null,
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => serde_json::from_str::<serde_json::Number>(text)
+            .map(Value::Number)
+            .unwrap_or_else(|_| Value::String(text.to_string())),
+    }
+}
+
+/// A value as YAML: strings plain where they read back the same, anything
+/// else as JSON, which YAML reads as flow style.
+fn yaml_value(value: &Value) -> String {
+    match value {
+        Value::String(text)
+            if !text.starts_with(['[', ']', '{', '}', '"', '\'', '#', '&', '*', '!', '|', '>', '%', '@', '`', '-', '?', ':', ','])
+                && !text.ends_with(':')
+                && !text.contains(": ")
+                && !text.contains(" #")
+                && !text.contains('\n')
+                && parse_scalar(text) == *value =>
+        {
+            text.clone()
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Tags as a list, or as Obsidian also takes them, separated by commas or
+/// spaces, with or without a `#`.
+fn tag_list(value: &Value) -> Vec<String> {
+    let tags: Vec<String> = match value {
+        Value::Array(items) => items.iter().map(|item| item.as_str().map_or_else(|| item.to_string(), str::to_string)).collect(),
+        Value::String(text) => text.split([',', ' ']).map(str::to_string).collect(),
+        Value::Null => Vec::new(),
+        other => vec![other.to_string()],
+    };
+    tags.iter()
+        .map(|tag| tag.trim().trim_start_matches('#').to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+fn parse_time(value: &Value) -> Option<DateTime<Utc>> {
+    let text = value.as_str()?;
+    DateTime::parse_from_rfc3339(text)
+        .map(|t| t.with_timezone(&Utc))
+        .ok()
+        .or_else(|| Some(NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?.and_hms_opt(0, 0, 0)?.and_utc()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::db::fixtures::{temp_database, user};
+
+    fn note(content: &str) -> Note {
+        let at = Utc.with_ymd_and_hms(2024, 6, 1, 9, 0, 0).unwrap();
+        let mut metadata = Metadata::new();
+        metadata.insert("source".to_string(), Value::from("phone"));
+        metadata.insert(PROPERTIES_KEY.to_string(), json!({ "aliases": ["Allotment"], "rating": 4 }));
+        Note {
+            id: "n1".to_string(),
+            user_id: "alice".to_string(),
+            timestamp: at,
+            content: content.to_string(),
+            priority: NotePriority::High,
+            parent_id: None,
+            tags: Some(vec!["garden".to_string(), "summer plans".to_string()]),
+            embedding: None,
+            metadata: Some(metadata),
+            created_at: at,
+            updated_at: at,
+        }
+    }
+
+    #[test]
+    fn test_page_reads_back_as_written() {
+        let page = Page::for_note(&note("# Garden\n\nTomatoes by [[Shed|the shed]]"), "- [[Compost]]".to_string());
+        let text = page.render();
+        assert!(text.starts_with("---\nid: n1\npriority: high\ntags: [\"garden\",\"summer plans\"]\n"));
+        assert!(text.contains("\naliases: [\"Allotment\"]\nrating: 4\nmetadata: {\"source\":\"phone\"}\n"));
+        assert!(text.ends_with("Tomatoes by [[Shed|the shed]]\n\n<!-- loom:links -->\n- [[Compost]]\n"));
+
+        let read = Page::parse(&text).unwrap();
+        assert_eq!(read, page);
+        assert!(!read.is_edited());
+        assert_eq!(read.note_links(), vec!["Shed", "Compost"]);
+
+        let mut applied = note("");
+        applied.metadata = None;
+        read.apply_to(&mut applied);
+        assert_eq!(applied.content, "# Garden\n\nTomatoes by [[Shed|the shed]]");
+        assert_eq!(applied.metadata, note("").metadata);
+
+        let edited = Page::parse(&text.replace("Tomatoes", "Beans")).unwrap();
+        assert!(edited.is_edited());
+    }
+
+    #[test]
+    fn test_front_matter_as_obsidian_writes_it() {
+        let text = "---\ntags:\n  - garden\n  - \"#summer\"\naliases: 'Bob''s plot'\ncssclasses:\npriority: LOW\n---\n\nJust text\n";
+        let page = Page::parse(text).unwrap();
+        assert_eq!(page.front.tags, vec!["garden", "summer"]);
+        assert_eq!(page.front.priority, Some(NotePriority::Low));
+        assert_eq!(
+            page.front.properties,
+            vec![("aliases".to_string(), json!("Bob's plot")), ("cssclasses".to_string(), Value::Null)]
+        );
+        assert_eq!(page.content, "Just text");
+        assert!(page.is_edited());
+
+        assert_eq!(Page::parse("No front matter").unwrap().content, "No front matter");
+        assert!(Page::parse("---\nid: n1\n").is_err());
+        assert!(Page::parse("---\npriority: urgent\n---\n").is_err());
+    }
+
+    #[test]
+    fn test_yaml_values() {
+        for value in [json!("plain text"), json!("true"), json!("12"), json!("a: b"), json!("- item"), json!(""), json!([1, "two"])] {
+            assert_eq!(parse_scalar(&yaml_value(&value)), value);
+        }
+        assert_eq!(yaml_value(&json!("2024-06-01T09:00:00Z")), "2024-06-01T09:00:00Z");
+        assert_eq!(parse_scalar("[a, 'b', 3]"), json!(["a", "b", 3]));
+    }
+
+    #[test]
+    fn test_links_resolve_by_path_or_unique_name() {
+        let files = [("Garden.md", "n1"), ("Garden/Shed.md", "n2"), ("Trips/Shed.md", "n3"), ("Compost.md", "n4")];
+        let index = LinkIndex::new(files.iter().map(|(rel, id)| (Path::new(*rel), *id)));
+        assert_eq!(index.resolve("garden"), Some("n1"));
+        assert_eq!(index.resolve("Trips/Shed"), Some("n3"));
+        assert_eq!(index.resolve("Shed"), None);
+        assert_eq!(index.names["n2"], "Garden/Shed");
+        assert_eq!(index.names["n4"], "Compost");
+
+        assert_eq!(wiki_links("See [[Compost#Layers|layers]], ![[Garden]] and [[ ]] or [[unclosed"), vec!["Compost", "Garden"]);
+    }
+
+    #[test]
+    fn test_file_names() {
+        assert_eq!(file_name(&note("## Plans: 2024/25?\nmore")), "Plans 202425");
+        assert_eq!(file_name(&note("\n\n")), "n1");
+
+        let mut taken = HashSet::from(["garden".to_string()]);
+        assert_eq!(free_path(Path::new(""), "Garden", &mut taken, None), PathBuf::from("Garden 2.md"));
+        assert_eq!(free_path(Path::new(""), "Garden", &mut taken, Some("garden")), PathBuf::from("Garden.md"));
+    }
+
+    #[test]
+    fn test_vault_round_trips_notes_and_takes_edits_back() -> Result<(), AccessError> {
+        let (dir, db) = temp_database()?;
+        db.insert_user(&user("alice"))?;
+        let alice = db.scoped(UserContext::new("alice"));
+        let at = Utc.with_ymd_and_hms(2024, 6, 1, 9, 0, 0).unwrap();
+        let note = |id: &str, content: &str, parent_id: Option<&str>| Note {
+            id: id.to_string(),
+            user_id: "alice".to_string(),
+            timestamp: at,
+            content: content.to_string(),
+            priority: NotePriority::Medium,
+            parent_id: parent_id.map(str::to_string),
+            tags: None,
+            embedding: None,
+            metadata: None,
+            created_at: at,
+            updated_at: at,
+        };
+        alice.insert_note(&note("n1", "# Garden\n\nTomatoes", None))?;
+        alice.insert_note(&note("n2", "Shed repairs", Some("n1")))?;
+        alice.insert_note(&note("n3", "Compost", None))?;
+        alice.attach_note("n1", &NoteTarget::Note { note_id: "n3".to_string() }, None)?;
+        alice.attach_note("n1", &NoteTarget::TimeWindow { start: at, end: at + chrono::Duration::hours(2) }, None)?;
+
+        let root = dir.path().join("vault");
+        let vault = Vault::new(&db, UserContext::new("alice"), &root);
+        assert_eq!(vault.export().unwrap().written, 3);
+        let garden = std::fs::read_to_string(root.join("Garden.md")).unwrap();
+        assert!(garden.contains("\n<!-- loom:links -->\n- [[Compost]]\n- time [[2024-06-01]] 09:00–11:00 UTC\n"));
+        assert!(root.join("Garden/Shed repairs.md").exists());
+        assert_eq!(vault.export().unwrap().unchanged, 3);
+        assert_eq!(vault.import(false).unwrap().unchanged, 3);
+
+        // Edited in Obsidian: a link in the text instead of the list, a new
+        // note under Garden, and the shed moved out from under it
+        std::fs::write(
+            root.join("Garden.md"),
+            garden.replace("Tomatoes", "Tomatoes, tools in [[Shed repairs]]").replace("- [[Compost]]\n", ""),
+        )
+        .unwrap();
+        std::fs::write(root.join("Garden/Beans.md"), "Beans by the fence, see [[Compost]]\n").unwrap();
+        std::fs::rename(root.join("Garden/Shed repairs.md"), root.join("Shed repairs.md")).unwrap();
+
+        let preview = vault.import(true).unwrap();
+        assert_eq!((preview.created, preview.updated, preview.unchanged), (1, 2, 1));
+        assert_eq!(alice.get_note("n1")?.content, "# Garden\n\nTomatoes");
+
+        let report = vault.import(false).unwrap();
+        assert_eq!((report.created, report.updated, report.links_added, report.links_removed), (1, 2, 2, 1));
+        assert_eq!(alice.get_note("n1")?.content, "# Garden\n\nTomatoes, tools in [[Shed repairs]]");
+        assert_eq!(alice.get_note("n2")?.parent_id, None);
+        let linked: Vec<String> = alice.get_note_references("n1")?.into_iter().map(|r| r.reference_id).collect();
+        assert!(linked.contains(&"n2".to_string()) && !linked.contains(&"n3".to_string()));
+        let beans = alice.get_all_notes()?.into_iter().find(|note| note.content.starts_with("Beans")).unwrap();
+        assert_eq!(beans.parent_id.as_deref(), Some("n1"));
+        assert_eq!(alice.get_note_references(&beans.id)?[0].reference_id, "n3");
+        assert!(std::fs::read_to_string(root.join("Garden/Beans.md")).unwrap().starts_with(&format!("---\nid: {}\n", beans.id)));
+
+        assert_eq!(vault.import(false).unwrap().unchanged, 4);
+        let export = vault.export().unwrap();
+        assert_eq!((export.written, export.moved), (0, 0));
+
+        // Changed on both sides: Loom's version wins and the file is left
+        let mut compost = alice.get_note("n3")?;
+        compost.content = "Compost, turned".to_string();
+        compost.updated_at = Utc::now() + chrono::Duration::hours(1);
+        alice.update_note(&compost)?;
+        let path = root.join("Compost.md");
+        std::fs::write(&path, std::fs::read_to_string(&path).unwrap().replace("Compost\n", "Compost heap\n")).unwrap();
+        assert_eq!(vault.import(false).unwrap().conflicts, vec!["Compost.md"]);
+        assert_eq!(alice.get_note("n3")?.content, "Compost, turned");
+        assert_eq!(vault.export().unwrap().kept, vec!["Compost.md"]);
+
+        Ok(())
+    }
+}
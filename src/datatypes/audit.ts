@@ -0,0 +1,61 @@
+import { AuditAction } from './types';
+
+export interface AuditPolicy {
+    user_id: string;
+    tables: string[];
+    daily_roots: boolean;
+    updated_at: Date;
+}
+
+export interface AuditEntry {
+    user_id: string;
+    seq: number;
+    action: AuditAction;
+    table_name: string;
+    device_id: string;
+    row_digests: string[];
+    recorded_at: Date;
+    prev_hash: string;
+    hash: string;
+}
+
+export interface AuditRoot {
+    user_id: string;
+    day: string;
+    root: string;
+    entries: number;
+    first_seq: number;
+    last_seq: number;
+    sealed_at: Date;
+}
+
+export interface AuditMismatch {
+    table_name: string;
+    device_id: string;
+    missing: number;
+    unexpected: number;
+}
+
+export interface AuditReport {
+    user_id: string;
+    entries: number;
+    broken_at?: number;
+    bad_roots: string[];
+    mismatches: AuditMismatch[];
+    verified_at: Date;
+}
+
+export interface MerkleStep {
+    hash: string;
+    left: boolean;
+}
+
+export interface AuditProof {
+    table_name: string;
+    device_id: string;
+    row: string;
+    row_digest: string;
+    entry: AuditEntry;
+    root?: AuditRoot;
+    path: MerkleStep[];
+}
@@ -0,0 +1,29 @@
+export interface BackupPart {
+    table_name: string;
+    day?: string;
+    rows: number;
+    object: string;
+}
+
+export interface BackupManifest {
+    id: string;
+    created_at: Date;
+    schema_version: number;
+    app_version: string;
+    parent?: string;
+    parts: BackupPart[];
+}
+
+export interface BackupSummary {
+    snapshot: BackupManifest;
+    new_objects: number;
+    reused_objects: number;
+    bytes_written: number;
+}
+
+export interface BackupCheck {
+    snapshots: number;
+    objects: number;
+    problems: string[];
+    checked_at: Date;
+}
@@ -1,4 +1,5 @@
-import { BaseEntity, CompressionAlgorithm, SyncPriority } from './types';
+import { BaseEntity, CompressionAlgorithm, ConsentLevel, SyncPriority } from './types';
+import { PrivacyZone } from './privacy';
 
 export class RetentionConfig implements BaseEntity {
     table_name!: string;
@@ -31,4 +32,46 @@ export class SyncPriorityConfig implements BaseEntity {
     constructor(data: Partial<SyncPriorityConfig>) {
         Object.assign(this, data);
     }
-} 
\ No newline at end of file
+} 
+
+export interface SensorPreference {
+    user_id: string;
+    table_name: string;
+    enabled: boolean;
+    interval_ms?: number;
+    updated_at: Date;
+}
+
+export interface SensorSchedule {
+    table_name: string;
+    interval_ms: number;
+    batch_size: number;
+    max_delay_seconds?: number;
+    local_only: boolean;
+}
+
+export interface CollectionPlan {
+    device_id: string;
+    generated_at: Date;
+    sensors: SensorSchedule[];
+    privacy_zones: PrivacyZone[];
+}
+
+export interface Consent {
+    user_id: string;
+    device_id: string;
+    table_name: string;
+    level: ConsentLevel;
+    updated_at: Date;
+}
+
+export interface ConsentChange {
+    id: string;
+    user_id: string;
+    device_id: string;
+    table_name: string;
+    previous_level?: ConsentLevel;
+    level: ConsentLevel;
+    purged_rows: number;
+    changed_at: Date;
+}
@@ -9,9 +9,11 @@ export class Device implements BaseEntity {
     app_version!: string;
     available_sensors!: string[];
     capabilities!: Record<string, any>;
+    name?: string;
     created_at!: Date;
     last_seen!: Date;
     updated_at!: Date;
+    retired_at?: Date;
 
     constructor(data: Partial<Device>) {
         Object.assign(this, data);
@@ -43,4 +43,20 @@ export class KnownEntity implements BaseEntity {
     constructor(data: Partial<KnownEntity>) {
         Object.assign(this, data);
     }
-} 
\ No newline at end of file
+} 
+export type NoteTarget =
+    | { type: 'sensor_event'; table: string; device_id: string; timestamp: Date }
+    | { type: 'time_window'; start: Date; end: Date }
+    | { type: 'location'; latitude: number; longitude: number; radius_m: number }
+    | { type: 'entity'; entity_id: string }
+    | { type: 'note'; note_id: string };
+
+export interface EntitySighting {
+    sighting_id: string;
+    entity_id: string;
+    device_id: string;
+    timestamp: Date;
+    source: string;
+    distance?: number;
+    metadata?: Record<string, any>;
+}
@@ -0,0 +1,52 @@
+import { PiiDetector, ZoneMode } from './types';
+
+export type DeletionScope =
+    | { type: 'time_range'; start: Date; end: Date; device_id?: string }
+    | { type: 'device'; device_id: string }
+    | { type: 'table'; table: string }
+    | { type: 'contact'; contact: string }
+    | { type: 'place'; latitude: number; longitude: number; radius_m: number }
+    | { type: 'everything' };
+
+export interface RemovedRows {
+    table_name: string;
+    rows: number;
+}
+
+export interface DeletionReceipt {
+    id: string;
+    user_id: string;
+    scope: DeletionScope;
+    removed: RemovedRows[];
+    total_rows: number;
+    deleted_at: Date;
+}
+
+export interface Tombstone {
+    id: string;
+    scope: DeletionScope;
+    deleted_at: Date;
+}
+
+export interface PrivacyZone {
+    id: string;
+    user_id: string;
+    name: string;
+    latitude?: number;
+    longitude?: number;
+    radius_m?: number;
+    ssid?: string;
+    bssid?: string;
+    mode: ZoneMode;
+    noise_m?: number;
+    created_at: Date;
+    updated_at: Date;
+}
+
+export interface RedactionPolicy {
+    user_id: string;
+    detectors: PiiDetector[];
+    on_ingest: boolean;
+    on_export: boolean;
+    updated_at: Date;
+}
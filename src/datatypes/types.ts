@@ -53,6 +53,31 @@ export enum SyncPriority {
     BACKGROUND = 'BACKGROUND'
 }
 
+export enum ConsentLevel {
+    COLLECT = 'COLLECT',
+    LOCAL_ONLY = 'LOCAL_ONLY',
+    NEVER = 'NEVER'
+}
+
+export enum ZoneMode {
+    DROP = 'DROP',
+    SNAP = 'SNAP',
+    FUZZ = 'FUZZ'
+}
+
+export enum PiiDetector {
+    PHONE_NUMBER = 'PHONE_NUMBER',
+    EMAIL = 'EMAIL',
+    CARD_NUMBER = 'CARD_NUMBER',
+    CONTACT_NAME = 'CONTACT_NAME'
+}
+
+export enum AuditAction {
+    INSERT = 'INSERT',
+    DELETE = 'DELETE',
+    EXPORT = 'EXPORT'
+}
+
 export enum CompressionAlgorithm {
     NONE = 'NONE',
     LZ4 = 'LZ4',